@@ -1,93 +1,428 @@
 #[macro_use]
 extern crate criterion;
 
-use criterion::Criterion;
+use criterion::{ Criterion, ParameterizedBenchmark, Throughput };
 
 extern crate rand;
 use rand::prelude::*;
+use rand::distributions::Alphanumeric;
 
 extern crate kvs;
 use kvs::{
     KvStore,
+    LOG_FORMAT_VERSION,
+    Pair,
+    KvsClient,
     KvsEngine,
-    SledKvsEngine
+    SledKvsEngine,
+    thread_pool::{
+        ThreadPool,
+        NaiveThreadPool,
+        SharedQueueThreadPool,
+        RayonThreadPool
+    }
 };
 
+use assert_cmd::prelude::*;
+use crossbeam_utils::sync::WaitGroup;
+use slog::{ o, Discard, Logger };
+use serde_json::json;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::net::{ TcpListener, TcpStream };
+use std::path::Path;
+use std::process::{ Command, Stdio };
+use std::sync::Arc;
+use std::thread;
+use std::time::{ Duration, Instant };
 use tempfile::TempDir;
 
-fn kvs_benchmarks(c: &mut Criterion) {
+/// Number of distinct pairs written/read per benchmark iteration, each size is benchmarked separately.
+/// Overridden by `KVS_BENCH_KEYS`, a comma separated list
+const DATASET_SIZES: [usize; 2] = [100, 1000];
 
-    let mut keys_bytes = [0u8; 100];
-    rand::thread_rng().fill_bytes(&mut keys_bytes);
+/// Number of client threads sharing one engine, each count is benchmarked separately
+const THREAD_COUNTS: [usize; 4] = [1, 2, 4, 8];
 
-    let keys: Vec<String> = keys_bytes.iter().map(|byte| byte.to_string()).collect();
+/// Operations each client thread performs per benchmark iteration, alternating set and get
+const OPS_PER_THREAD: usize = 100;
 
-    let mut values_bytes = [0u8; 100];
-    rand::thread_rng().fill_bytes(&mut values_bytes);
-    let values: Vec<String> = keys_bytes.iter().map(|byte| byte.to_string()).collect();
+/// Distinct keys overwritten by the compaction benchmark, kept small so nearly every write leaves a stale record
+const OVERWRITE_KEYS: usize = 100;
 
-    let pairs: Vec<(String, String)> = keys.clone().into_iter().zip(values.into_iter()).collect();
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store_1 = KvStore::open(temp_dir.path()).unwrap();
+/// Rounds of overwrites per iteration, enough stale records to cross the store's compaction threshold several times
+const OVERWRITE_ROUNDS: usize = 20;
+
+/// Length of each generated key
+const KEY_LENGTH: usize = 16;
+
+/// Length of each generated value, overridden by `KVS_BENCH_VALUE_SIZE`
+const VALUE_LENGTH: usize = 256;
+
+/// Share of operations in the mixed and concurrent benchmarks which are reads, overridden by `KVS_BENCH_READ_RATIO`
+const READ_RATIO: f64 = 0.5;
+
+/// Records in each log the open benchmark builds, each count is benchmarked separately.
+/// Overridden by `KVS_BENCH_OPEN_RECORDS`, a comma separated list
+const OPEN_RECORD_COUNTS: [usize; 2] = [100_000, 1_000_000];
+
+/// Records per live key in the open benchmark's logs, the rest are overwritten or removed so the index build
+/// has stale records to skip past
+const RECORDS_PER_KEY: usize = 10;
+
+/// File the timed runs are written to as JSON, overridden by `KVS_BENCH_JSON`
+const RESULTS_PATH: &str = "target/kvs-bench.json";
+
+/// Shape of the dataset and workload, read from the environment so CI can vary it without editing the benches
+#[derive(Clone)]
+struct BenchParams {
+    key_counts: Vec<usize>,
+    value_size: usize,
+    read_ratio: f64,
+    open_records: Vec<usize>
+}
+
+impl BenchParams {
+    fn from_env() -> BenchParams {
+        let key_counts = match env::var("KVS_BENCH_KEYS") {
+            Ok(counts) => counts.split(',')
+                .map(|count| count.trim().parse().expect("KVS_BENCH_KEYS should be a comma separated list of counts"))
+                .collect(),
+            Err(_) => DATASET_SIZES.to_vec()
+        };
+        let value_size = match env::var("KVS_BENCH_VALUE_SIZE") {
+            Ok(size) => size.parse().expect("KVS_BENCH_VALUE_SIZE should be a number of bytes"),
+            Err(_) => VALUE_LENGTH
+        };
+        let read_ratio = match env::var("KVS_BENCH_READ_RATIO") {
+            Ok(ratio) => ratio.parse().expect("KVS_BENCH_READ_RATIO should be a number from 0 to 1"),
+            Err(_) => READ_RATIO
+        };
+        assert!((0.0..=1.0).contains(&read_ratio), "KVS_BENCH_READ_RATIO should be from 0 to 1");
+        let open_records = match env::var("KVS_BENCH_OPEN_RECORDS") {
+            Ok(counts) => counts.split(',')
+                .map(|count| count.trim().parse().expect("KVS_BENCH_OPEN_RECORDS should be a comma separated list of counts"))
+                .collect(),
+            Err(_) => OPEN_RECORD_COUNTS.to_vec()
+        };
+        BenchParams { key_counts, value_size, read_ratio, open_records }
+    }
+
+    /// Whether operation `i` of a run is a read, spreading the reads evenly through the run
+    fn is_read(&self, i: usize) -> bool {
+        ((i + 1) as f64 * self.read_ratio).floor() > (i as f64 * self.read_ratio).floor()
+    }
+}
+
+fn random_string(rng: &mut ThreadRng, length: usize) -> String {
+    rng.sample_iter(&Alphanumeric).take(length).collect()
+}
+
+/// Generate `size` pairs with distinct random keys and random values of `value_size`
+fn generate_pairs(size: usize, value_size: usize) -> Vec<(String, String)> {
+    let mut rng = rand::thread_rng();
+
+    let mut keys = HashSet::with_capacity(size);
+    while keys.len() < size {
+        keys.insert(random_string(&mut rng, KEY_LENGTH));
+    }
 
-    c.bench_function_over_inputs("kvs_write", move |b, pairs| {
+    keys.into_iter()
+        .map(|key| (key, random_string(&mut rng, value_size)))
+        .collect()
+}
+
+fn engine_benchmarks<E: KvsEngine>(c: &mut Criterion, name: &str, open: fn(&Path) -> E) {
+    let params = BenchParams::from_env();
+    let value_size = params.value_size;
+
+    c.bench_function_over_inputs(&format!("{}_write", name), move |b, &size| {
+        let pairs = generate_pairs(size, value_size);
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open(temp_dir.path());
         b.iter(|| {
-            for pair in pairs {
-                store_1.set(pair.0.clone(), pair.1.clone()).unwrap();
+            for pair in &pairs {
+                store.set(pair.0.clone(), pair.1.clone()).unwrap();
             }
         });
     },
-    vec![pairs]);
+    params.key_counts.clone());
 
-    let mut store_2 = KvStore::open(temp_dir.path()).unwrap();
-    c.bench_function_over_inputs("kvs_read", move |b, keys| {
+    c.bench_function_over_inputs(&format!("{}_read", name), move |b, &size| {
+        let pairs = generate_pairs(size, value_size);
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open(temp_dir.path());
+        for pair in &pairs {
+            store.set(pair.0.clone(), pair.1.clone()).unwrap();
+        }
         b.iter(|| {
-            for key in keys {
-                store_2.get(key.clone()).unwrap().unwrap();
+            for pair in &pairs {
+                store.get(pair.0.clone()).unwrap().unwrap();
             }
         });
     },
-    vec![keys]);
+    params.key_counts.clone());
+
+    let mixed = params.clone();
+    c.bench_function_over_inputs(&format!("{}_mixed", name), move |b, &size| {
+        let pairs = generate_pairs(size, mixed.value_size);
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open(temp_dir.path());
+        for pair in &pairs {
+            store.set(pair.0.clone(), pair.1.clone()).unwrap();
+        }
+        b.iter(|| mixed_run(&store, &pairs, &mixed));
+    },
+    params.key_counts.clone());
     println!("Benchmarks finished");
 }
 
-fn sled_benchmarks(c: &mut Criterion) {
+/// Read or write each pair in turn, reading `params.read_ratio` of them
+fn mixed_run<E: KvsEngine>(store: &E, pairs: &[(String, String)], params: &BenchParams) {
+    for (i, pair) in pairs.iter().enumerate() {
+        if params.is_read(i) {
+            store.get(pair.0.clone()).unwrap().unwrap();
+        } else {
+            store.set(pair.0.clone(), pair.1.clone()).unwrap();
+        }
+    }
+}
+
+/// Many client threads on pool `P` hammering one shared engine, reports ops/sec through criterion's throughput
+fn concurrent_benchmark<E: KvsEngine, P: ThreadPool>(c: &mut Criterion, name: &str, open: fn(&Path) -> E) {
+    let params = Arc::new(BenchParams::from_env());
 
-    let mut keys_bytes = [0u8; 100];
-    rand::thread_rng().fill_bytes(&mut keys_bytes);
+    let benchmark = ParameterizedBenchmark::new(name, move |b, &threads| {
+        let pool = P::new(threads).unwrap();
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = open(temp_dir.path());
+        let pairs = Arc::new(generate_pairs(OPS_PER_THREAD, params.value_size));
+        for pair in pairs.iter() {
+            store.set(pair.0.clone(), pair.1.clone()).unwrap();
+        }
 
-    let keys: Vec<String> = keys_bytes.iter().map(|byte| byte.to_string()).collect();
+        b.iter(|| {
+            let wg = WaitGroup::new();
+            for _ in 0..threads {
+                let store = store.clone();
+                let pairs = pairs.clone();
+                let params = params.clone();
+                let wg = wg.clone();
+                pool.spawn(move || {
+                    mixed_run(&store, &pairs, &params);
+                    drop(wg);
+                });
+            }
+            wg.wait();
+        });
+    },
+    THREAD_COUNTS.to_vec())
+    .throughput(|&threads| Throughput::Elements((threads * OPS_PER_THREAD) as u32));
 
-    let mut values_bytes = [0u8; 100];
-    rand::thread_rng().fill_bytes(&mut values_bytes);
-    let values: Vec<String> = keys_bytes.iter().map(|byte| byte.to_string()).collect();
+    c.bench("concurrent", benchmark);
+}
 
-    let pairs: Vec<(String, String)> = keys.clone().into_iter().zip(values.into_iter()).collect();
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store_1 = SledKvsEngine::open(temp_dir.path()).unwrap();
+fn concurrent_benchmarks(c: &mut Criterion) {
+    concurrent_benchmark::<_, NaiveThreadPool>(c, "kvs_naive", |path| KvStore::open(path).unwrap());
+    concurrent_benchmark::<_, SharedQueueThreadPool>(c, "kvs_queued", |path| KvStore::open(path).unwrap());
+    concurrent_benchmark::<_, RayonThreadPool>(c, "kvs_rayon", |path| KvStore::open(path).unwrap());
+    concurrent_benchmark::<_, NaiveThreadPool>(c, "sled_naive", |path| SledKvsEngine::open(path).unwrap());
+    concurrent_benchmark::<_, SharedQueueThreadPool>(c, "sled_queued", |path| SledKvsEngine::open(path).unwrap());
+    concurrent_benchmark::<_, RayonThreadPool>(c, "sled_rayon", |path| SledKvsEngine::open(path).unwrap());
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[rank]
+}
+
+/// Overwrites a small key space so `KvStore` compacts during the run, sled handles its own compaction
+fn compaction_benchmarks(c: &mut Criterion) {
 
-    c.bench_function_over_inputs("sled_write", move |b, pairs| {
+    c.bench_function("kvs_overwrite", |b| {
+        let pairs = generate_pairs(OVERWRITE_KEYS, VALUE_LENGTH);
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path()).unwrap();
         b.iter(|| {
-            for pair in pairs {
-                store_1.set(pair.0.clone(), pair.1.clone()).unwrap();
+            for _ in 0..OVERWRITE_ROUNDS {
+                for pair in &pairs {
+                    store.set(pair.0.clone(), pair.1.clone()).unwrap();
+                }
             }
         });
+    });
+
+    // Criterion only reports averages, time every write on its own so compaction pauses show up in the tail
+    let pairs = generate_pairs(OVERWRITE_KEYS, VALUE_LENGTH);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut latencies = Vec::with_capacity(OVERWRITE_KEYS * OVERWRITE_ROUNDS);
+    for _ in 0..OVERWRITE_ROUNDS {
+        for pair in &pairs {
+            let start = Instant::now();
+            store.set(pair.0.clone(), pair.1.clone()).unwrap();
+            latencies.push(start.elapsed());
+        }
+    }
+    latencies.sort();
+    println!(
+        "kvs_overwrite latency: p50 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.99),
+        percentile(&latencies, 0.999),
+        latencies[latencies.len() - 1]
+    );
+}
+
+/// Write a log of `records` sets and removes straight to `dir`, far quicker than going through a store
+fn build_log(dir: &Path, records: usize, value_size: usize) {
+    let keys = (records / RECORDS_PER_KEY).max(1);
+    let mut rng = rand::thread_rng();
+    let value = random_string(&mut rng, value_size);
+
+    let mut log = b"KVS\0".to_vec();
+    log.extend_from_slice(&LOG_FORMAT_VERSION.to_le_bytes());
+    for i in 0..records {
+        let key = format!("key{}", rng.gen_range(0, keys));
+        let command = if i % RECORDS_PER_KEY == 0 {
+            kvs::Command::Remove(key)
+        } else {
+            kvs::Command::Set(Pair::new(key, value.clone()))
+        };
+        let command = serde_json::to_vec(&command).unwrap();
+        log.extend_from_slice(&(command.len() as u32).to_le_bytes());
+        log.extend_from_slice(&command);
+    }
+    fs::write(dir.join("log.log"), log).unwrap();
+}
+
+/// Time `KvStore::open` on large logs, once rebuilding the index by reading every record and once loading it
+/// from the hint a clean shutdown leaves. Compaction is off so opening only builds the index
+fn open_benchmarks(c: &mut Criterion) {
+    let params = BenchParams::from_env();
+    let value_size = params.value_size;
+    let open = |path: &Path| KvStore::builder().compaction(false).open(path).unwrap();
+
+    let benchmark = ParameterizedBenchmark::new("scan", move |b, &records| {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        build_log(temp_dir.path(), records, value_size);
+        let hint_path = temp_dir.path().join("log.hint");
+        b.iter_with_setup(|| { let _ = fs::remove_file(&hint_path); }, |_| open(temp_dir.path()));
     },
-    vec![pairs]);
+    params.open_records.clone())
+    .with_function("hint", move |b, &records| {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        build_log(temp_dir.path(), records, value_size);
+
+        // Dropping the store writes the hint every later open loads
+        drop(open(temp_dir.path()));
+        b.iter(|| open(temp_dir.path()));
+    })
+    .sample_size(10)
+    .throughput(|&records| Throughput::Elements(records as u32));
+
+    c.bench("kvs_open", benchmark);
+}
 
-    let mut store_2 = SledKvsEngine::open(temp_dir.path()).unwrap();
-    c.bench_function_over_inputs("sled_read", move |b, keys| {
+/// Round trip of a small set and get through a real `kvs-server`, with and without `TCP_NODELAY` on both ends
+fn client_benchmarks(c: &mut Criterion) {
+
+    let benchmark = ParameterizedBenchmark::new("round_trip", |b, &nodelay| {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut server = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", &addr.to_string(), "--nodelay", &nodelay.to_string()])
+            .current_dir(&temp_dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        while TcpStream::connect(addr).is_err() {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let client = KvsClient::new(Logger::root(Discard, o!()), addr).nodelay(nodelay);
         b.iter(|| {
-            for key in keys {
-                store_2.get(key.clone()).unwrap().unwrap();
-            }
+            client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+            client.get("key1".to_owned()).unwrap();
         });
+
+        server.kill().unwrap();
+        server.wait().unwrap();
     },
-    vec![keys]);
-    println!("Benchmarks finished");
+    vec![true, false]);
+
+    c.bench("client", benchmark);
+}
+
+/// Time every operation of a mixed run against a fresh store, giving a result for the JSON report
+fn timed_run<E: KvsEngine>(name: &str, open: fn(&Path) -> E, keys: usize, params: &BenchParams) -> serde_json::Value {
+    let pairs = generate_pairs(keys, params.value_size);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path());
+    for pair in &pairs {
+        store.set(pair.0.clone(), pair.1.clone()).unwrap();
+    }
+
+    let mut latencies = Vec::with_capacity(pairs.len());
+    let run = Instant::now();
+    for (i, pair) in pairs.iter().enumerate() {
+        let start = Instant::now();
+        if params.is_read(i) {
+            store.get(pair.0.clone()).unwrap().unwrap();
+        } else {
+            store.set(pair.0.clone(), pair.1.clone()).unwrap();
+        }
+        latencies.push(start.elapsed());
+    }
+    let elapsed = run.elapsed();
+    latencies.sort();
+
+    let micros = |latency: Duration| latency.as_secs_f64() * 1_000_000.0;
+    json!({
+        "name": format!("{}_mixed", name),
+        "engine": name,
+        "keys": keys,
+        "value_size": params.value_size,
+        "read_ratio": params.read_ratio,
+        "ops": latencies.len(),
+        "ops_per_sec": latencies.len() as f64 / elapsed.as_secs_f64(),
+        "p50_us": micros(percentile(&latencies, 0.5)),
+        "p99_us": micros(percentile(&latencies, 0.99)),
+        "max_us": micros(latencies[latencies.len() - 1])
+    })
+}
+
+/// Write a JSON report of timed mixed runs on both engines for regression tracking, alongside criterion's own
+/// output. Each run is timed once, so compare results across many CI runs rather than trusting any one
+fn json_report(_c: &mut Criterion) {
+    let params = BenchParams::from_env();
+    let mut results = Vec::new();
+    for &keys in &params.key_counts {
+        results.push(timed_run("kvs", |path| KvStore::open(path).unwrap(), keys, &params));
+        results.push(timed_run("sled", |path| SledKvsEngine::open(path).unwrap(), keys, &params));
+    }
+    let report = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "results": results
+    });
+
+    let path = env::var("KVS_BENCH_JSON").unwrap_or_else(|_| String::from(RESULTS_PATH));
+    fs::write(&path, serde_json::to_string_pretty(&report).unwrap()).unwrap();
+    println!("Benchmark results written to {}", path);
+}
+
+fn kvs_benchmarks(c: &mut Criterion) {
+    engine_benchmarks(c, "kvs", |path| KvStore::open(path).unwrap());
+}
+
+fn sled_benchmarks(c: &mut Criterion) {
+    engine_benchmarks(c, "sled", |path| SledKvsEngine::open(path).unwrap());
 }
 
 
 
-criterion_group!(benches, kvs_benchmarks, sled_benchmarks);
-criterion_main!(benches);
\ No newline at end of file
+criterion_group!(benches, kvs_benchmarks, sled_benchmarks, concurrent_benchmarks, compaction_benchmarks, open_benchmarks, client_benchmarks, json_report);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use kvs::network::{ Operation, Response, TcpMessage };
+use slog::{ Logger, Discard, o };
+
+// Both parsers take whatever a peer sends, they must return an error rather than panic
+fuzz_target!(|data: &[u8]| {
+    let text = String::from_utf8_lossy(data).into_owned();
+    let log = Logger::root(Discard, o!());
+
+    let _ = Operation::from_text(log.clone(), text.clone());
+    let _ = Response::from_text(log, text);
+});
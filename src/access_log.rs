@@ -0,0 +1,64 @@
+//! Access log for a KvsServer, one line per request in the style of the common log format
+use std::fs::{ File, OpenOptions };
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+use crate::Result;
+use crate::network::{ Operation, ResponseStatus };
+
+/// Appends a line per request to a file, kept apart from the server's diagnostic log so it can be
+/// rotated and parsed on its own. Clones share the file
+///
+/// Lines look like
+/// ```text
+/// 127.0.0.1:51234 - - [16/Oct/2026:09:30:12 +0000] "get key1" OK 0.154
+/// ```
+/// giving the client address, when the request was served, the operation and key, the response status
+/// and the latency in milliseconds. Requests which could not be parsed are logged as `"-"`
+#[derive(Clone)]
+pub struct AccessLog {
+    file: Arc<Mutex<File>>
+}
+
+impl AccessLog {
+
+    /// Open the access log at `path` for appending, creating it if needed
+    pub fn open(path: &Path) -> Result<AccessLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(AccessLog { file: Arc::new(Mutex::new(file)) })
+    }
+
+    /// Write the line for one request, `operation` is `None` when the request could not be parsed
+    pub fn record(&self, client: SocketAddr, operation: Option<&Operation>, status: ResponseStatus, latency: Duration) -> Result<()> {
+        let request = match operation {
+            Some(operation) => match operation.key() {
+                Some(key) => format!("{} {}", operation.code(), quote(key)),
+                None => String::from(operation.code())
+            },
+            None => String::from("-")
+        };
+        let line = format!(
+            "{} - - [{}] \"{}\" {} {:.3}\n",
+            client,
+            chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+            request,
+            status.to_text(),
+            latency.as_secs_f64() * 1000.0
+        );
+
+        // One write per line so concurrent connections never interleave within a line
+        self.file.lock().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Keys are escaped as on the wire, and quotes as well so the request field stays one quoted string
+fn quote(key: &str) -> String {
+    crate::network::escape(key).replace('"', "\\\"")
+}
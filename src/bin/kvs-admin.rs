@@ -0,0 +1,160 @@
+#[macro_use]
+extern crate clap;
+use clap::ArgMatches;
+
+extern crate slog;
+extern crate slog_term;
+extern crate slog_async;
+use slog::*;
+
+use std::fs;
+use std::path::Path;
+
+use failure::{ err_msg, format_err };
+
+extern crate kvs;
+use kvs::{ 
+    Result,
+    KvStore,
+    KvsEngine,
+    SledKvsEngine
+};
+
+fn initialize_root_logger() -> Logger {
+    let decorator = slog_term::TermDecorator::new().stderr().build();
+    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    slog::Logger::root(drain, o!("app_name" => "kvs-admin", "version" => env!("CARGO_PKG_VERSION")))
+}
+
+fn main() -> Result<()> {
+    let mut log = initialize_root_logger();
+    info!(log, "Starting up!");
+
+    let version = env!("CARGO_PKG_VERSION");
+    let author = env!("CARGO_PKG_AUTHORS");
+    let about = env!("CARGO_PKG_DESCRIPTION");
+    let matches: ArgMatches = clap_app!(kvs =>
+        (version: version)
+        (author: author)
+        (about: about)
+        (@subcommand compact =>
+            (about: "Compact a kvs store's log, run it while the server is stopped")
+            (@arg DATA_DIR: --("data-dir") +takes_value "Directory the store keeps its log in")
+        )
+        (@subcommand verify =>
+            (about: "Check every record in a kvs store's log parses and its hint agrees with the log")
+            (@arg DATA_DIR: --("data-dir") +takes_value "Directory the store keeps its log in")
+        )
+        (@subcommand migrate =>
+            (about: "Copy every pair into a different engine and switch the store over to it, run it while the server is stopped")
+            (@arg FROM: --from +takes_value +required "Engine the store uses now")
+            (@arg TO: --to +takes_value +required "Engine to move the store to")
+            (@arg DATA_DIR: --("data-dir") +takes_value "Directory the store keeps its files in")
+        )
+    )
+    .get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("compact") {
+
+        let data_dir = matches.value_of("DATA_DIR").unwrap_or("./");
+
+        log = log.new(o!("subcommand" => "compact", "data_dir" => String::from(data_dir)));
+        info!(log, "CLI arguments processed");
+
+        // Only the kvs engine has a log to compact, sled compacts itself
+        let engine = std::fs::read_to_string(Path::new(data_dir).join("engine")).unwrap_or_else(|_| String::from("kvs"));
+        if engine != "kvs" {
+            return Err(err_msg("Only stores using the kvs engine can be compacted"));
+        }
+
+        let store = KvStore::open(Path::new(data_dir))?;
+        let reclaimed = store.compact()?;
+        info!(log, "Compaction finished"; "bytes_reclaimed" => reclaimed);
+        println!("Reclaimed {} bytes", reclaimed);
+        Ok(())
+
+    } else if let Some(matches) = matches.subcommand_matches("verify") {
+
+        let data_dir = matches.value_of("DATA_DIR").unwrap_or("./");
+
+        log = log.new(o!("subcommand" => "verify", "data_dir" => String::from(data_dir)));
+        info!(log, "CLI arguments processed");
+
+        let engine = std::fs::read_to_string(Path::new(data_dir).join("engine")).unwrap_or_else(|_| String::from("kvs"));
+        if engine != "kvs" {
+            return Err(err_msg("Only stores using the kvs engine can be verified"));
+        }
+
+        let report = KvStore::verify(Path::new(data_dir))?;
+        info!(log, "Verification finished"; "records" => report.records, "problems" => report.problems.len());
+        println!("Checked {} records", report.records);
+        if report.is_ok() {
+            println!("No problems found");
+            Ok(())
+        } else {
+            for problem in report.problems.iter() {
+                println!("{}", problem);
+            }
+            println!("Found {} problems", report.problems.len());
+            std::process::exit(1);
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("migrate") {
+
+        let from = matches.value_of("FROM").expect("Required field FROM not retrieved");
+        let to = matches.value_of("TO").expect("Required field TO not retrieved");
+        let data_dir = Path::new(matches.value_of("DATA_DIR").unwrap_or("./"));
+
+        log = log.new(o!("subcommand" => "migrate", "from" => String::from(from), "to" => String::from(to)));
+        info!(log, "CLI arguments processed");
+
+        let engine = fs::read_to_string(data_dir.join("engine")).unwrap_or_else(|_| String::from("kvs"));
+        if engine != from {
+            return Err(format_err!("The store uses the {} engine, not {}", engine, from));
+        }
+
+        let copied = match (from, to) {
+            ("kvs", "sled") => {
+                let destination = SledKvsEngine::open(data_dir)?;
+                let copied = copy_pairs(&KvStore::open(data_dir)?, &destination)?;
+                destination.flush()?;
+                copied
+            },
+            ("sled", "kvs") => copy_pairs(&SledKvsEngine::open(data_dir)?, &KvStore::open(data_dir)?)?,
+            _ => return Err(format_err!("Can't migrate from {} to {}, expected kvs to sled or sled to kvs", from, to))
+        };
+
+        // Only once every pair is copied, through a rename so a crash leaves one marker or the other
+        let marker = data_dir.join("engine");
+        let temp_marker = data_dir.join("engine.tmp");
+        fs::write(&temp_marker, to)?;
+        fs::rename(&temp_marker, &marker)?;
+
+        info!(log, "Migration finished"; "pairs" => copied);
+        println!("Copied {} pairs from {} to {}", copied, from, to);
+        Ok(())
+
+    } else {
+        info!(log, "Sub command not recognized");
+        std::process::exit(1);
+    }
+}
+
+/// Copy every pair from one engine into another which must hold nothing yet, returns the number copied.
+/// Values are copied as bytes so binary ones survive. The source's files are left in place
+fn copy_pairs<From: KvsEngine, To: KvsEngine>(from: &From, to: &To) -> Result<usize> {
+    if !to.keys()?.is_empty() {
+        return Err(err_msg("The engine being migrated to already holds pairs, refusing to mix them in"));
+    }
+
+    let mut copied = 0;
+    for key in from.keys()? {
+        // A key removed since the listing is simply skipped
+        if let Some(value) = from.get_bytes(key.clone())? {
+            to.set_bytes(key, value)?;
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
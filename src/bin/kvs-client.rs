@@ -1,64 +1,222 @@
 #[macro_use]
 extern crate clap;
-use clap::ArgMatches;
+use clap::{ ArgMatches, Shell };
 
 extern crate slog;
 extern crate slog_term;
 extern crate slog_async;
 use slog::*;
 
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{ self, Write };
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{ Duration, Instant };
+
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+
+use failure::{ err_msg, format_err };
+
 extern crate kvs;
 use kvs::{ 
     Result,
+    KvsClient,
+    KvsError,
     network::{ 
+        self,
         Operation,
-        TcpMessage,
         Response,
         ResponseStatus
+    },
+    thread_pool::{
+        ThreadPool,
+        RayonThreadPool
     }
 };
 
-use std::net::{ TcpStream };
-use std::time::Duration;
-
-use failure::err_msg;
-
-fn initialize_root_logger() -> Logger {
-    let decorator = slog_term::TermDecorator::new().stderr().build();
-    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-    slog::Logger::root(drain, o!("app_name" => "kvs-client", "version" => env!("CARGO_PKG_VERSION")))
+/// Distinct keys each `bench` thread cycles through, bounding how many keys a long run leaves to clean up
+const BENCH_KEYS_PER_THREAD: usize = 100;
+
+/// Exit code for `get --strict` when the key has never been set
+const EXIT_KEY_ABSENT: i32 = 2;
+
+/// Exit code for `get --strict` when the key was set and then removed
+const EXIT_KEY_DELETED: i32 = 3;
+
+/// Exit code when the server rejects the request as invalid
+const EXIT_INVALID_REQUEST: i32 = 4;
+
+/// Exit code when the server's engine fails to carry out the request
+const EXIT_SERVER_ERROR: i32 = 5;
+
+/// Log to stderr, or append to `log_file` instead when one is given
+fn initialize_root_logger(log_file: Option<&Path>) -> Result<Logger> {
+    let drain = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| format_err!("Could not open log file {}: {}", path.display(), e))?;
+            let decorator = slog_term::PlainDecorator::new(file);
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            slog_async::Async::new(drain).build().fuse()
+        },
+        None => {
+            let decorator = slog_term::TermDecorator::new().stderr().build();
+            let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+            slog_async::Async::new(drain).build().fuse()
+        }
+    };
+    Ok(slog::Logger::root(drain, o!("app_name" => "kvs-client", "version" => env!("CARGO_PKG_VERSION"))))
 }
 
 fn main() -> Result<()>{
-    let mut log = initialize_root_logger();
-    info!(log, "Starting up!");
-
     let version = env!("CARGO_PKG_VERSION");
     let author = env!("CARGO_PKG_AUTHORS");
     let about = env!("CARGO_PKG_DESCRIPTION");
-    let matches: ArgMatches = clap_app!(kvs =>
+    let mut app = clap_app!(kvs =>
         (version: version)
         (author: author)
         (about: about)
+        (@arg LOG_FILE: --("log-file") +takes_value +global "File to append the client's log to instead of stderr")
         (@subcommand set =>
             (about: "Set the value of a string key to a string")
             (@arg KEY: +required "The string key to store with")
             (@arg VALUE: +required "The value to store")
-            (@arg ADDRESS: --addr +takes_value "Address to send to")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
         )
         (@subcommand get =>
             (about: "Get the string value of a given string key")
             (@arg KEY: +required "The string key used to store the value")
-            (@arg ADDRESS: --addr +takes_value "Address to send to")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+            (@arg STRICT: --strict "Exit with a non-zero code if the key is not found")
+            (@arg MAX_PRINT: --("max-print") +takes_value "Print at most this many bytes of the value, ending a cut value with ...")
+            (@arg RAW: --raw conflicts_with[MAX_PRINT] "Print the whole value however long it is, the default")
+            (@arg WITH_TTL: --("with-ttl") "Also print how long the key has left before it expires, or that it never expires")
+        )
+        (@subcommand hincr =>
+            (about: "Add to one field of a key holding a hash of counters, creating either if missing, and print the field's new value")
+            (@arg KEY: +required "The string key holding the counters")
+            (@arg FIELD: +required "The field to add to")
+            (@arg DELTA: +allow_hyphen_values "Whole number to add, negative to subtract (default 1)")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+        )
+        (@subcommand append =>
+            (about: "Append text to the value of a string key, creating the key if it isn't set, and print the new length")
+            (@arg KEY: +required "The string key to append to")
+            (@arg TEXT: +required "The text to append")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+        )
+        (@subcommand scan =>
+            (about: "Print every key and value in the store in key order as they arrive, one tab-separated pair per line")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+        )
+        (@subcommand grep =>
+            (about: "Print every key/value pair whose value contains TEXT in key order, tab separated. Reads every value on the server")
+            (@arg TEXT: +required "Text to look for in values")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to search, each bucket is its own keyspace")
+        )
+        (@subcommand touch =>
+            (about: "Make a key expire after TTL without changing its value, printing whether the key was set")
+            (@arg KEY: +required "The string key to touch")
+            (@arg TTL: +required "How long until the key expires, such as 30s or 500ms")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+        )
+        (@subcommand setnx =>
+            (about: "Set the value of a string key only if it isn't set, printing whether it was")
+            (@arg KEY: +required "The string key to store with")
+            (@arg VALUE: +required "The value to store")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+        )
+        (@subcommand cad =>
+            (about: "Remove a key only if it holds the expected value, printing whether it was removed")
+            (@arg KEY: +required "The string key to remove")
+            (@arg EXPECTED: +required "The value the key must hold to be removed")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+        )
+        (@subcommand recent =>
+            (about: "Print the most recently written keys, most recent first, one per line")
+            (@arg COUNT: -n +takes_value "How many keys to print (default 10)")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+        )
+        (@subcommand version =>
+            (about: "Print the server's version, engine and on-disk format")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+        )
+        (@subcommand stats =>
+            (about: "Print the server's operation counts, log size and free disk space, one per line")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+        )
+        (@subcommand bench =>
+            (about: "Drive load against the server from several threads, alternating sets and gets, and print ops/sec and latency percentiles. Keys it creates are removed afterwards")
+            (@arg OPS: --ops +takes_value "Operations to send in total (default 10000)")
+            (@arg THREADS: --threads +takes_value "Threads sending operations at once, each on its own connection (default 4)")
+            (@arg VALUE_SIZE: --("value-size") +takes_value "Length of each value set in bytes (default 100)")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+        )
+        (@subcommand wait =>
+            (about: "Wait until the server answers, exiting with a non-zero code if it doesn't within the timeout")
+            (@arg TIMEOUT: --timeout +takes_value "How long to wait, such as 10s or 500ms (default 10s)")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
         )
         (@subcommand rm =>
             (about: "Remove a given key")
             (@arg KEY: +required "The string key to store with")
-            (@arg ADDRESS: --addr +takes_value "Address to send to")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the key in, each bucket is its own keyspace")
+        )
+        (@subcommand rmbatch =>
+            (about: "Remove several keys in one request, printing each key and whether it was set, tab separated")
+            (@arg KEYS: +required +multiple "The string keys to remove")
+            (@arg ADDRESS: --addr +takes_value "Address to send to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+            (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on the connection, true or false (default true)")
+            (@arg BUCKET: --bucket +takes_value "Bucket to keep the keys in, each bucket is its own keyspace")
         )
-    )
-    .get_matches();
+        (@subcommand completions =>
+            (@setting Hidden)
+            (about: "Print a completion script for the given shell")
+            (@arg SHELL: +required possible_value[bash zsh fish] "Shell to complete in")
+        )
+    );
+    let matches: ArgMatches = app.clone().get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("completions") {
+        let shell: Shell = matches.value_of("SHELL").expect("Required field SHELL not retrieved").parse().map_err(err_msg)?;
+        app.gen_completions_to("kvs-client", shell, &mut io::stdout());
+        return Ok(());
+    }
+
+    let mut log = initialize_root_logger(matches.value_of("LOG_FILE").map(Path::new))?;
+    info!(log, "Starting up!");
 
     // You can handle information about subcommands by requesting their matches by name
     // (as below), requesting just the name used, or both at the same time
@@ -70,17 +228,13 @@ fn main() -> Result<()>{
         log = log.new(o!("subcommand" => "set", "key" => String::from(key), "value" => String::from(value)));
         info!(log, "CLI arguments processed");
 
-        let stream = open_stream(log.clone(), matches)?;
-
-        let operation = Operation::Set(String::from(key), String::from(value));
-        operation.write_to_stream(log.clone(), stream.try_clone()?)?;
-
-        let response = Response::read_from_stream(log, stream)?;
+        let client = open_client(log, matches)?;
+        let response = client.send(Operation::Set(String::from(key), String::from(value)))?;
 
         if response.status == ResponseStatus::Ok {
             Ok(())
         } else {
-            Err(err_msg("Error response recieved from server"))
+            exit_on_failure(response)
         }
         
 
@@ -91,27 +245,53 @@ fn main() -> Result<()>{
         log = log.new(o!("subcommand" => "get", "key" => String::from(key)));
         info!(log, "CLI arguments processed");
 
-        let stream = open_stream(log.clone(), matches)?;
-
-        let operation = Operation::Get(String::from(key));
-        operation.write_to_stream(log.clone(), stream.try_clone()?)?;
-
-        let response = Response::read_from_stream(log, stream)?;
+        let max_print = match matches.value_of("MAX_PRINT") {
+            Some(max) => Some(max.parse::<usize>().map_err(|_| format_err!("Invalid --max-print '{}', expected a number of bytes", max))?),
+            None => None
+        };
 
-        if response.status == ResponseStatus::Ok {
-
-            match response.data {
-                Some(value) => {
-                    print!("{}", value);
-                    Ok(())
-                },
-                None => {
-                    println!("Key not found");
-                    Ok(())
-                }
-            }
+        // An expired key comes back missing, the same as one which was never set
+        let with_ttl = matches.is_present("WITH_TTL");
+        let operation = if with_ttl {
+            Operation::GetTtl(String::from(key))
         } else {
-            std::process::exit(1);
+            Operation::Get(String::from(key))
+        };
+
+        let client = open_client(log, matches)?;
+        let response = client.send(operation)?;
+        let strict = matches.is_present("STRICT");
+
+        match response.status {
+            ResponseStatus::Ok => {
+                match response.data {
+                    Some(data) if with_ttl => {
+                        let (value, ttl) = network::split_ttl(&data)?;
+                        print_value(&value, max_print)?;
+                        match ttl {
+                            Some(ttl) => println!("TTL {}ms", ttl.as_millis()),
+                            None => println!("No expiry")
+                        }
+                        Ok(())
+                    },
+                    Some(value) => print_value(&value, max_print),
+                    None => {
+                        println!("Key not found");
+                        if strict {
+                            std::process::exit(EXIT_KEY_ABSENT);
+                        }
+                        Ok(())
+                    }
+                }
+            },
+            ResponseStatus::Deleted => {
+                println!("Key not found, it was removed");
+                if strict {
+                    std::process::exit(EXIT_KEY_DELETED);
+                }
+                Ok(())
+            },
+            _ => exit_on_failure(response)
         }
 
         
@@ -123,18 +303,205 @@ fn main() -> Result<()>{
         log = log.new(o!("subcommand" => "rm", "key" => String::from(key)));
         info!(log, "CLI arguments processed");
 
-        let stream = open_stream(log.clone(), matches)?;
-
-        let operation = Operation::Remove(String::from(key));
-        operation.write_to_stream(log.clone(), stream.try_clone()?)?;
-
-        let response = Response::read_from_stream(log, stream)?;
+        let client = open_client(log, matches)?;
+        let response = client.send(Operation::Remove(String::from(key)))?;
         if response.status == ResponseStatus::Ok {
             Ok(())
         } else {
-            eprintln!("Key not found");
+            exit_on_failure(response)
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("rmbatch") {
+
+        let keys: Vec<String> = matches.values_of("KEYS").expect("Required field KEYS not retrieved").map(String::from).collect();
+
+        log = log.new(o!("subcommand" => "rmbatch", "keys" => keys.len()));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        let response = client.send(Operation::RemoveBatch(keys.clone()))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(existed)) => {
+                for (key, existed) in keys.iter().zip(network::split_fields(&existed)?) {
+                    println!("{}\t{}", key, existed);
+                }
+                Ok(())
+            },
+            (status, data) => exit_on_failure(Response { status, data })
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("append") {
+
+        let key = matches.value_of("KEY").expect("Required field KEY not retrieved");
+        let text = matches.value_of("TEXT").expect("Required field TEXT not retrieved");
+
+        log = log.new(o!("subcommand" => "append", "key" => String::from(key), "text" => String::from(text)));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        let response = client.send(Operation::Append(String::from(key), String::from(text)))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(len)) => {
+                println!("{}", len);
+                Ok(())
+            },
+            (status, data) => exit_on_failure(Response { status, data })
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("hincr") {
+
+        let key = matches.value_of("KEY").expect("Required field KEY not retrieved");
+        let field = matches.value_of("FIELD").expect("Required field FIELD not retrieved");
+        let delta = matches.value_of("DELTA").unwrap_or("1");
+        let delta: i64 = delta.parse().map_err(|_| format_err!("Invalid delta '{}', expected a whole number", delta))?;
+
+        log = log.new(o!("subcommand" => "hincr", "key" => String::from(key), "field" => String::from(field), "delta" => delta));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        println!("{}", client.hincr(String::from(key), String::from(field), delta)?);
+        Ok(())
+
+    } else if let Some(matches) = matches.subcommand_matches("scan") {
+
+        log = log.new(o!("subcommand" => "scan"));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        client.scan(|key, value| println!("{}\t{}", key, value))?;
+        Ok(())
+
+    } else if let Some(matches) = matches.subcommand_matches("grep") {
+
+        let text = matches.value_of("TEXT").expect("Required field TEXT not retrieved");
+
+        log = log.new(o!("subcommand" => "grep", "text" => String::from(text)));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        client.grep(String::from(text), |key, value| println!("{}\t{}", key, value))?;
+        Ok(())
+
+    } else if let Some(matches) = matches.subcommand_matches("setnx") {
+
+        let key = matches.value_of("KEY").expect("Required field KEY not retrieved");
+        let value = matches.value_of("VALUE").expect("Required field VALUE not retrieved");
+
+        log = log.new(o!("subcommand" => "setnx", "key" => String::from(key), "value" => String::from(value)));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        let response = client.send(Operation::SetNx(String::from(key), String::from(value)))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(set)) => {
+                println!("{}", set);
+                Ok(())
+            },
+            (status, data) => exit_on_failure(Response { status, data })
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("cad") {
+
+        let key = matches.value_of("KEY").expect("Required field KEY not retrieved");
+        let expected = matches.value_of("EXPECTED").expect("Required field EXPECTED not retrieved");
+
+        log = log.new(o!("subcommand" => "cad", "key" => String::from(key), "expected" => String::from(expected)));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        let response = client.send(Operation::Cad(String::from(key), String::from(expected)))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(removed)) => {
+                println!("{}", removed);
+                Ok(())
+            },
+            (status, data) => exit_on_failure(Response { status, data })
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("touch") {
+
+        let key = matches.value_of("KEY").expect("Required field KEY not retrieved");
+        let ttl = parse_duration(matches.value_of("TTL").expect("Required field TTL not retrieved"))?;
+
+        log = log.new(o!("subcommand" => "touch", "key" => String::from(key), "ttl_ms" => ttl.as_millis() as u64));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        let response = client.send(Operation::Touch(String::from(key), ttl))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(touched)) => {
+                println!("{}", touched);
+                Ok(())
+            },
+            (status, data) => exit_on_failure(Response { status, data })
+        }
+
+    } else if let Some(matches) = matches.subcommand_matches("version") {
+
+        log = log.new(o!("subcommand" => "version"));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        println!("{}", client.version()?);
+        Ok(())
+
+    } else if let Some(matches) = matches.subcommand_matches("stats") {
+
+        log = log.new(o!("subcommand" => "stats"));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        let stats = client.stats()?;
+        println!("reads {}", stats.engine.reads);
+        println!("writes {}", stats.engine.writes);
+        println!("removes {}", stats.engine.removes);
+        println!("bytes_written {}", stats.engine.bytes_written);
+        println!("log_bytes {}", stats.log_bytes);
+        println!("disk_free {}", stats.disk_free);
+        println!("compactions {}", stats.engine.compactions);
+        println!("bytes_reclaimed {}", stats.engine.bytes_reclaimed);
+        println!("compaction_ms {}", stats.engine.compaction_ms);
+        Ok(())
+
+    } else if let Some(matches) = matches.subcommand_matches("recent") {
+
+        let count = matches.value_of("COUNT").unwrap_or("10");
+        let count: usize = count.parse().map_err(|_| format_err!("Invalid count '{}', expected a number of keys", count))?;
+
+        log = log.new(o!("subcommand" => "recent", "count" => count));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        for key in client.recent_keys(count)? {
+            println!("{}", key);
+        }
+        Ok(())
+
+    } else if let Some(matches) = matches.subcommand_matches("bench") {
+
+        let ops = parse_count(matches, "OPS", 10_000)?;
+        let threads = parse_count(matches, "THREADS", 4)?.max(1);
+        let value_size = parse_count(matches, "VALUE_SIZE", 100)?;
+
+        log = log.new(o!("subcommand" => "bench", "ops" => ops, "threads" => threads, "value_size" => value_size));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        run_bench(client, ops, threads, value_size)
+
+    } else if let Some(matches) = matches.subcommand_matches("wait") {
+
+        let timeout = parse_duration(matches.value_of("TIMEOUT").unwrap_or("10s"))?;
+
+        log = log.new(o!("subcommand" => "wait", "timeout_ms" => timeout.as_millis() as u64));
+        info!(log, "CLI arguments processed");
+
+        let client = open_client(log, matches)?;
+        if let Err(e) = client.wait_until_ready(timeout) {
+            eprintln!("{}", e);
             std::process::exit(1);
         }
+        Ok(())
 
     } else {
         info!(log, "Sub command not recognized");
@@ -142,16 +509,160 @@ fn main() -> Result<()>{
     }
 }
 
-fn open_stream(mut log: Logger, matches: &ArgMatches) -> Result<TcpStream> {
-    let address = matches.value_of("ADDRESS").unwrap_or("127.0.0.1:4000");
-    log = log.new(o!("address" => String::from(address)));
-    info!(log, "Server address read");
+/// Parse a whole number argument, `default` if it isn't given
+fn parse_count(matches: &ArgMatches, name: &str, default: usize) -> Result<usize> {
+    match matches.value_of(name) {
+        Some(count) => count.parse().map_err(|_| format_err!("Invalid {} '{}', expected a whole number", name.to_lowercase(), count)),
+        None => Ok(default)
+    }
+}
 
-    info!(log, "Opening TCP connection...");
-    let stream = TcpStream::connect_timeout(&address.parse()?, Duration::from_secs(5))?;
-    
-    log = log.new(o!("server_addr" => stream.peer_addr()?));
-    info!(log, "TCP connection established");
+/// Send `ops` operations split across `threads`, each setting one of its own keys and then getting it back,
+/// and print the throughput and latency percentiles. Every key set is removed again, even if the run fails
+fn run_bench(client: KvsClient, ops: usize, threads: usize, value_size: usize) -> Result<()> {
+    let pool = RayonThreadPool::new(threads)?;
+    let (results, received) = mpsc::channel();
+    let value: String = rand::thread_rng().sample_iter(&Alphanumeric).take(value_size).collect();
+    let start = Instant::now();
+
+    for thread in 0..threads {
+        // The first threads take one extra op each when they don't divide evenly
+        let thread_ops = ops / threads + if thread < ops % threads { 1 } else { 0 };
+        let client = client.clone();
+        let value = value.clone();
+        let results = results.clone();
+        pool.spawn(move || {
+            let mut latencies = Vec::with_capacity(thread_ops);
+            let mut keys = HashSet::new();
+            let mut outcome = Ok(());
+            for i in 0..thread_ops {
+                let key = bench_key(thread, i / 2 % BENCH_KEYS_PER_THREAD);
+                let op_start = Instant::now();
+                let sent = if i % 2 == 0 {
+                    // Only a key which was set needs removing afterwards
+                    client.set(key.clone(), value.clone()).map(|()| {
+                        keys.insert(key);
+                    })
+                } else {
+                    client.get(key).map(|_| ())
+                };
+                latencies.push(op_start.elapsed());
+                if let Err(e) = sent {
+                    outcome = Err(e);
+                    break;
+                }
+            }
+            let _ = results.send((latencies, keys, outcome));
+        });
+    }
+    drop(results);
+
+    let mut latencies = Vec::with_capacity(ops);
+    let mut keys = Vec::new();
+    let mut failure = None;
+    for (thread_latencies, thread_keys, outcome) in received {
+        latencies.extend(thread_latencies);
+        keys.extend(thread_keys);
+        if let Err(e) = outcome {
+            failure.get_or_insert(e);
+        }
+    }
+    let elapsed = start.elapsed();
+
+    // Every key is tried even once one fails to be removed, a key already gone is as good as removed
+    let mut cleanup_failure = None;
+    for key in keys {
+        match client.remove(key) {
+            Ok(()) => {},
+            Err(e) => match e.downcast_ref::<KvsError>() {
+                Some(KvsError::KeyNotFound) => {},
+                _ => { cleanup_failure.get_or_insert(e); }
+            }
+        }
+    }
+    // The run failing explains more than the cleanup which followed it failing
+    if let Some(e) = failure.or(cleanup_failure) {
+        return Err(e);
+    }
 
-    Ok(stream)
+    latencies.sort();
+    let percentile = |p: f64| latencies.get(((latencies.len().max(1) - 1) as f64 * p).round() as usize).copied().unwrap_or_default();
+    println!("ops {}", latencies.len());
+    println!("elapsed_ms {}", elapsed.as_millis());
+    println!("ops_per_sec {:.1}", latencies.len() as f64 / elapsed.as_secs_f64());
+    println!("latency_p50_us {}", percentile(0.5).as_micros());
+    println!("latency_p99_us {}", percentile(0.99).as_micros());
+    println!("latency_p999_us {}", percentile(0.999).as_micros());
+    println!("latency_max_us {}", percentile(1.0).as_micros());
+    Ok(())
+}
+
+/// Key used by `bench`, named after this process so several benches against one server don't collide
+fn bench_key(thread: usize, n: usize) -> String {
+    format!("__kvs_bench_{}_{}_{}", std::process::id(), thread, n)
+}
+
+fn open_client(log: Logger, matches: &ArgMatches) -> Result<KvsClient> {
+    // The flag wins over the environment, which lets containers set the address once for every command
+    let address = matches.value_of("ADDRESS")
+        .map(String::from)
+        .or_else(|| env::var("KVS_ADDR").ok())
+        .unwrap_or_else(|| String::from("127.0.0.1:4000"));
+    info!(log, "Server address read"; "address" => &address);
+
+    let nodelay = matches.value_of("NODELAY").unwrap_or("true").parse()?;
+
+    let client = KvsClient::new(log, address.parse()?).nodelay(nodelay);
+    match matches.value_of("BUCKET") {
+        Some(bucket) => Ok(client.bucket(String::from(bucket))),
+        None => Ok(client)
+    }
+}
+
+/// Parse a duration such as `10s`, `500ms` or `2m`, a bare number is taken as seconds
+fn parse_duration(text: &str) -> Result<Duration> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => text.split_at(split),
+        None => (text, "s")
+    };
+    let number: u64 = number.parse().map_err(|_| format_err!("Invalid duration '{}', expected a number such as 10s or 500ms", text))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        _ => Err(format_err!("Invalid duration '{}', the unit must be ms, s or m", text))
+    }
+}
+
+/// Print a value on its own line, cut to at most `max_print` bytes if given. The cut falls back to the previous
+/// character boundary so a multi-byte character is never split
+fn print_value(value: &str, max_print: Option<usize>) -> Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match max_print {
+        Some(max) if value.len() > max => {
+            let cut = (0..=max).rev().find(|&i| value.is_char_boundary(i)).unwrap_or(0);
+            out.write_all(&value.as_bytes()[..cut])?;
+            out.write_all(b"...\n")?;
+        },
+        _ => {
+            out.write_all(value.as_bytes())?;
+            out.write_all(b"\n")?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Print why the server failed a request and exit with the code for its status, a missing key exits with 1
+fn exit_on_failure(response: Response) -> ! {
+    match response.data {
+        Some(description) => eprintln!("{}", description),
+        None => eprintln!("Error response recieved from server")
+    }
+    std::process::exit(match response.status {
+        ResponseStatus::InvalidRequest => EXIT_INVALID_REQUEST,
+        ResponseStatus::Internal => EXIT_SERVER_ERROR,
+        _ => 1
+    })
 }
@@ -1,18 +1,21 @@
 #[macro_use]
 extern crate clap;
-use clap::ArgMatches;
+use clap::{ ArgMatches, Shell };
 
 extern crate slog;
 extern crate slog_term;
 extern crate slog_async;
 use slog::*;
 
-use std::net::{ TcpListener, TcpStream };
+use std::io;
+use std::fs::{ self, OpenOptions, create_dir_all };
+use std::env;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+use std::sync::{ Arc, atomic::{ AtomicBool, Ordering } };
+use std::thread;
 
-use std::io::prelude::*;
-use std::fs::{ OpenOptions };
-
-use failure::err_msg;
+use failure::{ err_msg, format_err };
 
 extern crate num_cpus;
 
@@ -20,14 +23,12 @@ extern crate kvs;
 use kvs::{ 
     Result, 
     KvStore,
-    KvsEngine,
     SledKvsEngine,
-    network::{
-        Operation,
-        TcpMessage,
-        Response,
-        ResponseStatus
-    },
+    SledKvsEngineBuilder,
+    ServerConfig,
+    network,
+    server::{ self, ServerHandle, DEFAULT_ADDRESS, DEFAULT_MAX_IN_FLIGHT },
+    sync_strategy::{ self, SyncStrategy },
     thread_pool::{
         ThreadPool,
         SharedQueueThreadPool,
@@ -36,146 +37,495 @@ use kvs::{
     }
 };
 
-fn initialize_root_logger() -> Logger {
-    let decorator = slog_term::TermDecorator::new().stderr().build();
-    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-    slog::Logger::root(drain, o!("app_name" => "kvs-server", "version" => env!("CARGO_PKG_VERSION")))
+/// Values accepted by `--engine`
+const ENGINES: [&str; 2] = ["kvs", "sled"];
+
+/// Values accepted by `--tp`
+const POOLS: [&str; 3] = ["naive", "queued", "rayon"];
+
+/// Most connections the naive pool serves at once unless `--threads` says otherwise
+const NAIVE_MAX_THREADS: usize = 1024;
+
+/// Log to stderr, or append to `log_file` instead when one is given
+fn initialize_root_logger(log_file: Option<&Path>) -> Result<Logger> {
+    let drain = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)
+                .map_err(|e| format_err!("Could not open log file {}: {}", path.display(), e))?;
+            let decorator = slog_term::PlainDecorator::new(file);
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            slog_async::Async::new(drain).build().fuse()
+        },
+        None => {
+            let decorator = slog_term::TermDecorator::new().stderr().build();
+            let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+            slog_async::Async::new(drain).build().fuse()
+        }
+    };
+    Ok(slog::Logger::root(drain, o!("app_name" => "kvs-server", "version" => env!("CARGO_PKG_VERSION"))))
 }
 
 fn main() -> Result<()> {
 
-    let mut log = initialize_root_logger();
-    info!(log, "Starting up!");
-    
     let version = env!("CARGO_PKG_VERSION");
     let author = env!("CARGO_PKG_AUTHORS");
     let about = env!("CARGO_PKG_DESCRIPTION");
-    let matches: ArgMatches = clap_app!(kvs =>
+    let mut app = clap_app!(kvs =>
         (version: version)
         (author: author)
         (about: about)
-        (@arg ADDRESS: --addr +takes_value "Address to listen to")
-        (@arg ENGINE: --engine +takes_value "Backend engine to use")
+        // The version reports the engine in the data directory, which isn't known until the config is read
+        (@setting DisableVersion)
+        (@arg VERSION: -V --version "Print the version, along with the engine and on-disk format in the data directory")
+        (@arg CONFIG: --config +takes_value "TOML file to read settings from, flags override it")
+        (@arg ADDRESS: --addr +takes_value "Address to listen to, defaults to $KVS_ADDR or 127.0.0.1:4000")
+        (@arg ENGINE: --engine +takes_value "Backend engine to use, defaults to $KVS_ENGINE or kvs")
         (@arg THREADPOOL: --tp +takes_value "Thread pool implementation to use")
-    )
-    .get_matches();
+        (@arg THREADS: --threads +takes_value "Number of threads in the pool, defaults to the number of CPUs, or 1024 for the naive pool")
+        (@arg DATA_DIR: --("data-dir") +takes_value "Directory to keep the engine's files in, defaults to the current directory")
+        (@arg LIST_ENGINES: --("list-engines") "Print the supported engines and exit")
+        (@arg LIST_POOLS: --("list-pools") "Print the supported thread pools and exit")
+        (@arg PRINT_CONFIG: --("print-config") "Print every setting as the server would use it, after the config file, environment and flags, as TOML and exit")
+        (@arg SLED_CACHE_MB: --("sled-cache-mb") +takes_value "Size of sled's page cache in megabytes")
+        (@arg SLED_FLUSH_MS: --("sled-flush-ms") +takes_value "Milliseconds between sled flushing to disk")
+        (@arg CHECKPOINT_MS: --("checkpoint-interval") +takes_value "Milliseconds between syncing the kvs log to disk")
+        (@arg OPEN_TIMEOUT_MS: --("open-timeout") +takes_value "Milliseconds to wait for a kvs store another process still has open, such as the server being replaced (default 0)")
+        (@arg MAX_CONNECTIONS: --("max-connections") +takes_value "Turn away connections beyond this many open at once")
+        (@arg NODELAY: --nodelay +takes_value "Set TCP_NODELAY on client connections, true or false (default true)")
+        (@arg OP_LOG_SAMPLE: --("op-log-sample") +takes_value "Log one in every N operations, 0 for none (default 1). Failures, slow operations and connections are always logged")
+        (@arg SLOW_OP_MS: --("slow-op-ms") +takes_value "Log a warning for operations slower than this many milliseconds")
+        (@arg INDEX_MEMORY_MB: --("index-memory-mb") +takes_value "Megabytes of the kvs index to keep in memory, colder keys are spilled to disk")
+        (@arg ACCESS_LOG: --("access-log") +takes_value "File to append a line per request to, separate from the server log")
+        (@arg LOG_FILE: --("log-file") +takes_value "File to append the server's log to instead of stderr")
+        (@arg PID_FILE: --("pid-file") +takes_value "File to write the process ID to, removed again on SIGINT or SIGTERM")
+        (@arg MAX_REQUEST_BYTES: --("max-request-bytes") +takes_value "Refuse requests longer than this many bytes and close the connection, defaults to 64MiB")
+        (@arg MAX_IN_FLIGHT: --("max-in-flight") +takes_value "Requests to read from a connection ahead of the one being served, reading pauses beyond it (default 16)")
+        (@arg IDLE_TIMEOUT_MS: --("idle-timeout") +takes_value "Close connections which send nothing for this many milliseconds once their requests are answered, 0 to keep them open (the default)")
+        (@arg OP_TIMEOUT_MS: --("op-timeout") +takes_value "Fail engine operations which take longer than this many milliseconds rather than waiting on them, 0 to wait however long they take (the default)")
+        (@arg HTTP_ADDRESS: --("http-addr") +takes_value "Also serve GET, PUT and DELETE of /key over HTTP on this address")
+        (@arg RATE_LIMIT: --("rate-limit") +takes_value "Requests a second to allow from each client address, those beyond it get RATE_LIMITED")
+        (@arg KEY_PATTERN: --("key-pattern") +takes_value "Refuse to set keys over the kvs protocol unless the whole key matches this regular expression, such as [a-zA-Z0-9:_-]+")
+        (@arg SYNC: --sync +takes_value "When to sync the kvs log to disk after a write: never (default), always, or a number of milliseconds between syncs")
+        (@arg NO_COMPACTION: --("no-compaction") "Never compact the kvs engine's log, keeping every write at the cost of the log growing without bound")
+        (@arg SELF_TEST: --("self-test") +takes_value "Write, read back and remove a key before accepting connections, true or false (default true)")
+        (@subcommand completions =>
+            (@setting Hidden)
+            (about: "Print a completion script for the given shell")
+            (@arg SHELL: +required possible_value[bash zsh fish] "Shell to complete in")
+        )
+    );
+    let matches: ArgMatches = app.clone().get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("completions") {
+        let shell: Shell = matches.value_of("SHELL").expect("Required field SHELL not retrieved").parse().map_err(err_msg)?;
+        app.gen_completions_to("kvs-server", shell, &mut io::stdout());
+        return Ok(());
+    }
+
+    if matches.is_present("LIST_ENGINES") {
+        println!("{}", ENGINES.join("\n"));
+        return Ok(());
+    }
+    if matches.is_present("LIST_POOLS") {
+        println!("{}", POOLS.join("\n"));
+        return Ok(());
+    }
 
-    let address = matches.value_of("ADDRESS").unwrap_or("127.0.0.1:4000");
-    let engine = matches.value_of("ENGINE").unwrap_or("kvs");
-    log = log.new(o!("address" => String::from(address), "engine" => String::from(engine)));
+    let config = match matches.value_of("CONFIG") {
+        Some(path) => ServerConfig::load(Path::new(path))?,
+        None => ServerConfig::default()
+    };
+    let config = with_defaults(override_config(config, &matches)?);
+
+    if matches.is_present("VERSION") {
+        let data_dir = config.data_dir.clone().unwrap_or_else(|| PathBuf::from("./"));
+        let engine = fs::read_to_string(data_dir.join("engine")).unwrap_or_else(|_| String::from("none"));
+        println!("kvs {}", server::version_info(&engine));
+        return Ok(());
+    }
+
+    if matches.is_present("PRINT_CONFIG") {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
+    let mut log = initialize_root_logger(config.log_file.as_deref())?;
+    info!(log, "Starting up!");
+
+    let address = config.addr.clone().unwrap_or_else(|| String::from(DEFAULT_ADDRESS));
+    let engine = config.engine.clone().unwrap_or_else(|| String::from("kvs"));
+    let thread_pool_type = config.tp.clone().unwrap_or_else(|| String::from("queued"));
+    let threads = config.threads.unwrap_or_else(num_cpus::get);
+    let data_dir = config.data_dir.clone().unwrap_or_else(|| PathBuf::from("./"));
+    check_choice("engine", &engine, &ENGINES)?;
+    check_choice("thread pool", &thread_pool_type, &POOLS)?;
+    log = log.new(o!("address" => address, "engine" => engine.clone()));
     info!(log, "Command line arguments read");
 
-    let mut engine_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .append(true)
-        .truncate(false)
-        .open("./engine")?;
-    let buf = &mut String::new();
-    engine_file.read_to_string(buf)?;
+    create_dir_all(&data_dir)?;
+    check_engine_marker(&log, &data_dir, &engine)?;
 
-    if buf != engine && !buf.is_empty() {
-        return Err(err_msg("Server cannot be started in a different engine than before"));
-    } else if buf.is_empty() {
-        engine_file.write_all(engine.as_bytes())?;
+    let mut sled = SledKvsEngine::builder();
+    if let Some(mb) = config.sled_cache_mb {
+        sled = sled.cache_capacity_mb(mb);
+    }
+    if let Some(ms) = config.sled_flush_ms {
+        sled = sled.flush_every_ms(ms);
     }
 
-    let thread_pool_type = matches.value_of("THREADPOOL").unwrap_or("queued");
+    // The server always runs in the foreground, leaving daemonizing to a supervisor such as systemd
+    let _pid_file = match &config.pid_file {
+        Some(path) => Some(PidFile::create(path)?),
+        None => None
+    };
+
+    let options = EngineOptions {
+        engine,
+        data_dir,
+        sled,
+        checkpoint: config.checkpoint_interval.map(Duration::from_millis),
+        open_timeout: Duration::from_millis(config.open_timeout_ms.unwrap_or(0)),
+        index_memory: config.index_memory_mb.map(|mb| mb * 1024 * 1024),
+        compaction: config.compaction.unwrap_or(true),
+        // Never syncing is what every engine does without being asked
+        sync: match config.sync.as_deref() {
+            None | Some("never") => None,
+            Some(sync) => Some(parse_sync(sync)?)
+        }
+    };
 
-    match thread_pool_type {
+    match thread_pool_type.as_str() {
         "naive" => {
-            start_server(log.clone(),  NaiveThreadPool::new(0)?, address, engine)?;
+            start_server(log.clone(),  NaiveThreadPool::new(config.threads.unwrap_or(NAIVE_MAX_THREADS))?, &config, options)?;
         },
         "queued" => {
-            start_server(log.clone(),  SharedQueueThreadPool::new(num_cpus::get())?, address, engine)?;
+            start_server(log.clone(),  SharedQueueThreadPool::new(threads)?, &config, options)?;
         },
         "rayon" => {
-            start_server(log.clone(),  RayonThreadPool::new(num_cpus::get())?, address, engine)?;
+            start_server(log.clone(),  RayonThreadPool::new(threads)?, &config, options)?;
         },
-        _ => { return Err(err_msg("Invalid thread pool type")) }
+        _ => { return Err(invalid_choice("thread pool", &thread_pool_type, &POOLS)) }
     }
 
     info!(log, "Server terminating");
     Ok(())
 }
 
-fn start_server<Pool: ThreadPool>(log: Logger, tp: Pool, address: &str, engine: &str) -> Result<()> {
+/// Fill in the server's default for every setting which has one and was left unset
+fn with_defaults(mut config: ServerConfig) -> ServerConfig {
+    config.addr.get_or_insert_with(|| String::from(DEFAULT_ADDRESS));
+    config.engine.get_or_insert_with(|| String::from("kvs"));
+    let tp = config.tp.get_or_insert_with(|| String::from("queued"));
+    let threads = if tp == "naive" { NAIVE_MAX_THREADS } else { num_cpus::get() };
+    config.threads.get_or_insert(threads);
+    config.data_dir.get_or_insert_with(|| PathBuf::from("./"));
+    config.nodelay.get_or_insert(true);
+    config.op_log_sample.get_or_insert(1);
+    config.slow_op_ms.get_or_insert(1000);
+    config.open_timeout_ms.get_or_insert(0);
+    config.self_test.get_or_insert(true);
+    config.max_request_bytes.get_or_insert(network::DEFAULT_MAX_REQUEST_BYTES);
+    config.max_in_flight.get_or_insert(DEFAULT_MAX_IN_FLIGHT);
+    config.compaction.get_or_insert(true);
+    config.sync.get_or_insert_with(|| String::from("never"));
+    config
+}
+
+/// Settings given as flags replace those from the config file. The address and engine can also be set with
+/// `KVS_ADDR` and `KVS_ENGINE`, which replace the config file but give way to the flags
+fn override_config(mut config: ServerConfig, matches: &ArgMatches) -> Result<ServerConfig> {
+    if let Some(addr) = matches.value_of("ADDRESS").map(String::from).or_else(|| env::var("KVS_ADDR").ok()) {
+        config.addr = Some(addr);
+    }
+    if let Some(engine) = matches.value_of("ENGINE").map(String::from).or_else(|| env::var("KVS_ENGINE").ok()) {
+        config.engine = Some(engine);
+    }
+    if let Some(tp) = matches.value_of("THREADPOOL") {
+        config.tp = Some(String::from(tp));
+    }
+    if let Some(threads) = matches.value_of("THREADS") {
+        config.threads = Some(threads.parse()?);
+    }
+    if let Some(data_dir) = matches.value_of("DATA_DIR") {
+        config.data_dir = Some(PathBuf::from(data_dir));
+    }
+    if let Some(mb) = matches.value_of("SLED_CACHE_MB") {
+        config.sled_cache_mb = Some(mb.parse()?);
+    }
+    if let Some(ms) = matches.value_of("SLED_FLUSH_MS") {
+        config.sled_flush_ms = Some(ms.parse()?);
+    }
+    if let Some(ms) = matches.value_of("CHECKPOINT_MS") {
+        config.checkpoint_interval = Some(ms.parse()?);
+    }
+    if let Some(ms) = matches.value_of("OPEN_TIMEOUT_MS") {
+        config.open_timeout_ms = Some(ms.parse()?);
+    }
+    if let Some(max) = matches.value_of("MAX_CONNECTIONS") {
+        config.max_connections = Some(max.parse()?);
+    }
+    if let Some(nodelay) = matches.value_of("NODELAY") {
+        config.nodelay = Some(nodelay.parse()?);
+    }
+    if let Some(every) = matches.value_of("OP_LOG_SAMPLE") {
+        config.op_log_sample = Some(every.parse()?);
+    }
+    if let Some(ms) = matches.value_of("SLOW_OP_MS") {
+        config.slow_op_ms = Some(ms.parse()?);
+    }
+    if let Some(mb) = matches.value_of("INDEX_MEMORY_MB") {
+        config.index_memory_mb = Some(mb.parse()?);
+    }
+    if let Some(path) = matches.value_of("ACCESS_LOG") {
+        config.access_log = Some(PathBuf::from(path));
+    }
+    if let Some(path) = matches.value_of("LOG_FILE") {
+        config.log_file = Some(PathBuf::from(path));
+    }
+    if let Some(path) = matches.value_of("PID_FILE") {
+        config.pid_file = Some(PathBuf::from(path));
+    }
+    if let Some(max) = matches.value_of("MAX_REQUEST_BYTES") {
+        config.max_request_bytes = Some(max.parse()?);
+    }
+    if let Some(max) = matches.value_of("MAX_IN_FLIGHT") {
+        config.max_in_flight = Some(max.parse()?);
+    }
+    if let Some(ms) = matches.value_of("IDLE_TIMEOUT_MS") {
+        config.idle_timeout_ms = Some(ms.parse()?);
+    }
+    if let Some(ms) = matches.value_of("OP_TIMEOUT_MS") {
+        config.op_timeout_ms = Some(ms.parse()?);
+    }
+    if let Some(addr) = matches.value_of("HTTP_ADDRESS") {
+        config.http_addr = Some(String::from(addr));
+    }
+    if let Some(rate) = matches.value_of("RATE_LIMIT") {
+        config.rate_limit = Some(rate.parse()?);
+    }
+    if let Some(pattern) = matches.value_of("KEY_PATTERN") {
+        config.key_pattern = Some(String::from(pattern));
+    }
+    if let Some(sync) = matches.value_of("SYNC") {
+        config.sync = Some(String::from(sync));
+    }
+    if matches.is_present("NO_COMPACTION") {
+        config.compaction = Some(false);
+    }
+    if let Some(self_test) = matches.value_of("SELF_TEST") {
+        config.self_test = Some(self_test.parse()?);
+    }
+    Ok(config)
+}
+
+/// Make sure the data directory was last used with `engine`, recording it in the marker file if the directory is new.
+/// A directory without a marker which holds another engine's data is refused rather than claimed for `engine`, while
+/// another engine's data beside a marker naming `engine`, as a migration leaves behind, is only warned about.
+/// A marker which already names the engine is only read, so a read-only marker or filesystem is fine
+fn check_engine_marker(log: &Logger, data_dir: &Path, engine: &str) -> Result<()> {
+    let path = data_dir.join("engine");
+    let recorded = match fs::read_to_string(&path) {
+        Ok(recorded) => recorded,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format_err!("Could not read the engine marker {}: {}", path.display(), e))
+    };
+    let other = ENGINES.iter().find(|other| **other != engine && holds_engine_data(data_dir, other));
+
+    if recorded == engine {
+        if let Some(other) = other {
+            warn!(log, "The data directory also holds data for another engine, which is ignored"; "other_engine" => *other);
+        }
+        Ok(())
+    } else if !recorded.is_empty() {
+        Err(format_err!(
+            "Server cannot be started in a different engine than before, the data directory was last used with {}. \
+            Start with --engine {}, or move the store over with kvs-admin migrate --from {} --to {}",
+            recorded,
+            recorded,
+            recorded,
+            engine
+        ))
+    } else if let Some(other) = other {
+        Err(format_err!(
+            "The data directory holds data for the {} engine but doesn't record which engine it uses, refusing to \
+            start {} beside it. Start with --engine {}, or move the store over with kvs-admin migrate",
+            other,
+            engine,
+            other
+        ))
+    } else {
+        fs::write(&path, engine).map_err(|e| format_err!(
+            "Could not record the engine in {}: {}. The data directory must be writable the first time the server \
+            starts in it, or create the file holding '{}' beforehand",
+            path.display(),
+            e,
+            engine
+        ))
+    }
+}
+
+/// Whether `data_dir` holds files written by `engine`, the kvs engine's log or sled's database
+fn holds_engine_data(data_dir: &Path, engine: &str) -> bool {
     match engine {
-        "kvs" => {
-            listen_for_connections(log, address, KvStore::new()?, tp)?;
-        },
-        "sled" => {
-            listen_for_connections(log, address, SledKvsEngine::new()?, tp)?;
-        },
-        _ => { return Err(err_msg("Invalid engine type")) }
+        "kvs" => data_dir.join("log.log").exists(),
+        "sled" => data_dir.join("db").exists(),
+        _ => false
     }
-    Ok(())
 }
 
-fn listen_for_connections<Engine: KvsEngine, Pool: ThreadPool>(mut log: Logger, address: &str, store: Engine, tp: Pool) -> Result<()> {
-    info!(log, "Starting TCP server");
-    let listener = TcpListener::bind(address)?;
-    info!(log, "Waiting for connections...");
+/// Fails listing the valid choices when `value` isn't one of them, checked before anything touches the disk
+fn check_choice(kind: &str, value: &str, choices: &[&str]) -> Result<()> {
+    if choices.contains(&value) {
+        Ok(())
+    } else {
+        Err(invalid_choice(kind, value, choices))
+    }
+}
 
-    for stream in listener.incoming() {
-        let stream: TcpStream = stream?;
-        let client_addr = stream.peer_addr()?;
+/// Strategy named by `--sync`, a bare number being the milliseconds between periodic syncs
+fn parse_sync(value: &str) -> Result<Arc<dyn SyncStrategy>> {
+    match value {
+        "never" => Ok(Arc::new(sync_strategy::Never)),
+        "always" => Ok(Arc::new(sync_strategy::Always)),
+        ms => match ms.parse() {
+            Ok(ms) => Ok(Arc::new(sync_strategy::Periodic::new(Duration::from_millis(ms)))),
+            Err(_) => Err(format_err!("Invalid sync '{}', expected never, always or a number of milliseconds", value))
+        }
+    }
+}
+
+fn invalid_choice(kind: &str, value: &str, choices: &[&str]) -> failure::Error {
+    format_err!("Invalid {} '{}', expected one of: {}", kind, value, choices.join(", "))
+}
 
-        log = log.new(o!("client_addr" => client_addr));
-        info!(log, "TCP connection established");
-        let store = store.clone();
-        let log = log.clone();
+/// Holds the server's process ID in a file for as long as it runs, removing it when dropped
+struct PidFile {
+    path: PathBuf
+}
+
+impl PidFile {
 
-        tp.spawn(move || handle_connection(log, stream, store));
-        
+    /// Write this process's ID to `path`. Fails if the file names another process which is still running,
+    /// a file left behind by a server which was killed outright is replaced
+    fn create(path: &Path) -> Result<PidFile> {
+        if let Ok(pid) = fs::read_to_string(path) {
+            if let Ok(pid) = pid.trim().parse::<libc::pid_t>() {
+                // Signal 0 only checks whether the process exists
+                if pid != std::process::id() as libc::pid_t && unsafe { libc::kill(pid, 0) } == 0 {
+                    return Err(format_err!("PID file {} names process {}, which is still running", path.display(), pid));
+                }
+            }
+        }
+        fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile { path: PathBuf::from(path) })
     }
-    Ok(())
 }
 
-fn handle_connection<Engine: KvsEngine>(log: Logger, stream: TcpStream, store: Engine) {
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
 
-    let operation = Operation::read_from_stream(log.clone(), stream.try_clone().unwrap()).unwrap();
+/// Set by SIGINT or SIGTERM, passed on to the server as a shutdown
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
-    let op_result = handle_operation(log.clone(), operation, store);
+/// Set by SIGUSR1, passed on to the server as a drain
+static DRAINING: AtomicBool = AtomicBool::new(false);
 
-    let response = match op_result {
-        Ok(data) => {
-            Response {
-                status: ResponseStatus::Ok,
-                data
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn request_drain(_signal: libc::c_int) {
+    DRAINING.store(true, Ordering::SeqCst);
+}
+
+/// Catch SIGINT and SIGTERM so the server returns from `run_server` and cleans up rather than dying on the spot, and
+/// SIGUSR1 to drain. Little is safe to do inside a signal handler, so a watcher thread passes them on to `handle`
+fn handle_signals(handle: ServerHandle) {
+    unsafe {
+        libc::signal(libc::SIGINT, request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGUSR1, request_drain as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    thread::spawn(move || {
+        let mut drain_sent = false;
+        loop {
+            if SHUTDOWN.load(Ordering::SeqCst) {
+                handle.shutdown();
+                return;
             }
-        },
-        Err(_) => {
-            Response {
-                status: ResponseStatus::Fail,
-                data: None
+            if !drain_sent && DRAINING.load(Ordering::SeqCst) {
+                handle.drain();
+                drain_sent = true;
             }
+            thread::sleep(Duration::from_millis(100));
         }
-    };
-
-    response.write_to_stream(log, stream).unwrap();
+    });
 }
 
-fn handle_operation<Engine: KvsEngine>(log: Logger, operation: Operation, store: Engine) -> Result<Option<String>> {
+/// Everything read from the command line which decides how the engine is opened, the rest of the settings are
+/// left to `run_server`
+struct EngineOptions {
+    engine: String,
+    data_dir: PathBuf,
+    sled: SledKvsEngineBuilder,
+    checkpoint: Option<Duration>,
+    open_timeout: Duration,
+    index_memory: Option<usize>,
+    compaction: bool,
+    sync: Option<Arc<dyn SyncStrategy>>
+}
 
-    match operation {
-        Operation::Set(key, value) => {
-            store.set(key, value)?;
-            info!(log, "Store SET successful");
-            Ok(None)
-        },
-        Operation::Get(key) => {
-            let result = Ok(store.get(key)?);
-            info!(log, "Store GET successful");
-            result
+/// Open the engine and serve it until the server is told to stop
+fn start_server<Pool: ThreadPool>(log: Logger, tp: Pool, config: &ServerConfig, options: EngineOptions) -> Result<()> {
+    let handle = ServerHandle::new();
+    handle_signals(handle.clone());
+    match options.engine.as_str() {
+        "kvs" => {
+            let mut builder = KvStore::builder().compaction(options.compaction).lock_timeout(options.open_timeout);
+            if let Some(sync) = &options.sync {
+                builder = builder.sync_strategy(sync.clone());
+            }
+            if let Some(bytes) = options.index_memory {
+                builder = builder.index_limit(bytes);
+            }
+            let mut store = match builder.open(&options.data_dir) {
+                Ok(store) => store,
+                Err(e) => {
+                    crit!(log, "Could not open the kvs engine, server not started"; "error" => %e);
+                    return Err(format_err!("Could not open the kvs engine in {}: {}", options.data_dir.display(), e));
+                }
+            };
+            if let Some(interval) = options.checkpoint {
+                store = store.with_checkpoint_interval(interval);
+            }
+            kvs::run_server(config, log, store, tp, handle)?;
         },
-        Operation::Remove(key) => {
-            store.remove(key)?;
-            info!(log, "Store REMOVE successful");
-            Ok(None)
+        "sled" => {
+            if options.index_memory.is_some() {
+                warn!(log, "The index memory limit only applies to the kvs engine, ignoring it");
+            }
+            if !options.compaction {
+                warn!(log, "Turning compaction off only applies to the kvs engine, ignoring it");
+            }
+            if options.sync.is_some() {
+                warn!(log, "Syncing after writes only applies to the kvs engine, sled flushes on its own interval");
+            }
+            if options.open_timeout > Duration::from_millis(0) {
+                warn!(log, "The open timeout only applies to the kvs engine, sled fails at once if the store is open");
+            }
+            let store = match options.sled.clone().open(&options.data_dir) {
+                Ok(store) => store,
+                Err(e) => {
+                    crit!(log, "Could not open the sled engine, server not started"; "error" => %e);
+                    return Err(e);
+                }
+            };
+            kvs::run_server(config, log, store, tp, handle)?;
         },
+        _ => { return Err(invalid_choice("engine", &options.engine, &ENGINES)) }
     }
-    
-}
\ No newline at end of file
+    Ok(())
+}
@@ -0,0 +1,90 @@
+//! Values too large to keep in a KvStore's log are written to files of their own, and the log records only the
+//! name of the file. Blob files are never changed once written, a new value for the key gets a new file
+use std::collections::HashSet;
+use std::fs::{ self, File };
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::Result;
+
+const EXTENSION: &str = "blob";
+
+/// The directory of a store's blobs, created when the first blob is written
+pub(crate) struct BlobStore {
+    dir: PathBuf,
+    next: AtomicU64,
+}
+
+impl BlobStore {
+
+    /// Blobs kept in `dir`, new ones are numbered after any already there
+    pub fn open(dir: &Path) -> Result<BlobStore> {
+        let mut next = 0;
+        if dir.is_dir() {
+            for entry in fs::read_dir(dir)? {
+                if let Some(number) = blob_number(&entry?.path()) {
+                    next = next.max(number + 1);
+                }
+            }
+        }
+        Ok(BlobStore { dir: dir.to_path_buf(), next: AtomicU64::new(next) })
+    }
+
+    /// Name for a new blob, for the log to refer to it by. Nothing is written until `write` is called with it,
+    /// and a name never written just leaves a gap in the numbering
+    pub fn reserve(&self) -> String {
+        format!("{}.{}", self.next.fetch_add(1, Ordering::SeqCst), EXTENSION)
+    }
+
+    /// Write `value` to the blob called `name`, a name from `reserve`
+    pub fn write(&self, name: &str, value: &[u8], sync: bool) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut file = File::create(self.dir.join(name))?;
+        file.write_all(value)?;
+        if sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Read the blob called `name`
+    pub fn read(&self, name: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.dir.join(name))?)
+    }
+
+    /// Delete every blob but those named in `live`, called once the log no longer refers to the rest
+    pub fn retain(&self, live: &HashSet<String>) -> Result<()> {
+        if !self.dir.is_dir() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            if blob_number(&path).is_some() && !live.contains(name) {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Total bytes of the blobs
+    pub fn size(&self) -> Result<u64> {
+        if !self.dir.is_dir() {
+            return Ok(0);
+        }
+        let mut size = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            size += entry?.metadata()?.len();
+        }
+        Ok(size)
+    }
+}
+
+/// The number in a blob's file name, `None` for files which aren't blobs
+fn blob_number(path: &Path) -> Option<u64> {
+    if path.extension()? != EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
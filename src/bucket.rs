@@ -0,0 +1,167 @@
+//! Buckets give clients sharing one server their own keyspaces
+use std::time::Duration;
+
+use crate::{ Result, KvsEngine, KeyState, KvsError, SetOutcome, EngineStats };
+
+/// Separates a bucket's name from its keys in the underlying engine
+const SEPARATOR: char = '\0';
+
+/// Wraps an engine so every key lives in a bucket. Buckets share the inner engine's keyspace, a key is
+/// stored as `<bucket>\0<key>`. Keys sent by clients can't contain a NUL, so none can reach into a bucket
+/// from outside it
+#[derive(Clone)]
+pub struct BucketedEngine<E: KvsEngine> {
+    inner: E,
+    bucket: String,
+}
+
+impl<E: KvsEngine> BucketedEngine<E> {
+
+    /// Wrap `inner`, keeping every key in `bucket`. Bucket names must be non-empty and can't contain a NUL
+    pub fn new(inner: E, bucket: String) -> Result<BucketedEngine<E>> {
+        check_bucket(&bucket)?;
+        Ok(BucketedEngine {
+            inner,
+            bucket
+        })
+    }
+
+    fn key(&self, k: String) -> Result<String> {
+        if k.is_empty() {
+            return Err(KvsError::EmptyKey.into());
+        }
+        reject_separator(&k)?;
+        Ok(format!("{}{}{}", self.bucket, SEPARATOR, k))
+    }
+}
+
+/// Rejects bucket names which would be ambiguous once joined to a key
+pub fn check_bucket(bucket: &str) -> Result<()> {
+    if bucket.is_empty() || bucket.contains(SEPARATOR) {
+        return Err(KvsError::InvalidBucket(String::from(bucket)).into());
+    }
+    Ok(())
+}
+
+/// Rejects keys containing the NUL which separates a bucket's name from its keys. A key from a client with one in
+/// it could name a key in any bucket, such as `app\0secret` in bucket `app`
+pub(crate) fn reject_separator(k: &str) -> Result<()> {
+    if k.contains(SEPARATOR) {
+        return Err(KvsError::InvalidKey(String::from(k)).into());
+    }
+    Ok(())
+}
+
+impl<E: KvsEngine> KvsEngine for BucketedEngine<E> {
+
+    fn set(&self, k: String, v: String) -> Result<()> {
+        self.inner.set(self.key(k)?, v)
+    }
+
+    fn get(&self, k: String) -> Result<Option<String>> {
+        self.inner.get(self.key(k)?)
+    }
+
+    fn remove(&self, k: String) -> Result<()> {
+        self.inner.remove(self.key(k)?)
+    }
+
+    fn get_state(&self, k: String) -> Result<KeyState> {
+        self.inner.get_state(self.key(k)?)
+    }
+
+    fn set_bytes(&self, k: String, v: Vec<u8>) -> Result<()> {
+        self.inner.set_bytes(self.key(k)?, v)
+    }
+
+    fn get_bytes(&self, k: String) -> Result<Option<Vec<u8>>> {
+        self.inner.get_bytes(self.key(k)?)
+    }
+
+    fn set_reporting(&self, k: String, v: String) -> Result<SetOutcome> {
+        self.inner.set_reporting(self.key(k)?, v)
+    }
+
+    fn remove_reporting(&self, k: String) -> Result<Vec<u8>> {
+        self.inner.remove_reporting(self.key(k)?)
+    }
+
+    fn range(&self, from: String, to: String) -> Result<Vec<(String, String)>> {
+        // Both bounds get the prefix, so the range never reaches outside the bucket
+        let prefix = format!("{}{}", self.bucket, SEPARATOR);
+        Ok(self.inner.range(format!("{}{}", prefix, from), format!("{}{}", prefix, to))?
+            .into_iter()
+            .filter_map(|(k, v)| k.strip_prefix(&prefix).map(|k| (k.to_owned(), v)))
+            .collect())
+    }
+
+    fn remove_batch(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let keys = keys.into_iter().map(|k| self.key(k)).collect::<Result<Vec<String>>>()?;
+        self.inner.remove_batch(keys)
+    }
+
+    fn append(&self, k: String, suffix: String) -> Result<usize> {
+        self.inner.append(self.key(k)?, suffix)
+    }
+
+    fn hincr(&self, k: String, field: String, delta: i64) -> Result<i64> {
+        self.inner.hincr(self.key(k)?, field, delta)
+    }
+
+    fn compare_and_delete(&self, k: String, expected: String) -> Result<bool> {
+        self.inner.compare_and_delete(self.key(k)?, expected)
+    }
+
+    fn set_if_absent(&self, k: String, v: String) -> Result<bool> {
+        self.inner.set_if_absent(self.key(k)?, v)
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        // The whole inner engine, every bucket included
+        self.inner.size_on_disk()
+    }
+
+    fn touch(&self, k: String, ttl: Duration) -> Result<bool> {
+        self.inner.touch(self.key(k)?, ttl)
+    }
+
+    fn get_with_ttl(&self, k: String) -> Result<Option<(String, Option<Duration>)>> {
+        self.inner.get_with_ttl(self.key(k)?)
+    }
+
+    fn stats(&self) -> EngineStats {
+        // Counts for the whole inner engine, every bucket included
+        self.inner.stats()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let prefix = format!("{}{}", self.bucket, SEPARATOR);
+        Ok(self.inner.keys()?
+            .into_iter()
+            .filter_map(|k| k.strip_prefix(&prefix).map(String::from))
+            .collect())
+    }
+
+    fn sorted_keys(&self) -> Result<Vec<String>> {
+        // Every key in the bucket shares the prefix, so stripping it keeps them in order
+        let prefix = format!("{}{}", self.bucket, SEPARATOR);
+        Ok(self.inner.sorted_keys()?
+            .into_iter()
+            .filter_map(|k| k.strip_prefix(&prefix).map(String::from))
+            .collect())
+    }
+
+    fn recent_keys(&self, n: usize) -> Result<Vec<String>> {
+        // Other buckets' keys are mixed in, so every key is asked for and this bucket's picked out
+        let prefix = format!("{}{}", self.bucket, SEPARATOR);
+        Ok(self.inner.recent_keys(usize::MAX)?
+            .into_iter()
+            .filter_map(|k| k.strip_prefix(&prefix).map(String::from))
+            .take(n)
+            .collect())
+    }
+}
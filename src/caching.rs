@@ -0,0 +1,226 @@
+//! A read-through cache which can be layered over any KvsEngine
+use std::collections::{ BTreeMap, HashMap };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+use crate::{ Result, KvsEngine, KeyState, SetOutcome, EngineStats };
+
+/// Wraps an engine with a bounded LRU cache of values. Reads are served from the cache when possible,
+/// mutations write through to the inner engine and update the cache while holding its lock, so a read
+/// can never put back a value older than the latest write
+#[derive(Clone)]
+pub struct CachingEngine<E: KvsEngine> {
+    inner: E,
+    cache: Arc<Mutex<Lru>>,
+}
+
+impl<E: KvsEngine> CachingEngine<E> {
+
+    /// Wrap `inner`, caching at most `capacity` values
+    pub fn new(inner: E, capacity: usize) -> CachingEngine<E> {
+        CachingEngine {
+            inner,
+            cache: Arc::new(Mutex::new(Lru::new(capacity)))
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for CachingEngine<E> {
+
+    fn set(&self, k: String, v: String) -> Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        self.inner.set(k.clone(), v.clone())?;
+        cache.insert(k, v);
+        Ok(())
+    }
+
+    fn get(&self, k: String) -> Result<Option<String>> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(v) = cache.get(&k) {
+            return Ok(Some(v));
+        }
+
+        // The cache doesn't know when values expire, so one with a TTL is read from the inner engine every time
+        match self.inner.get_with_ttl(k.clone())? {
+            Some((v, None)) => {
+                cache.insert(k, v.clone());
+                Ok(Some(v))
+            },
+            Some((v, Some(_))) => Ok(Some(v)),
+            None => Ok(None)
+        }
+    }
+
+    fn remove(&self, k: String) -> Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove(&k);
+        self.inner.remove(k)
+    }
+
+    fn get_state(&self, k: String) -> Result<KeyState> {
+        match self.get(k.clone())? {
+            Some(v) => Ok(KeyState::Present(v)),
+            // Only the inner engine knows whether a missing key was removed
+            None => self.inner.get_state(k)
+        }
+    }
+
+    fn set_bytes(&self, k: String, v: Vec<u8>) -> Result<()> {
+        // Binary values may not be valid UTF-8 so they aren't cached, drop any stale string instead
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove(&k);
+        self.inner.set_bytes(k, v)
+    }
+
+    fn set_reporting(&self, k: String, v: String) -> Result<SetOutcome> {
+        let mut cache = self.cache.lock().unwrap();
+        let outcome = self.inner.set_reporting(k.clone(), v.clone())?;
+        cache.insert(k, v);
+        Ok(outcome)
+    }
+
+    fn remove_reporting(&self, k: String) -> Result<Vec<u8>> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove(&k);
+        self.inner.remove_reporting(k)
+    }
+
+    fn remove_batch(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut cache = self.cache.lock().unwrap();
+        for k in keys.iter() {
+            cache.remove(k);
+        }
+        self.inner.remove_batch(keys)
+    }
+
+    fn append(&self, k: String, suffix: String) -> Result<usize> {
+        // Only the inner engine sees the whole new value, so the cached one is dropped rather than rebuilt
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove(&k);
+        self.inner.append(k, suffix)
+    }
+
+    fn hincr(&self, k: String, field: String, delta: i64) -> Result<i64> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove(&k);
+        self.inner.hincr(k, field, delta)
+    }
+
+    fn compare_and_delete(&self, k: String, expected: String) -> Result<bool> {
+        let mut cache = self.cache.lock().unwrap();
+        let removed = self.inner.compare_and_delete(k.clone(), expected)?;
+        if removed {
+            cache.remove(&k);
+        }
+        Ok(removed)
+    }
+
+    fn set_if_absent(&self, k: String, v: String) -> Result<bool> {
+        let mut cache = self.cache.lock().unwrap();
+        let set = self.inner.set_if_absent(k.clone(), v.clone())?;
+        if set {
+            cache.insert(k, v);
+        }
+        Ok(set)
+    }
+
+    fn stats(&self) -> EngineStats {
+        self.inner.stats()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn sorted_keys(&self) -> Result<Vec<String>> {
+        self.inner.sorted_keys()
+    }
+
+    fn range(&self, from: String, to: String) -> Result<Vec<(String, String)>> {
+        self.inner.range(from, to)
+    }
+
+    fn recent_keys(&self, n: usize) -> Result<Vec<String>> {
+        self.inner.recent_keys(n)
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        self.inner.size_on_disk()
+    }
+
+    fn touch(&self, k: String, ttl: Duration) -> Result<bool> {
+        // A key given a TTL is dropped from the cache, and `get` won't cache it again while it has one
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove(&k);
+        self.inner.touch(k, ttl)
+    }
+
+    fn get_with_ttl(&self, k: String) -> Result<Option<(String, Option<Duration>)>> {
+        // Only the inner engine knows when values expire
+        self.inner.get_with_ttl(k)
+    }
+
+    fn get_bytes(&self, k: String) -> Result<Option<Vec<u8>>> {
+        if let Some(v) = self.cache.lock().unwrap().get(&k) {
+            return Ok(Some(v.into_bytes()));
+        }
+        self.inner.get_bytes(k)
+    }
+}
+
+/// Least recently used cache, recency is a tick which increases on every access
+pub(crate) struct Lru {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<String, (String, u64)>,
+    recency: BTreeMap<u64, String>,
+}
+
+impl Lru {
+
+    pub(crate) fn new(capacity: usize) -> Lru {
+        Lru {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new()
+        }
+    }
+
+    pub(crate) fn get(&mut self, k: &str) -> Option<String> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (v, last_used) = self.entries.get_mut(k)?;
+        self.recency.remove(last_used);
+        self.recency.insert(tick, k.to_owned());
+        *last_used = tick;
+        Some(v.clone())
+    }
+
+    pub(crate) fn insert(&mut self, k: String, v: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&k);
+
+        self.tick += 1;
+        self.recency.insert(self.tick, k.clone());
+        self.entries.insert(k, (v, self.tick));
+
+        if self.entries.len() > self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, k: &str) {
+        if let Some((_, last_used)) = self.entries.remove(k) {
+            self.recency.remove(&last_used);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+//! Background syncing of a KvStore's log, bounding how much a crash can lose
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex, mpsc::{ self, Sender, RecvTimeoutError } };
+use std::thread;
+use std::time::Duration;
+
+/// Syncs the log to disk every interval until the last clone of its KvStore is dropped
+pub(crate) struct Checkpointer {
+    _stop: Mutex<Sender<()>>,
+}
+
+impl Checkpointer {
+
+    pub fn start(log_path: PathBuf, writer: Arc<Mutex<()>>, interval: Duration) -> Checkpointer {
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        thread::spawn(move || {
+            // Dropping the sender disconnects the channel, which is the signal to stop
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let _writer = writer.lock().unwrap();
+                if let Ok(f) = OpenOptions::new().write(true).open(&log_path) {
+                    let _ = f.sync_all();
+                }
+            }
+        });
+
+        Checkpointer { _stop: Mutex::new(stop) }
+    }
+}
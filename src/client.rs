@@ -0,0 +1,466 @@
+//! Client library for talking to a running KvsServer over TCP
+use slog::*;
+
+use failure::format_err;
+use rand::Rng;
+
+use std::io::{ BufRead, BufReader };
+use std::net::{ SocketAddr, TcpStream };
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use crate::{ Result, KeyState, KvsError, Lru, SetOutcome };
+use crate::network::{ self, Operation, Response, ResponseStatus, ScanItem, ServerStats, TcpMessage };
+
+/// Delays between attempts to reach the server, doubling after each failure up to a cap. Each delay is cut by a
+/// random share of up to `jitter`, so a fleet of clients which lost the server together don't all retry at
+/// the same moment when it comes back
+///
+/// # Example
+/// ```
+/// use kvs::client::Backoff;
+/// use std::time::Duration;
+///
+/// let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).jitter(0.0);
+/// assert_eq!(backoff.delay(0), Duration::from_millis(100));
+/// assert_eq!(backoff.delay(2), Duration::from_millis(400));
+/// assert_eq!(backoff.delay(10), Duration::from_secs(1));
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64
+}
+
+impl Backoff {
+
+    /// Wait `initial` after the first failure, doubling each time up to `max`, with half of each delay jittered
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff { initial, max, jitter: 0.5 }
+    }
+
+    /// Share of each delay which is random, from 0 for none to 1 for a delay anywhere from nothing to the full delay
+    pub fn jitter(mut self, jitter: f64) -> Backoff {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// How long to wait after failed attempt number `attempt`, counting from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let full = self.initial.checked_mul(1 << attempt.min(31)).unwrap_or(self.max).min(self.max);
+        let cut = self.jitter * rand::thread_rng().gen::<f64>();
+        full.mul_f64(1.0 - cut)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new(Duration::from_millis(100), Duration::from_secs(2))
+    }
+}
+
+/// Client for a KvsServer, opens a new connection for each operation sent
+/// 
+/// # Example
+/// ```no_run
+/// use kvs::KvsClient;
+/// use slog::{ Logger, Discard, o };
+/// 
+/// let client = KvsClient::new(Logger::root(Discard, o!()), "127.0.0.1:4000".parse().unwrap());
+/// client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+/// assert_eq!(client.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+/// ```
+#[derive(Clone)]
+pub struct KvsClient {
+    log: Logger,
+    addr: SocketAddr,
+    connect_timeout: Duration,
+    connect_retries: u32,
+    backoff: Backoff,
+    nodelay: bool,
+    bucket: Option<String>,
+    cache: Option<Arc<Mutex<Lru>>>,
+}
+
+impl KvsClient {
+
+    /// Create a client for the server at the given address, no connection is made until an operation is sent
+    pub fn new(log: Logger, addr: SocketAddr) -> KvsClient {
+        let log = log.new(o!("address" => addr));
+        KvsClient {
+            log,
+            addr,
+            connect_timeout: Duration::from_secs(5),
+            connect_retries: 0,
+            backoff: Backoff::default(),
+            nodelay: true,
+            bucket: None,
+            cache: None
+        }
+    }
+
+    /// Send every operation to `bucket`, a keyspace of its own on the server
+    pub fn bucket(mut self, bucket: String) -> KvsClient {
+        self.bucket = Some(bucket);
+        self
+    }
+
+    /// Whether to set `TCP_NODELAY` on connections, on by default since every operation is a small
+    /// request waiting on a small response, which Nagle's algorithm would only delay
+    pub fn nodelay(mut self, nodelay: bool) -> KvsClient {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Retry a connection which fails up to `retries` times before giving up, waiting between attempts as set by
+    /// `backoff`. None by default, so an unreachable server fails at once
+    pub fn connect_retries(mut self, retries: u32) -> KvsClient {
+        self.connect_retries = retries;
+        self
+    }
+
+    /// Delays between connection attempts, for `connect_retries` and `wait_until_ready`
+    pub fn backoff(mut self, backoff: Backoff) -> KvsClient {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Keep up to `capacity` values this client has read, serving repeat gets of them without asking the server.
+    /// A key drops out of the cache once this client sends anything which could change it, but writes from other
+    /// clients and keys expiring on the server go unseen, so only cache where reading a stale value is acceptable.
+    /// Clones of the client share the cache, whichever buckets they use
+    pub fn cache(mut self, capacity: usize) -> KvsClient {
+        self.cache = Some(Arc::new(Mutex::new(Lru::new(capacity))));
+        self
+    }
+
+    /// Key a value is cached under. Clones sharing the cache can each be sent to a different bucket, so the key is
+    /// qualified by the bucket, with the default bucket left empty since bucket names can't be
+    fn cache_key(&self, k: &str) -> String {
+        format!("{}\0{}", self.bucket.as_deref().unwrap_or(""), k)
+    }
+
+    /// Send an operation to the server and wait for its response
+    pub fn send(&self, operation: Operation) -> Result<Response> {
+        // Drop the cached value before sending, so it's gone even if the write fails partway
+        if let Some(cache) = &self.cache {
+            match (&operation, operation.key()) {
+                (Operation::Get(_), _) => {},
+                (Operation::RemoveBatch(keys), _) => {
+                    let mut cache = cache.lock().unwrap();
+                    for key in keys.iter() {
+                        cache.remove(&self.cache_key(key));
+                    }
+                },
+                (_, Some(key)) => cache.lock().unwrap().remove(&self.cache_key(key)),
+                (_, None) => {}
+            }
+        }
+
+        let stream = self.open_stream()?;
+
+        if let Some(bucket) = &self.bucket {
+            Operation::Use(bucket.clone()).write_to_stream(self.log.clone(), stream.try_clone()?)?;
+            let response = Response::read_from_stream(self.log.clone(), stream.try_clone()?)?;
+            if response.status != ResponseStatus::Ok {
+                return Ok(response);
+            }
+        }
+
+        operation.write_to_stream(self.log.clone(), stream.try_clone()?)?;
+        Response::read_from_stream(self.log.clone(), stream)
+    }
+
+    /// Set the value of a key on the server
+    pub fn set(&self, k: String, v: String) -> Result<()> {
+        let response = self.send(Operation::Set(k, v))?;
+        if response.status == ResponseStatus::Ok {
+            Ok(())
+        } else {
+            Err(response_error(response))
+        }
+    }
+
+    /// Set the value of a key on the server, reporting whether the key was created or its value replaced
+    pub fn set_reporting(&self, k: String, v: String) -> Result<SetOutcome> {
+        let response = self.send(Operation::Set(k, v))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(outcome)) => SetOutcome::from_text(&outcome),
+            (status, data) => Err(response_error(Response { status, data }))
+        }
+    }
+
+    /// Get the value of a key from the server, None if the key is not set
+    pub fn get(&self, k: String) -> Result<Option<String>> {
+        match self.get_state(k)? {
+            KeyState::Present(v) => Ok(Some(v)),
+            KeyState::Deleted | KeyState::Absent => Ok(None)
+        }
+    }
+
+    /// Get the value of a key from the server, telling removed keys apart where the server's engine supports it
+    pub fn get_state(&self, k: String) -> Result<KeyState> {
+        if let Some(v) = self.cache.as_ref().and_then(|cache| cache.lock().unwrap().get(&self.cache_key(&k))) {
+            return Ok(KeyState::Present(v));
+        }
+
+        let response = self.send(Operation::Get(k.clone()))?;
+        match response.status {
+            ResponseStatus::Ok => {
+                match response.data {
+                    Some(v) => {
+                        if let Some(cache) = &self.cache {
+                            cache.lock().unwrap().insert(self.cache_key(&k), v.clone());
+                        }
+                        Ok(KeyState::Present(v))
+                    },
+                    None => Ok(KeyState::Absent)
+                }
+            },
+            ResponseStatus::Deleted => Ok(KeyState::Deleted),
+            _ => Err(response_error(response))
+        }
+    }
+
+    /// Get the value of a key from the server along with how long it has left before it expires, `None` for the TTL
+    /// if it never expires. An expired key is `None` like any other missing key, and servers whose engine doesn't
+    /// expire keys report every value as never expiring
+    pub fn get_with_ttl(&self, k: String) -> Result<Option<(String, Option<Duration>)>> {
+        let response = self.send(Operation::GetTtl(k))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(data)) => Ok(Some(network::split_ttl(&data)?)),
+            (ResponseStatus::Ok, None) => Ok(None),
+            (status, data) => Err(response_error(Response { status, data }))
+        }
+    }
+
+    /// Remove a key from the server, fails if the key is not set
+    pub fn remove(&self, k: String) -> Result<()> {
+        let response = self.send(Operation::Remove(k))?;
+        if response.status == ResponseStatus::Ok {
+            Ok(())
+        } else {
+            Err(response_error(response))
+        }
+    }
+
+    /// Remove every key in `keys` from the server in one request, returning whether each held a value, in order
+    pub fn remove_batch(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let response = self.send(Operation::RemoveBatch(keys))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(existed)) => {
+                network::split_fields(&existed)?.iter().map(|e| Ok(e.parse::<bool>()?)).collect()
+            },
+            (status, data) => Err(response_error(Response { status, data }))
+        }
+    }
+
+    /// Set the value of a key on the server to raw bytes
+    pub fn set_bytes(&self, k: String, v: Vec<u8>) -> Result<()> {
+        let response = self.send(Operation::SetBytes(k, v))?;
+        if response.status == ResponseStatus::Ok {
+            Ok(())
+        } else {
+            Err(response_error(response))
+        }
+    }
+
+    /// Get the value of a key from the server as raw bytes, None if the key is not set
+    pub fn get_bytes(&self, k: String) -> Result<Option<Vec<u8>>> {
+        let response = self.send(Operation::GetBytes(k))?;
+        if response.status == ResponseStatus::Ok {
+            match response.data {
+                Some(data) => Ok(Some(base64::decode(&data)?)),
+                None => Ok(None)
+            }
+        } else {
+            Err(response_error(response))
+        }
+    }
+
+    /// Append text to a key's value on the server, creating the key if it isn't set. Returns the value's new length
+    pub fn append(&self, k: String, suffix: String) -> Result<usize> {
+        let response = self.send(Operation::Append(k, suffix))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(len)) => Ok(len.parse()?),
+            (status, data) => Err(response_error(Response { status, data }))
+        }
+    }
+
+    /// Add `delta` to one field of a key holding a hash of counters on the server, returning the field's new value.
+    /// A missing key or field starts at 0
+    pub fn hincr(&self, k: String, field: String, delta: i64) -> Result<i64> {
+        let response = self.send(Operation::HIncr(k, field, delta))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(count)) => Ok(count.parse()?),
+            (status, data) => Err(response_error(Response { status, data }))
+        }
+    }
+
+    /// Stream every key/value pair from the server in key order, calling `f` with each one as it arrives so nothing
+    /// is buffered. Values which aren't valid UTF-8 arrive with the invalid bytes replaced
+    pub fn scan<F: FnMut(String, String)>(&self, f: F) -> Result<()> {
+        self.stream_pairs(Operation::Scan, f)
+    }
+
+    /// Stream every key/value pair from the server whose value contains `text`, calling `f` with each one. The
+    /// server reads every value to find them
+    pub fn grep<F: FnMut(String, String)>(&self, text: String, f: F) -> Result<()> {
+        self.stream_pairs(Operation::Grep(text), f)
+    }
+
+    /// Send an operation answered with a stream of `ScanItem`s, calling `f` with each pair until the response
+    fn stream_pairs<F: FnMut(String, String)>(&self, operation: Operation, mut f: F) -> Result<()> {
+        let stream = self.open_stream()?;
+
+        // Items are read line by line off one reader, a reader per line could buffer away the next item
+        let mut reader = BufReader::new(stream.try_clone()?);
+        if let Some(bucket) = &self.bucket {
+            Operation::Use(bucket.clone()).write_to_stream(self.log.clone(), stream.try_clone()?)?;
+            let response = Response::from_text(self.log.clone(), read_line(&mut reader)?)?;
+            if response.status != ResponseStatus::Ok {
+                return Err(response_error(response));
+            }
+        }
+
+        operation.write_to_stream(self.log.clone(), stream)?;
+        loop {
+            let line = read_line(&mut reader)?;
+            match ScanItem::from_text(&line)? {
+                Some(item) => f(item.key, item.value),
+                None => {
+                    let response = Response::from_text(self.log.clone(), line)?;
+                    if response.status == ResponseStatus::Ok {
+                        return Ok(());
+                    }
+                    return Err(response_error(response));
+                }
+            }
+        }
+    }
+
+    /// Set a key on the server only if it holds no value, returns whether it was set
+    pub fn set_if_absent(&self, k: String, v: String) -> Result<bool> {
+        let response = self.send(Operation::SetNx(k, v))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(set)) => Ok(set.parse()?),
+            (status, data) => Err(response_error(Response { status, data }))
+        }
+    }
+
+    /// Remove a key on the server only if it holds `expected`, returns whether it was removed
+    pub fn compare_and_delete(&self, k: String, expected: String) -> Result<bool> {
+        let response = self.send(Operation::Cad(k, expected))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(removed)) => Ok(removed.parse()?),
+            (status, data) => Err(response_error(Response { status, data }))
+        }
+    }
+
+    /// Make a key on the server expire once `ttl` has passed, without resending its value. Returns whether the key
+    /// held a value, fails if the server's engine doesn't expire keys
+    pub fn touch(&self, k: String, ttl: Duration) -> Result<bool> {
+        let response = self.send(Operation::Touch(k, ttl))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(touched)) => Ok(touched.parse()?),
+            (status, data) => Err(response_error(Response { status, data }))
+        }
+    }
+
+    /// Up to `n` of the most recently written keys which still hold a value, most recent first
+    pub fn recent_keys(&self, n: usize) -> Result<Vec<String>> {
+        let response = self.send(Operation::Recent(n))?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(keys)) => network::split_fields(&keys),
+            (status, data) => Err(response_error(Response { status, data }))
+        }
+    }
+
+    /// Get the server's version, along with the engine it is running and that engine's on-disk format
+    pub fn version(&self) -> Result<String> {
+        let response = self.send(Operation::Version)?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(version)) => Ok(version),
+            (status, data) => Err(response_error(Response { status, data }))
+        }
+    }
+
+    /// Get the server's operation counts, the size of its engine's files and the space left on its data volume
+    pub fn stats(&self) -> Result<ServerStats> {
+        let response = self.send(Operation::Stats)?;
+        match (response.status, response.data) {
+            (ResponseStatus::Ok, Some(stats)) => ServerStats::from_text(&stats),
+            (status, data) => Err(response_error(Response { status, data }))
+        }
+    }
+
+    /// Ping the server with `version` until it answers or `timeout` elapses, for scripts which start a server
+    /// and need to know when it is ready. Fails with the last error seen if the server never answers
+    pub fn wait_until_ready(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut failures = 0;
+        loop {
+            // A server which isn't listening yet refuses at once, but an unreachable host could hold
+            // a connection attempt past the deadline
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut attempt = self.clone();
+            attempt.connect_timeout = attempt.connect_timeout.min(remaining).max(Duration::from_millis(1));
+
+            // Pings are retried here, not by each one
+            attempt.connect_retries = 0;
+
+            match attempt.version() {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    let delay = self.backoff.delay(failures);
+                    if Instant::now() + delay >= deadline {
+                        return Err(format_err!("Server did not answer within {:?}: {}", timeout, e));
+                    }
+                    info!(self.log, "Server not ready, retrying"; "error" => %e, "delay_ms" => delay.as_millis() as u64);
+                    thread::sleep(delay);
+                    failures += 1;
+                }
+            }
+        }
+    }
+
+    fn open_stream(&self) -> Result<TcpStream> {
+        let mut attempt = 0;
+        let stream = loop {
+            info!(self.log, "Opening TCP connection...");
+            match TcpStream::connect_timeout(&self.addr, self.connect_timeout) {
+                Ok(stream) => break stream,
+                Err(e) if attempt < self.connect_retries => {
+                    let delay = self.backoff.delay(attempt);
+                    warn!(self.log, "Could not connect, retrying"; "error" => %e, "attempt" => attempt + 1, "delay_ms" => delay.as_millis() as u64);
+                    thread::sleep(delay);
+                    attempt += 1;
+                },
+                Err(e) => return Err(e.into())
+            }
+        };
+        stream.set_nodelay(self.nodelay)?;
+        info!(self.log, "TCP connection established");
+        Ok(stream)
+    }
+}
+
+/// Read a line sent by the server, failing if it closes the connection instead
+fn read_line(reader: &mut BufReader<TcpStream>) -> Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(KvsError::ConnectionClosed.into());
+    }
+    Ok(line)
+}
+
+/// Turn a failed response into an error, a missing key becomes `KvsError::KeyNotFound` so callers can tell it apart
+fn response_error(response: Response) -> failure::Error {
+    match (response.status, response.data) {
+        (ResponseStatus::KeyNotFound, _) => KvsError::KeyNotFound.into(),
+        (status, Some(description)) => format_err!("Error response recieved from server: {} {}", status.to_text(), description),
+        (status, None) => format_err!("Error response recieved from server: {}", status.to_text())
+    }
+}
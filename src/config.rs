@@ -0,0 +1,122 @@
+//! Settings for running a KvsServer, loaded from a TOML file
+use serde::{ Deserialize, Serialize };
+use std::fs;
+use std::path::{ Path, PathBuf };
+
+use crate::Result;
+
+/// Everything a KvsServer can be configured with. Every setting is optional, anything left unset
+/// falls back to the command line and then to the server's defaults
+///
+/// # Example
+/// ```toml
+/// addr = "127.0.0.1:4000"
+/// engine = "kvs"
+/// tp = "queued"
+/// threads = 4
+/// data-dir = "/var/lib/kvs"
+/// max-connections = 256
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerConfig {
+    /// Address to listen to
+    pub addr: Option<String>,
+
+    /// Backend engine to use
+    pub engine: Option<String>,
+
+    /// Thread pool implementation to use
+    pub tp: Option<String>,
+
+    /// Number of threads in the pool
+    pub threads: Option<usize>,
+
+    /// Directory the engine keeps its files in
+    pub data_dir: Option<PathBuf>,
+
+    /// Size of sled's page cache in megabytes
+    pub sled_cache_mb: Option<u64>,
+
+    /// Milliseconds between sled flushing to disk
+    pub sled_flush_ms: Option<u64>,
+
+    /// Milliseconds between syncing the kvs log to disk
+    pub checkpoint_interval: Option<u64>,
+
+    /// Milliseconds to wait on startup for a kvs store still held by another process, such as a server shutting down
+    pub open_timeout_ms: Option<u64>,
+
+    /// Connections beyond this many open at once are turned away
+    pub max_connections: Option<usize>,
+
+    /// Whether to set TCP_NODELAY on client connections
+    pub nodelay: Option<bool>,
+
+    /// Only one in this many operations is logged, 0 for none. Failures and slow operations are logged regardless
+    pub op_log_sample: Option<u64>,
+
+    /// Operations slower than this many milliseconds are logged as warnings
+    pub slow_op_ms: Option<u64>,
+
+    /// File to append a line per request to
+    pub access_log: Option<PathBuf>,
+
+    /// Megabytes of the kvs engine's index to keep in memory, the rest is spilled to disk
+    pub index_memory_mb: Option<usize>,
+
+    /// File to append the server's log to instead of stderr
+    pub log_file: Option<PathBuf>,
+
+    /// File to write the server's process ID to while it runs
+    pub pid_file: Option<PathBuf>,
+
+    /// Whether to write, read back and remove a key before accepting connections
+    pub self_test: Option<bool>,
+
+    /// Requests a second allowed from each client address, those beyond it are refused
+    pub rate_limit: Option<u32>,
+
+    /// Address to serve the HTTP frontend on, none is served unless set
+    pub http_addr: Option<String>,
+
+    /// Longest request read from a client in bytes, longer ones are refused and the connection closed
+    pub max_request_bytes: Option<usize>,
+
+    /// Requests read from a connection ahead of the one being served, reading pauses while this many are waiting
+    pub max_in_flight: Option<usize>,
+
+    /// Connections with nothing to answer for this many milliseconds are closed, unset or 0 to keep them open
+    pub idle_timeout_ms: Option<u64>,
+
+    /// Engine operations taking longer than this many milliseconds fail rather than holding up the connection,
+    /// unset or 0 to wait however long they take
+    pub op_timeout_ms: Option<u64>,
+
+    /// Keys set over the kvs protocol must wholly match this regular expression
+    pub key_pattern: Option<String>,
+
+    /// Whether the kvs engine compacts its log, with it off the log keeps every write
+    pub compaction: Option<bool>,
+
+    /// When the kvs engine syncs its log after a write: never, always, or a number of milliseconds between syncs
+    pub sync: Option<String>,
+}
+
+impl ServerConfig {
+
+    /// Parse a config from TOML text, unknown settings are an error so typos don't go unnoticed
+    pub fn from_toml(text: &str) -> Result<ServerConfig> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Write the config as TOML in the form `from_toml` reads, leaving out settings which are unset
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Load a config from a TOML file
+    pub fn load(path: &Path) -> Result<ServerConfig> {
+        ServerConfig::from_toml(&fs::read_to_string(path)?)
+    }
+}
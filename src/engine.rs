@@ -1,4 +1,8 @@
-use crate::Result;
+use failure::{ err_msg, format_err };
+use std::collections::BTreeMap;
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use crate::{ Result, KvsError };
 
 /// Trait for defining the interface of a Key/Value store
 pub trait KvsEngine: Send + 'static + Clone {
@@ -13,22 +17,367 @@ pub trait KvsEngine: Send + 'static + Clone {
 
     /// Remove a K/V entry from the store, will do nothing if the entry doesn't exist
     fn remove(&self, k: String) -> Result<()>;
-    
+
+    /// Get the value for a key along with whether a missing key was explicitly removed.
+    /// Engines which don't remember removals report every missing key as `KeyState::Absent`
+    fn get_state(&self, k: String) -> Result<KeyState> {
+        match self.get(k)? {
+            Some(v) => Ok(KeyState::Present(v)),
+            None => Ok(KeyState::Absent)
+        }
+    }
+
+    /// Sets raw bytes as the value of a key, for values which may not be valid UTF-8.
+    /// The default only accepts UTF-8, engines which can store binary override it
+    fn set_bytes(&self, k: String, v: Vec<u8>) -> Result<()> {
+        let v = String::from_utf8(v).map_err(|_| KvsError::InvalidUtf8)?;
+        self.set(k, v)
+    }
+
+    /// Get the value of a key as raw bytes, works for values set with either `set` or `set_bytes`
+    fn get_bytes(&self, k: String) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(k)?.map(String::into_bytes))
+    }
+
+    /// Sets a value like `set`, reporting whether the key was newly created or an existing value overwritten.
+    /// The default checks and then sets, which isn't atomic, engines which can do both at once override it
+    fn set_reporting(&self, k: String, v: String) -> Result<SetOutcome> {
+        let existed = self.get_bytes(k.clone())?.is_some();
+        self.set(k, v)?;
+        Ok(SetOutcome::from_existed(existed))
+    }
+
+    /// Remove a key like `remove`, returning the value it held as bytes. The default reads and then removes, which
+    /// isn't atomic, engines which can do both at once override it so the value returned is always the one removed
+    fn remove_reporting(&self, k: String) -> Result<Vec<u8>> {
+        let v = self.get_bytes(k.clone())?.ok_or(KvsError::KeyNotFound)?;
+        self.remove(k)?;
+        Ok(v)
+    }
+
+    /// Remove every key in `keys`, returning whether each held a value, in the same order. The default removes them
+    /// one at a time and flushes once at the end, engines which can write them all together override it
+    fn remove_batch(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut existed = Vec::with_capacity(keys.len());
+        for k in keys {
+            let present = self.get_bytes(k.clone())?.is_some();
+            if present {
+                self.remove(k)?;
+            }
+            existed.push(present);
+        }
+        self.flush()?;
+        Ok(existed)
+    }
+
+    /// Append `suffix` to a key's value, returning the new length of the value in bytes. A missing key is created
+    /// holding just the suffix. The default reads and then sets, which isn't atomic, engines which can do both
+    /// at once override it so concurrent appends are never lost
+    fn append(&self, k: String, suffix: String) -> Result<usize> {
+        let mut v = self.get(k.clone())?.unwrap_or_default();
+        v.push_str(&suffix);
+        let len = v.len();
+        self.set(k, v)?;
+        Ok(len)
+    }
+
+    /// Set a key only if it holds no value, returning whether it was set. The default checks and then sets, which
+    /// isn't atomic, engines which can do both at once override it so only one of several racing callers wins
+    fn set_if_absent(&self, k: String, v: String) -> Result<bool> {
+        if self.get_bytes(k.clone())?.is_some() {
+            return Ok(false);
+        }
+        self.set(k, v)?;
+        Ok(true)
+    }
+
+    /// Add `delta` to one field of a key holding a hash of counters, returning the field's new value. A missing key
+    /// or field starts at 0. The default reads and then sets, which isn't atomic, engines which can do both at once
+    /// override it so concurrent increments are never lost, even to different fields of one key
+    fn hincr(&self, k: String, field: String, delta: i64) -> Result<i64> {
+        let current = self.get_bytes(k.clone())?;
+        let (v, n) = increment_field(&k, current.as_deref(), &field, delta)?;
+        self.set(k, v)?;
+        Ok(n)
+    }
+
+    /// Remove a key only if it holds `expected`, returning whether it was removed. An absent key or a different value
+    /// leaves the store alone. The default checks and then removes, which isn't atomic, engines which can do both at
+    /// once override it so a value changed in between is never removed
+    fn compare_and_delete(&self, k: String, expected: String) -> Result<bool> {
+        if self.get_bytes(k.clone())?.as_deref() != Some(expected.as_bytes()) {
+            return Ok(false);
+        }
+        self.remove(k)?;
+        Ok(true)
+    }
+
+    /// Every key currently holding a value, in no particular order. Engines which can't list their keys
+    /// leave the default, which fails
+    fn keys(&self) -> Result<Vec<String>> {
+        Err(err_msg("This engine can't list its keys"))
+    }
+
+    /// Every key currently holding a value, sorted lexicographically so the order is the same from one call to the
+    /// next. The default sorts `keys`, engines which keep their keys in order override it to skip the sort
+    fn sorted_keys(&self) -> Result<Vec<String>> {
+        let mut keys = self.keys()?;
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Every key from `from` up to but not including `to` along with its value, in key order. Values which aren't
+    /// valid UTF-8 are left out, as with `filter_values`. The default filters `sorted_keys`, engines which can find
+    /// a range without listing every key override it
+    fn range(&self, from: String, to: String) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for key in self.sorted_keys()?.into_iter().filter(|key| from <= *key && *key < to) {
+            // A key removed since it was listed is just left out
+            if let Some(Ok(value)) = self.get_bytes(key.clone())?.map(String::from_utf8) {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Every key whose value `predicate` accepts, along with the value, in no particular order. Reads every live
+    /// value in the store, so it takes time in proportion to the whole store however few values match, and is
+    /// meant for admin queries rather than serving requests. Values which aren't valid UTF-8 are never matched.
+    /// Built on `keys`, so engines which can't list their keys fail
+    fn filter_values<F: Fn(&str) -> bool>(&self, predicate: F) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for key in self.keys()? {
+            // A key removed since it was listed just isn't matched
+            if let Some(Ok(value)) = self.get_bytes(key.clone())?.map(String::from_utf8) {
+                if predicate(&value) {
+                    pairs.push((key, value));
+                }
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// The `n` most recently written keys which still hold a value, most recent first. Engines which don't
+    /// keep track of the order of writes leave the default, which fails
+    fn recent_keys(&self, _n: usize) -> Result<Vec<String>> {
+        Err(err_msg("This engine can't list its recently written keys"))
+    }
+
+    /// Bytes the engine's files take up on disk, for `KvStore` the size of its log. Engines which don't keep
+    /// files leave the default, which fails
+    fn size_on_disk(&self) -> Result<u64> {
+        Err(err_msg("This engine can't report its size on disk"))
+    }
+
+    /// Make a key expire once `ttl` has passed from now, without changing its value, returning whether the key
+    /// held a value. Engines which don't expire keys leave the default, which fails
+    fn touch(&self, _k: String, _ttl: Duration) -> Result<bool> {
+        Err(err_msg("This engine doesn't support expiring keys"))
+    }
+
+    /// Get the value for a key along with how long it has left before it expires, `None` if it never expires.
+    /// Engines which don't expire keys leave the default, which reports every value as never expiring
+    fn get_with_ttl(&self, k: String) -> Result<Option<(String, Option<Duration>)>> {
+        Ok(self.get(k)?.map(|v| (v, None)))
+    }
+
+    /// Counts of the operations carried out since the engine was opened, shared by all its clones.
+    /// Engines which don't count leave the default, which reports nothing
+    fn stats(&self) -> EngineStats {
+        EngineStats::default()
+    }
+
+    /// Make sure everything written so far is on disk rather than in buffers, such as before a server exits.
+    /// Engines which write straight through leave the default, which does nothing
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+}
+
+/// Counts of an engine's operations, as reported by `KvsEngine::stats`
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct EngineStats {
+    /// Keys looked up, whether or not they held a value
+    pub reads: u64,
+
+    /// Values written by any kind of set or append
+    pub writes: u64,
+
+    /// Keys removed
+    pub removes: u64,
+
+    /// Bytes the engine wrote for those writes and removes, not counting any later rewriting such as compaction
+    pub bytes_written: u64,
+
+    /// Times the engine compacted its files, whether set off by writes or asked for. Always 0 for engines which
+    /// don't compact, such as sled
+    pub compactions: u64,
+
+    /// Bytes those compactions took off the engine's files, the size before each less the size after
+    pub bytes_reclaimed: u64,
+
+    /// Milliseconds spent compacting, during which writes wait
+    pub compaction_ms: u64,
+}
+
+/// Counters behind `EngineStats`, updated without locking
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    removes: AtomicU64,
+    bytes_written: AtomicU64,
+    compactions: AtomicU64,
+    bytes_reclaimed: AtomicU64,
+    compaction_us: AtomicU64,
+}
+
+impl StatsCounters {
+
+    pub fn read(&self) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn write(&self, bytes: u64) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn remove(&self, bytes: u64) {
+        self.removes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a compaction which took `took`, kept in microseconds so short compactions still add up
+    pub fn compaction(&self, reclaimed: u64, took: Duration) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.bytes_reclaimed.fetch_add(reclaimed, Ordering::Relaxed);
+        self.compaction_us.fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> EngineStats {
+        EngineStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            bytes_reclaimed: self.bytes_reclaimed.load(Ordering::Relaxed),
+            compaction_ms: self.compaction_us.load(Ordering::Relaxed) / 1000
+        }
+    }
+}
+
+/// State of a key in the store, as reported by `KvsEngine::get_state`
+#[derive(Debug, PartialEq, Clone)]
+pub enum KeyState {
+    /// Key holds the contained value
+    Present(String),
+
+    /// Key held a value, but it has since been removed
+    Deleted,
+
+    /// Key has never held a value
+    Absent
+}
+
+/// Whether `KvsEngine::set_reporting` created a key or overwrote its value
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SetOutcome {
+    /// Key held no value before the set
+    Created,
+
+    /// Key's existing value was overwritten
+    Updated
+}
+
+impl SetOutcome {
+    pub(crate) fn from_existed(existed: bool) -> SetOutcome {
+        if existed {
+            SetOutcome::Updated
+        } else {
+            SetOutcome::Created
+        }
+    }
+
+    /// Text sent on the wire for this outcome, as the data of the response to a set
+    pub fn to_text(&self) -> &'static str {
+        match self {
+            SetOutcome::Created => "created",
+            SetOutcome::Updated => "updated"
+        }
+    }
+
+    /// Parse the text `to_text` sends
+    pub fn from_text(text: &str) -> Result<SetOutcome> {
+        match text {
+            "created" => Ok(SetOutcome::Created),
+            "updated" => Ok(SetOutcome::Updated),
+            _ => Err(KvsError::Protocol(format!("'{}' is not the outcome of a set", text)).into())
+        }
+    }
+}
+
+/// Rejects keys which can't be stored, shared by every engine so they behave the same
+pub(crate) fn check_key(k: &str) -> Result<()> {
+    if k.is_empty() {
+        return Err(KvsError::EmptyKey.into());
+    }
+    Ok(())
+}
+
+/// Add `delta` to `field` of a hash value, returning the new value to store and the field's new count.
+///
+/// A hash of counters is stored as a JSON object of field names to whole numbers, such as `{"views":3}`,
+/// so `get` shows it as is and `set` can seed one
+pub(crate) fn increment_field(k: &str, current: Option<&[u8]>, field: &str, delta: i64) -> Result<(String, i64)> {
+    let mut fields: BTreeMap<String, i64> = match current {
+        Some(bytes) => serde_json::from_slice(bytes).map_err(|_| KvsError::NotAHash(k.to_owned()))?,
+        None => BTreeMap::new()
+    };
+    let count = fields.entry(field.to_owned()).or_insert(0);
+    *count = count.checked_add(delta)
+        .ok_or_else(|| format_err!("Adding {} to field '{}' of '{}' would overflow", delta, field, k))?;
+    let count = *count;
+    Ok((serde_json::to_string(&fields)?, count))
 }
 
-use sled::{ Db, IVec };
+use sled::{ Db, IVec, ConfigBuilder, Tree };
 use std::path;
 use std::path::PathBuf;
-use std::sync::Arc;
-use std::fs::create_dir;
-use std::str::from_utf8;
-use sled::Error;
-use failure::err_msg;
+use std::sync::{ Arc, Mutex, mpsc::{ self, Sender, RecvTimeoutError } };
+use std::panic::{ self, AssertUnwindSafe };
+use std::fs::{ self, create_dir };
+use std::thread;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+/// Tree holding the values, each led by a header saying when it was written and whether and when it expires.
+/// Stores from before the header kept bare values in sled's default tree, which are moved across when opened
+const VALUES_TREE: &[u8] = b"values";
+
+/// Header flag for a value which expires, when is given as milliseconds since the Unix epoch
+const HAS_EXPIRY: u8 = 1;
+
+/// Header flag for a value which records its place in the order of writes, an ID from sled which only grows
+const HAS_WRITTEN: u8 = 2;
+
+/// A value as sled holds it, see `encode`
+struct Stored<'a> {
+    /// Place in the order of writes, values moved across from before the header was added count as written first
+    written: u64,
+    expiry: Option<u64>,
+    value: &'a [u8],
+}
 
 /// Implementation of KvsEngine which uses the `sled` crate as its backend
 #[derive(Clone)]
 pub struct SledKvsEngine {
-    tree: Db,
+    db: Db,
+    tree: Arc<Tree>,
+    stats: Arc<StatsCounters>,
+    path: PathBuf,
+    _sweeper: Option<Arc<Sweeper>>,
 }
 
 impl SledKvsEngine {
@@ -48,45 +397,499 @@ impl SledKvsEngine {
 
     /// Get a new SledKvsEngine instance, uses the given path for file storage
     pub fn open(path: &path::Path) -> Result<SledKvsEngine> {
-        
-        let tree = Db::start_default(path)?;
+        SledKvsEngine::builder().open(path)
+    }
 
-        Ok(SledKvsEngine {
-            tree
-        })
+    /// Builder for a SledKvsEngine with sled's tunables set, anything left unset keeps sled's default
+    pub fn builder() -> SledKvsEngineBuilder {
+        SledKvsEngineBuilder::default()
+    }
+
+    /// Sets a value which expires once `ttl` has passed. An expired key reads as absent straight away, and is
+    /// removed from sled when next read or by the sweep if one is running, see `SledKvsEngineBuilder::ttl_sweep_ms`.
+    /// Setting the key again without a TTL makes it permanent
+    pub fn set_with_ttl(&self, k: String, v: String, ttl: Duration) -> Result<()> {
+        check_key(&k)?;
+        let expiry = now_ms().saturating_add(ttl.as_millis() as u64);
+        self.tree.set(k.as_bytes(), encode(v.as_bytes(), self.db.generate_id()?, Some(expiry)))?;
+        self.stats.write((k.len() + v.len()) as u64);
+        Ok(())
+    }
 
+    /// Remove every expired key from sled, returning how many were removed. The sweep thread calls this on its
+    /// interval, it only needs calling directly when there is no sweep
+    pub fn sweep_expired(&self) -> Result<usize> {
+        sweep(&self.tree)
     }
 
-    fn convert_sled_result(sled_result: std::result::Result<Option<IVec>, Error>) -> Result<Option<String>> {
-        Ok(sled_result.map(|o: Option<IVec>| {
-            o.map(|v| {
-                let bytes: Arc<[u8]> = v.into();
-                String::from(from_utf8(bytes.as_ref()).expect("Value is corrupted"))
-            })
-        })?)
+    /// The live value of a key along with what sled holds for it, which is needed to swap it out. An expired
+    /// value is removed as it's found, unless a write has replaced it in the meantime
+    fn get_live(&self, k: &str) -> Result<(Option<Vec<u8>>, Option<IVec>)> {
+        let stored = self.tree.get(k.as_bytes())?;
+        let stored = match stored {
+            Some(stored) => stored,
+            None => return Ok((None, None))
+        };
+        if is_expired(&stored, now_ms())? {
+            return match self.tree.cas(k.as_bytes(), Some(&stored), None as Option<&[u8]>)? {
+                Ok(()) => Ok((None, None)),
+                Err(_) => self.get_live(k)
+            };
+        }
+        let value = decode(&stored)?.value.to_vec();
+        Ok((Some(value), Some(stored)))
+    }
+}
+
+/// Lead a value with a header of a flags byte, then when it was written, then its expiry if it has one, each a
+/// little-endian u64
+fn encode(value: &[u8], written: u64, expiry: Option<u64>) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(17 + value.len());
+    match expiry {
+        Some(expiry) => {
+            stored.push(HAS_WRITTEN | HAS_EXPIRY);
+            stored.extend_from_slice(&written.to_le_bytes());
+            stored.extend_from_slice(&expiry.to_le_bytes());
+        },
+        None => {
+            stored.push(HAS_WRITTEN);
+            stored.extend_from_slice(&written.to_le_bytes());
+        }
+    }
+    stored.extend_from_slice(value);
+    stored
+}
+
+/// Split a stored value into its header and the value itself
+fn decode(stored: &[u8]) -> Result<Stored<'_>> {
+    let unrecognised = || err_msg("Value stored in sled has an unrecognised header");
+    let (&flags, mut rest) = stored.split_first().ok_or_else(unrecognised)?;
+    if flags & !(HAS_WRITTEN | HAS_EXPIRY) != 0 {
+        return Err(unrecognised());
+    }
+
+    let mut field = |present: bool| -> Result<Option<u64>> {
+        if !present {
+            return Ok(None);
+        }
+        if rest.len() < 8 {
+            return Err(unrecognised());
+        }
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&rest[..8]);
+        rest = &rest[8..];
+        Ok(Some(u64::from_le_bytes(bytes)))
+    };
+    let written = field(flags & HAS_WRITTEN != 0)?.unwrap_or(0);
+    let expiry = field(flags & HAS_EXPIRY != 0)?;
+
+    Ok(Stored { written, expiry, value: rest })
+}
+
+fn is_expired(stored: &[u8], now: u64) -> Result<bool> {
+    Ok(match decode(stored)?.expiry {
+        Some(expiry) => expiry <= now,
+        None => false
+    })
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Remove every expired key in `tree`. Each is only removed if it still holds the expired value, so a write
+/// landing during the sweep is never lost
+fn sweep(tree: &Tree) -> Result<usize> {
+    let now = now_ms();
+    let mut removed = 0;
+    for pair in tree.iter() {
+        let (k, stored) = pair?;
+        if is_expired(&stored, now)? && tree.cas(&k, Some(&stored), None as Option<&[u8]>)?.is_ok() {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Sweeps expired keys every interval until the last clone of its SledKvsEngine is dropped
+struct Sweeper {
+    _stop: Mutex<Sender<()>>,
+}
+
+impl Sweeper {
+
+    fn start(tree: Arc<Tree>, interval: Duration) -> Sweeper {
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        thread::spawn(move || {
+            // Dropping the sender disconnects the channel, which is the signal to stop
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let _ = sweep(&tree);
+            }
+        });
+
+        Sweeper { _stop: Mutex::new(stop) }
+    }
+}
+
+/// Total size of the files in `dir` and the directories within it
+fn dir_size(dir: &path::Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() { dir_size(&entry.path())? } else { metadata.len() };
+    }
+    Ok(size)
+}
+
+/// Open the tree of values, first moving across any bare values left in the default tree by a store from
+/// before expiries. The default tree is only cleared once every value is safely copied, so a crash part way
+/// just means copying them again next time
+fn open_values(db: &Db) -> Result<Arc<Tree>> {
+    let values = db.open_tree(VALUES_TREE)?;
+    if !db.is_empty() {
+        for pair in db.iter() {
+            let (k, v) = pair?;
+            values.set(k, encode(&v, 0, None))?;
+        }
+        values.flush()?;
+        db.clear()?;
+        db.flush()?;
+    }
+    Ok(values)
+}
+
+/// Start sled with the given config, describing any failure as `KvsError::SledOpen`.
+/// Sled panics rather than returning an error when it can't open its files, such as when another
+/// process holds its lock, so the panic is caught and turned into an error too
+fn start_sled(path: &path::Path, config: ConfigBuilder) -> Result<Db> {
+    let sled_open_error = |reason: String| KvsError::SledOpen(PathBuf::from(path), reason);
+
+    let config = panic::catch_unwind(AssertUnwindSafe(|| config.build()))
+        .map_err(|payload| {
+            let reason = match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => String::from(*payload.downcast_ref::<&str>().unwrap_or(&"sled panicked while opening"))
+            };
+            sled_open_error(reason)
+        })?;
+
+    Ok(Db::start(config).map_err(|e| sled_open_error(e.to_string()))?)
+}
+
+/// Sets sled's cache capacity and flush interval before opening a SledKvsEngine
+#[derive(Debug, Default, Clone)]
+pub struct SledKvsEngineBuilder {
+    cache_capacity_mb: Option<u64>,
+    flush_every_ms: Option<u64>,
+    ttl_sweep_ms: Option<u64>,
+}
+
+impl SledKvsEngineBuilder {
+
+    /// Maximum size of sled's page cache in megabytes
+    pub fn cache_capacity_mb(mut self, mb: u64) -> SledKvsEngineBuilder {
+        self.cache_capacity_mb = Some(mb);
+        self
+    }
+
+    /// Milliseconds between sled flushing its IO buffers to disk
+    pub fn flush_every_ms(mut self, ms: u64) -> SledKvsEngineBuilder {
+        self.flush_every_ms = Some(ms);
+        self
+    }
+
+    /// Milliseconds between background sweeps removing expired keys. Off by default, since each sweep reads
+    /// every key, which leaves expired keys in place until they're next read
+    pub fn ttl_sweep_ms(mut self, ms: u64) -> SledKvsEngineBuilder {
+        self.ttl_sweep_ms = Some(ms);
+        self
+    }
+
+    /// Open a SledKvsEngine with these settings, uses the given path for file storage
+    pub fn open(self, path: &path::Path) -> Result<SledKvsEngine> {
+        let mut config = ConfigBuilder::default().path(path);
+        if let Some(mb) = self.cache_capacity_mb {
+            config = config.cache_capacity(mb * 1024 * 1024);
+        }
+        if let Some(ms) = self.flush_every_ms {
+            config = config.flush_every_ms(Some(ms));
+        }
+
+        let db = start_sled(path, config)?;
+        let tree = open_values(&db)?;
+        let sweeper = self.ttl_sweep_ms
+            .map(|ms| Arc::new(Sweeper::start(tree.clone(), Duration::from_millis(ms))));
+
+        Ok(SledKvsEngine {
+            db,
+            tree,
+            stats: Arc::new(StatsCounters::default()),
+            path: PathBuf::from(path),
+            _sweeper: sweeper
+        })
     }
 }
 
 impl KvsEngine for SledKvsEngine {
 
     fn set(&self, k: String, v: String) -> Result<()> {
-        self.tree.set(k.as_bytes(), v.as_bytes())?;
-        Ok(())
+        self.set_bytes(k, v.into_bytes())
     }
 
     fn get(&self, k: String) -> Result<Option<String>> {
-        let result = self.tree.get(k.as_bytes());
-
-        SledKvsEngine::convert_sled_result(result)
+        match self.get_bytes(k)? {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes).map_err(|_| KvsError::InvalidUtf8)?)),
+            None => Ok(None)
+        }
     }
 
     fn remove(&self, k: String) -> Result<()> {
+        self.remove_reporting(k)?;
+        Ok(())
+    }
+
+    fn remove_reporting(&self, k: String) -> Result<Vec<u8>> {
+        check_key(&k)?;
         let result = self.tree.del(k.as_bytes())?;
 
-        if result.is_some() {
-            Ok(())
-        } else {
-            Err(err_msg("Key not found"))
+        // An expired value is gone as far as callers can tell, even if it was still there to delete
+        match result {
+            Some(stored) if !is_expired(&stored, now_ms())? => {
+                self.stats.remove(k.len() as u64);
+                Ok(decode(&stored)?.value.to_vec())
+            },
+            _ => Err(KvsError::KeyNotFound.into())
+        }
+    }
+
+    fn set_bytes(&self, k: String, v: Vec<u8>) -> Result<()> {
+        check_key(&k)?;
+        let bytes = (k.len() + v.len()) as u64;
+        self.tree.set(k.as_bytes(), encode(&v, self.db.generate_id()?, None))?;
+        self.stats.write(bytes);
+        Ok(())
+    }
+
+    fn set_reporting(&self, k: String, v: String) -> Result<SetOutcome> {
+        check_key(&k)?;
+        let previous = self.tree.set(k.as_bytes(), encode(v.as_bytes(), self.db.generate_id()?, None))?;
+        self.stats.write((k.len() + v.len()) as u64);
+        let existed = match previous {
+            Some(stored) => !is_expired(&stored, now_ms())?,
+            None => false
+        };
+        Ok(SetOutcome::from_existed(existed))
+    }
+
+    fn append(&self, k: String, suffix: String) -> Result<usize> {
+        check_key(&k)?;
+
+        // Retry until no other write lands between reading the value and swapping in the longer one.
+        // The value keeps its expiry, an expired one is replaced as though the key were absent
+        let mut current = self.tree.get(k.as_bytes())?;
+        loop {
+            let now = now_ms();
+            let (expiry, mut v) = match &current {
+                Some(stored) if !is_expired(stored, now)? => {
+                    let stored = decode(stored)?;
+                    (stored.expiry, stored.value.to_vec())
+                },
+                _ => (None, Vec::new())
+            };
+            if std::str::from_utf8(&v).is_err() {
+                return Err(KvsError::InvalidUtf8.into());
+            }
+            v.extend_from_slice(suffix.as_bytes());
+            let len = v.len();
+
+            match self.tree.cas(k.as_bytes(), current.as_ref(), Some(encode(&v, self.db.generate_id()?, expiry)))? {
+                Ok(()) => {
+                    self.stats.write((k.len() + len) as u64);
+                    return Ok(len);
+                },
+                Err(actual) => current = actual
+            }
+        }
+    }
+
+    fn hincr(&self, k: String, field: String, delta: i64) -> Result<i64> {
+        check_key(&k)?;
+
+        // As with append, retry until the swap lands on the value the increment was worked out from
+        let mut current = self.tree.get(k.as_bytes())?;
+        loop {
+            let now = now_ms();
+            let (expiry, fields) = match &current {
+                Some(stored) if !is_expired(stored, now)? => {
+                    let stored = decode(stored)?;
+                    (stored.expiry, Some(stored.value))
+                },
+                _ => (None, None)
+            };
+            let (v, count) = increment_field(&k, fields, &field, delta)?;
+
+            match self.tree.cas(k.as_bytes(), current.as_ref(), Some(encode(v.as_bytes(), self.db.generate_id()?, expiry)))? {
+                Ok(()) => {
+                    self.stats.write((k.len() + v.len()) as u64);
+                    return Ok(count);
+                },
+                Err(actual) => current = actual
+            }
+        }
+    }
+
+    fn compare_and_delete(&self, k: String, expected: String) -> Result<bool> {
+        check_key(&k)?;
+
+        // Swap the exact stored bytes for nothing, so a write landing after the comparison makes the swap fail
+        let mut current = self.tree.get(k.as_bytes())?;
+        loop {
+            let stored = match &current {
+                Some(stored) if !is_expired(stored, now_ms())? => stored.clone(),
+                _ => return Ok(false)
+            };
+            if decode(&stored)?.value != expected.as_bytes() {
+                return Ok(false);
+            }
+            match self.tree.cas(k.as_bytes(), Some(&stored), None as Option<IVec>)? {
+                Ok(()) => {
+                    self.stats.remove(k.len() as u64);
+                    return Ok(true);
+                },
+                Err(actual) => current = actual
+            }
         }
     }
-}
\ No newline at end of file
+
+    fn set_if_absent(&self, k: String, v: String) -> Result<bool> {
+        check_key(&k)?;
+        let bytes = (k.len() + v.len()) as u64;
+        let stored = encode(v.as_bytes(), self.db.generate_id()?, None);
+
+        // An expired value counts as absent, so it's swapped out rather than only setting over nothing
+        let mut current: Option<IVec> = None;
+        loop {
+            if let Some(existing) = &current {
+                if !is_expired(existing, now_ms())? {
+                    return Ok(false);
+                }
+            }
+            match self.tree.cas(k.as_bytes(), current.as_ref(), Some(stored.clone()))? {
+                Ok(()) => {
+                    self.stats.write(bytes);
+                    return Ok(true);
+                },
+                Err(actual) => current = actual
+            }
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let now = now_ms();
+        let mut keys = Vec::new();
+        for pair in self.tree.iter() {
+            let (key, stored) = pair?;
+            if !is_expired(&stored, now)? {
+                keys.push(String::from_utf8(key).map_err(|_| KvsError::InvalidUtf8)?);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn sorted_keys(&self) -> Result<Vec<String>> {
+        // Sled iterates in byte order, which for UTF-8 keys is the same as sorting them
+        self.keys()
+    }
+
+    fn range(&self, from: String, to: String) -> Result<Vec<(String, String)>> {
+        if from >= to {
+            return Ok(Vec::new());
+        }
+        let now = now_ms();
+        let mut pairs = Vec::new();
+        for pair in self.tree.range(from.as_bytes()..to.as_bytes()) {
+            let (key, stored) = pair?;
+            if is_expired(&stored, now)? {
+                continue;
+            }
+            if let Ok(value) = String::from_utf8(decode(&stored)?.value.to_vec()) {
+                self.stats.read();
+                pairs.push((String::from_utf8(key).map_err(|_| KvsError::InvalidUtf8)?, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        dir_size(&self.path)
+    }
+
+    fn touch(&self, k: String, ttl: Duration) -> Result<bool> {
+        check_key(&k)?;
+        let expiry = now_ms().saturating_add(ttl.as_millis() as u64);
+
+        // Only the header changes, the value and its place in the order of writes are kept
+        let (_, mut current) = self.get_live(&k)?;
+        loop {
+            let stored = match &current {
+                Some(stored) if !is_expired(stored, now_ms())? => stored,
+                _ => return Ok(false)
+            };
+            let decoded = decode(stored)?;
+            let touched = encode(decoded.value, decoded.written, Some(expiry));
+            match self.tree.cas(k.as_bytes(), Some(stored), Some(touched))? {
+                Ok(()) => return Ok(true),
+                Err(actual) => current = actual
+            }
+        }
+    }
+
+    fn get_with_ttl(&self, k: String) -> Result<Option<(String, Option<Duration>)>> {
+        check_key(&k)?;
+        self.stats.read();
+        let stored = match self.get_live(&k)?.1 {
+            Some(stored) => stored,
+            None => return Ok(None)
+        };
+        let stored = decode(&stored)?;
+        let v = String::from_utf8(stored.value.to_vec()).map_err(|_| KvsError::InvalidUtf8)?;
+
+        // A value which expires between being read and now has none of its TTL left, rather than being absent
+        let ttl = stored.expiry.map(|expiry| Duration::from_millis(expiry.saturating_sub(now_ms())));
+        Ok(Some((v, ttl)))
+    }
+
+    fn recent_keys(&self, n: usize) -> Result<Vec<String>> {
+        let now = now_ms();
+        let mut written = Vec::new();
+        for pair in self.tree.iter() {
+            let (key, stored) = pair?;
+            if !is_expired(&stored, now)? {
+                written.push((decode(&stored)?.written, key));
+            }
+        }
+        written.sort_unstable_by(|a, b| b.cmp(a));
+        written.into_iter()
+            .take(n)
+            .map(|(_, key)| Ok(String::from_utf8(key).map_err(|_| KvsError::InvalidUtf8)?))
+            .collect()
+    }
+
+    fn stats(&self) -> EngineStats {
+        self.stats.snapshot()
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Writes everything sled is holding in memory rather than waiting for its next flush
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get_bytes(&self, k: String) -> Result<Option<Vec<u8>>> {
+        check_key(&k)?;
+        self.stats.read();
+        Ok(self.get_live(&k)?.0)
+    }
+}
@@ -0,0 +1,83 @@
+use failure::Fail;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Errors specific to the KvStore, for callers which need to tell failures apart
+#[derive(Debug)]
+pub enum KvsError {
+    /// An empty string was given as a key, keys must have at least one character
+    EmptyKey,
+
+    /// Key to remove is not in the store
+    KeyNotFound,
+
+    /// A key was refused by the rules for which keys may be set, contains the key
+    InvalidKey(String),
+
+    /// Bucket name is empty or contains a NUL
+    InvalidBucket(String),
+
+    /// A value stored as bytes was requested as a string, but isn't valid UTF-8
+    InvalidUtf8,
+
+    /// Store was opened on a path which exists but is not a directory
+    NotADirectory(PathBuf),
+
+    /// Store was opened in a directory another open store holds, and wasn't let go of in time
+    StoreLocked(PathBuf),
+
+    /// Sled could not open its store in the directory, contains sled's description of the problem
+    SledOpen(PathBuf, String),
+
+    /// An index entry doesn't point at the latest `Set` of its key, contains what was found there instead
+    IndexCorrupt(String),
+
+    /// Text received over the network could not be parsed, contains a description of the problem
+    Protocol(String),
+
+    /// The other end closed the connection before sending anything
+    ConnectionClosed,
+
+    /// A request ran past the most bytes allowed without ending, contains the limit
+    RequestTooLarge(usize),
+
+    /// A hash operation was given a key whose value isn't a hash of counters, contains the key
+    NotAHash(String),
+
+    /// Writing would grow the log past its size limit even after compacting, contains the limit in bytes
+    StoreFull(u64),
+
+    /// An engine operation didn't finish within its timeout, contains the timeout
+    Timeout(Duration),
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvsError::EmptyKey => write!(f, "Key must not be empty"),
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::InvalidKey(key) => write!(f, "Key '{}' is not allowed, it doesn't match the rules for keys", key.escape_default()),
+            KvsError::InvalidBucket(bucket) => write!(f, "Invalid bucket name '{}', it must be non-empty and contain no NUL", bucket.escape_default()),
+            KvsError::InvalidUtf8 => write!(f, "Value is not valid UTF-8, get it as bytes instead"),
+            KvsError::NotADirectory(path) => write!(f, "Cannot open store in {}, it is not a directory", path.display()),
+            KvsError::StoreLocked(path) => write!(f, "Cannot open store in {}, it is open elsewhere. Check no other server is using the directory", path.display()),
+            KvsError::SledOpen(path, reason) => write!(
+                f,
+                "Cannot open sled store in {}: {}. Check no other server is using the directory, \
+                if the store is corrupt move it aside and start again with an empty directory",
+                path.display(),
+                reason
+            ),
+            KvsError::IndexCorrupt(reason) => write!(f, "Index is inconsistent with the log: {}. Run kvs-admin verify on the store for details", reason),
+            KvsError::Protocol(reason) => write!(f, "Protocol error: {}", reason),
+            KvsError::ConnectionClosed => write!(f, "Connection closed by peer"),
+            KvsError::NotAHash(key) => write!(f, "Value of '{}' is not a hash of counters", key.escape_default()),
+            KvsError::StoreFull(max) => write!(f, "Store is full, the write would grow the log past its limit of {} bytes", max),
+            KvsError::RequestTooLarge(max) => write!(f, "Protocol error: request is longer than the {} bytes allowed", max),
+            KvsError::Timeout(timeout) => write!(f, "Engine operation took longer than {}ms, it may still complete", timeout.as_millis()),
+        }
+    }
+}
+
+impl Fail for KvsError {}
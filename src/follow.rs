@@ -0,0 +1,44 @@
+//! Following a KvStore's log as it's written, such as to keep a replica in step
+use std::sync::Mutex;
+use std::sync::mpsc::{ self, Receiver, Sender };
+
+use crate::Command;
+
+/// What a follower of the log is told, see `KvStore::follow`
+#[derive(Debug, PartialEq, Clone)]
+pub enum LogEvent {
+    /// A command was appended to the log
+    Command(Command),
+
+    /// The log was compacted, rewriting it with only the latest set of each live key. The commands already
+    /// received still describe the store, but anything remembered about the log itself, such as how many
+    /// commands it holds, is out of date and should be read again with `KvStore::iter_commands`
+    Compacted
+}
+
+/// Everyone following a KvStore's log. Events are sent under the writer lock, so each follower sees them in the
+/// order they happened in the log
+#[derive(Default)]
+pub(crate) struct Followers {
+    senders: Mutex<Vec<Sender<LogEvent>>>,
+}
+
+impl Followers {
+
+    pub fn subscribe(&self) -> Receiver<LogEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.senders.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Send an event to every follower, dropping those whose receiver is gone. The event is only built if
+    /// someone is following, which saves copying every command when nobody is
+    pub fn publish<F: FnOnce() -> LogEvent>(&self, event: F) {
+        let mut senders = self.senders.lock().unwrap();
+        if senders.is_empty() {
+            return;
+        }
+        let event = event();
+        senders.retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,87 @@
+//! Hint files let a KvStore skip scanning its whole log on open, the classic bitcask startup optimization
+use serde::{ Serialize, Deserialize };
+use std::collections::{ HashMap, HashSet };
+use std::fs::{ self, File };
+use std::io::{ BufReader, BufWriter, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex, atomic::{ AtomicUsize, Ordering } };
+
+use crate::Result;
+use crate::index::{ LockedIndex, ShardedIndex };
+
+/// Snapshot of a KvStore's in-memory index, along with the log it was taken from
+#[derive(Deserialize)]
+pub(crate) struct Hint {
+    pub log_len: u64,
+    pub records: usize,
+    pub index: HashMap<String, usize>,
+    pub removed: HashSet<String>,
+}
+
+#[derive(Serialize)]
+struct HintRef<'a> {
+    log_len: u64,
+    records: usize,
+    index: &'a LockedIndex<'a>,
+    removed: &'a HashSet<String>,
+}
+
+impl Hint {
+
+    /// Load the hint file if it describes the log as it is right now.
+    /// The log is append only between compactions and compaction rewrites the hint, so a log of any
+    /// other length than the one recorded has changed since the hint was written
+    pub fn load(hint_path: &Path, log_path: &Path) -> Option<Hint> {
+        let log_len = fs::metadata(log_path).ok()?.len();
+        let hint_file = File::open(hint_path).ok()?;
+        let hint: Hint = serde_json::from_reader(BufReader::new(hint_file)).ok()?;
+
+        if hint.log_len == log_len {
+            Some(hint)
+        } else {
+            None
+        }
+    }
+
+    /// Write a hint for the log as it is right now, through a temporary file so a crash can't leave half a hint.
+    /// Does nothing for a limited index, part of which is only on disk
+    pub fn save(hint_path: &Path, log_path: &Path, records: usize, index: &LockedIndex, removed: &HashSet<String>) -> Result<()> {
+        if index.is_limited() {
+            return Ok(());
+        }
+
+        let hint = HintRef {
+            log_len: fs::metadata(log_path)?.len(),
+            records,
+            index,
+            removed
+        };
+
+        let temp_path = hint_path.with_extension("hint.tmp");
+        let mut bw = BufWriter::new(File::create(&temp_path)?);
+        serde_json::to_writer(&mut bw, &hint)?;
+        bw.flush()?;
+        drop(bw);
+        fs::rename(&temp_path, hint_path)?;
+
+        Ok(())
+    }
+}
+
+/// Writes a hint once the last clone of a KvStore is dropped, so a clean shutdown reopens without a scan
+pub(crate) struct HintOnDrop {
+    pub index: Arc<ShardedIndex>,
+    pub removed: Arc<Mutex<HashSet<String>>>,
+    pub records: Arc<AtomicUsize>,
+    pub log_path: PathBuf,
+    pub hint_path: PathBuf,
+}
+
+impl Drop for HintOnDrop {
+    fn drop(&mut self) {
+        if let (Some(index), Ok(removed)) = (self.index.try_lock_all(), self.removed.lock()) {
+            // Nothing to report the error to while dropping, the next open falls back to scanning the log
+            let _ = Hint::save(&self.hint_path, &self.log_path, self.records.load(Ordering::SeqCst), &index, &removed);
+        }
+    }
+}
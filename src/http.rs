@@ -0,0 +1,158 @@
+//! HTTP frontend for a KvsServer, so the store can be reached with curl or a browser
+//!
+//! Keys are the request path, percent-decoded and without its leading `/`
+//! - `GET /key` answers `200` with the value as the body, or `404` if the key isn't set
+//! - `PUT /key` sets the key to the request body and answers `204`
+//! - `DELETE /key` removes the key and answers `204`, or `404` if it wasn't set
+//!
+//! Each connection serves one request and is then closed
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+
+use failure::format_err;
+use slog::{ Logger, info, warn };
+
+use crate::{ KvsEngine, KvsError, Result, bucket::reject_separator };
+
+/// Largest body accepted for a `PUT`, bigger requests are answered with `413`
+const MAX_BODY: usize = 64 * 1024 * 1024;
+
+/// Request read from an HTTP client
+struct Request {
+    method: String,
+    key: String,
+    body: Vec<u8>
+}
+
+/// Response to write back, with its status code and reason
+struct HttpResponse {
+    code: u16,
+    reason: &'static str,
+    body: Vec<u8>
+}
+
+impl HttpResponse {
+    fn new(code: u16, reason: &'static str) -> HttpResponse {
+        HttpResponse { code, reason, body: Vec::new() }
+    }
+
+    fn with_body(code: u16, reason: &'static str, body: Vec<u8>) -> HttpResponse {
+        HttpResponse { code, reason, body }
+    }
+}
+
+/// Serve one request from `stream` against `store`, then close the connection
+pub fn serve_http<Engine: KvsEngine>(log: Logger, stream: TcpStream, store: Engine) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let response = match read_request(&mut reader) {
+        Ok(request) => {
+            let response = handle_request(&request, store);
+            info!(log, "HTTP request served"; "method" => &request.method, "key" => &request.key, "code" => response.code);
+            response
+        },
+        Err(e) => {
+            warn!(log, "Could not parse HTTP request"; "error" => %e);
+            HttpResponse::with_body(400, "Bad Request", format!("{}\n", e).into_bytes())
+        }
+    };
+    write_response(stream, &response)
+}
+
+/// Carry out a request against the engine
+fn handle_request<Engine: KvsEngine>(request: &Request, store: Engine) -> HttpResponse {
+    if request.key.is_empty() {
+        return HttpResponse::with_body(400, "Bad Request", b"Request a key, as in /key\n".to_vec());
+    }
+    let result = match request.method.as_str() {
+        "GET" => store.get_bytes(request.key.clone()).map(|value| match value {
+            Some(value) => HttpResponse::with_body(200, "OK", value),
+            None => HttpResponse::new(404, "Not Found")
+        }),
+        "PUT" => store.set_bytes(request.key.clone(), request.body.clone())
+            .map(|()| HttpResponse::new(204, "No Content")),
+        "DELETE" => store.remove(request.key.clone())
+            .map(|()| HttpResponse::new(204, "No Content")),
+        _ => return HttpResponse::new(405, "Method Not Allowed")
+    };
+    result.unwrap_or_else(|e| match e.downcast_ref::<KvsError>() {
+        Some(KvsError::KeyNotFound) => HttpResponse::new(404, "Not Found"),
+        Some(KvsError::EmptyKey) | Some(KvsError::InvalidUtf8) => HttpResponse::with_body(400, "Bad Request", format!("{}\n", e).into_bytes()),
+        _ => HttpResponse::with_body(500, "Internal Server Error", format!("{}\n", e).into_bytes())
+    })
+}
+
+/// Read the request line, headers and body of one request
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => (method.to_owned(), target.to_owned()),
+        _ => return Err(format_err!("Malformed request line {:?}", line.trim_end()))
+    };
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(format_err!("Connection closed before the end of the headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()
+                    .map_err(|_| format_err!("Invalid Content-Length {:?}", value.trim()))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(format_err!("Body of {} bytes is larger than the {} allowed", content_length, MAX_BODY));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    // Any query string is ignored
+    let path = target.split('?').next().unwrap_or("");
+    let key = percent_decode(path.strip_prefix('/').unwrap_or(path))?;
+    Ok(Request { method, key, body })
+}
+
+/// Decode `%XX` escapes in a path, which must come out as UTF-8 without a `%00`, since a NUL could name a key in a bucket
+fn percent_decode(path: &str) -> Result<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = path.get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format_err!("Invalid escape in path {:?}", path))?;
+            decoded.push(hex);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    let key = String::from_utf8(decoded).map_err(|_| format_err!("Path {:?} is not UTF-8 once decoded", path))?;
+    reject_separator(&key)?;
+    Ok(key)
+}
+
+fn write_response(mut stream: TcpStream, response: &HttpResponse) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nContent-Type: application/octet-stream\r\nConnection: close\r\n\r\n",
+        response.code,
+        response.reason,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()?;
+    Ok(())
+}
@@ -0,0 +1,549 @@
+//! KvStore's in-memory index of key -> log offset, sharded for concurrency and optionally capped with cold entries
+//! spilled to disk
+use serde::{ Serialize, Serializer, ser::SerializeMap };
+use std::collections::{ BTreeMap, HashMap };
+use std::collections::hash_map::{ DefaultHasher, RandomState };
+use std::fs::{ self, File, OpenOptions };
+use std::hash::{ BuildHasher, Hash, Hasher };
+use std::io::{ BufRead, BufReader, BufWriter, Seek, SeekFrom, Write };
+use std::ops::Bound;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Mutex, MutexGuard };
+
+use crate::Result;
+
+use failure::format_err;
+
+/// Rough bytes an in-memory entry costs on top of its key: the key is held twice (map and recency),
+/// plus the String headers, offset, tick and the maps' own bookkeeping
+const ENTRY_OVERHEAD: usize = 96;
+
+/// Stale entries allowed to build up in the spill file before it is rewritten, on top of one per live entry
+const SPILL_REWRITE_SLACK: usize = 1024;
+
+/// Hash function used to route keys to the index's shards
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum IndexHasher {
+    /// The standard library's SipHash with random keys, resistant to clients choosing keys which all land in one shard
+    #[default]
+    SipHash,
+
+    /// aHash, faster than SipHash while still randomly keyed. Needs the `ahash` feature
+    #[cfg(feature = "ahash")]
+    AHash,
+}
+
+/// Randomly keyed state of the chosen `IndexHasher`, fixed for the life of the index so each key keeps its shard
+enum Router {
+    SipHash(RandomState),
+    #[cfg(feature = "ahash")]
+    AHash(ahash::RandomState),
+}
+
+impl Router {
+
+    fn new(hasher: IndexHasher) -> Router {
+        match hasher {
+            IndexHasher::SipHash => Router::SipHash(RandomState::new()),
+            #[cfg(feature = "ahash")]
+            IndexHasher::AHash => Router::AHash(ahash::RandomState::new())
+        }
+    }
+
+    fn hash(&self, key: &str) -> u64 {
+        match self {
+            Router::SipHash(state) => state.hash_one(key),
+            #[cfg(feature = "ahash")]
+            Router::AHash(state) => state.hash_one(key)
+        }
+    }
+}
+
+/// The index split into shards, each behind its own lock, so reads of keys in different shards don't wait on each
+/// other. Writes go through a single shard too, while compaction and hints lock every shard at once
+pub(crate) struct ShardedIndex {
+    shards: Vec<Mutex<Index>>,
+    router: Router,
+}
+
+impl ShardedIndex {
+
+    /// Index with `shards` shards, each limited to its share of `limit` if given. Spill files for the shards are
+    /// kept in the directory given with the limit
+    pub fn new(shards: usize, hasher: IndexHasher, limit: Option<(usize, &Path)>) -> Result<ShardedIndex> {
+        let shards = (0..shards.max(1))
+            .map(|i| match limit {
+                Some((bytes, dir)) => Index::limited(bytes / shards.max(1), dir.join(format!("index.spill.{}", i))),
+                None => Ok(Index::new())
+            })
+            .map(|shard| shard.map(Mutex::new))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(ShardedIndex {
+            shards,
+            router: Router::new(hasher)
+        })
+    }
+
+    /// Lock the shard holding `key`
+    pub fn shard(&self, key: &str) -> MutexGuard<'_, Index> {
+        self.shards[self.route(key)].lock().unwrap()
+    }
+
+    /// Lock every shard, always in the same order so two callers can't deadlock
+    pub fn lock_all(&self) -> LockedIndex<'_> {
+        LockedIndex {
+            shards: self.shards.iter().map(|shard| shard.lock().unwrap()).collect(),
+            index: self
+        }
+    }
+
+    /// Like `lock_all`, but `None` if any shard's lock was poisoned by a panic
+    pub fn try_lock_all(&self) -> Option<LockedIndex<'_>> {
+        Some(LockedIndex {
+            shards: self.shards.iter().map(|shard| shard.lock().ok()).collect::<Option<Vec<_>>>()?,
+            index: self
+        })
+    }
+
+    /// Number of live keys in each shard
+    pub fn shard_lens(&self) -> Vec<usize> {
+        self.shards.iter().map(|shard| shard.lock().unwrap().len()).collect()
+    }
+
+    /// Number of live keys, locking each shard in turn
+    pub fn len(&self) -> usize {
+        self.shard_lens().iter().sum()
+    }
+
+    fn route(&self, key: &str) -> usize {
+        (self.router.hash(key) % self.shards.len() as u64) as usize
+    }
+}
+
+/// Every shard of a `ShardedIndex` locked, for work which needs the whole index to hold still
+pub(crate) struct LockedIndex<'a> {
+    shards: Vec<MutexGuard<'a, Index>>,
+    index: &'a ShardedIndex,
+}
+
+impl<'a> LockedIndex<'a> {
+
+    /// The shard holding `key`
+    pub fn shard_mut(&mut self, key: &str) -> &mut Index {
+        &mut self.shards[self.index.route(key)]
+    }
+
+    /// See `Index::get`
+    pub fn get(&mut self, key: &str) -> Result<Option<usize>> {
+        self.shard_mut(key).get(key)
+    }
+
+    /// See `Index::insert`
+    pub fn insert(&mut self, key: String, offset: usize) -> Result<Option<usize>> {
+        self.shard_mut(&key).insert(key, offset)
+    }
+
+    /// Number of live keys across every shard
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    /// Every key in every shard
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::with_capacity(self.len());
+        for shard in self.shards.iter() {
+            keys.extend(shard.keys()?);
+        }
+        Ok(keys)
+    }
+
+    /// Whether entries may be spilled to disk, a limited index can't be snapshotted in a hint
+    pub fn is_limited(&self) -> bool {
+        self.shards.iter().any(|shard| shard.is_limited())
+    }
+
+    /// Every key from `from` up to but not including `to`, in order. Each shard's keys in the range are gathered
+    /// and then merged
+    pub fn range(&self, from: &str, to: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            keys.extend(shard.range(from, to)?);
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Keep every shard's in-memory entries in key order from now on, see `Index::make_ordered`
+    pub fn make_ordered(&mut self) {
+        for shard in self.shards.iter_mut() {
+            shard.make_ordered();
+        }
+    }
+}
+
+/// Hints store the index as a plain map of key -> offset, only an unlimited index is ever written
+impl<'a> Serialize for LockedIndex<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for shard in self.shards.iter() {
+            for (key, (offset, _)) in shard.hot.iter() {
+                map.serialize_entry(key, offset)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// In-memory entries of a shard, each key's offset and when it was last used. Hashed by default, or kept in key
+/// order so a range of keys can be found without looking at every key, at the cost of slower point lookups
+enum Entries {
+    Hashed(HashMap<String, (usize, u64)>),
+    Ordered(BTreeMap<String, (usize, u64)>),
+}
+
+impl Entries {
+
+    fn get(&self, key: &str) -> Option<&(usize, u64)> {
+        match self {
+            Entries::Hashed(map) => map.get(key),
+            Entries::Ordered(map) => map.get(key)
+        }
+    }
+
+    fn insert(&mut self, key: String, entry: (usize, u64)) -> Option<(usize, u64)> {
+        match self {
+            Entries::Hashed(map) => map.insert(key, entry),
+            Entries::Ordered(map) => map.insert(key, entry)
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<(usize, u64)> {
+        match self {
+            Entries::Hashed(map) => map.remove(key),
+            Entries::Ordered(map) => map.remove(key)
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Entries::Hashed(map) => map.len(),
+            Entries::Ordered(map) => map.len()
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, &(usize, u64))> + '_> {
+        match self {
+            Entries::Hashed(map) => Box::new(map.iter()),
+            Entries::Ordered(map) => Box::new(map.iter())
+        }
+    }
+
+    /// Keys from `from` up to but not including `to`, in order only if the entries are ordered
+    fn range(&self, from: &str, to: &str) -> Vec<String> {
+        match self {
+            Entries::Hashed(map) => {
+                map.keys().filter(|key| from <= key.as_str() && key.as_str() < to).cloned().collect()
+            },
+            Entries::Ordered(map) => {
+                map.range::<str, _>((Bound::Included(from), Bound::Excluded(to))).map(|(key, _)| key.clone()).collect()
+            }
+        }
+    }
+}
+
+/// One shard's index of every live key to the offset of its latest `Set` in the log.
+/// Unlimited by default, in which case it is a plain map. With a limit, the least recently used entries beyond
+/// it are moved to a spill file and brought back in when next used, so lookups of cold keys cost a disk read
+pub(crate) struct Index {
+    hot: Entries,
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    hot_bytes: usize,
+    limit: Option<(usize, Spill)>,
+}
+
+impl Index {
+
+    /// Index held entirely in memory
+    pub fn new() -> Index {
+        Index {
+            hot: Entries::Hashed(HashMap::new()),
+            recency: BTreeMap::new(),
+            tick: 0,
+            hot_bytes: 0,
+            limit: None
+        }
+    }
+
+    /// Index keeping about `bytes` of entries in memory, spilling the rest to a file at `spill_path`.
+    /// The file is only scratch space, it is emptied here and removed when the index is dropped
+    pub fn limited(bytes: usize, spill_path: PathBuf) -> Result<Index> {
+        let mut index = Index::new();
+        index.limit = Some((bytes, Spill::create(spill_path)?));
+        Ok(index)
+    }
+
+    /// Whether entries may be spilled to disk, a limited index can't be snapshotted in a hint
+    pub fn is_limited(&self) -> bool {
+        self.limit.is_some()
+    }
+
+    /// Number of live keys, in memory or spilled
+    pub fn len(&self) -> usize {
+        match &self.limit {
+            Some((_, spill)) => self.hot.len() + spill.live,
+            None => self.hot.len()
+        }
+    }
+
+    /// Every key in the index, spilled keys are read back from disk without being brought into memory
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self.hot.iter().map(|(key, _)| key.clone()).collect();
+        if let Some((_, spill)) = &self.limit {
+            for positions in spill.positions.values() {
+                for position in positions {
+                    keys.push(spill.read_entry(*position)?.0);
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Keys from `from` up to but not including `to`, in no particular order. Ordered entries find the range
+    /// without looking at keys outside it, spilled keys are all read back from disk and checked
+    pub fn range(&self, from: &str, to: &str) -> Result<Vec<String>> {
+        if from >= to {
+            return Ok(Vec::new());
+        }
+        let mut keys = self.hot.range(from, to);
+        if let Some((_, spill)) = &self.limit {
+            for positions in spill.positions.values() {
+                for position in positions {
+                    let (key, _) = spill.read_entry(*position)?;
+                    if from <= key.as_str() && key.as_str() < to {
+                        keys.push(key);
+                    }
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Move the in-memory entries into a map kept in key order, which later inserts keep to. Spilled entries stay
+    /// where they are
+    pub fn make_ordered(&mut self) {
+        let ordered = match &mut self.hot {
+            Entries::Hashed(map) => map.drain().collect(),
+            Entries::Ordered(_) => return
+        };
+        self.hot = Entries::Ordered(ordered);
+    }
+
+    /// Offset of `key`'s latest `Set`, a spilled entry is brought back into memory
+    pub fn get(&mut self, key: &str) -> Result<Option<usize>> {
+        if self.limit.is_none() {
+            return Ok(self.hot.get(key).map(|(offset, _)| *offset));
+        }
+        if let Some(offset) = self.take_hot(key) {
+            self.insert_hot(key.to_owned(), offset)?;
+            return Ok(Some(offset));
+        }
+        match self.take_spilled(key)? {
+            Some(offset) => {
+                self.insert_hot(key.to_owned(), offset)?;
+                Ok(Some(offset))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Point `key` at `offset`, returns the offset it pointed at before
+    pub fn insert(&mut self, key: String, offset: usize) -> Result<Option<usize>> {
+        let previous = match self.take_hot(&key) {
+            Some(previous) => Some(previous),
+            None => self.take_spilled(&key)?
+        };
+        self.insert_hot(key, offset)?;
+        Ok(previous)
+    }
+
+    /// Drop `key` from the index, returns the offset it pointed at
+    pub fn remove(&mut self, key: &str) -> Result<Option<usize>> {
+        match self.take_hot(key) {
+            Some(offset) => Ok(Some(offset)),
+            None => self.take_spilled(key)
+        }
+    }
+
+    fn take_hot(&mut self, key: &str) -> Option<usize> {
+        let (offset, last_used) = self.hot.remove(key)?;
+        if self.limit.is_some() {
+            self.recency.remove(&last_used);
+            self.hot_bytes -= entry_size(key);
+        }
+        Some(offset)
+    }
+
+    fn take_spilled(&mut self, key: &str) -> Result<Option<usize>> {
+        match &mut self.limit {
+            Some((_, spill)) => spill.take(key),
+            None => Ok(None)
+        }
+    }
+
+    /// Insert into memory as the most recently used entry, then spill the least recently used past the limit.
+    /// The entry just inserted always stays, however large its key
+    fn insert_hot(&mut self, key: String, offset: usize) -> Result<()> {
+        let (limit, spill) = match &mut self.limit {
+            Some((limit, spill)) => (*limit, spill),
+            None => {
+                self.hot.insert(key, (offset, 0));
+                return Ok(());
+            }
+        };
+
+        self.tick += 1;
+        self.hot_bytes += entry_size(&key);
+        self.recency.insert(self.tick, key.clone());
+        self.hot.insert(key, (offset, self.tick));
+
+        while self.hot_bytes > limit && self.hot.len() > 1 {
+            let (_, coldest) = match self.recency.pop_first() {
+                Some(entry) => entry,
+                None => break
+            };
+            if let Some((offset, _)) = self.hot.remove(&coldest) {
+                self.hot_bytes -= entry_size(&coldest);
+                spill.put(&coldest, offset)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn entry_size(key: &str) -> usize {
+    2 * key.len() + ENTRY_OVERHEAD
+}
+
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Append only file of spilled `[key, offset]` entries. Only a hash of each key is kept in memory, pointing at
+/// the entries on disk which share it. Taking an entry back leaves it stale in the file, which is rewritten
+/// once stale entries outnumber live ones
+struct Spill {
+    path: PathBuf,
+    file: File,
+    positions: HashMap<u64, Vec<u64>>,
+    live: usize,
+    written: usize,
+}
+
+impl Spill {
+
+    fn create(path: PathBuf) -> Result<Spill> {
+        let file = open_spill_file(&path, true)?;
+        Ok(Spill {
+            path,
+            file,
+            positions: HashMap::new(),
+            live: 0,
+            written: 0
+        })
+    }
+
+    fn read_entry(&self, position: u64) -> Result<(String, usize)> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(position))?;
+        let mut line = String::new();
+        BufReader::new(file).read_line(&mut line)?;
+        serde_json::from_str(&line)
+            .map_err(|e| format_err!("Entry at byte {} of {} is not a valid index entry: {}", position, self.path.display(), e))
+    }
+
+    /// Remove `key`'s entry, returning its offset
+    fn take(&mut self, key: &str) -> Result<Option<usize>> {
+        let hash = hash_key(key);
+        let candidates = match self.positions.get(&hash) {
+            Some(candidates) => candidates.clone(),
+            None => return Ok(None)
+        };
+
+        for (i, position) in candidates.iter().enumerate() {
+            let (spilled_key, offset) = self.read_entry(*position)?;
+            if spilled_key == key {
+                if candidates.len() == 1 {
+                    self.positions.remove(&hash);
+                } else if let Some(positions) = self.positions.get_mut(&hash) {
+                    positions.swap_remove(i);
+                }
+                self.live -= 1;
+                return Ok(Some(offset));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Add an entry for `key`, which must not already have one
+    fn put(&mut self, key: &str, offset: usize) -> Result<()> {
+        let mut line = serde_json::to_string(&(key, offset))?;
+        line.push('\n');
+        let position = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(line.as_bytes())?;
+
+        self.positions.entry(hash_key(key)).or_default().push(position);
+        self.live += 1;
+        self.written += 1;
+
+        if self.written > 2 * self.live + SPILL_REWRITE_SLACK {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    /// Copy only the live entries to a new file, through a temporary file so a failure leaves the old one usable
+    fn rewrite(&mut self) -> Result<()> {
+        let temp_path = self.path.with_extension("spill.tmp");
+        let mut bw = BufWriter::new(File::create(&temp_path)?);
+        let mut positions: HashMap<u64, Vec<u64>> = HashMap::with_capacity(self.positions.len());
+        let mut position = 0;
+
+        for (hash, old_positions) in self.positions.iter() {
+            for old_position in old_positions {
+                let mut line = serde_json::to_string(&self.read_entry(*old_position)?)?;
+                line.push('\n');
+                bw.write_all(line.as_bytes())?;
+                positions.entry(*hash).or_default().push(position);
+                position += line.len() as u64;
+            }
+        }
+        bw.flush()?;
+        drop(bw);
+
+        fs::rename(&temp_path, &self.path)?;
+        self.file = open_spill_file(&self.path, false)?;
+        self.positions = positions;
+        self.written = self.live;
+        Ok(())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        // Spilled entries are rebuilt from the log on every open, so there's nothing worth keeping
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn open_spill_file(path: &Path, truncate: bool) -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(truncate)
+        .open(path)?)
+}
@@ -2,30 +2,106 @@
 #![deny(missing_docs)]
 
 mod engine;
+mod error;
+mod index;
+use index::{ Index, LockedIndex, ShardedIndex };
+pub use index::IndexHasher;
+mod hint;
+use hint::{ Hint, HintOnDrop };
+mod record;
+use record::Records;
+mod blob;
+use blob::BlobStore;
+mod lock;
+use lock::StoreLock;
+mod checkpoint;
+mod retry;
+pub use retry::RetryWriter;
+mod writer;
+use writer::WriteQueue;
+pub use writer::WriteTicket;
+mod verify;
+pub use verify::VerifyReport;
+mod follow;
+use follow::Followers;
+pub use follow::LogEvent;
+use checkpoint::Checkpointer;
+use std::time::{ Duration, Instant };
 use std::sync::{
     Arc,
-    Mutex
+    Mutex,
+    mpsc::Receiver,
+    atomic::{
+        AtomicBool,
+        AtomicU64,
+        AtomicUsize,
+        Ordering
+    }
 };
 pub use engine::KvsEngine;
+pub use engine::KeyState;
+pub use engine::SetOutcome;
+pub use engine::EngineStats;
+use engine::StatsCounters;
 pub use engine::SledKvsEngine;
+pub use engine::SledKvsEngineBuilder;
+pub use error::KvsError;
+use engine::check_key;
 
 /// Module contains structs which define the network protocol between KvsClient and KvsServer
 pub mod network;
 
+pub mod client;
+pub use client::KvsClient;
+
 pub mod thread_pool;
 
+pub mod config;
+pub use config::ServerConfig;
+
+pub mod server;
+pub use server::{ run_server, ServerHandle };
+
+pub mod access_log;
+pub use access_log::AccessLog;
+
+pub mod rate_limit;
+pub use rate_limit::RateLimiter;
+
+pub mod sync_strategy;
+pub use sync_strategy::SyncStrategy;
+
+#[cfg(feature = "http")]
+pub mod http;
+
+mod caching;
+pub use caching::CachingEngine;
+pub(crate) use caching::Lru;
+
+mod replicated;
+pub use replicated::{ ReplicatedEngine, ReplicationPolicy };
+
+mod bucket;
+pub use bucket::{ BucketedEngine, check_bucket };
+
+mod timeout;
+pub use timeout::TimeoutEngine;
+
 use std::path;
 use std::path::PathBuf;
 use serde::{Serialize, Deserialize};
 use std::io::prelude::*;
-use std::io::{ BufWriter, BufReader };
-use std::fs::{ File, OpenOptions, create_dir };
-use failure::err_msg;
-use std::collections::HashMap;
+use std::io::{ BufWriter, BufReader, SeekFrom };
+use std::fs::{ self, File, OpenOptions, create_dir_all };
+use std::collections::HashSet;
 
 /// Result type returned by KvStore
 pub type Result<T> = std::result::Result<T, failure::Error>;
 
+/// Version of the KvStore log format, bumped whenever the way commands are written to disk changes.
+/// Version 1 was a JSON command per line, version 2 frames each command with its length
+pub const LOG_FORMAT_VERSION: u32 = 2;
+
 /// Represents a Key/Value Pair, elementary data stored by the KvStore
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Pair {
@@ -33,22 +109,93 @@ pub struct Pair {
     v: String,
 }
 
+impl Pair {
+
+    /// Pair a key with its value, for a `SetBytes` the value is the bytes base64 encoded
+    pub fn new(k: String, v: String) -> Pair {
+        Pair { k, v }
+    }
+
+    /// The key being set
+    pub fn key(&self) -> &str {
+        &self.k
+    }
+
+    /// The value being set, base64 encoded in a `SetBytes`
+    pub fn value(&self) -> &str {
+        &self.v
+    }
+}
+
 /// Commands which KvStore enters into log
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum Command {
     /// Set the value of a Pair, or add a new one
     Set(Pair),
 
+    /// Set the value of a Pair to raw bytes, the value is stored base64 encoded
+    SetBytes(Pair),
+
+    /// Set the value of a Pair to bytes kept out of the log, the value is the name of the blob file holding them.
+    /// Written in place of a set whose value reaches the blob threshold, see `KvStore::with_blob_threshold`
+    SetBlob(Pair),
+
     /// Remove a Pair
     Remove(String)
 }
 
+impl Command {
+
+    /// Read one record, framed by its length as in the log, from wherever `reader` is positioned. A reader handing
+    /// back the frame a few bytes at a time is read from until the whole of it has arrived
+    pub fn read_framed<R: Read>(reader: &mut R) -> Result<Command> {
+        record::read_command(reader)
+    }
+
+    fn key(&self) -> &str {
+        match self {
+            Command::Set(pair) | Command::SetBytes(pair) | Command::SetBlob(pair) => &pair.k,
+            Command::Remove(key) => key
+        }
+    }
+}
+
+/// Function applied to every key given to a `KvStore`, see `KvStore::with_key_normalizer`
+pub type KeyNormalizer = dyn Fn(&str) -> String + Send + Sync;
+
+/// Function deciding whether a key may be written to a `KvStore`, see `KvStore::with_key_validator`
+pub type KeyValidator = dyn Fn(&str) -> bool + Send + Sync;
+
 /// Store for storing key value pair
 #[derive(Clone)]
 pub struct KvStore {
-    index: Arc<Mutex<HashMap<String, usize>>>,
+    index: Arc<ShardedIndex>,
+    removed: Arc<Mutex<HashSet<String>>>,
+    records: Arc<AtomicUsize>,
+    writer: Arc<Mutex<()>>,
+    stats: Arc<StatsCounters>,
+    write_retries: u32,
     log_path: PathBuf,
-    log_threshold: i32
+    hint_path: PathBuf,
+    log_threshold: usize,
+    human_log: Arc<AtomicBool>,
+    compaction: Arc<AtomicBool>,
+    skip_redundant_sets: Arc<AtomicBool>,
+    sync: Arc<dyn SyncStrategy>,
+    // Zero for no limit
+    max_log_bytes: Arc<AtomicU64>,
+    blobs: Arc<BlobStore>,
+    // Zero keeps every value in the log
+    blob_threshold: Arc<AtomicU64>,
+    key_normalizer: Option<Arc<KeyNormalizer>>,
+    key_validator: Option<Arc<KeyValidator>>,
+    followers: Arc<Followers>,
+    // Dropped before the hint is written, so every queued write makes it into the hint
+    writes: Option<Arc<WriteQueue>>,
+    _hint_on_drop: Arc<HintOnDrop>,
+    _checkpointer: Option<Arc<Checkpointer>>,
+    // Last, so the directory is only let go of once the hint is written
+    _lock: Arc<StoreLock>
 }
 
 
@@ -56,118 +203,619 @@ impl KvStore {
 
     /// Creates a new empty KvStore with a default log file in the current directory
     pub fn new() -> Result<KvStore> {
+        KvStore::open(path::Path::new("./"))
+    }
+
+    /// Create a new empty KvStore with a log file in the specified directory.
+    /// The directory and any missing parents are created, fails if the path is an existing file.
+    /// A last record left cut short by a crash is dropped from the log. Fails with `StoreLocked` if the store is
+    /// already open, in this process or another
+    pub fn open(path: &path::Path) -> Result<KvStore> {
+        KvStore::builder().open(path)
+    }
 
-        let log_folder = "./";
-        let log_path = PathBuf::from(log_folder);
+    /// Builder for a KvStore with its index tuned, anything left unset keeps the default
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
 
-        if !log_path.exists() {
-            create_dir(log_folder)?;
-        }
+    /// Like `open`, but keeps only about `bytes` of the index in memory, see `KvStoreBuilder::index_limit`
+    pub fn open_with_index_limit(path: &path::Path, bytes: usize) -> Result<KvStore> {
+        KvStore::builder().index_limit(bytes).open(path)
+    }
 
-        KvStore::open(&log_path)
+    /// Like `open`, but waits up to `timeout` for a store already open in the directory to be closed, see
+    /// `KvStoreBuilder::lock_timeout`
+    pub fn open_with_timeout(path: &path::Path, timeout: Duration) -> Result<KvStore> {
+        KvStore::builder().lock_timeout(timeout).open(path)
     }
 
-    /// Create a new empty KvStore with a log file in the specified directory
-    pub fn open(path: &path::Path) -> Result<KvStore> {
+    fn open_store(path: &path::Path, options: KvStoreBuilder) -> Result<KvStore> {
+
+        if path.exists() && !path.is_dir() {
+            return Err(KvsError::NotADirectory(PathBuf::from(path)).into());
+        }
+        create_dir_all(path)?;
+        let lock = StoreLock::acquire(path, options.lock_timeout)?;
 
         let mut log_path = PathBuf::from(path);
         log_path.push("log.log");
+        let mut hint_path = PathBuf::from(path);
+        hint_path.push("log.hint");
+        record::prepare_log(&log_path, &hint_path)?;
+
+        let limit = options.index_limit.map(|bytes| (bytes, path));
+        let index = Arc::new(ShardedIndex::new(options.shards, options.hasher, limit)?);
+        let removed = Arc::new(Mutex::new(HashSet::new()));
+        let records = Arc::new(AtomicUsize::new(0));
+        let hint_on_drop = HintOnDrop {
+            index: index.clone(),
+            removed: removed.clone(),
+            records: records.clone(),
+            log_path: log_path.clone(),
+            hint_path: hint_path.clone()
+        };
 
         let mut store = KvStore { 
-            index: Arc::new(Mutex::new(HashMap::new())),
+            index,
+            removed,
+            records,
+            writer: Arc::new(Mutex::new(())),
+            stats: Arc::new(StatsCounters::default()),
+            write_retries: options.write_retries,
             log_path,
+            hint_path,
             log_threshold: 500,
+            human_log: Arc::new(AtomicBool::new(false)),
+            compaction: Arc::new(AtomicBool::new(options.compaction)),
+            skip_redundant_sets: Arc::new(AtomicBool::new(false)),
+            sync: options.sync.clone(),
+            max_log_bytes: Arc::new(AtomicU64::new(0)),
+            blobs: Arc::new(BlobStore::open(&path.join("blobs"))?),
+            blob_threshold: Arc::new(AtomicU64::new(0)),
+            key_normalizer: None,
+            key_validator: None,
+            followers: Arc::new(Followers::default()),
+            writes: None,
+            _hint_on_drop: Arc::new(hint_on_drop),
+            _checkpointer: None,
+            _lock: Arc::new(lock)
         };
-        store.generate_index()?;
+
+        match Hint::load(&store.hint_path, &store.log_path).filter(|_| options.index_limit.is_none()) {
+            Some(hint) => {
+                let mut index = store.index.lock_all();
+                for (key, offset) in hint.index {
+                    index.insert(key, offset)?;
+                }
+                *store.removed.lock().unwrap() = hint.removed;
+                store.records.store(hint.records, Ordering::SeqCst);
+            },
+            None => {
+                store.generate_index()?;
+            }
+        }
+        if options.verify_index {
+            store.verify_index()?;
+        }
+
+        if options.writer_thread {
+            let writer = store.clone();
+            store.writes = Some(Arc::new(WriteQueue::start(move |command| writer.write_command_now(command))));
+        }
 
         Ok(store)
     }
 
-    /// Create an index of key -> file offsets for storage in memory. This makes reads much faster
-    /// Must be regenerated on each write
-    fn generate_index(&mut self) -> Result<()> {
-        let br = self.open_reader()?;
+    /// Sync the log to disk every `interval` on a background thread, so a crash loses at most that window of writes.
+    /// The thread stops once the last clone of the store is dropped
+    pub fn with_checkpoint_interval(mut self, interval: Duration) -> KvStore {
+        self._checkpointer = Some(Arc::new(Checkpointer::start(self.log_path.clone(), self.writer.clone(), interval)));
+        self
+    }
 
-        //TODO add back log compaction on its own thread
-        let index = &mut self.index.lock().unwrap();
-        // let mut should_compact_log = false;
-        for (offset, line) in br.lines().enumerate() {
-            let line = line?;
-            let command = serde_json::from_str(&line)?;
-            match command {
-                Command::Set(pair) => {
-                    index.insert(pair.k, offset);
+    /// Keep the index in key order, so `range` finds its keys without looking at every key in the store. Point
+    /// lookups and writes get slightly slower, a tree being searched rather than a hash table. Keys spilled to disk
+    /// by an index limit are still all read to find a range. Applies to every clone of the store
+    pub fn with_ordered_index(self) -> KvStore {
+        self.index.lock_all().make_ordered();
+        self
+    }
+
+    /// Write new records pretty-printed, one field per line, for inspecting the log while debugging. Off by
+    /// default since the records take more room and longer to write. Applies to every clone of the store, and
+    /// records already written keep their format, which reads back just the same
+    pub fn with_human_log(self, human: bool) -> KvStore {
+        self.human_log.store(human, Ordering::SeqCst);
+        self
+    }
+
+    /// Whether the log is compacted once enough stale records build up, on by default. With it off the store only
+    /// ever appends, so the log keeps every command written and `iter_commands` returns the full history, at the
+    /// cost of the log growing with every write however few keys are live. `compact` still compacts when called.
+    /// Applies to every clone of the store. Opening may already have compacted, see `KvStoreBuilder::compaction`
+    pub fn with_compaction(self, compaction: bool) -> KvStore {
+        self.compaction.store(compaction, Ordering::SeqCst);
+        self
+    }
+
+    /// Skip writing a set whose value is already the key's value, so repeating a set doesn't grow the log. Off by
+    /// default since every set then costs a read of the current value. A skipped set still succeeds and reports the
+    /// key as existing, but isn't counted as a write or sent to followers. Applies to every clone of the store
+    pub fn with_skip_redundant_sets(self, skip: bool) -> KvStore {
+        self.skip_redundant_sets.store(skip, Ordering::SeqCst);
+        self
+    }
+
+    /// Refuse writes which would grow the log past `bytes`, failing them with `StoreFull` rather than filling the
+    /// disk. A write which doesn't fit compacts the log first to make room, unless compaction is turned off.
+    /// Removes are always written, even past the limit, so space can be freed by removing keys. Applies to every
+    /// clone of the store
+    pub fn with_max_log_bytes(self, bytes: u64) -> KvStore {
+        self.max_log_bytes.store(bytes, Ordering::SeqCst);
+        self
+    }
+
+    /// Keep values of `bytes` or more out of the log, each in a blob file of its own under `blobs` in the store's
+    /// directory, with the log recording only the file's name. Compaction then copies the name rather than the
+    /// value, and blobs no live key refers to are deleted once the log is compacted. Zero, the default, keeps every
+    /// value in the log. Applies to every clone of the store, values already written stay where they are
+    pub fn with_blob_threshold(self, bytes: u64) -> KvStore {
+        self.blob_threshold.store(bytes, Ordering::SeqCst);
+        self
+    }
+
+    /// Pass every key through `normalizer` before it's used, so keys which normalize alike are the same key, such
+    /// as lowercasing for case-insensitive keys. Keys are stored in their normalized form, and a key which
+    /// normalizes to nothing is empty. The normalizer should give the same key back when applied to a normalized
+    /// key. Keys already in the log aren't rewritten, so use the same normalizer every time the store is opened.
+    /// Applies to clones made from the store afterwards
+    pub fn with_key_normalizer<F>(mut self, normalizer: F) -> KvStore where F: Fn(&str) -> String + Send + Sync + 'static {
+        self.key_normalizer = Some(Arc::new(normalizer));
+        self
+    }
+
+    /// Refuse to set keys `validator` returns false for, failing the write with `InvalidKey`, such as keys outside
+    /// a character set or without a required prefix. Only writes which set a value are checked, so keys already
+    /// stored can still be read and removed. The validator sees keys after normalizing. Applies to clones made from
+    /// the store afterwards
+    pub fn with_key_validator<F>(mut self, validator: F) -> KvStore where F: Fn(&str) -> bool + Send + Sync + 'static {
+        self.key_validator = Some(Arc::new(validator));
+        self
+    }
+
+    /// Apply the key normalizer, if there is one, and check the key that comes out
+    fn normalize_key(&self, k: String) -> Result<String> {
+        let k = match &self.key_normalizer {
+            Some(normalizer) => normalizer(&k),
+            None => k
+        };
+        check_key(&k)?;
+        Ok(k)
+    }
+
+    /// Check the log and hint in `path` without opening the store: every record should parse, and every entry in
+    /// a current hint should point at the latest `Set` of its key. Problems are reported rather than fixed
+    pub fn verify(path: &path::Path) -> Result<VerifyReport> {
+        verify::verify(path)
+    }
+
+    /// Read every command in the log, oldest first, including those a later command has overwritten or removed.
+    /// Reads the log as it was when called, later writes aren't seen and a compaction doesn't disturb the read.
+    /// Iteration stops after the first error, since the records after a bad one can't be found
+    pub fn iter_commands(&self) -> Result<impl Iterator<Item = Result<Command>>> {
+        let log_path = self.log_path.clone();
+        let records = {
+            let _writer = self.writer.lock().unwrap();
+            Records::open(&log_path)?
+        };
+        Ok(records.enumerate().map(move |(number, record)| {
+            let (offset, payload) = record?;
+            record::parse_command(&log_path, number + 1, offset, &payload)
+        }))
+    }
+
+    /// Receive every command written to the log from now on, as it's written, and a `LogEvent::Compacted` each
+    /// time the log is compacted. Commands written before the call aren't sent, so a replica catching up should
+    /// follow first and then read what's already there with `iter_commands`, which may repeat a few commands it
+    /// then receives. Events queue up until received, following stops when the receiver is dropped
+    pub fn follow(&self) -> Receiver<LogEvent> {
+        let _writer = self.writer.lock().unwrap();
+        self.followers.subscribe()
+    }
+
+    /// Every live key in the index with the offset of its latest `Set` in the log, in log order. For checking the
+    /// index against the log, alongside `iter_commands` or `verify`
+    pub fn dump_index(&self) -> Result<Vec<(String, usize)>> {
+        let mut index = self.index.lock_all();
+        let mut entries = Vec::with_capacity(index.len());
+        for key in index.keys()? {
+            if let Some(offset) = index.get(&key)? {
+                entries.push((key, offset));
+            }
+        }
+        entries.sort_unstable_by_key(|&(_, offset)| offset);
+        Ok(entries)
+    }
+
+    /// Number of live keys in each shard of the index, showing how evenly the hasher spreads keys
+    pub fn shard_lens(&self) -> Vec<usize> {
+        self.index.shard_lens()
+    }
+
+    /// Compact the log now rather than waiting for enough stale records to build up, returns the number of bytes reclaimed.
+    /// Like the compactions writes set off, it runs on the calling thread with the writer and every shard of the index
+    /// locked, so a store never compacts more than once at a time and compaction starts no threads of its own
+    pub fn compact(&self) -> Result<u64> {
+        let _writer = self.writer.lock().unwrap();
+        let mut index = self.index.lock_all();
+        let removed = self.removed.lock().unwrap();
+
+        let before = self.log_path.metadata()?.len();
+        self.compact_log(&mut index, &removed)?;
+        let after = self.log_path.metadata()?.len();
+
+        Ok(before.saturating_sub(after))
+    }
+
+    /// Create an index of key -> file offsets for storage in memory by scanning the whole log. This makes reads much faster
+    /// Only needed on open, writes keep the index up to date as they append
+    fn generate_index(&self) -> Result<()> {
+        let mut index = self.index.lock_all();
+        let mut removed = self.removed.lock().unwrap();
+        let mut records = 0;
+        let mut log = Records::open(&self.log_path)?;
+        let mut failure = None;
+        for (number, record) in log.by_ref().enumerate() {
+            let (offset, payload) = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            };
+            let command = record::parse_command(&self.log_path, number + 1, offset, &payload)?;
+            let shard = index.shard_mut(command.key());
+            KvStore::index_command(shard, &mut removed, command, offset as usize)?;
+            records += 1;
+        }
+        if let Some(e) = failure {
+            // A record cut short by a crash was never acknowledged, so it's dropped and later writes go after the
+            // last whole one
+            match log.torn_at() {
+                Some(offset) => OpenOptions::new().write(true).open(&self.log_path)?.set_len(offset)?,
+                None => return Err(e)
+            }
+        }
+        self.records.store(records, Ordering::SeqCst);
+
+        if self.needs_compaction(records, index.len()) {
+            self.compact_log(&mut index, &removed)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that every entry in the index points at a `Set` of its own key, failing with `IndexCorrupt` listing
+    /// those which don't. Only reads the records the index points at, unlike `verify` which checks the whole log
+    fn verify_index(&self) -> Result<()> {
+        let mut index = self.index.lock_all();
+        let mut br = self.open_reader()?;
+        let mut problems = Vec::new();
+
+        let keys = index.keys()?;
+        for key in keys.iter() {
+            let offset = match index.get(key)? {
+                Some(offset) => offset,
+                None => continue
+            };
+            br.seek(SeekFrom::Start(offset as u64))?;
+            match record::read_command(&mut br) {
+                Ok(Command::Set(pair)) | Ok(Command::SetBytes(pair)) | Ok(Command::SetBlob(pair)) if &pair.k == key => {},
+                Ok(Command::Set(pair)) | Ok(Command::SetBytes(pair)) | Ok(Command::SetBlob(pair)) => {
+                    problems.push(format!("'{}' points at byte {}, which sets '{}'", key, offset, pair.k));
+                },
+                Ok(Command::Remove(_)) => {
+                    problems.push(format!("'{}' points at byte {}, which is a remove command", key, offset));
                 },
-                Command::Remove(key) => {
-                    index.remove(&key);
+                Err(_) => {
+                    problems.push(format!("'{}' points at byte {}, which is not the start of a valid record", key, offset));
                 }
             }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            let summary = format!("{} of {} keys don't match the log: {}", problems.len(), keys.len(), problems.join(", "));
+            Err(KvsError::IndexCorrupt(summary).into())
+        }
+    }
+
+    /// Apply a command at `offset` in the log to the shard of the index holding its key, returns whether the key held
+    /// a value before
+    fn index_command(index: &mut Index, removed: &mut HashSet<String>, command: Command, offset: usize) -> Result<bool> {
+        match command {
+            Command::Set(pair) | Command::SetBytes(pair) | Command::SetBlob(pair) => {
+                removed.remove(&pair.k);
+                Ok(index.insert(pair.k, offset)?.is_some())
+            },
+            Command::Remove(key) => {
+                let existed = index.remove(&key)?.is_some();
+                removed.insert(key);
+                Ok(existed)
+            }
+        }
+    }
+
+    /// Rewrite the log keeping only the latest `Set` of each live key, and point the index at the new offsets.
+    /// Takes every shard of the index locked so no reader can follow an offset while the log is being rewritten.
+    /// Live records are copied one at a time into a new log which then replaces the old one, so memory holds the
+    /// moved keys and a single record at a time rather than every live value. Blobs stay where they are, the new log
+    /// refers to live ones just as the old one did, and the rest are deleted once the old log is gone
+    fn compact_log(&self, index: &mut LockedIndex, removed: &HashSet<String>) -> Result<()> {
+        let started = Instant::now();
+        let before = self.log_path.metadata()?.len();
+        let temp_path = self.log_path.with_extension("log.compact");
+        let mut bw = BufWriter::new(RetryWriter::new(File::create(&temp_path)?, self.write_retries));
+        record::write_header(&mut bw)?;
 
-            // if offset > self.log_threshold as usize {
+        let mut moved = Vec::with_capacity(index.len());
+        let mut live_blobs = HashSet::new();
+        let mut new_offset = record::HEADER_LEN;
+        for (number, record) in Records::open(&self.log_path)?.enumerate() {
+            let (offset, payload) = record?;
+            let command = record::parse_command(&self.log_path, number + 1, offset, &payload)?;
 
-            //     should_compact_log = true;
+            let is_blob = matches!(command, Command::SetBlob(_));
+            if let Command::Set(pair) | Command::SetBytes(pair) | Command::SetBlob(pair) = command {
+                if index.get(&pair.k)? == Some(offset as usize) {
+                    if is_blob {
+                        live_blobs.insert(pair.v);
+                    }
+                    moved.push((pair.k, new_offset as usize));
+                    new_offset += record::write_frame(&mut bw, &payload)?;
+                }
+            }
+        }
+        bw.flush()?;
+        drop(bw);
+        fs::rename(&temp_path, &self.log_path)?;
 
-            // }
+        // Only once the new log is in place, a failure before here leaves the old log and index untouched
+        let records = moved.len();
+        for (key, offset) in moved {
+            index.insert(key, offset)?;
         }
+        self.records.store(records, Ordering::SeqCst);
+        self.followers.publish(|| LogEvent::Compacted);
+        self.blobs.retain(&live_blobs)?;
 
-        // if should_compact_log {
-        //     self.compact_log()?;
-        // }
+        Hint::save(&self.hint_path, &self.log_path, records, index, removed)?;
 
+        let after = self.log_path.metadata()?.len();
+        self.stats.compaction(before.saturating_sub(after), started.elapsed());
         Ok(())
     }
 
-    // fn compact_log(&mut self) -> Result<()> {
-
-    //     let br = self.open_reader()?;
-
-    //     let mut new_log: Vec<Command> = Vec::new();
-    //     for line in br.lines() {
-
-    //         let line = line?;
-    //         let command: Command = serde_json::from_str(&line)?;
-
-    //         KvStore::add_or_replace_command_in_vec(&mut new_log, command);
-    //     }
-
-    //     let mut bw = self.open_writer(false)?;
-
-    //     for command in new_log.iter() {
-    //         let command_json = serde_json::to_string(&command)?;
-    //         bw.write_all(command_json.as_bytes())?;
-    //         bw.write_all(b"\n")?;
-    //     }
-    //     bw.flush()?;
-
-    //     Ok(())
-    // }
-
-    // fn add_or_replace_command_in_vec(vec: &mut Vec<Command>, command: Command) { 
-    //     match command {
-    //         Command::Set(pair) => {
-    //             let command_dup = Command::Set(Pair { k: pair.k.clone(), v: pair.v.clone() });
-    //             let index_opt = vec.iter().position(|c| {
-    //                 match c {
-    //                     Command::Set(pair_inner) => {
-    //                         pair.k == pair_inner.k
-    //                     },
-    //                     Command::Remove(_) => { false }
-    //                 }
-    //             });
-    //             if let Some(index) = index_opt {
-    //                 vec.remove(index);
-    //                 vec.push(command_dup);
-    //             } else {
-    //                 vec.push(command_dup);
-    //             }
-    //         },
-    //         Command::Remove(_) => {
-    //             vec.push(command);
-    //         }
-    //     }
-    // }
-
-    fn open_writer(&self, append: bool) -> Result<BufWriter<File>> {
+    /// Set a key through the writer thread without waiting for it, see `KvStoreBuilder::writer_thread`. Without a
+    /// writer thread the set happens before this returns
+    pub fn set_async(&self, k: String, v: String) -> Result<WriteTicket> {
+        let k = self.normalize_key(k)?;
+        Ok(self.submit_command(Command::Set(Pair { k, v })))
+    }
+
+    /// Remove a key through the writer thread without waiting for it, the ticket fails with `KeyNotFound` if the
+    /// key held no value when the writer got to it
+    pub fn remove_async(&self, k: String) -> Result<WriteTicket> {
+        let k = self.normalize_key(k)?;
+        Ok(self.submit_command(Command::Remove(k)))
+    }
+
+    fn submit_command(&self, command: Command) -> WriteTicket {
+        match &self.writes {
+            Some(queue) => queue.submit(command),
+            None => WriteTicket::done(self.write_command_now(command))
+        }
+    }
+
+    /// Append a command to the log and add it to the index, through the writer thread if there is one.
+    /// Compacts the log once enough stale records have built up. Returns whether the command's key held a value before,
+    /// a `Remove` of a key without one fails with `KeyNotFound` and isn't written
+    fn write_command(&self, command: Command) -> Result<bool> {
+        match &self.writes {
+            Some(queue) => queue.submit(command).wait_existed(),
+            None => self.write_command_now(command)
+        }
+    }
+
+    /// `write_command` on the calling thread, holding the writer lock throughout
+    fn write_command_now(&self, command: Command) -> Result<bool> {
+        let _writer = self.writer.lock().unwrap();
+        self.write_command_locked(command)
+    }
+
+    /// `write_command` for callers already holding the writer lock
+    fn write_command_locked(&self, command: Command) -> Result<bool> {
+        // Checked under the writer lock, otherwise two removes of one key could both be appended
+        if let Command::Remove(key) = &command {
+            if self.index.shard(key).get(key)?.is_none() {
+                return Err(KvsError::KeyNotFound.into());
+            }
+        }
+        if let (Command::Set(pair) | Command::SetBytes(pair), Some(validator)) = (&command, &self.key_validator) {
+            if !validator(&pair.k) {
+                return Err(KvsError::InvalidKey(pair.k.clone()).into());
+            }
+        }
+        if self.skip_redundant_sets.load(Ordering::SeqCst) && self.is_redundant(&command)? {
+            return Ok(true);
+        }
+        let (logged, blob) = self.move_to_blob(&command)?;
+        let record = self.encode_command(&logged)?;
+        if let Command::Set(_) | Command::SetBytes(_) | Command::SetBlob(_) = &logged {
+            self.make_room(record.len() as u64)?;
+        }
+        // Only written once make_room is done, a compaction there would delete a blob the log doesn't refer to yet,
+        // and a write refused as StoreFull leaves no blob behind
+        let blob_bytes = match blob {
+            Some((name, value)) => {
+                self.blobs.write(&name, &value, self.sync.should_sync())?;
+                value.len() as u64
+            },
+            None => 0
+        };
+        let (offset, bytes) = self.append_record(&record)?;
+        // Followers get the value itself, the blob it was moved to means nothing outside this store
+        self.followers.publish(|| LogEvent::Command(command.clone()));
+        match &command {
+            Command::Remove(_) => self.stats.remove(bytes),
+            _ => self.stats.write(bytes + blob_bytes)
+        }
+
+        let records = self.records.fetch_add(1, Ordering::SeqCst) + 1;
+        let existed = {
+            let mut shard = self.index.shard(logged.key());
+            let mut removed = self.removed.lock().unwrap();
+            KvStore::index_command(&mut shard, &mut removed, logged, offset)?
+        };
+
+        // Only writers change the number of live keys, and the writer lock is held
+        if self.needs_compaction(records, self.index.len()) {
+            let mut index = self.index.lock_all();
+            let removed = self.removed.lock().unwrap();
+            self.compact_log(&mut index, &removed)?;
+        }
+
+        Ok(existed)
+    }
+
+    /// The command to log for `command`, with a value reaching the blob threshold replaced by a `SetBlob` of a
+    /// newly reserved blob. Also returns that blob's name and the value still to be written to it
+    fn move_to_blob(&self, command: &Command) -> Result<(Command, Option<(String, Vec<u8>)>)> {
+        let threshold = self.blob_threshold.load(Ordering::SeqCst);
+        let (k, v) = match command {
+            _ if threshold == 0 => return Ok((command.clone(), None)),
+            Command::Set(pair) => (&pair.k, pair.v.as_bytes().to_vec()),
+            Command::SetBytes(pair) => (&pair.k, base64::decode(&pair.v)?),
+            Command::SetBlob(_) | Command::Remove(_) => return Ok((command.clone(), None))
+        };
+        if (v.len() as u64) < threshold {
+            return Ok((command.clone(), None));
+        }
+        let name = self.blobs.reserve();
+        Ok((Command::SetBlob(Pair { k: k.clone(), v: name.clone() }), Some((name, v))))
+    }
+
+    /// Whether a set would store the value its key already holds
+    fn is_redundant(&self, command: &Command) -> Result<bool> {
+        let (k, v) = match command {
+            Command::Set(pair) => (&pair.k, pair.v.clone().into_bytes()),
+            Command::SetBytes(pair) => (&pair.k, base64::decode(&pair.v)?),
+            Command::SetBlob(_) | Command::Remove(_) => return Ok(false)
+        };
+        Ok(self.read_value(k)? == Some(v))
+    }
+
+    /// Whether enough of the log's records are stale to compact it, never if compaction is turned off
+    fn needs_compaction(&self, records: usize, live: usize) -> bool {
+        self.compaction.load(Ordering::SeqCst) && records - live > self.log_threshold
+    }
+
+    /// Frame a command as a record in the format the log is being written in
+    fn encode_command(&self, command: &Command) -> Result<Vec<u8>> {
+        let mut record = Vec::new();
+        if self.human_log.load(Ordering::SeqCst) {
+            record::write_human_command(&mut record, command)?;
+        } else {
+            record::write_command(&mut record, command)?;
+        }
+        Ok(record)
+    }
+
+    /// Check a record of `len` bytes fits in the log's size limit, compacting to make room if it doesn't.
+    /// Called with the writer lock held
+    fn make_room(&self, len: u64) -> Result<()> {
+        let max = self.max_log_bytes.load(Ordering::SeqCst);
+        if max == 0 {
+            return Ok(());
+        }
+        let fits = || -> Result<bool> { Ok(self.log_path.metadata()?.len() + len <= max) };
+        if fits()? {
+            return Ok(());
+        }
+        if self.compaction.load(Ordering::SeqCst) {
+            let mut index = self.index.lock_all();
+            let removed = self.removed.lock().unwrap();
+            self.compact_log(&mut index, &removed)?;
+        }
+        if fits()? {
+            Ok(())
+        } else {
+            Err(KvsError::StoreFull(max).into())
+        }
+    }
+
+    /// Append a framed record to the end of the log, returns the offset it was written at and the bytes written
+    fn append_record(&self, record: &[u8]) -> Result<(usize, u64)> {
+        Ok(self.append_records(&[record])?[0])
+    }
+
+    /// Append framed records to the end of the log one after another, flushing and syncing once after the last.
+    /// Returns the offset each was written at and the bytes written for it
+    fn append_records(&self, records: &[&[u8]]) -> Result<Vec<(usize, u64)>> {
+        let mut bw = self.open_writer(true)?;
+        let mut offset = bw.get_ref().get_ref().metadata()?.len();
+        let mut placed = Vec::with_capacity(records.len());
+        for record in records {
+            bw.write_all(record)?;
+            placed.push((offset as usize, record.len() as u64));
+            offset += record.len() as u64;
+        }
+        bw.flush()?;
+        if self.sync.should_sync() {
+            bw.get_ref().get_ref().sync_data()?;
+        }
+        Ok(placed)
+    }
+
+    /// Read a key's value from the log without counting it as a read
+    fn read_value(&self, k: &str) -> Result<Option<Vec<u8>>> {
+        let mut shard = self.index.shard(k);
+        if let Some(offset) = shard.get(k)? {
+
+            let br = self.open_reader()?;
+
+            // The key's shard is locked, so neither a write nor a compaction can move the record from under us
+            let corrupt = |found: &str| KvsError::IndexCorrupt(format!("'{}' points at byte {} of the log, which {}", k, offset, found));
+            let mut br = br;
+            br.seek(SeekFrom::Start(offset as u64))?;
+            let command = record::read_command(&mut br).map_err(|_| corrupt("is not the start of a valid record"))?;
+
+            match command {
+                Command::Set(pair) if pair.k == k => {
+                    Ok(Some(pair.v.into_bytes()))
+                },
+                Command::SetBytes(pair) if pair.k == k => {
+                    Ok(Some(base64::decode(&pair.v)?))
+                },
+                Command::SetBlob(pair) if pair.k == k => {
+                    Ok(Some(self.blobs.read(&pair.v)?))
+                },
+                Command::Set(pair) | Command::SetBytes(pair) | Command::SetBlob(pair) => {
+                    Err(corrupt(&format!("sets '{}'", pair.k)).into())
+                },
+                Command::Remove(_) => {
+                    Err(corrupt("is a remove command").into())
+                }
+            }
+
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn open_writer(&self, append: bool) -> Result<BufWriter<RetryWriter<File>>> {
         let f = OpenOptions::new()
         .read(false)
         .write(true)
@@ -176,7 +824,7 @@ impl KvStore {
         .truncate(!append)
         .open(&self.log_path)?;
 
-        Ok(BufWriter::new(f))
+        Ok(BufWriter::new(RetryWriter::new(f, self.write_retries)))
     }
 
     fn open_reader(&self) -> Result<BufReader<File>> {
@@ -184,93 +832,310 @@ impl KvStore {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(&self.log_path)?;
 
         Ok(BufReader::new(f))
     }
 }
 
-impl KvsEngine for KvStore {
+/// Shards of the index by default, enough that concurrent readers rarely meet on one lock
+const DEFAULT_SHARDS: usize = 16;
 
-    fn set(&self, k: String, v: String) -> Result<()> {
-        let command = Command::Set(Pair { k, v });
+/// Times a transiently failing log write is retried by default
+const DEFAULT_WRITE_RETRIES: u32 = 3;
 
-        let mut bw = self.open_writer(true)?;
-        let command_json = serde_json::to_string(&command)?;
-        bw.write_all(command_json.as_bytes())?;
-        bw.write_all(b"\n")?;
-        bw.flush()?;
-        
-        // TODO see if this is necessary? Trying to get a mutable reference
-        // to the index, probably a better way
-        let mut clone = self.clone();
-        clone.generate_index()?;
+/// Sets how a KvStore's index is sharded and how much of it is kept in memory before opening the store
+#[derive(Debug, Clone)]
+pub struct KvStoreBuilder {
+    index_limit: Option<usize>,
+    shards: usize,
+    hasher: IndexHasher,
+    verify_index: bool,
+    write_retries: u32,
+    writer_thread: bool,
+    compaction: bool,
+    sync: Arc<dyn SyncStrategy>,
+    lock_timeout: Duration,
+}
 
-        Ok(())
+impl Default for KvStoreBuilder {
+    fn default() -> KvStoreBuilder {
+        KvStoreBuilder {
+            index_limit: None,
+            shards: DEFAULT_SHARDS,
+            hasher: IndexHasher::default(),
+            verify_index: false,
+            write_retries: DEFAULT_WRITE_RETRIES,
+            writer_thread: false,
+            compaction: true,
+            sync: Arc::new(sync_strategy::Never),
+            lock_timeout: Duration::from_secs(0)
+        }
+    }
+}
+
+impl KvStoreBuilder {
+
+    /// Keep only about `bytes` of the index in memory, shared between its shards. The least recently used keys are
+    /// spilled to scratch files in the directory and looked up there, so they are slower to read. Spilled keys still
+    /// cost a few dozen bytes each in memory. A limited index can't be saved as a hint, so every open scans the log
+    pub fn index_limit(mut self, bytes: usize) -> KvStoreBuilder {
+        self.index_limit = Some(bytes);
+        self
+    }
+
+    /// Number of shards the index is split into, each with its own lock. At least one
+    pub fn shards(mut self, shards: usize) -> KvStoreBuilder {
+        self.shards = shards.max(1);
+        self
+    }
+
+    /// Hash function routing keys to shards
+    pub fn hasher(mut self, hasher: IndexHasher) -> KvStoreBuilder {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Check on open that every key in the index points at a record setting that key, whether the index came from
+    /// a hint or a scan of the log. Costs a read per key, opening fails with `IndexCorrupt` if any don't match
+    pub fn verify_index(mut self, verify: bool) -> KvStoreBuilder {
+        self.verify_index = verify;
+        self
+    }
+
+    /// Times a write to the log is retried when interrupted by a signal or told it would block, before the
+    /// operation fails. Zero fails on the first error
+    pub fn write_retries(mut self, retries: u32) -> KvStoreBuilder {
+        self.write_retries = retries;
+        self
+    }
 
+    /// Carry out sets and removes on a dedicated thread which takes them from a queue in order, so writers don't
+    /// contend for the log's lock. `KvStore::set_async` and `KvStore::remove_async` return as soon as the write
+    /// is queued. Appends and other read-then-write operations still run on the caller's thread. Once the last
+    /// clone of the store is dropped the thread finishes every queued write before the drop returns
+    pub fn writer_thread(mut self, enabled: bool) -> KvStoreBuilder {
+        self.writer_thread = enabled;
+        self
+    }
+
+    /// Whether the log is compacted, including when opening, see `KvStore::with_compaction`. On by default
+    pub fn compaction(mut self, compaction: bool) -> KvStoreBuilder {
+        self.compaction = compaction;
+        self
+    }
+
+    /// When the log is synced to disk after each append, see `SyncStrategy`. Defaults to `sync_strategy::Never`
+    pub fn sync_strategy<S: SyncStrategy + 'static>(mut self, strategy: S) -> KvStoreBuilder {
+        self.sync = Arc::new(strategy);
+        self
+    }
+
+    /// How long opening waits for a store already open in the directory, by this or another process, to be
+    /// closed. Opening fails with `StoreLocked` if it's still open after that. Zero, the default, fails at once
+    pub fn lock_timeout(mut self, timeout: Duration) -> KvStoreBuilder {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Open a KvStore with these settings, see `KvStore::open`
+    pub fn open(self, path: &path::Path) -> Result<KvStore> {
+        KvStore::open_store(path, self)
+    }
+}
+
+impl KvsEngine for KvStore {
+
+    fn set(&self, k: String, v: String) -> Result<()> {
+        let k = self.normalize_key(k)?;
+        self.write_command(Command::Set(Pair { k, v }))?;
+        Ok(())
     }
 
     fn get(&self, k: String) -> Result<Option<String>> {
-        
-        let index = self.index.lock().unwrap();
-        if let Some(offset) = index.get(&k) {
+        match self.get_bytes(k)? {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes).map_err(|_| KvsError::InvalidUtf8)?)),
+            None => Ok(None)
+        }
+    }
 
-            let br = self.open_reader()?;
+    fn remove(&self, k: String) -> Result<()> {
+        let k = self.normalize_key(k)?;
+        self.write_command(Command::Remove(k))?;
+        Ok(())
+    }
 
-            let command_json = br.lines().nth(*offset).ok_or_else(|| err_msg("File pointer in index points to non-existant command"))??;
+    fn remove_reporting(&self, k: String) -> Result<Vec<u8>> {
+        let k = self.normalize_key(k)?;
 
-            let command: Command = serde_json::from_str(&command_json)?;
+        // As with compare_and_delete, the writer lock keeps any other write from landing between the read and the remove
+        let _writer = self.writer.lock().unwrap();
+        let v = self.read_value(&k)?.ok_or(KvsError::KeyNotFound)?;
+        self.write_command_locked(Command::Remove(k))?;
+        Ok(v)
+    }
 
-            match command {
-                Command::Set(pair) => {
-                    return Ok(Some(pair.v));
-                },
-                Command::Remove(_) => {
-                    return Err(err_msg("File pointer in index points to remove command"));
+    fn get_state(&self, k: String) -> Result<KeyState> {
+        let k = self.normalize_key(k)?;
+        match self.get(k.clone())? {
+            Some(v) => Ok(KeyState::Present(v)),
+            None => {
+                if self.removed.lock().unwrap().contains(&k) {
+                    Ok(KeyState::Deleted)
+                } else {
+                    Ok(KeyState::Absent)
                 }
             }
+        }
+    }
 
-        } else {
-            Ok(None)
+    fn set_bytes(&self, k: String, v: Vec<u8>) -> Result<()> {
+        let k = self.normalize_key(k)?;
+        self.write_command(Command::SetBytes(Pair { k, v: base64::encode(&v) }))?;
+        Ok(())
+    }
+
+    fn set_reporting(&self, k: String, v: String) -> Result<SetOutcome> {
+        let k = self.normalize_key(k)?;
+        let existed = self.write_command(Command::Set(Pair { k, v }))?;
+        Ok(SetOutcome::from_existed(existed))
+    }
+
+    fn append(&self, k: String, suffix: String) -> Result<usize> {
+        let k = self.normalize_key(k)?;
+
+        // Holding the writer lock from the read to the write means no other write can come in between
+        let _writer = self.writer.lock().unwrap();
+        let mut v = match self.read_value(&k)? {
+            Some(bytes) => String::from_utf8(bytes).map_err(|_| KvsError::InvalidUtf8)?,
+            None => String::new()
+        };
+        v.push_str(&suffix);
+        let len = v.len();
+        self.write_command_locked(Command::Set(Pair { k, v }))?;
+        Ok(len)
+    }
+
+    fn hincr(&self, k: String, field: String, delta: i64) -> Result<i64> {
+        let k = self.normalize_key(k)?;
+
+        // As with append, the writer lock makes the read and the write one step
+        let _writer = self.writer.lock().unwrap();
+        let current = self.read_value(&k)?;
+        let (v, count) = engine::increment_field(&k, current.as_deref(), &field, delta)?;
+        self.write_command_locked(Command::Set(Pair { k, v }))?;
+        Ok(count)
+    }
+
+    fn compare_and_delete(&self, k: String, expected: String) -> Result<bool> {
+        let k = self.normalize_key(k)?;
+
+        // As with set_if_absent, the writer lock keeps any other write from landing between the check and the remove
+        let _writer = self.writer.lock().unwrap();
+        if self.read_value(&k)? != Some(expected.into_bytes()) {
+            return Ok(false);
         }
+        self.write_command_locked(Command::Remove(k))?;
+        Ok(true)
     }
 
-    fn remove(&self, k: String) -> Result<()> {
-        
-        let entry_opt = self.get(k.clone())?;
+    fn remove_batch(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let keys = keys.into_iter().map(|k| self.normalize_key(k)).collect::<Result<Vec<String>>>()?;
+
+        // Every shard stays locked from checking which keys exist until they're all out of the index, so readers
+        // see either none of the batch removed or all of it
+        let _writer = self.writer.lock().unwrap();
+        let mut index = self.index.lock_all();
+        let mut existed = Vec::with_capacity(keys.len());
+        let mut removing = Vec::new();
+        let mut seen = HashSet::new();
+        for k in keys {
+            // A key named twice is only there to remove the first time
+            let present = index.get(&k)?.is_some() && seen.insert(k.clone());
+            if present {
+                removing.push(k);
+            }
+            existed.push(present);
+        }
+        if removing.is_empty() {
+            return Ok(existed);
+        }
 
-        if entry_opt.is_some() {
+        let framed = removing.iter()
+            .map(|k| self.encode_command(&Command::Remove(k.clone())))
+            .collect::<Result<Vec<Vec<u8>>>>()?;
+        let placed = self.append_records(&framed.iter().map(Vec::as_slice).collect::<Vec<&[u8]>>())?;
 
-            let mut bw = self.open_writer(true)?;
-            let command = Command::Remove(k);
-            let command_json = serde_json::to_string(&command)?;
-            bw.write_all(command_json.as_bytes())?;
-            bw.write_all(b"\n")?;
-            bw.flush()?;
+        let mut removed = self.removed.lock().unwrap();
+        for (k, (offset, bytes)) in removing.iter().zip(placed) {
+            self.followers.publish(|| LogEvent::Command(Command::Remove(k.clone())));
+            self.stats.remove(bytes);
+            KvStore::index_command(index.shard_mut(k), &mut removed, Command::Remove(k.clone()), offset)?;
+        }
 
-            // TODO see if this is necessary? Trying to get a mutable reference
-            // to the index, probably a better way
-            let mut clone = self.clone();
-            clone.generate_index()?;
+        let records = self.records.fetch_add(removing.len(), Ordering::SeqCst) + removing.len();
+        if self.needs_compaction(records, index.len()) {
+            self.compact_log(&mut index, &removed)?;
+        }
+        Ok(existed)
+    }
 
-            Ok(())
+    fn set_if_absent(&self, k: String, v: String) -> Result<bool> {
+        let k = self.normalize_key(k)?;
 
-        } else {
-            Err(err_msg("Key not found"))
+        // As with append, the writer lock keeps any other write from landing between the check and the set
+        let _writer = self.writer.lock().unwrap();
+        if self.index.shard(&k).get(&k)?.is_some() {
+            return Ok(false);
         }
+        self.write_command_locked(Command::Set(Pair { k, v }))?;
+        Ok(true)
     }
 
-}
+    fn keys(&self) -> Result<Vec<String>> {
+        self.index.lock_all().keys()
+    }
+
+    fn range(&self, from: String, to: String) -> Result<Vec<(String, String)>> {
+        // Bounds are compared with keys as they're stored, so they aren't normalized
+        let keys = self.index.lock_all().range(&from, &to)?;
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            self.stats.read();
+            // As with filter_values, a key removed since the range was found is left out
+            if let Some(Ok(value)) = self.read_value(&key)?.map(String::from_utf8) {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn recent_keys(&self, n: usize) -> Result<Vec<String>> {
+        // Each key points at its latest set, and the log only grows, so later offsets were written later.
+        // Compaction copies records in order, which keeps it that way
+        Ok(self.dump_index()?.into_iter().rev().take(n).map(|(key, _)| key).collect())
+    }
+
+    fn stats(&self) -> EngineStats {
+        self.stats.snapshot()
+    }
 
-// extern crate failure;
-// #[macro_use] extern crate failure_derive;
-
-// #[derive(Fail, Debug)]
-// enum MyError {
-//     #[fail(display = "{} is not a valid version.", _0)]
-//     InvalidVersion(u32),
-//     #[fail(display = "IO error: {}", error)]
-//     IoError { error: io::Error },
-//     #[fail(display = "An unknown error has occurred.")]
-//     UnknownError,
-// }
+    fn size_on_disk(&self) -> Result<u64> {
+        Ok(self.log_path.metadata()?.len() + self.blobs.size()?)
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Writes reach the OS as they're made, this syncs them to the disk under the writer lock
+        let _writer = self.writer.lock().unwrap();
+        OpenOptions::new().write(true).open(&self.log_path)?.sync_all()?;
+        Ok(())
+    }
+
+    fn get_bytes(&self, k: String) -> Result<Option<Vec<u8>>> {
+        let k = self.normalize_key(k)?;
+        self.stats.read();
+        self.read_value(&k)
+    }
+
+}
@@ -0,0 +1,39 @@
+//! A KvStore holds a lock on its directory while open, so two processes can't both append to one log
+use std::fs::{ File, OpenOptions };
+use std::path::Path;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use fs2::FileExt;
+
+use crate::{ Result, KvsError };
+
+/// How long to wait between attempts at a lock held by someone else
+const RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Exclusive lock on a store's directory, released when dropped
+pub(crate) struct StoreLock {
+    _file: File,
+}
+
+impl StoreLock {
+
+    /// Lock the store in `dir`, waiting up to `timeout` for whoever holds it to let go. A zero timeout fails at
+    /// once with `StoreLocked` if the store is already held
+    pub fn acquire(dir: &Path, timeout: Duration) -> Result<StoreLock> {
+        let file = OpenOptions::new().write(true).create(true).truncate(false).open(dir.join("log.lock"))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(StoreLock { _file: file }),
+                Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    if Instant::now() >= deadline {
+                        return Err(KvsError::StoreLocked(dir.to_path_buf()).into());
+                    }
+                    thread::sleep(RETRY_INTERVAL);
+                },
+                Err(e) => return Err(e.into())
+            }
+        }
+    }
+}
@@ -7,12 +7,35 @@ use failure::err_msg;
 
 use std::net::TcpStream;
 use std::io::*;
+use std::time::Duration;
 
-use crate::Result;
+use crate::{ Result, KvsError, EngineStats, bucket::reject_separator };
 
 const SET_CODE: &str = "set";
 const GET_CODE: &str = "get";
 const REMOVE_CODE: &str = "rm";
+const SET_BYTES_CODE: &str = "setb";
+const GET_BYTES_CODE: &str = "getb";
+const VERSION_CODE: &str = "version";
+const USE_CODE: &str = "use";
+const APPEND_CODE: &str = "append";
+const SCAN_CODE: &str = "scan";
+const SET_NX_CODE: &str = "setnx";
+const RECENT_CODE: &str = "recent";
+const TOUCH_CODE: &str = "touch";
+const STATS_CODE: &str = "stats";
+const HINCR_CODE: &str = "hincr";
+const CAD_CODE: &str = "cad";
+const GREP_CODE: &str = "grep";
+const GET_TTL_CODE: &str = "getttl";
+const REMOVE_BATCH_CODE: &str = "rmbatch";
+const ITEM_CODE: &str = "ITEM";
+
+/// TTL sent in a `GetTtl` response for a value which never expires
+const NO_EXPIRY: &str = "none";
+
+/// Longest request `read_from_stream` accepts in bytes, newline included
+pub const DEFAULT_MAX_REQUEST_BYTES: usize = 64 * 1024 * 1024;
 
 /// Trait defining a message to be sent between KvsServer and KvsClient, ensures the object is easy to use
 pub trait TcpMessage {
@@ -31,7 +54,7 @@ pub trait TcpMessage {
 }
 
 /// Operations the KvsClient sends to the KvsServer
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Operation {
 
     /// Set a new Key/Value pair
@@ -40,8 +63,121 @@ pub enum Operation {
     /// Retrieve the value for a given key
     Get(String),
 
+    /// Retrieve the value for a given key along with how long it has left before it expires. The response data is
+    /// the value and then the milliseconds left, or `none` if it never expires, packed with `join_fields`
+    GetTtl(String),
+
     /// Remove a Key/Value pair
-    Remove(String)
+    Remove(String),
+
+    /// Remove every listed key, the response data is `true` or `false` for each key in order, whether it held a
+    /// value, packed with `join_fields`
+    RemoveBatch(Vec<String>),
+
+    /// Set a Key to a binary value, sent base64 encoded
+    SetBytes(String, Vec<u8>),
+
+    /// Retrieve the value for a given key as bytes, the response data is base64 encoded
+    GetBytes(String),
+
+    /// Retrieve the server's version, engine and on-disk format
+    Version,
+
+    /// Switch the rest of the connection to the given bucket, each bucket is its own keyspace
+    Use(String),
+
+    /// Append text to a key's value, the response data is the value's new length in bytes
+    Append(String, String),
+
+    /// Stream every key/value pair in key order, the server sends a `ScanItem` for each then a `Response` to end the scan
+    Scan,
+
+    /// Stream every key/value pair whose value contains the text, sent like a `Scan`
+    Grep(String),
+
+    /// Set a key only if it holds no value, the response data is `true` if it was set and `false` if not
+    SetNx(String, String),
+
+    /// List up to this many of the most recently written keys, most recent first. The response data is the keys,
+    /// each escaped and separated by spaces
+    Recent(usize),
+
+    /// Make a key expire after this long without changing its value, sent in milliseconds. The response data is
+    /// `true` if the key held a value and `false` if not
+    Touch(String, Duration),
+
+    /// Report the server's health, the response data is a `ServerStats`
+    Stats,
+
+    /// Add to one field of a key holding a hash of counters, the response data is the field's new value
+    HIncr(String, String, i64),
+
+    /// Remove a key only if it holds the given value, the response data is `true` if it was removed and `false` if not
+    Cad(String, String)
+}
+
+impl Operation {
+
+    /// The code which starts this operation on the wire
+    pub fn code(&self) -> &'static str {
+        match self {
+            Operation::Set(_, _) => SET_CODE,
+            Operation::Get(_) => GET_CODE,
+            Operation::GetTtl(_) => GET_TTL_CODE,
+            Operation::Remove(_) => REMOVE_CODE,
+            Operation::RemoveBatch(_) => REMOVE_BATCH_CODE,
+            Operation::SetBytes(_, _) => SET_BYTES_CODE,
+            Operation::GetBytes(_) => GET_BYTES_CODE,
+            Operation::Version => VERSION_CODE,
+            Operation::Use(_) => USE_CODE,
+            Operation::Append(_, _) => APPEND_CODE,
+            Operation::Scan => SCAN_CODE,
+            Operation::Grep(_) => GREP_CODE,
+            Operation::SetNx(_, _) => SET_NX_CODE,
+            Operation::Recent(_) => RECENT_CODE,
+            Operation::Touch(_, _) => TOUCH_CODE,
+            Operation::Stats => STATS_CODE,
+            Operation::HIncr(_, _, _) => HINCR_CODE,
+            Operation::Cad(_, _) => CAD_CODE
+        }
+    }
+
+    /// The key this operation acts on, the bucket name for `Use`, `None` for operations without one
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Operation::Set(key, _) | Operation::Get(key) | Operation::GetTtl(key) | Operation::Remove(key)
+                | Operation::SetBytes(key, _) | Operation::GetBytes(key) | Operation::Use(key)
+                | Operation::Append(key, _) | Operation::SetNx(key, _) | Operation::Touch(key, _) | Operation::HIncr(key, _, _)
+                | Operation::Cad(key, _) => Some(key),
+            Operation::Version | Operation::Scan | Operation::Grep(_) | Operation::Recent(_) | Operation::Stats
+                | Operation::RemoveBatch(_) => None
+        }
+    }
+
+    /// Read an operation out of a `TcpStream`, reading no more than `max_bytes` of it. A longer request fails
+    /// with `KvsError::RequestTooLarge`, leaving the rest of it unread
+    pub fn read_limited(log: Logger, stream: TcpStream, max_bytes: usize) -> Result<Operation> {
+        Operation::read_buffered(log, &mut BufReader::new(stream), max_bytes)
+    }
+
+    /// Like `read_limited`, but from a reader kept for the whole connection, so requests a client sends without
+    /// waiting for the last response stay buffered for the next read rather than being dropped with the buffer
+    pub fn read_buffered<R: BufRead>(mut log: Logger, reader: &mut R, max_bytes: usize) -> Result<Operation> {
+        let mut br = reader.take(max_bytes as u64 + 1);
+
+        let mut request = String::new();
+        if br.read_line(&mut request)? == 0 {
+            return Err(KvsError::ConnectionClosed.into());
+        }
+        if request.len() > max_bytes {
+            return Err(KvsError::RequestTooLarge(max_bytes).into());
+        }
+
+        log = log.new(o!("net_request" => request.clone()));
+        info!(log, "Operation recieved from client");
+
+        Operation::from_text(log.clone(), request)
+    }
 }
 
 impl TcpMessage for Operation {
@@ -51,9 +187,10 @@ impl TcpMessage for Operation {
 
         if v[0] == SET_CODE {
             
-            let key = v[1];
-            let value = v[2];
-            let op = Operation::Set(String::from(key), String::from(value));
+            expect_arguments(&v, 2)?;
+            let key = key_argument(&v, 1)?;
+            let value = argument(&v, 2)?;
+            let op = Operation::Set(key, value);
             log = log.new(o!(op.clone()));
             info!(log, "Request parsed");
             Ok(op)
@@ -61,16 +198,164 @@ impl TcpMessage for Operation {
 
         } else if v[0] == GET_CODE {
 
-            let key = v[1];
-            let op = Operation::Get(String::from(key));
+            expect_arguments(&v, 1)?;
+            let key = key_argument(&v, 1)?;
+            let op = Operation::Get(key);
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == GET_TTL_CODE {
+
+            expect_arguments(&v, 1)?;
+            let key = key_argument(&v, 1)?;
+            let op = Operation::GetTtl(key);
             log = log.new(o!(op.clone()));
             info!(log, "Request parsed");
             Ok(op)
 
         } else if v[0] == REMOVE_CODE {
 
-            let key = v[1];
-            let op = Operation::Remove(String::from(key));
+            expect_arguments(&v, 1)?;
+            let key = key_argument(&v, 1)?;
+            let op = Operation::Remove(key);
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == REMOVE_BATCH_CODE {
+
+            // Takes any number of keys, each its own argument
+            let keys = (1..v.len()).map(|position| key_argument(&v, position)).collect::<Result<Vec<String>>>()?;
+            let op = Operation::RemoveBatch(keys);
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == SET_BYTES_CODE {
+
+            expect_arguments(&v, 2)?;
+            let key = key_argument(&v, 1)?;
+            let value = base64::decode(&argument(&v, 2)?)
+                .map_err(|e| KvsError::Protocol(format!("'{}' value is not valid base64: {}", SET_BYTES_CODE, e)))?;
+            let op = Operation::SetBytes(key, value);
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == GET_BYTES_CODE {
+
+            expect_arguments(&v, 1)?;
+            let key = key_argument(&v, 1)?;
+            let op = Operation::GetBytes(key);
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == VERSION_CODE {
+
+            expect_arguments(&v, 0)?;
+            let op = Operation::Version;
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == USE_CODE {
+
+            expect_arguments(&v, 1)?;
+            let bucket = argument(&v, 1)?;
+            let op = Operation::Use(bucket);
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == APPEND_CODE {
+
+            expect_arguments(&v, 2)?;
+            let key = key_argument(&v, 1)?;
+            let suffix = argument(&v, 2)?;
+            let op = Operation::Append(key, suffix);
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == HINCR_CODE {
+
+            expect_arguments(&v, 3)?;
+            let key = key_argument(&v, 1)?;
+            let field = argument(&v, 2)?;
+            let delta = argument(&v, 3)?;
+            let delta = delta.parse()
+                .map_err(|_| KvsError::Protocol(format!("'{}' delta '{}' is not a whole number", HINCR_CODE, delta)))?;
+            let op = Operation::HIncr(key, field, delta);
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == CAD_CODE {
+
+            expect_arguments(&v, 2)?;
+            let key = key_argument(&v, 1)?;
+            let expected = argument(&v, 2)?;
+            let op = Operation::Cad(key, expected);
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == SET_NX_CODE {
+
+            expect_arguments(&v, 2)?;
+            let key = key_argument(&v, 1)?;
+            let value = argument(&v, 2)?;
+            let op = Operation::SetNx(key, value);
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == RECENT_CODE {
+
+            expect_arguments(&v, 1)?;
+            let n = argument(&v, 1)?;
+            let n = n.parse()
+                .map_err(|_| KvsError::Protocol(format!("'{}' count '{}' is not a number", RECENT_CODE, n)))?;
+            let op = Operation::Recent(n);
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == TOUCH_CODE {
+
+            expect_arguments(&v, 2)?;
+            let key = key_argument(&v, 1)?;
+            let ms = argument(&v, 2)?;
+            let ms = ms.parse()
+                .map_err(|_| KvsError::Protocol(format!("'{}' TTL '{}' is not a number of milliseconds", TOUCH_CODE, ms)))?;
+            let op = Operation::Touch(key, Duration::from_millis(ms));
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == STATS_CODE {
+
+            expect_arguments(&v, 0)?;
+            let op = Operation::Stats;
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == SCAN_CODE {
+
+            expect_arguments(&v, 0)?;
+            let op = Operation::Scan;
+            log = log.new(o!(op.clone()));
+            info!(log, "Request parsed");
+            Ok(op)
+
+        } else if v[0] == GREP_CODE {
+
+            expect_arguments(&v, 1)?;
+            let text = argument(&v, 1)?;
+            let op = Operation::Grep(text);
             log = log.new(o!(op.clone()));
             info!(log, "Request parsed");
             Ok(op)
@@ -84,13 +369,61 @@ impl TcpMessage for Operation {
 
         match self {
             Operation::Get(key) => {
-                format!("{} {}", GET_CODE, key)
+                format!("{} {}", GET_CODE, escape(key))
+            },
+            Operation::GetTtl(key) => {
+                format!("{} {}", GET_TTL_CODE, escape(key))
             },
             Operation::Remove(key) => {
-                format!("{} {}", REMOVE_CODE, key)
+                format!("{} {}", REMOVE_CODE, escape(key))
+            },
+            Operation::RemoveBatch(keys) if keys.is_empty() => {
+                String::from(REMOVE_BATCH_CODE)
+            },
+            Operation::RemoveBatch(keys) => {
+                format!("{} {}", REMOVE_BATCH_CODE, join_fields(keys))
             },
             Operation::Set(key, value) => {
-                format!("{} {} {}", SET_CODE, key, value)
+                format!("{} {} {}", SET_CODE, escape(key), escape(value))
+            },
+            Operation::SetBytes(key, value) => {
+                format!("{} {} {}", SET_BYTES_CODE, escape(key), base64::encode(value))
+            },
+            Operation::GetBytes(key) => {
+                format!("{} {}", GET_BYTES_CODE, escape(key))
+            },
+            Operation::Version => {
+                String::from(VERSION_CODE)
+            },
+            Operation::Use(bucket) => {
+                format!("{} {}", USE_CODE, escape(bucket))
+            },
+            Operation::Append(key, suffix) => {
+                format!("{} {} {}", APPEND_CODE, escape(key), escape(suffix))
+            },
+            Operation::Scan => {
+                String::from(SCAN_CODE)
+            },
+            Operation::Grep(text) => {
+                format!("{} {}", GREP_CODE, escape(text))
+            },
+            Operation::SetNx(key, value) => {
+                format!("{} {} {}", SET_NX_CODE, escape(key), escape(value))
+            },
+            Operation::Touch(key, ttl) => {
+                format!("{} {} {}", TOUCH_CODE, escape(key), ttl.as_millis())
+            },
+            Operation::Stats => {
+                String::from(STATS_CODE)
+            },
+            Operation::Cad(key, expected) => {
+                format!("{} {} {}", CAD_CODE, escape(key), escape(expected))
+            },
+            Operation::HIncr(key, field, delta) => {
+                format!("{} {} {} {}", HINCR_CODE, escape(key), escape(field), delta)
+            },
+            Operation::Recent(n) => {
+                format!("{} {}", RECENT_CODE, n)
             }
         }
     }
@@ -104,33 +437,118 @@ impl TcpMessage for Operation {
         Ok(())
     }
 
-    fn read_from_stream(mut log: Logger, stream: TcpStream) -> Result<Operation> {
-        let mut br = BufReader::new(stream.try_clone()?);
+    fn read_from_stream(log: Logger, stream: TcpStream) -> Result<Operation> {
+        Operation::read_limited(log, stream, DEFAULT_MAX_REQUEST_BYTES)
+    }
+}
 
-        let mut request = String::new();
-        br.read_line(&mut request)?;
+/// Check a split request has exactly `count` arguments after its operation code
+fn expect_arguments(v: &[&str], count: usize) -> Result<()> {
+    if v.len() - 1 == count {
+        Ok(())
+    } else {
+        Err(KvsError::Protocol(format!("'{}' request takes {} arguments, got {}", v[0], count, v.len() - 1)).into())
+    }
+}
 
-        log = log.new(o!("net_request" => request.clone()));
-        info!(log, "Operation recieved from client");
+/// Get the unescaped argument at `position` of a split request, the operation code being position 0
+fn argument(v: &[&str], position: usize) -> Result<String> {
+    match v.get(position) {
+        Some(arg) => unescape(arg),
+        None => Err(KvsError::Protocol(format!("'{}' request is missing argument {}", v[0], position)).into())
+    }
+}
 
-        Operation::from_text(log.clone(), request)
+/// Like `argument`, for an argument naming a key, which can't contain the NUL bucketed keys are stored with
+fn key_argument(v: &[&str], position: usize) -> Result<String> {
+    let key = argument(v, position)?;
+    reject_separator(&key)?;
+    Ok(key)
+}
+
+/// Escape text for the wire. Fields are separated by spaces and messages end with a newline, so within a field
+/// a backslash is sent as `\\`, a space as `\s` and a newline as `\n`. Nothing else is changed,
+/// keeping the protocol readable
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ' ' => escaped.push_str("\\s"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c)
+        }
     }
+    escaped
 }
 
-fn remove_newline_from_end(string: String) -> String {
-    let len = string.len();
+/// Reverse `escape`, fails on a backslash which doesn't start one of its sequences
+fn unescape(text: &str) -> Result<String> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('s') => unescaped.push(' '),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => return Err(KvsError::Protocol(format!("unknown escape sequence '\\{}'", other)).into()),
+            None => return Err(KvsError::Protocol(String::from("text ends with an unfinished escape sequence")).into())
+        }
+    }
+    Ok(unescaped)
+}
 
-    let halves = string.split_at(len - 1);
+/// Pack a list into the data of one response, each entry escaped and separated by a space
+pub fn join_fields(fields: &[String]) -> String {
+    fields.iter().map(|field| escape(field)).collect::<Vec<String>>().join(" ")
+}
 
-    if halves.1 == "\n" {
-        String::from(halves.0)
-    } else {
-        string
+/// Reverse `join_fields`, an empty string is an empty list
+pub fn split_fields(text: &str) -> Result<Vec<String>> {
+    if text.is_empty() {
+        return Ok(Vec::new());
+    }
+    text.split(' ').map(unescape).collect()
+}
+
+/// Pack a value and how long it has left before it expires into the data of a `GetTtl` response
+pub fn join_ttl(value: String, ttl: Option<Duration>) -> String {
+    let ttl = match ttl {
+        Some(ttl) => ttl.as_millis().to_string(),
+        None => String::from(NO_EXPIRY)
+    };
+    join_fields(&[value, ttl])
+}
+
+/// Reverse `join_ttl`, the TTL being `None` for a value which never expires
+pub fn split_ttl(text: &str) -> Result<(String, Option<Duration>)> {
+    let mut fields = split_fields(text)?;
+    if fields.len() != 2 {
+        return Err(KvsError::Protocol(format!("'{}' response takes a value and a TTL, got {} fields", GET_TTL_CODE, fields.len())).into());
+    }
+    let ttl = fields.pop().expect("TTL field checked above");
+    let value = fields.pop().expect("Value field checked above");
+    if ttl == NO_EXPIRY {
+        return Ok((value, None));
+    }
+    let ms = ttl.parse()
+        .map_err(|_| KvsError::Protocol(format!("'{}' TTL '{}' is not a number of milliseconds", GET_TTL_CODE, ttl)))?;
+    Ok((value, Some(Duration::from_millis(ms))))
+}
+
+fn remove_newline_from_end(string: String) -> String {
+    match string.strip_suffix('\n') {
+        Some(trimmed) => String::from(trimmed),
+        None => string
     }
 }
 
 impl KV for Operation {
-    fn serialize(&self, _record: &Record, serializer: &mut Serializer) -> slog::Result<()> {
+    fn serialize(&self, _record: &Record, serializer: &mut dyn Serializer) -> slog::Result<()> {
         match self {
             Operation::Set(key, value) => {
 
@@ -141,26 +559,219 @@ impl KV for Operation {
 
                 serializer.emit_str("parsed_operation", &format!("Get {}", key))?;
                 
+            }
+            Operation::GetTtl(key) => {
+
+                serializer.emit_str("parsed_operation", &format!("GetTtl {}", key))?;
+
             }
             Operation::Remove(key) => {
 
                 serializer.emit_str("parsed_operation", &format!("Remove {}", key))?;
                 
             }
+            Operation::RemoveBatch(keys) => {
+
+                serializer.emit_str("parsed_operation", &format!("RemoveBatch {} keys", keys.len()))?;
+
+            }
+            Operation::SetBytes(key, value) => {
+
+                serializer.emit_str("parsed_operation", &format!("SetBytes {}->{} bytes", key, value.len()))?;
+
+            }
+            Operation::GetBytes(key) => {
+
+                serializer.emit_str("parsed_operation", &format!("GetBytes {}", key))?;
+
+            }
+            Operation::Version => {
+
+                serializer.emit_str("parsed_operation", "Version")?;
+
+            }
+            Operation::Use(bucket) => {
+
+                serializer.emit_str("parsed_operation", &format!("Use {}", bucket))?;
+
+            }
+            Operation::Append(key, suffix) => {
+
+                serializer.emit_str("parsed_operation", &format!("Append {}->{}", key, suffix))?;
+
+            }
+            Operation::Scan => {
+
+                serializer.emit_str("parsed_operation", "Scan")?;
+
+            }
+            Operation::Grep(text) => {
+
+                serializer.emit_str("parsed_operation", &format!("Grep {}", text))?;
+
+            }
+            Operation::SetNx(key, value) => {
+
+                serializer.emit_str("parsed_operation", &format!("SetNx {}->{}", key, value))?;
+
+            }
+            Operation::Recent(n) => {
+
+                serializer.emit_str("parsed_operation", &format!("Recent {}", n))?;
+
+            }
+            Operation::Touch(key, ttl) => {
+
+                serializer.emit_str("parsed_operation", &format!("Touch {} {}ms", key, ttl.as_millis()))?;
+
+            }
+            Operation::Stats => {
+
+                serializer.emit_str("parsed_operation", "Stats")?;
+
+            }
+            Operation::Cad(key, expected) => {
+
+                serializer.emit_str("parsed_operation", &format!("Cad {}=={}", key, expected))?;
+
+            }
+            Operation::HIncr(key, field, delta) => {
+
+                serializer.emit_str("parsed_operation", &format!("HIncr {}.{} by {}", key, field, delta))?;
+
+            }
         }
         Ok(())
     }
 }
 
+/// One key/value pair of a scan, sent by the KvsServer ahead of the `Response` which ends the scan
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanItem {
+    /// Key of the pair
+    pub key: String,
+
+    /// Value of the pair
+    pub value: String
+}
+
+impl ScanItem {
+
+    /// Text sent on the wire for this item
+    pub fn to_text(&self) -> String {
+        format!("{} {} {}", ITEM_CODE, escape(&self.key), escape(&self.value))
+    }
+
+    /// Parse a line of a scan, `None` if the line isn't an item and so is the `Response` ending the scan
+    pub fn from_text(text: &str) -> Result<Option<ScanItem>> {
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        let v: Vec<&str> = text.split(' ').collect();
+        if v[0] != ITEM_CODE {
+            return Ok(None);
+        }
+
+        expect_arguments(&v, 2)?;
+        Ok(Some(ScanItem {
+            key: argument(&v, 1)?,
+            value: argument(&v, 2)?
+        }))
+    }
+}
+
+/// Health of a KvsServer, the data of its response to `Operation::Stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerStats {
+    /// Counts of the engine's operations since the server started
+    pub engine: EngineStats,
+
+    /// Bytes the engine's files take up on disk, for the kvs engine the size of its log
+    pub log_bytes: u64,
+
+    /// Bytes free on the volume holding the data directory
+    pub disk_free: u64
+}
+
+impl ServerStats {
+
+    /// Text sent as the response data, `name=value` pairs separated by spaces
+    pub fn to_text(&self) -> String {
+        format!(
+            "reads={} writes={} removes={} bytes_written={} log_bytes={} disk_free={} compactions={} bytes_reclaimed={} compaction_ms={}",
+            self.engine.reads,
+            self.engine.writes,
+            self.engine.removes,
+            self.engine.bytes_written,
+            self.log_bytes,
+            self.disk_free,
+            self.engine.compactions,
+            self.engine.bytes_reclaimed,
+            self.engine.compaction_ms
+        )
+    }
+
+    /// Parse the data of a stats response, unknown names are skipped so servers can report more. The compaction
+    /// counts are left at 0 if missing, servers from before they were reported don't send them
+    pub fn from_text(text: &str) -> Result<ServerStats> {
+        let mut stats = ServerStats { engine: EngineStats::default(), log_bytes: 0, disk_free: 0 };
+        let mut found = 0;
+        for pair in text.split_whitespace() {
+            let (name, value) = pair.split_once('=')
+                .ok_or_else(|| KvsError::Protocol(format!("stats entry '{}' is not a name=value pair", pair)))?;
+            let (field, required) = match name {
+                "reads" => (&mut stats.engine.reads, true),
+                "writes" => (&mut stats.engine.writes, true),
+                "removes" => (&mut stats.engine.removes, true),
+                "bytes_written" => (&mut stats.engine.bytes_written, true),
+                "log_bytes" => (&mut stats.log_bytes, true),
+                "disk_free" => (&mut stats.disk_free, true),
+                "compactions" => (&mut stats.engine.compactions, false),
+                "bytes_reclaimed" => (&mut stats.engine.bytes_reclaimed, false),
+                "compaction_ms" => (&mut stats.engine.compaction_ms, false),
+                _ => continue
+            };
+            *field = value.parse()
+                .map_err(|_| KvsError::Protocol(format!("stats entry '{}' is not a number", pair)))?;
+            if required {
+                found += 1;
+            }
+        }
+        if found < 6 {
+            return Err(KvsError::Protocol(format!("stats '{}' are missing entries", text)).into());
+        }
+        Ok(stats)
+    }
+}
+
 /// Status for a Response sent back by the KvsServer
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ResponseStatus {
 
     /// Operation was successful, requested data should be in `Response`
     Ok,
 
-    /// Operation failed
-    Fail
+    /// Operation failed for an unspecified reason
+    Fail,
+
+    /// Key requested by a get was explicitly removed, rather than never set
+    Deleted,
+
+    /// Key to remove is not in the store
+    KeyNotFound,
+
+    /// Request could not be parsed, or was rejected by the engine as invalid
+    InvalidRequest,
+
+    /// Engine failed while carrying out a valid request
+    Internal,
+
+    /// Server is at its connection limit and turned the connection away, try again later
+    Busy,
+
+    /// Server is draining before it exits and turned the connection away, connect to another
+    Draining,
+
+    /// Client has sent more requests than its rate limit allows, the request was not carried out
+    RateLimited
 }
 
 impl ResponseStatus {
@@ -168,21 +779,43 @@ impl ResponseStatus {
     /// Create a response status from a String
     pub fn from_text(text: String) -> Result<ResponseStatus> {
         let trimmed = remove_newline_from_end(text);
-        if trimmed == "OK" {
-            Ok(ResponseStatus::Ok)
-        } else if trimmed == "FAIL" {
-            Ok(ResponseStatus::Fail)
-        } else {
-            Err(err_msg("Text could not be converted to response status"))
+        match trimmed.as_str() {
+            "OK" => Ok(ResponseStatus::Ok),
+            "FAIL" => Ok(ResponseStatus::Fail),
+            "DELETED" => Ok(ResponseStatus::Deleted),
+            "NOT_FOUND" => Ok(ResponseStatus::KeyNotFound),
+            "INVALID" => Ok(ResponseStatus::InvalidRequest),
+            "INTERNAL" => Ok(ResponseStatus::Internal),
+            "BUSY" => Ok(ResponseStatus::Busy),
+            "DRAINING" => Ok(ResponseStatus::Draining),
+            "RATE_LIMITED" => Ok(ResponseStatus::RateLimited),
+            _ => Err(err_msg("Text could not be converted to response status"))
+        }
+    }
+
+    /// Text sent on the wire for this status
+    pub fn to_text(&self) -> &'static str {
+        match self {
+            ResponseStatus::Ok => "OK",
+            ResponseStatus::Fail => "FAIL",
+            ResponseStatus::Deleted => "DELETED",
+            ResponseStatus::KeyNotFound => "NOT_FOUND",
+            ResponseStatus::InvalidRequest => "INVALID",
+            ResponseStatus::Internal => "INTERNAL",
+            ResponseStatus::Busy => "BUSY",
+            ResponseStatus::Draining => "DRAINING",
+            ResponseStatus::RateLimited => "RATE_LIMITED"
         }
     }
 }
 
 /// Response the KvsServer send back to the client
+#[derive(Debug, PartialEq)]
 pub struct Response {
     /// Status of the response, see `ResponseStatus` for details
     pub status: ResponseStatus,
-    /// Data requested by client, will be None depending on the operation sent
+    /// Data requested by client, will be None depending on the operation sent.
+    /// Failures may carry a description of the error
     pub data: Option<String>
 }
 
@@ -191,11 +824,12 @@ impl TcpMessage for Response {
     fn from_text(log: Logger, req: String) -> Result<Response> {
         
         info!(log, "Parsing Response from text");
-        let v: Vec<&str> = req.split(' ').collect();
+        let req = remove_newline_from_end(req);
+        let v: Vec<&str> = req.splitn(2, ' ').collect();
         if v.len() == 2 {
             Ok(Response {
                 status: ResponseStatus::from_text(String::from(v[0]))?,
-                data: Some(String::from(v[1]))
+                data: Some(unescape(v[1])?)
             })
         } else if v.len() == 1 {
             Ok(Response {
@@ -210,20 +844,9 @@ impl TcpMessage for Response {
     }
 
     fn to_text(&self) -> String {
-        match self.status {
-            ResponseStatus::Ok => {
-                match &self.data {
-                    Some(data) => {
-                        format!("OK {}", data)
-                    },
-                    None => {
-                        String::from("OK")
-                    }
-                }
-            },
-            ResponseStatus::Fail => {
-                String::from("FAIL")
-            }
+        match &self.data {
+            Some(data) => format!("{} {}", self.status.to_text(), escape(data)),
+            None => String::from(self.status.to_text())
         }
     }
 
@@ -238,7 +861,9 @@ impl TcpMessage for Response {
     fn read_from_stream(mut log: Logger, stream: TcpStream) -> Result<Response> {
         let mut br = BufReader::new(stream);
         let mut response_text = String::new();
-        br.read_line(&mut response_text)?;
+        if br.read_line(&mut response_text)? == 0 {
+            return Err(KvsError::ConnectionClosed.into());
+        }
 
         let response = Response::from_text(log.clone(), response_text.clone())?;
 
@@ -0,0 +1,74 @@
+//! Per-client rate limiting for a KvsServer
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{ Arc, Mutex };
+use std::time::Instant;
+
+/// Buckets kept before those which have filled back up are dropped, so clients which come and go don't
+/// leave their buckets behind
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// Token bucket for each client address, allowing `ops_per_sec` requests a second on average and bursts of up
+/// to a second's worth. Clones share the buckets, so one limiter can be handed to every connection
+///
+/// # Example
+/// ```
+/// use kvs::RateLimiter;
+///
+/// let limiter = RateLimiter::new(2);
+/// let client = "127.0.0.1".parse().unwrap();
+/// assert!(limiter.allow(client));
+/// assert!(limiter.allow(client));
+/// assert!(!limiter.allow(client));
+/// ```
+#[derive(Clone)]
+pub struct RateLimiter {
+    ops_per_sec: f64,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+
+    /// Allow each client address `ops_per_sec` requests a second
+    pub fn new(ops_per_sec: u32) -> RateLimiter {
+        RateLimiter {
+            ops_per_sec: f64::from(ops_per_sec),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Take a token from `client`'s bucket, returns false if it's empty and the request should be refused
+    pub fn allow(&self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_IDLE_BUCKETS && !buckets.contains_key(&client) {
+            let ops_per_sec = self.ops_per_sec;
+            buckets.retain(|_, bucket| bucket.refill(now, ops_per_sec) < ops_per_sec);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: self.ops_per_sec, refilled: now });
+        if bucket.refill(now, self.ops_per_sec) >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Bucket {
+
+    /// Add the tokens earned since the last refill, up to a second's worth, returns how many there are now
+    fn refill(&mut self, now: Instant, ops_per_sec: f64) -> f64 {
+        let earned = now.duration_since(self.refilled).as_secs_f64() * ops_per_sec;
+        self.tokens = (self.tokens + earned).min(ops_per_sec);
+        self.refilled = now;
+        self.tokens
+    }
+}
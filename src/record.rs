@@ -0,0 +1,203 @@
+//! Framing of the records in a KvStore's log. The log opens with a header naming its format, then each command is
+//! written as its length, a little-endian u32, followed by that many bytes of JSON. Nothing inside a record can be
+//! mistaken for a boundary, and a record can be read straight from its offset without scanning for newlines
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ BufRead, BufReader, BufWriter, Read, Write };
+use std::path::{ Path, PathBuf };
+
+use failure::format_err;
+
+use crate::{ Result, Command, LOG_FORMAT_VERSION };
+
+/// Marks a file as a KvStore log, followed in the header by the format version
+const MAGIC: &[u8; 4] = b"KVS\0";
+
+/// Bytes taken by the header, the first record starts here
+pub(crate) const HEADER_LEN: u64 = 8;
+
+fn header() -> [u8; HEADER_LEN as usize] {
+    let mut header = [0; HEADER_LEN as usize];
+    header[..4].copy_from_slice(MAGIC);
+    header[4..].copy_from_slice(&LOG_FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// Make sure the log at `log_path` exists in the current format. A new log gets a header, and a log from before
+/// records were framed (one JSON command per line, with no header) is rewritten in place, dropping its hint
+/// whose offsets no longer apply
+pub(crate) fn prepare_log(log_path: &Path, hint_path: &Path) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(log_path)?;
+
+    if file.metadata()?.len() == 0 {
+        file.write_all(&header())?;
+        return Ok(());
+    }
+
+    let mut found = Vec::with_capacity(HEADER_LEN as usize);
+    (&mut file).take(HEADER_LEN).read_to_end(&mut found)?;
+    if found[..] == header() {
+        return Ok(());
+    }
+    if found.starts_with(MAGIC) {
+        return Err(format_err!("Log in {} uses an unsupported format, version {:?}", log_path.display(), &found[4..]));
+    }
+    drop(file);
+
+    let temp_path = log_path.with_extension("log.tmp");
+    let mut bw = BufWriter::new(File::create(&temp_path)?);
+    bw.write_all(&header())?;
+    for (number, line) in BufReader::new(File::open(log_path)?).lines().enumerate() {
+        let line = line.map_err(|e| format_err!("Could not read line {} of {}: {}", number + 1, log_path.display(), e))?;
+        let command: Command = serde_json::from_str(&line)
+            .map_err(|e| format_err!("Line {} of {} is not a valid command: {}", number + 1, log_path.display(), e))?;
+        write_command(&mut bw, &command)?;
+    }
+    bw.flush()?;
+    drop(bw);
+
+    fs::rename(&temp_path, log_path)?;
+    if hint_path.exists() {
+        fs::remove_file(hint_path)?;
+    }
+    Ok(())
+}
+
+/// Write the header which starts every log
+pub(crate) fn write_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(&header())?;
+    Ok(())
+}
+
+/// Frame and write a record's JSON, returns the bytes written. The frame goes out in one write so a concurrent
+/// reader of the file never sees a length without its record
+pub(crate) fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<u64> {
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    Ok(frame.len() as u64)
+}
+
+/// Serialize and write a command, returns the bytes written
+pub(crate) fn write_command<W: Write>(writer: &mut W, command: &Command) -> Result<u64> {
+    write_frame(writer, &serde_json::to_vec(command)?)
+}
+
+/// Parse the JSON of a record read by `Records`, an invalid command names the record, counting from 1, and its
+/// offset so it can be found in the log
+pub(crate) fn parse_command(log_path: &Path, number: usize, offset: u64, payload: &[u8]) -> Result<Command> {
+    serde_json::from_slice(payload)
+        .map_err(|e| format_err!("Record {} at byte {} of {} is not a valid command: {}", number, offset, log_path.display(), e))
+}
+
+/// Like `write_command`, but pretty-printed and ending in a newline so the log is easier to read by eye.
+/// Readers don't mind which way a record was written, so the two can be mixed in one log
+pub(crate) fn write_human_command<W: Write>(writer: &mut W, command: &Command) -> Result<u64> {
+    let mut payload = serde_json::to_vec_pretty(command)?;
+    payload.push(b'\n');
+    write_frame(writer, &payload)
+}
+
+/// Read the command of the record starting where `reader` is positioned. Reads can come back short, so both the
+/// length and the payload are read until they're complete. Reading from a bad offset finds a nonsense length, so
+/// the payload grows as bytes arrive rather than being allocated up front
+pub(crate) fn read_command<R: Read>(reader: &mut R) -> Result<Command> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u64::from(u32::from_le_bytes(len));
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < len {
+        return Err(format_err!("Record of {} bytes runs past the end of the log", len));
+    }
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+/// Reads a log's records in order, yielding each one's offset and JSON. A record running past the end of the file
+/// is an error, after which there is nothing more to read since the next boundary can't be known
+pub(crate) struct Records {
+    path: PathBuf,
+    reader: BufReader<File>,
+    offset: u64,
+    len: u64,
+    torn_at: Option<u64>,
+}
+
+impl Records {
+
+    /// Read the records of the log at `log_path`, which must start with the current header
+    pub fn open(log_path: &Path) -> Result<Records> {
+        let file = File::open(log_path)?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        let mut found = [0; HEADER_LEN as usize];
+        reader.read_exact(&mut found).map_err(|_| format_err!("Log in {} has no header", log_path.display()))?;
+        if found != header() {
+            return Err(format_err!("Log in {} has an unrecognised header", log_path.display()));
+        }
+
+        Ok(Records {
+            path: log_path.to_path_buf(),
+            reader,
+            offset: HEADER_LEN,
+            len,
+            torn_at: None
+        })
+    }
+
+    /// Where the record which ran past the end of the file starts, once reading has stopped at it. Only the last
+    /// record can be torn like this, as when the process dies part way through appending it
+    pub fn torn_at(&self) -> Option<u64> {
+        self.torn_at
+    }
+
+    fn read_frame(&mut self) -> Result<Vec<u8>> {
+        let path = self.path.display().to_string();
+        let truncated = |offset| format_err!("Record at byte {} runs past the end of the log in {}", offset, path);
+
+        if self.len - self.offset < 4 {
+            self.torn_at = Some(self.offset);
+            return Err(truncated(self.offset));
+        }
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = u64::from(u32::from_le_bytes(len));
+        if self.len - self.offset - 4 < len {
+            self.torn_at = Some(self.offset);
+            return Err(truncated(self.offset));
+        }
+
+        let mut payload = vec![0; len as usize];
+        self.reader.read_exact(&mut payload)?;
+        Ok(payload)
+    }
+}
+
+impl Iterator for Records {
+    type Item = Result<(u64, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.len {
+            return None;
+        }
+        match self.read_frame() {
+            Ok(payload) => {
+                let offset = self.offset;
+                self.offset += 4 + payload.len() as u64;
+                Some(Ok((offset, payload)))
+            },
+            Err(e) => {
+                // Stop here, there's no telling where the next record would start
+                self.offset = self.len;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
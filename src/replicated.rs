@@ -0,0 +1,178 @@
+//! An engine which mirrors its writes to remote KvsServers
+use slog::*;
+
+use std::time::Duration;
+
+use crate::{ Result, KvsEngine, KeyState, KvsClient, SetOutcome, EngineStats };
+
+/// What a ReplicatedEngine does when a remote server fails to take a write
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplicationPolicy {
+    /// Log the failure and report the write as successful, the local engine has it
+    BestEffort,
+
+    /// Fail the write. It has already been applied locally and to any replica before the failing one,
+    /// nothing is rolled back
+    Required,
+}
+
+/// Wraps a local engine, forwarding every `set` and `remove` to each replica after applying it locally.
+/// Reads are only ever served by the local engine
+#[derive(Clone)]
+pub struct ReplicatedEngine<E: KvsEngine> {
+    log: Logger,
+    local: E,
+    replicas: Vec<KvsClient>,
+    policy: ReplicationPolicy,
+}
+
+impl<E: KvsEngine> ReplicatedEngine<E> {
+
+    /// Wrap `local`, mirroring its writes to `replicas` according to `policy`
+    pub fn new(log: Logger, local: E, replicas: Vec<KvsClient>, policy: ReplicationPolicy) -> ReplicatedEngine<E> {
+        ReplicatedEngine {
+            log,
+            local,
+            replicas,
+            policy
+        }
+    }
+
+    fn replicate<F: Fn(&KvsClient) -> Result<()>>(&self, write: F) -> Result<()> {
+        for replica in self.replicas.iter() {
+            if let Err(e) = write(replica) {
+                match self.policy {
+                    ReplicationPolicy::BestEffort => {
+                        warn!(self.log, "Could not replicate write"; "error" => %e);
+                    },
+                    ReplicationPolicy::Required => {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ReplicatedEngine<E> {
+
+    fn set(&self, k: String, v: String) -> Result<()> {
+        self.local.set(k.clone(), v.clone())?;
+        self.replicate(|replica| replica.set(k.clone(), v.clone()))
+    }
+
+    fn get(&self, k: String) -> Result<Option<String>> {
+        self.local.get(k)
+    }
+
+    fn remove(&self, k: String) -> Result<()> {
+        self.local.remove(k.clone())?;
+        self.replicate(|replica| replica.remove(k.clone()))
+    }
+
+    fn get_state(&self, k: String) -> Result<KeyState> {
+        self.local.get_state(k)
+    }
+
+    fn set_bytes(&self, k: String, v: Vec<u8>) -> Result<()> {
+        self.local.set_bytes(k.clone(), v.clone())?;
+        self.replicate(|replica| replica.set_bytes(k.clone(), v.clone()))
+    }
+
+    fn get_bytes(&self, k: String) -> Result<Option<Vec<u8>>> {
+        self.local.get_bytes(k)
+    }
+
+    fn get_with_ttl(&self, k: String) -> Result<Option<(String, Option<Duration>)>> {
+        self.local.get_with_ttl(k)
+    }
+
+    fn remove_reporting(&self, k: String) -> Result<Vec<u8>> {
+        let v = self.local.remove_reporting(k.clone())?;
+        self.replicate(|replica| replica.remove(k.clone()))?;
+        Ok(v)
+    }
+
+    fn remove_batch(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let existed = self.local.remove_batch(keys.clone())?;
+
+        // Only keys the local engine removed are sent on, as with compare_and_delete
+        let removed: Vec<String> = keys.into_iter().zip(existed.iter()).filter(|(_, e)| **e).map(|(k, _)| k).collect();
+        if !removed.is_empty() {
+            self.replicate(|replica| replica.remove_batch(removed.clone()).map(|_| ()))?;
+        }
+        Ok(existed)
+    }
+
+    fn set_if_absent(&self, k: String, v: String) -> Result<bool> {
+        // The local engine decides, replicas just follow its value whatever they held
+        let set = self.local.set_if_absent(k.clone(), v.clone())?;
+        if set {
+            self.replicate(|replica| replica.set(k.clone(), v.clone()))?;
+        }
+        Ok(set)
+    }
+
+    fn compare_and_delete(&self, k: String, expected: String) -> Result<bool> {
+        // As with set_if_absent the local engine decides, replicas just remove the key too
+        let removed = self.local.compare_and_delete(k.clone(), expected)?;
+        if removed {
+            self.replicate(|replica| replica.remove(k.clone()))?;
+        }
+        Ok(removed)
+    }
+
+    fn stats(&self) -> EngineStats {
+        self.local.stats()
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Replicas are other servers, which flush for themselves
+        self.local.flush()
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.local.keys()
+    }
+
+    fn sorted_keys(&self) -> Result<Vec<String>> {
+        self.local.sorted_keys()
+    }
+
+    fn range(&self, from: String, to: String) -> Result<Vec<(String, String)>> {
+        self.local.range(from, to)
+    }
+
+    fn recent_keys(&self, n: usize) -> Result<Vec<String>> {
+        self.local.recent_keys(n)
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        self.local.size_on_disk()
+    }
+
+    fn touch(&self, k: String, ttl: Duration) -> Result<bool> {
+        let touched = self.local.touch(k.clone(), ttl)?;
+        self.replicate(|replica| replica.touch(k.clone(), ttl).map(|_| ()))?;
+        Ok(touched)
+    }
+
+    fn set_reporting(&self, k: String, v: String) -> Result<SetOutcome> {
+        let outcome = self.local.set_reporting(k.clone(), v.clone())?;
+        self.replicate(|replica| replica.set(k.clone(), v.clone()))?;
+        Ok(outcome)
+    }
+
+    fn append(&self, k: String, suffix: String) -> Result<usize> {
+        let len = self.local.append(k.clone(), suffix.clone())?;
+        self.replicate(|replica| replica.append(k.clone(), suffix.clone()).map(|_| ()))?;
+        Ok(len)
+    }
+
+    fn hincr(&self, k: String, field: String, delta: i64) -> Result<i64> {
+        let count = self.local.hincr(k.clone(), field.clone(), delta)?;
+        self.replicate(|replica| replica.hincr(k.clone(), field.clone(), delta).map(|_| ()))?;
+        Ok(count)
+    }
+}
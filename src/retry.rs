@@ -0,0 +1,59 @@
+//! Retrying of writes which fail for reasons that pass, such as a signal arriving mid-write
+use std::io::{ self, ErrorKind, Write };
+use std::thread;
+use std::time::Duration;
+
+/// Pause before retrying a write the OS said would block
+const WOULD_BLOCK_DELAY: Duration = Duration::from_millis(1);
+
+/// Wraps a writer, retrying each write and flush up to a fixed number of times when it fails with an
+/// `Interrupted` or `WouldBlock` error. Any other error is returned at once, as is the last transient one
+/// once the retries run out. Each `write` is retried on its own, so `write_all` never repeats bytes which
+/// were already written
+pub struct RetryWriter<W: Write> {
+    inner: W,
+    retries: u32,
+}
+
+impl<W: Write> RetryWriter<W> {
+
+    /// Wrap `inner`, retrying a failed write up to `retries` times
+    pub fn new(inner: W, retries: u32) -> RetryWriter<W> {
+        RetryWriter { inner, retries }
+    }
+
+    /// The wrapped writer
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    fn retry<T, F: FnMut(&mut W) -> io::Result<T>>(&mut self, mut attempt: F) -> io::Result<T> {
+        let mut retries = self.retries;
+        loop {
+            match attempt(&mut self.inner) {
+                Err(e) if retries > 0 && is_transient(&e) => {
+                    retries -= 1;
+                    if e.kind() == ErrorKind::WouldBlock {
+                        thread::sleep(WOULD_BLOCK_DELAY);
+                    }
+                },
+                result => return result
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for RetryWriter<W> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(|inner| inner.flush())
+    }
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock)
+}
@@ -0,0 +1,779 @@
+//! The KvsServer itself, serving an engine over the kvs protocol. `kvs-server` reads its settings and opens the
+//! engine, then hands both to `run_server`, which programs embedding the server can call the same way
+use slog::*;
+
+use std::net::{ Shutdown, SocketAddr, TcpListener, TcpStream };
+use std::io::prelude::*;
+use std::io::{ self, BufReader, BufWriter };
+use std::path::PathBuf;
+use std::time::{ Duration, Instant };
+use std::sync::{ Arc, Mutex, atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering } };
+use std::sync::mpsc::{ self, SyncSender };
+use std::thread;
+
+use failure::{ err_msg, format_err };
+use regex::Regex;
+
+use crate::{
+    Result,
+    LOG_FORMAT_VERSION,
+    KvsEngine,
+    KeyState,
+    KvsError,
+    ServerConfig,
+    AccessLog,
+    RateLimiter,
+    BucketedEngine,
+    TimeoutEngine,
+    check_bucket,
+    network::{
+        self,
+        Operation,
+        TcpMessage,
+        Response,
+        ScanItem,
+        ServerStats,
+        ResponseStatus
+    },
+    thread_pool::ThreadPool
+};
+
+/// Address the server listens to unless told otherwise
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+
+/// Requests read from a connection ahead of the one being served unless `max_in_flight` says otherwise
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+/// Compile `key_pattern` so it has to match the whole key, not just part of it
+fn key_pattern(pattern: &str) -> Result<Regex> {
+    Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| format_err!("Invalid key pattern '{}': {}", pattern, e))
+}
+
+/// Key an operation sets a value for, which has to match `key_pattern`. Other operations only read or remove
+/// keys, which stay reachable whatever they're called
+fn created_key(operation: &Operation) -> Option<&String> {
+    match operation {
+        Operation::Set(key, _) | Operation::SetBytes(key, _) | Operation::Append(key, _) | Operation::SetNx(key, _)
+        | Operation::HIncr(key, _, _) => Some(key),
+        _ => None
+    }
+}
+
+/// Crate version along with the engine and its on-disk format, reported by `--version` and the `version` operation
+pub fn version_info(engine: &str) -> String {
+    let format = match engine {
+        "kvs" => format!("kvs-log-v{}", LOG_FORMAT_VERSION),
+        "sled" => String::from("sled-0.24"),
+        _ => String::from("none")
+    };
+    format!("{} engine={} format={}", env!("CARGO_PKG_VERSION"), engine, format)
+}
+
+/// How long a drain waits for open connections to close before exiting regardless
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Tells a server started with `run_server` to stop, from another thread or a signal handler's watcher. Clones
+/// share the same server, each call to `run_server` should be given a handle of its own
+#[derive(Clone, Default)]
+pub struct ServerHandle {
+    shutdown: Arc<AtomicBool>,
+    draining: Arc<AtomicBool>,
+    address: Arc<Mutex<Option<SocketAddr>>>
+}
+
+impl ServerHandle {
+
+    /// Handle for a server which hasn't been told to stop
+    pub fn new() -> ServerHandle {
+        ServerHandle::default()
+    }
+
+    /// Stop accepting connections, `run_server` returns without waiting on those already open
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Turn new connections away as draining, `run_server` returns once those already open have closed
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Record the address the server is listening on, waking it straight away if it was told to stop before it
+    /// got this far
+    fn listening(&self, address: SocketAddr) {
+        *self.address.lock().unwrap() = Some(address);
+        if self.is_shutdown() || self.is_draining() {
+            self.wake();
+        }
+    }
+
+    /// Accepting blocks, so the listener is woken with a connection of its own to notice it's been told to stop
+    fn wake(&self) {
+        if let Some(address) = *self.address.lock().unwrap() {
+            let _ = TcpStream::connect(address);
+        }
+    }
+}
+
+/// Key written and removed by the startup self-test, chosen to be unlikely to clash with a client's
+const SELF_TEST_KEY: &str = "__kvs_self_test__";
+
+/// Write a sentinel key, read it back and remove it, so a read-only filesystem or a store which can't be
+/// written fails startup rather than the first client's request
+fn self_test<Engine: KvsEngine>(store: &Engine) -> Result<()> {
+    let value = format!("{}", std::process::id());
+    store.set(String::from(SELF_TEST_KEY), value.clone())
+        .map_err(|e| format_err!("Self-test failed writing a key: {}", e))?;
+    match store.get(String::from(SELF_TEST_KEY)) {
+        Ok(Some(ref read)) if read == &value => {},
+        Ok(read) => return Err(format_err!("Self-test failed, read back {:?} rather than the value written", read)),
+        Err(e) => return Err(format_err!("Self-test failed reading a key back: {}", e))
+    }
+    store.remove(String::from(SELF_TEST_KEY))
+        .map_err(|e| format_err!("Self-test failed removing a key: {}", e))
+}
+
+/// Serve `store` over the kvs protocol until `handle` is told to shut down, or until a drain it starts finishes, handing
+/// each connection to `pool`. Everything is logged through `log`, so a program embedding the server can send the log
+/// to a drain of its own. Opening the engine is left to the caller, so only the settings in `config` for serving
+/// connections are used, and any left unset take the same defaults as `kvs-server`
+pub fn run_server<Engine: KvsEngine, Pool: ThreadPool>(config: &ServerConfig, log: Logger, store: Engine, pool: Pool, handle: ServerHandle) -> Result<()> {
+    let connection_options = ConnectionOptions {
+        version: version_info(config.engine.as_deref().unwrap_or("kvs")),
+        data_dir: config.data_dir.clone().unwrap_or_else(|| PathBuf::from("./")),
+        slow_op: Duration::from_millis(config.slow_op_ms.unwrap_or(1000)),
+        access_log: match &config.access_log {
+            Some(path) => Some(AccessLog::open(path)?),
+            None => None
+        },
+        rate_limit: config.rate_limit.map(RateLimiter::new),
+        max_request: config.max_request_bytes.unwrap_or(network::DEFAULT_MAX_REQUEST_BYTES),
+        max_in_flight: config.max_in_flight.unwrap_or(DEFAULT_MAX_IN_FLIGHT),
+        idle_timeout: config.idle_timeout_ms.filter(|ms| *ms > 0).map(Duration::from_millis),
+        key_pattern: config.key_pattern.as_deref().map(key_pattern).transpose()?,
+        op_log: OpLogSampler::new(config.op_log_sample.unwrap_or(1))
+    };
+    let nodelay = config.nodelay.unwrap_or(true);
+    let store = TimeoutEngine::new(store, config.op_timeout_ms.filter(|ms| *ms > 0).map(Duration::from_millis));
+
+    if config.self_test.unwrap_or(true) {
+        if let Err(e) = self_test(&store) {
+            crit!(log, "Self-test failed, server not started"; "error" => %e);
+            return Err(e);
+        }
+        info!(log, "Self-test passed");
+    }
+
+    if let Some(address) = &config.http_addr {
+        listen_for_http(log.clone(), store.clone(), address)?;
+    }
+
+    info!(log, "Starting TCP server");
+    let listener = TcpListener::bind(config.addr.as_deref().unwrap_or(DEFAULT_ADDRESS))?;
+    handle.listening(listener.local_addr()?);
+    info!(log, "Waiting for connections...");
+
+    let open_connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        if handle.is_shutdown() {
+            info!(log, "Shutdown requested, no longer accepting connections");
+            break;
+        }
+        if handle.is_draining() {
+            info!(log, "Drain requested, turning new connections away"; "open_connections" => open_connections.load(Ordering::SeqCst));
+            if let Ok(stream) = stream {
+                turn_away(&log, stream, ResponseStatus::Draining);
+            }
+            drain(&log, &listener, &open_connections, &handle)?;
+            store.flush()?;
+            info!(log, "Drain finished");
+            break;
+        }
+        let stream: TcpStream = stream?;
+        let client_addr = stream.peer_addr()?;
+
+        let log = log.new(o!("client_addr" => client_addr));
+        info!(log, "TCP connection established");
+
+        if let Err(e) = stream.set_nodelay(nodelay) {
+            warn!(log, "Could not set TCP_NODELAY"; "error" => %e);
+        }
+
+        if let Some(max) = config.max_connections {
+            if open_connections.load(Ordering::SeqCst) >= max {
+                warn!(log, "Connection limit reached, turning connection away"; "max_connections" => max);
+                turn_away(&log, stream, ResponseStatus::Busy);
+                continue;
+            }
+        }
+
+        let connection = ConnectionGuard::new(open_connections.clone());
+        let store = store.clone();
+        let connection_options = connection_options.clone();
+
+        let connection_log = log.clone();
+        pool.spawn(move || {
+            handle_connection(connection_log, stream, client_addr, store, &connection_options);
+            drop(connection);
+        });
+
+        // Connections only queue up once every thread is serving one
+        let queued = pool.queue_len();
+        if queued > 0 {
+            warn!(log, "Every pool thread is busy, connection is waiting for one"; "queue_len" => queued);
+        }
+
+    }
+    Ok(())
+}
+
+/// Serve the HTTP frontend on `address` from a thread of its own, each request on a thread of its own too so
+/// HTTP clients can't hold up the pool serving the kvs protocol
+#[cfg(feature = "http")]
+fn listen_for_http<Engine: KvsEngine>(log: Logger, store: Engine, address: &str) -> Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!(log, "Serving HTTP"; "http_addr" => address);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!(log, "Could not accept HTTP connection"; "error" => %e);
+                    continue;
+                }
+            };
+            let log = match stream.peer_addr() {
+                Ok(client_addr) => log.new(o!("client_addr" => client_addr, "protocol" => "http")),
+                Err(_) => log.new(o!("protocol" => "http"))
+            };
+            let store = store.clone();
+            thread::spawn(move || {
+                if let Err(e) = crate::http::serve_http(log.clone(), stream, store) {
+                    warn!(log, "Could not serve HTTP request"; "error" => %e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "http"))]
+fn listen_for_http<Engine: KvsEngine>(_log: Logger, _store: Engine, _address: &str) -> Result<()> {
+    Err(err_msg("kvs-server was built without the http feature, --http-addr is unavailable"))
+}
+
+/// Answer a connection with `status` and close it without serving any of its requests
+fn turn_away(log: &Logger, stream: TcpStream, status: ResponseStatus) {
+    let response = Response {
+        status,
+        data: None
+    };
+    if let Err(e) = response.write_to_stream(log.clone(), stream) {
+        warn!(log, "Could not write response to client"; "error" => %e);
+    }
+}
+
+/// Turn new connections away as draining while those already open are served to the end, returning once they
+/// have all closed. Gives up waiting after `DRAIN_TIMEOUT`, or straight away if a shutdown is requested
+fn drain(log: &Logger, listener: &TcpListener, open_connections: &AtomicUsize, handle: &ServerHandle) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+
+    while open_connections.load(Ordering::SeqCst) > 0 {
+        if handle.is_shutdown() {
+            info!(log, "Shutdown requested while draining");
+            break;
+        }
+        if Instant::now() >= deadline {
+            warn!(log, "Connections still open after the drain timeout, exiting anyway"; "open_connections" => open_connections.load(Ordering::SeqCst));
+            break;
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // Accepted sockets don't inherit the listener's non-blocking mode
+                turn_away(log, stream, ResponseStatus::Draining);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(50)),
+            Err(e) => return Err(e.into())
+        }
+    }
+    Ok(())
+}
+
+/// Counts a connection as open until dropped, even if handling it panics
+struct ConnectionGuard {
+    open_connections: Arc<AtomicUsize>
+}
+
+impl ConnectionGuard {
+    fn new(open_connections: Arc<AtomicUsize>) -> ConnectionGuard {
+        open_connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard { open_connections }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Settings every connection is served with
+#[derive(Clone)]
+struct ConnectionOptions {
+    version: String,
+    data_dir: PathBuf,
+    slow_op: Duration,
+    access_log: Option<AccessLog>,
+    rate_limit: Option<RateLimiter>,
+    max_request: usize,
+    max_in_flight: usize,
+    idle_timeout: Option<Duration>,
+    key_pattern: Option<Regex>,
+    op_log: OpLogSampler
+}
+
+/// Picks which operations get their own log lines, one in every `every` across all connections or none if it's 0
+#[derive(Clone)]
+struct OpLogSampler {
+    every: u64,
+    seen: Arc<AtomicU64>
+}
+
+impl OpLogSampler {
+    fn new(every: u64) -> OpLogSampler {
+        OpLogSampler { every, seen: Arc::new(AtomicU64::new(0)) }
+    }
+
+    /// Whether the next operation should be logged
+    fn sample(&self) -> bool {
+        self.every != 0 && self.seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.every)
+    }
+}
+
+/// Serve operations from one client until it closes the connection
+fn handle_connection<Engine: KvsEngine>(log: Logger, stream: TcpStream, client_addr: SocketAddr, store: Engine, options: &ConnectionOptions) {
+    let slow_op = options.slow_op;
+    let access_log = &options.access_log;
+
+    // Keys live in the default keyspace until the client picks a bucket
+    let mut bucket: Option<String> = None;
+
+    let read_stream = match stream.try_clone() {
+        Ok(read_stream) => read_stream,
+        Err(e) => {
+            error!(log, "Could not clone TCP stream"; "error" => %e);
+            return;
+        }
+    };
+    let (sender, requests) = mpsc::sync_channel(options.max_in_flight);
+    let activity = Arc::new(Activity::new());
+    let reader = {
+        let log = log.clone();
+        let sampler = options.op_log.clone();
+        let max_request = options.max_request;
+        let idle_timeout = options.idle_timeout;
+        let activity = activity.clone();
+        thread::spawn(move || read_requests(log, read_stream, sampler, max_request, idle_timeout, activity, sender))
+    };
+
+    for (op_log, request) in requests.iter() {
+        let start = Instant::now();
+        let limited = request.is_ok() && match &options.rate_limit {
+            Some(limiter) => !limiter.allow(client_addr.ip()),
+            None => false
+        };
+        let mut close_after_response = false;
+        let response = match &request {
+            Ok(_) if limited => {
+                warn!(log, "Rate limit exceeded, refusing request");
+                Response { status: ResponseStatus::RateLimited, data: None }
+            },
+            Ok(Operation::Use(name)) => {
+                match check_bucket(name) {
+                    Ok(()) => {
+                        info!(op_log, "Switched bucket"; "bucket" => name);
+                        bucket = Some(name.clone());
+                        Response { status: ResponseStatus::Ok, data: None }
+                    },
+                    Err(e) => failure_response(&e)
+                }
+            },
+            Ok(Operation::Scan) => {
+                let result = match &bucket {
+                    Some(name) => BucketedEngine::new(store.clone(), name.clone())
+                        .and_then(|store| scan_store(store, &stream, false)),
+                    None => scan_store(store.clone(), &stream, true)
+                };
+                match result {
+                    Ok(pairs) => {
+                        info!(op_log, "Store SCAN successful"; "pairs" => pairs);
+                        Response { status: ResponseStatus::Ok, data: None }
+                    },
+                    Err(e) => {
+                        warn!(log, "Scan failed"; "error" => %e);
+                        failure_response(&e)
+                    }
+                }
+            },
+            Ok(Operation::Grep(text)) => {
+                let result = match &bucket {
+                    Some(name) => BucketedEngine::new(store.clone(), name.clone())
+                        .and_then(|store| grep_store(store, &stream, false, text)),
+                    None => grep_store(store.clone(), &stream, true, text)
+                };
+                match result {
+                    Ok(pairs) => {
+                        info!(op_log, "Store GREP successful"; "pairs" => pairs);
+                        Response { status: ResponseStatus::Ok, data: None }
+                    },
+                    Err(e) => {
+                        warn!(log, "Grep failed"; "error" => %e);
+                        failure_response(&e)
+                    }
+                }
+            },
+            Ok(operation) => {
+                let result = match &bucket {
+                    Some(name) => BucketedEngine::new(store.clone(), name.clone())
+                        .and_then(|store| handle_operation(op_log.clone(), operation.clone(), store, options)),
+                    None => handle_operation(op_log.clone(), operation.clone(), store.clone(), options)
+                };
+                let elapsed = start.elapsed();
+
+                // Long compactions and disk stalls show up here first
+                if elapsed > slow_op {
+                    warn!(log.new(o!(operation.clone())), "Slow operation"; "elapsed_ms" => elapsed.as_millis() as u64);
+                }
+
+                match result {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!(log, "Operation failed"; "error" => %e);
+                        failure_response(&e)
+                    }
+                }
+            },
+            Err(e) => {
+                if let Some(KvsError::ConnectionClosed) = e.downcast_ref::<KvsError>() {
+                    info!(log, "TCP connection closed");
+                    break;
+                }
+                if e.downcast_ref::<std::io::Error>().is_some() {
+                    warn!(log, "Could not read from client"; "error" => %e);
+                    break;
+                }
+                if let Some(KvsError::RequestTooLarge(_)) = e.downcast_ref::<KvsError>() {
+                    // The rest of the request is still unread, so the connection can't carry on after the response
+                    warn!(log, "Request too large, closing connection"; "error" => %e);
+                    close_after_response = true;
+                }
+
+                warn!(log, "Could not read operation from client"; "error" => %e);
+                Response {
+                    status: ResponseStatus::InvalidRequest,
+                    data: Some(e.to_string())
+                }
+            }
+        };
+
+        // Written before the response so a client which sees its response will find the request logged
+        if let Some(access_log) = &access_log {
+            if let Err(e) = access_log.record(client_addr, request.as_ref().ok(), response.status, start.elapsed()) {
+                warn!(log, "Could not write to access log"; "error" => %e);
+            }
+        }
+
+        match stream.try_clone() {
+            Ok(write_stream) => {
+                if let Err(e) = response.write_to_stream(op_log.clone(), write_stream) {
+                    error!(log, "Could not write response to client"; "error" => %e);
+                    break;
+                }
+            },
+            Err(e) => {
+                error!(log, "Could not clone TCP stream"; "error" => %e);
+                break;
+            }
+        }
+        activity.answered();
+        if close_after_response {
+            break;
+        }
+    }
+
+    // The reader may be waiting on the client or on room for another request, this wakes it either way
+    drop(requests);
+    let _ = stream.shutdown(Shutdown::Both);
+    if reader.join().is_err() {
+        error!(log, "Request reader panicked");
+    }
+}
+
+/// Read a connection's requests ahead of them being served, each with the logger its operation is logged through.
+/// Reading pauses while the channel is full, leaving further requests to wait in the socket where TCP holds the
+/// client back, and stops after a request the connection can't carry on from, or once the connection has been idle
+/// for `idle_timeout`, which closes it
+fn read_requests(
+    log: Logger,
+    stream: TcpStream,
+    sampler: OpLogSampler,
+    max_request: usize,
+    idle_timeout: Option<Duration>,
+    activity: Arc<Activity>,
+    requests: SyncSender<(Logger, Result<Operation>)>
+) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        if let Some(idle_timeout) = idle_timeout {
+            match wait_for_request(&mut reader, idle_timeout, &activity) {
+                Ok(true) => {},
+                Ok(false) => {
+                    info!(log, "Closing idle connection"; "idle_timeout_ms" => idle_timeout.as_millis() as u64);
+                    return;
+                },
+                Err(e) => {
+                    warn!(log, "Could not read from client"; "error" => %e);
+                    return;
+                }
+            }
+        }
+
+        // Failures and slow operations are logged through `log` whether or not the operation is sampled
+        let op_log = if sampler.sample() { log.clone() } else { Logger::root(Discard, o!()) };
+
+        let request = Operation::read_buffered(op_log.clone(), &mut reader, max_request);
+        let last = match &request {
+            Ok(_) => false,
+            Err(e) => e.downcast_ref::<std::io::Error>().is_some()
+                || matches!(e.downcast_ref::<KvsError>(), Some(KvsError::ConnectionClosed) | Some(KvsError::RequestTooLarge(_)))
+        };
+        activity.received();
+        if requests.send((op_log, request)).is_err() || last {
+            return;
+        }
+    }
+}
+
+/// Wait for the client to start its next request, returning `false` if the connection stays idle for `idle_timeout`.
+/// Only peeks at the stream, so nothing of a request is lost to a timeout. The end of the stream counts as a
+/// request, for reading it to report the connection closed
+fn wait_for_request(reader: &mut BufReader<TcpStream>, idle_timeout: Duration, activity: &Activity) -> Result<bool> {
+    loop {
+        let wait = match activity.idle_for() {
+            Some(idle) if idle >= idle_timeout => return Ok(false),
+            Some(idle) => idle_timeout - idle,
+            // A connection waiting on the server isn't idle, so look again once the timeout has passed
+            None => idle_timeout
+        };
+        reader.get_ref().set_read_timeout(Some(wait))?;
+        let peeked = reader.fill_buf().map(|_| ());
+        reader.get_ref().set_read_timeout(None)?;
+        match peeked {
+            Ok(()) => return Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+            Err(e) => return Err(e.into())
+        }
+    }
+}
+
+/// How many of a connection's requests are still to be answered and when it last answered one, from which the
+/// connection's reader works out how long it has been idle
+struct Activity {
+    outstanding: AtomicUsize,
+    last_answered: Mutex<Instant>
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity { outstanding: AtomicUsize::new(0), last_answered: Mutex::new(Instant::now()) }
+    }
+
+    fn received(&self) {
+        self.outstanding.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn answered(&self) {
+        // The time goes in first, so a reader which sees nothing outstanding also sees when the last answer went
+        *self.last_answered.lock().unwrap() = Instant::now();
+        self.outstanding.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// How long since the last request was answered, `None` while a request is still being served
+    fn idle_for(&self) -> Option<Duration> {
+        if self.outstanding.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+        Some(self.last_answered.lock().unwrap().elapsed())
+    }
+}
+
+/// Send a `ScanItem` for every pair in the store in key order as it's read, returns how many were sent. Keys removed
+/// while the scan runs are skipped, and values which aren't valid UTF-8 are sent with the invalid bytes replaced
+fn scan_store<Engine: KvsEngine>(store: Engine, stream: &TcpStream, default_bucket: bool) -> Result<usize> {
+    let mut bw = BufWriter::new(stream.try_clone()?);
+    let mut pairs = 0;
+    for key in store.sorted_keys()? {
+        // Bucketed keys are stored with a NUL after the bucket name and clients can't send keys with one, so any key
+        // with a NUL is in a bucket and only shows up in that bucket's scans
+        if default_bucket && key.contains('\0') {
+            continue;
+        }
+        if let Some(value) = store.get_bytes(key.clone())? {
+            let item = ScanItem { key, value: String::from_utf8_lossy(&value).into_owned() };
+            writeln!(bw, "{}", item.to_text())?;
+            pairs += 1;
+        }
+    }
+    bw.flush()?;
+    Ok(pairs)
+}
+
+/// Send a `ScanItem` for every pair whose value contains `text` in key order, returns how many were sent. Unlike a
+/// scan the matches are all found before any are sent
+fn grep_store<Engine: KvsEngine>(store: Engine, stream: &TcpStream, default_bucket: bool, text: &str) -> Result<usize> {
+    let mut bw = BufWriter::new(stream.try_clone()?);
+    let mut matches = store.filter_values(|value| value.contains(text))?;
+    matches.sort_unstable();
+    let mut pairs = 0;
+    for (key, value) in matches {
+        if default_bucket && key.contains('\0') {
+            continue;
+        }
+        writeln!(bw, "{}", ScanItem { key, value }.to_text())?;
+        pairs += 1;
+    }
+    bw.flush()?;
+    Ok(pairs)
+}
+
+/// Response for an operation the engine failed to carry out, with a status telling the client why
+fn failure_response(e: &failure::Error) -> Response {
+    let status = match e.downcast_ref::<KvsError>() {
+        Some(KvsError::KeyNotFound) => ResponseStatus::KeyNotFound,
+        Some(KvsError::EmptyKey) | Some(KvsError::InvalidUtf8) | Some(KvsError::InvalidBucket(_))
+        | Some(KvsError::NotAHash(_)) | Some(KvsError::InvalidKey(_)) => ResponseStatus::InvalidRequest,
+        _ => ResponseStatus::Internal
+    };
+    Response {
+        status,
+        data: Some(e.to_string())
+    }
+}
+
+fn handle_operation<Engine: KvsEngine>(log: Logger, operation: Operation, store: Engine, options: &ConnectionOptions) -> Result<Response> {
+
+    if let (Some(pattern), Some(key)) = (&options.key_pattern, created_key(&operation)) {
+        if !pattern.is_match(key) {
+            return Err(KvsError::InvalidKey(key.clone()).into());
+        }
+    }
+
+    match operation {
+        Operation::Set(key, value) => {
+            let outcome = store.set_reporting(key, value)?;
+            info!(log, "Store SET successful"; "outcome" => outcome.to_text());
+            Ok(Response { status: ResponseStatus::Ok, data: Some(outcome.to_text().to_owned()) })
+        },
+        Operation::Get(key) => {
+            let response = match store.get_state(key)? {
+                KeyState::Present(value) => Response { status: ResponseStatus::Ok, data: Some(value) },
+                KeyState::Absent => Response { status: ResponseStatus::Ok, data: None },
+                KeyState::Deleted => Response { status: ResponseStatus::Deleted, data: None }
+            };
+            info!(log, "Store GET successful");
+            Ok(response)
+        },
+        Operation::GetTtl(key) => {
+            let data = store.get_with_ttl(key)?.map(|(value, ttl)| network::join_ttl(value, ttl));
+            info!(log, "Store GET TTL successful");
+            Ok(Response { status: ResponseStatus::Ok, data })
+        },
+        Operation::Remove(key) => {
+            store.remove(key)?;
+            info!(log, "Store REMOVE successful");
+            Ok(Response { status: ResponseStatus::Ok, data: None })
+        },
+        Operation::RemoveBatch(keys) => {
+            let existed: Vec<String> = store.remove_batch(keys)?.iter().map(bool::to_string).collect();
+            info!(log, "Store REMOVE BATCH successful"; "keys" => existed.len());
+            Ok(Response { status: ResponseStatus::Ok, data: Some(network::join_fields(&existed)) })
+        },
+        Operation::SetBytes(key, value) => {
+            store.set_bytes(key, value)?;
+            info!(log, "Store SET BYTES successful");
+            Ok(Response { status: ResponseStatus::Ok, data: None })
+        },
+        Operation::GetBytes(key) => {
+            let data = store.get_bytes(key)?.map(base64::encode);
+            info!(log, "Store GET BYTES successful");
+            Ok(Response { status: ResponseStatus::Ok, data })
+        },
+        Operation::Append(key, suffix) => {
+            let len = store.append(key, suffix)?;
+            info!(log, "Store APPEND successful");
+            Ok(Response { status: ResponseStatus::Ok, data: Some(len.to_string()) })
+        },
+        Operation::HIncr(key, field, delta) => {
+            let count = store.hincr(key, field, delta)?;
+            info!(log, "Store HINCR successful");
+            Ok(Response { status: ResponseStatus::Ok, data: Some(count.to_string()) })
+        },
+        Operation::Cad(key, expected) => {
+            let removed = store.compare_and_delete(key, expected)?;
+            info!(log, "Store CAD successful"; "removed" => removed);
+            Ok(Response { status: ResponseStatus::Ok, data: Some(removed.to_string()) })
+        },
+        Operation::SetNx(key, value) => {
+            let set = store.set_if_absent(key, value)?;
+            info!(log, "Store SETNX successful"; "set" => set);
+            Ok(Response { status: ResponseStatus::Ok, data: Some(set.to_string()) })
+        },
+        Operation::Stats => {
+            let stats = ServerStats {
+                engine: store.stats(),
+                log_bytes: store.size_on_disk()?,
+                disk_free: fs2::available_space(&options.data_dir)?
+            };
+            info!(log, "Store STATS successful");
+            Ok(Response { status: ResponseStatus::Ok, data: Some(stats.to_text()) })
+        },
+        Operation::Touch(key, ttl) => {
+            let touched = store.touch(key, ttl)?;
+            info!(log, "Store TOUCH successful"; "touched" => touched);
+            Ok(Response { status: ResponseStatus::Ok, data: Some(touched.to_string()) })
+        },
+        Operation::Recent(n) => {
+            // As with scans, keys in a bucket are left out of the default keyspace's list. They're mixed in among
+            // the rest, so every key is asked for and the first `n` kept
+            let keys: Vec<String> = store.recent_keys(usize::MAX)?
+                .into_iter()
+                .filter(|key| !key.contains('\0'))
+                .take(n)
+                .collect();
+            info!(log, "Store RECENT successful"; "keys" => keys.len());
+            Ok(Response { status: ResponseStatus::Ok, data: Some(network::join_fields(&keys)) })
+        },
+        Operation::Version => {
+            Ok(Response { status: ResponseStatus::Ok, data: Some(options.version.clone()) })
+        },
+        Operation::Use(_) => {
+            Err(err_msg("Buckets are switched per connection, not by the engine"))
+        },
+        Operation::Scan | Operation::Grep(_) => {
+            Err(err_msg("Scans are streamed over the connection, not answered by the engine"))
+        },
+    }
+    
+}
@@ -0,0 +1,81 @@
+//! When a KvStore syncs its log to disk after appending, trading write speed for how much a crash can lose
+use std::fmt;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+
+/// Consulted by a KvStore after each record is appended to its log, the log is synced to disk whenever it
+/// answers `true`. Records which aren't synced reach the OS straight away, so only a crash of the machine
+/// rather than the process can lose them
+///
+/// # Example
+/// ```
+/// use kvs::KvStore;
+/// use kvs::sync_strategy::Periodic;
+/// use std::time::Duration;
+///
+/// let dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStore::builder()
+///     .sync_strategy(Periodic::new(Duration::from_millis(100)))
+///     .open(dir.path())
+///     .unwrap();
+/// ```
+pub trait SyncStrategy: Send + Sync + fmt::Debug {
+
+    /// Whether to sync the log now that another record has been appended, called under the store's writer lock
+    fn should_sync(&self) -> bool;
+}
+
+impl<S: SyncStrategy + ?Sized> SyncStrategy for Arc<S> {
+    fn should_sync(&self) -> bool {
+        (**self).should_sync()
+    }
+}
+
+/// Never sync after appending, leaving it to the OS, `KvsEngine::flush` or a checkpoint interval. The default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Never;
+
+impl SyncStrategy for Never {
+    fn should_sync(&self) -> bool {
+        false
+    }
+}
+
+/// Sync after every append, so a write which succeeded survives a crash. The slowest choice by far
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Always;
+
+impl SyncStrategy for Always {
+    fn should_sync(&self) -> bool {
+        true
+    }
+}
+
+/// Sync after an append once `interval` has passed since the last sync, bounding how many writes a crash loses
+/// while the store is busy. Appends are what trigger a sync, so the last writes before the store goes quiet wait
+/// for the next one, pair it with `KvStore::with_checkpoint_interval` to cover those too
+#[derive(Debug)]
+pub struct Periodic {
+    interval: Duration,
+    last_sync: Mutex<Instant>,
+}
+
+impl Periodic {
+
+    /// Sync at most once every `interval`, counting from when the strategy is made
+    pub fn new(interval: Duration) -> Periodic {
+        Periodic { interval, last_sync: Mutex::new(Instant::now()) }
+    }
+}
+
+impl SyncStrategy for Periodic {
+    fn should_sync(&self) -> bool {
+        let mut last_sync = self.last_sync.lock().unwrap();
+        if last_sync.elapsed() >= self.interval {
+            *last_sync = Instant::now();
+            true
+        } else {
+            false
+        }
+    }
+}
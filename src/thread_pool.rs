@@ -3,6 +3,12 @@ use std::collections::VecDeque;
 use std::sync::{
     Arc,
     Mutex,
+    Condvar,
+    mpsc::{
+        channel,
+        Sender,
+        Receiver
+    },
     atomic::{
         AtomicUsize,
         Ordering
@@ -18,42 +24,79 @@ pub trait ThreadPool {
 
     /// Pass a job to the ThreadPool
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static;
+
+    /// Jobs spawned which are still waiting for a thread, always 0 for pools which don't queue jobs
+    fn queue_len(&self) -> usize {
+        0
+    }
 }
 
+type Permits = Arc<(Mutex<usize>, Condvar)>;
+
 /// Thread pool which doesn't actually pool threads
 /// Just spawns new a thread for each job given
+///
+/// At most `threads` jobs run at once, `spawn` blocks until one finishes once that many are running.
+/// With 0 threads there is no bound and every job gets its thread straight away
 pub struct NaiveThreadPool {
+    permits: Option<Permits>
+}
 
+/// Hands a job's permit back when its thread finishes, even if the job panicked
+struct PermitGuard {
+    permits: Permits
 }
 
-impl NaiveThreadPool {
-    
+impl Drop for PermitGuard {
+    fn drop(&mut self) {
+        let (free, released) = &*self.permits;
+        if let Ok(mut free) = free.lock() {
+            *free += 1;
+        }
+        released.notify_one();
+    }
 }
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(_threads: usize) -> Result<Self> {
+    fn new(threads: usize) -> Result<Self> {
+        let permits = if threads == 0 {
+            None
+        } else {
+            Some(Arc::new((Mutex::new(threads), Condvar::new())))
+        };
         Ok(NaiveThreadPool {
-
+            permits
         })
     }
 
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        let guard = self.permits.as_ref().map(|permits| {
+            let (free, released) = &**permits;
+            let mut free = free.lock().expect("Could not spawn job, permits could not be locked");
+            while *free == 0 {
+                free = released.wait(free).expect("Could not spawn job, permits could not be locked");
+            }
+            *free -= 1;
+            PermitGuard { permits: permits.clone() }
+        });
         std::thread::spawn(move || {
+            let _guard = guard;
             job();
         });
     }
 }
 
-type FnOnceBox = Box<FnOnce() + Send + 'static>;
-type JobQueue = Arc<Mutex<VecDeque<ThreadPoolMessage>>>;
+type FnOnceBox = Box<dyn FnOnce() + Send + 'static>;
+type JobQueue = Arc<(Mutex<VecDeque<ThreadPoolMessage>>, Condvar)>;
 
 enum ThreadPoolMessage {
     RunJob(FnOnceBox),
-    Shutdown //TODO
+    Shutdown
 }
 
 struct ThreadWatcher {
-    threads_spawned: Arc<AtomicUsize>
+    threads_spawned: Arc<AtomicUsize>,
+    restart: Sender<()>
 }
 
 impl Drop for ThreadWatcher {
@@ -61,6 +104,7 @@ impl Drop for ThreadWatcher {
         if std::thread::panicking() {
             println!("Thread panicked, reducing number of threads spawned for watcher thread");
             self.threads_spawned.fetch_sub(1, Ordering::Relaxed);
+            let _ = self.restart.send(());
         } else {
             println!("Watcher dropped without thread panicking");
         }
@@ -81,21 +125,24 @@ impl Drop for ThreadWatcher {
 /// ```
 pub struct SharedQueueThreadPool {
     job_queue: JobQueue,
+    threads: usize,
 }
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: usize) -> Result<Self> {
 
-        let job_queue = Arc::new(Mutex::new(VecDeque::new()));
+        let job_queue = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
         let threads_spawned = Arc::new(AtomicUsize::new(threads));
+        let (restart_sender, restart_receiver) = channel();
 
         println!("Starting up job threads");
         for _ in 0..threads {
             println!("Spawning job thread");
             let shared_queue = job_queue.clone();
             let shared_threads_spawned = threads_spawned.clone();
+            let restart = restart_sender.clone();
             std::thread::spawn(move || {
-                job_thread_closure(shared_queue, shared_threads_spawned);
+                job_thread_closure(shared_queue, shared_threads_spawned, restart);
             });
         }
 
@@ -103,42 +150,68 @@ impl ThreadPool for SharedQueueThreadPool {
         let shared_queue = job_queue.clone();
         let shared_threads_spawned = threads_spawned.clone();
         std::thread::spawn(move || {
-            watcher_thread_closure(threads, shared_queue, shared_threads_spawned);
+            watcher_thread_closure(threads, shared_queue, shared_threads_spawned, restart_sender, restart_receiver);
         });
 
 
         Ok(SharedQueueThreadPool {
-            job_queue
+            job_queue,
+            threads
         })
     }
 
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
-        self.job_queue.lock().expect("Could not send job to threads, job_queue could not be locked").push_front(ThreadPoolMessage::RunJob(Box::new(job)));
+        let (queue, job_available) = &*self.job_queue;
+        queue.lock().expect("Could not send job to threads, job_queue could not be locked").push_back(ThreadPoolMessage::RunJob(Box::new(job)));
+        job_available.notify_one();
+    }
+
+    fn queue_len(&self) -> usize {
+        let (queue, _) = &*self.job_queue;
+        queue.lock().map(|queue| queue.len()).unwrap_or(0)
     }
 }
 
-fn watcher_thread_closure(threads: usize, job_queue: JobQueue, threads_spawned: Arc<AtomicUsize>) {
-    loop {
-        let new_to_spawn = threads - threads_spawned.load(Ordering::Relaxed);
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        let (queue, job_available) = &*self.job_queue;
+        if let Ok(mut queue) = queue.lock() {
+            for _ in 0..self.threads {
+                queue.push_back(ThreadPoolMessage::Shutdown);
+            }
+        }
+        job_available.notify_all();
+    }
+}
+
+/// Blocks until a job thread reports a panic, then spawns replacements until the pool is back to full size
+fn watcher_thread_closure(threads: usize, job_queue: JobQueue, threads_spawned: Arc<AtomicUsize>, restart_sender: Sender<()>, restarts: Receiver<()>) {
+    while restarts.recv().is_ok() {
+        let new_to_spawn = threads.saturating_sub(threads_spawned.load(Ordering::Relaxed));
 
         for _ in 0..new_to_spawn {
             println!("Spawning job thread due to restart");
             let shared_threads_spawned = threads_spawned.clone();
             let shared_queue = job_queue.clone();
+            let restart = restart_sender.clone();
             shared_threads_spawned.fetch_add(1, Ordering::Relaxed);
             std::thread::spawn(move || {
-                job_thread_closure(shared_queue, shared_threads_spawned)
+                job_thread_closure(shared_queue, shared_threads_spawned, restart)
             });
             
         }
     }
 }
 
-fn job_thread_closure(job_queue: JobQueue, threads_spawned: Arc<AtomicUsize>) {
-    let _watcher = ThreadWatcher { threads_spawned };
+fn job_thread_closure(job_queue: JobQueue, threads_spawned: Arc<AtomicUsize>, restart: Sender<()>) {
+    let _watcher = ThreadWatcher { threads_spawned, restart };
+    let (queue, job_available) = &*job_queue;
     loop {
         
-        let mut job_queue = job_queue.lock().expect("Job thread could not lock job_queue");
+        let mut job_queue = queue.lock().expect("Job thread could not lock job_queue");
+        while job_queue.is_empty() {
+            job_queue = job_available.wait(job_queue).expect("Job thread could not lock job_queue");
+        }
         let message_exists = job_queue.pop_front();
         
         if let Some(message) = message_exists {
@@ -158,7 +231,6 @@ fn job_thread_closure(job_queue: JobQueue, threads_spawned: Arc<AtomicUsize>) {
 }
 
 extern crate rayon;
-use rayon::prelude::*;
 
 /// Thread pool implementation which uses Rayon under the hood, for benchmarking
 pub struct RayonThreadPool {
@@ -167,7 +239,11 @@ pub struct RayonThreadPool {
 
 impl ThreadPool for RayonThreadPool {
     fn new(thread: usize) -> Result<Self> {
-        let pool = rayon::ThreadPoolBuilder::new().num_threads(thread).build()?;
+        // Without a handler rayon aborts the process when a spawned job panics
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(thread)
+            .panic_handler(|_| println!("Job panicked on rayon thread"))
+            .build()?;
 
         Ok(RayonThreadPool {
             pool
@@ -175,7 +251,7 @@ impl ThreadPool for RayonThreadPool {
     }
 
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
-        self.pool.install(job);
+        self.pool.spawn(job);
     }
 }
 
@@ -0,0 +1,140 @@
+//! An engine which gives up on operations running past a deadline
+use failure::err_msg;
+
+use std::sync::mpsc::{ self, RecvTimeoutError };
+use std::thread;
+use std::time::Duration;
+
+use crate::{ Result, KvsEngine, KvsError, KeyState, SetOutcome, EngineStats };
+
+/// Wraps an engine, failing any operation which takes longer than the timeout with `KvsError::Timeout` so a
+/// stalled disk or a long compaction holds up one request rather than the thread serving it. Each operation runs
+/// on a thread of its own while the caller waits, and one which times out carries on there in the background, so
+/// a write reported as timed out may still land. With no timeout operations run on the caller's thread as usual
+#[derive(Clone)]
+pub struct TimeoutEngine<E: KvsEngine> {
+    inner: E,
+    timeout: Option<Duration>,
+}
+
+impl<E: KvsEngine> TimeoutEngine<E> {
+
+    /// Wrap `inner`, giving each operation up to `timeout` to finish, or as long as it takes if `None`
+    pub fn new(inner: E, timeout: Option<Duration>) -> TimeoutEngine<E> {
+        TimeoutEngine {
+            inner,
+            timeout
+        }
+    }
+
+    fn run<T, F>(&self, operation: F) -> Result<T> where T: Send + 'static, F: FnOnce(&E) -> Result<T> + Send + 'static {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return operation(&self.inner)
+        };
+
+        let inner = self.inner.clone();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            // Nobody is listening once the caller has given up, the result is dropped
+            let _ = tx.send(operation(&inner));
+        });
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(KvsError::Timeout(timeout).into()),
+            Err(RecvTimeoutError::Disconnected) => Err(err_msg("Engine operation panicked"))
+        }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for TimeoutEngine<E> {
+
+    fn set(&self, k: String, v: String) -> Result<()> {
+        self.run(move |inner| inner.set(k, v))
+    }
+
+    fn get(&self, k: String) -> Result<Option<String>> {
+        self.run(move |inner| inner.get(k))
+    }
+
+    fn remove(&self, k: String) -> Result<()> {
+        self.run(move |inner| inner.remove(k))
+    }
+
+    fn get_state(&self, k: String) -> Result<KeyState> {
+        self.run(move |inner| inner.get_state(k))
+    }
+
+    fn set_bytes(&self, k: String, v: Vec<u8>) -> Result<()> {
+        self.run(move |inner| inner.set_bytes(k, v))
+    }
+
+    fn get_bytes(&self, k: String) -> Result<Option<Vec<u8>>> {
+        self.run(move |inner| inner.get_bytes(k))
+    }
+
+    fn set_reporting(&self, k: String, v: String) -> Result<SetOutcome> {
+        self.run(move |inner| inner.set_reporting(k, v))
+    }
+
+    fn remove_reporting(&self, k: String) -> Result<Vec<u8>> {
+        self.run(move |inner| inner.remove_reporting(k))
+    }
+
+    fn remove_batch(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.run(move |inner| inner.remove_batch(keys))
+    }
+
+    fn append(&self, k: String, suffix: String) -> Result<usize> {
+        self.run(move |inner| inner.append(k, suffix))
+    }
+
+    fn set_if_absent(&self, k: String, v: String) -> Result<bool> {
+        self.run(move |inner| inner.set_if_absent(k, v))
+    }
+
+    fn hincr(&self, k: String, field: String, delta: i64) -> Result<i64> {
+        self.run(move |inner| inner.hincr(k, field, delta))
+    }
+
+    fn compare_and_delete(&self, k: String, expected: String) -> Result<bool> {
+        self.run(move |inner| inner.compare_and_delete(k, expected))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.run(|inner| inner.keys())
+    }
+
+    fn sorted_keys(&self) -> Result<Vec<String>> {
+        self.run(|inner| inner.sorted_keys())
+    }
+
+    fn range(&self, from: String, to: String) -> Result<Vec<(String, String)>> {
+        self.run(move |inner| inner.range(from, to))
+    }
+
+    fn recent_keys(&self, n: usize) -> Result<Vec<String>> {
+        self.run(move |inner| inner.recent_keys(n))
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        self.run(|inner| inner.size_on_disk())
+    }
+
+    fn touch(&self, k: String, ttl: Duration) -> Result<bool> {
+        self.run(move |inner| inner.touch(k, ttl))
+    }
+
+    fn get_with_ttl(&self, k: String) -> Result<Option<(String, Option<Duration>)>> {
+        self.run(move |inner| inner.get_with_ttl(k))
+    }
+
+    fn stats(&self) -> EngineStats {
+        self.inner.stats()
+    }
+
+    fn flush(&self) -> Result<()> {
+        // Flushing before the server exits should finish however long it takes
+        self.inner.flush()
+    }
+}
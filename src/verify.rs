@@ -0,0 +1,115 @@
+//! Offline integrity check of a KvStore's files, for diagnosing a store which returns errors on reads
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::{ Result, Command };
+use crate::hint::Hint;
+use crate::record::Records;
+
+/// What `KvStore::verify` found, an empty list of problems means the store is consistent
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// Number of records in the log
+    pub records: usize,
+
+    /// Description of each inconsistency found
+    pub problems: Vec<String>,
+}
+
+impl VerifyReport {
+
+    /// Whether no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// The part of a record the index cares about, `None` for records which didn't parse
+enum Record {
+    Set(String),
+    Remove(String),
+}
+
+pub(crate) fn verify(path: &Path) -> Result<VerifyReport> {
+    let mut problems = Vec::new();
+    let log_path = path.join("log.log");
+    let hint_path = path.join("log.hint");
+
+    // Records are keyed by their offset in the log, which is what the index points at
+    let mut records: HashMap<usize, Option<Record>> = HashMap::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    if log_path.exists() {
+        for record in Records::open(&log_path)? {
+            let (offset, payload) = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    problems.push(e.to_string());
+                    break;
+                }
+            };
+            let offset = offset as usize;
+
+            let record = match serde_json::from_slice::<Command>(&payload) {
+                Ok(Command::Set(pair)) | Ok(Command::SetBlob(pair)) => Some(Record::Set(pair.k)),
+                Ok(Command::SetBytes(pair)) => match base64::decode(&pair.v) {
+                    Ok(_) => Some(Record::Set(pair.k)),
+                    Err(e) => {
+                        problems.push(format!("Record at byte {} sets '{}' to bytes which aren't valid base64: {}", offset, pair.k, e));
+                        None
+                    }
+                },
+                Ok(Command::Remove(key)) => Some(Record::Remove(key)),
+                Err(e) => {
+                    problems.push(format!("Record at byte {} is not a valid command: {}", offset, e));
+                    None
+                }
+            };
+
+            match &record {
+                Some(Record::Set(key)) => {
+                    index.insert(key.clone(), offset);
+                },
+                Some(Record::Remove(key)) => {
+                    let was_set = index.remove(key).is_some();
+                    if !was_set {
+                        problems.push(format!("Record at byte {} removes '{}', which was not set", offset, key));
+                    }
+                },
+                None => {}
+            }
+            records.insert(offset, record);
+        }
+    }
+
+    // A stale hint is ignored on open so only a current one can mislead reads
+    if let Some(hint) = Hint::load(&hint_path, &log_path) {
+        if hint.records != records.len() {
+            problems.push(format!("Hint counts {} records but the log has {}", hint.records, records.len()));
+        }
+
+        for (key, offset) in hint.index.iter() {
+            match records.get(offset) {
+                Some(Some(Record::Set(set))) if set == key => {
+                    if index.get(key) != Some(offset) {
+                        problems.push(format!("Hint points '{}' at byte {} of the log, which has been overwritten", key, offset));
+                    }
+                },
+                Some(Some(Record::Set(set))) => problems.push(format!("Hint points '{}' at byte {} of the log, which sets '{}'", key, offset, set)),
+                Some(Some(Record::Remove(_))) => problems.push(format!("Hint points '{}' at byte {} of the log, which is a remove command", key, offset)),
+                Some(None) => problems.push(format!("Hint points '{}' at byte {} of the log, which is not a valid record", key, offset)),
+                None => problems.push(format!("Hint points '{}' at byte {}, which is not the start of a record", key, offset))
+            }
+        }
+
+        for key in index.keys() {
+            if !hint.index.contains_key(key) {
+                problems.push(format!("Hint is missing '{}', which is set in the log", key));
+            }
+        }
+    }
+
+    Ok(VerifyReport {
+        records: records.len(),
+        problems
+    })
+}
@@ -0,0 +1,82 @@
+//! Optional writer thread for a KvStore, which takes writes from a queue so callers needn't wait on the log
+use failure::err_msg;
+use std::sync::Mutex;
+use std::sync::mpsc::{ self, Receiver, Sender };
+use std::thread::{ self, JoinHandle };
+
+use crate::{ Result, Command };
+
+/// A write waiting in the queue, along with where to send its outcome
+type Job = (Command, Sender<Result<bool>>);
+
+/// Queue of writes for a single thread which carries them out in order. Dropping the queue lets the thread
+/// finish every write already submitted, then waits for it to exit
+pub(crate) struct WriteQueue {
+    jobs: Option<Mutex<Sender<Job>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WriteQueue {
+
+    /// Start the writer thread, which hands each command to `write` and reports back what it returned
+    pub fn start<F: Fn(Command) -> Result<bool> + Send + 'static>(write: F) -> WriteQueue {
+        let (jobs, queued) = mpsc::channel::<Job>();
+        let thread = thread::spawn(move || {
+            for (command, done) in queued {
+                // Nobody is waiting on a fire-and-forget write, so a failed send is expected
+                let _ = done.send(write(command));
+            }
+        });
+
+        WriteQueue {
+            jobs: Some(Mutex::new(jobs)),
+            thread: Some(thread)
+        }
+    }
+
+    /// Queue a write, returning a ticket to wait on it with
+    pub fn submit(&self, command: Command) -> WriteTicket {
+        let (done, result) = mpsc::channel();
+        if let Some(jobs) = &self.jobs {
+            // The thread only stops once the queue is dropped, if it died anyway the ticket reports it
+            let _ = jobs.lock().unwrap().send((command, done));
+        }
+        WriteTicket { result }
+    }
+}
+
+impl Drop for WriteQueue {
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Outcome of a write submitted with `KvStore::set_async` or `KvStore::remove_async`. Wait on it to know the
+/// write is in the log, as it would be when a plain `set` returns, or drop it to carry on regardless
+pub struct WriteTicket {
+    result: Receiver<Result<bool>>,
+}
+
+impl WriteTicket {
+
+    /// Ticket for a write which has already been carried out
+    pub(crate) fn done(result: Result<bool>) -> WriteTicket {
+        let (done, ticket) = mpsc::channel();
+        let _ = done.send(result);
+        WriteTicket { result: ticket }
+    }
+
+    /// Block until the write is in the log, failing if it failed
+    pub fn wait(self) -> Result<()> {
+        self.wait_existed()?;
+        Ok(())
+    }
+
+    /// Like `wait`, also returning whether the key held a value before the write
+    pub(crate) fn wait_existed(self) -> Result<bool> {
+        self.result.recv().map_err(|_| err_msg("The writer thread stopped before carrying out the write"))?
+    }
+}
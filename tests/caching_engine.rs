@@ -0,0 +1,120 @@
+use kvs::{CachingEngine, KeyState, KvStore, KvsEngine, Result, SledKvsEngine};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// In-memory engine which counts how often it is read from
+#[derive(Clone, Default)]
+struct CountingEngine {
+    map: Arc<Mutex<HashMap<String, String>>>,
+    gets: Arc<AtomicUsize>,
+}
+
+impl CountingEngine {
+    fn gets(&self) -> usize {
+        self.gets.load(Ordering::SeqCst)
+    }
+}
+
+impl KvsEngine for CountingEngine {
+    fn set(&self, k: String, v: String) -> Result<()> {
+        self.map.lock().unwrap().insert(k, v);
+        Ok(())
+    }
+
+    fn get(&self, k: String) -> Result<Option<String>> {
+        self.gets.fetch_add(1, Ordering::SeqCst);
+        Ok(self.map.lock().unwrap().get(&k).cloned())
+    }
+
+    fn remove(&self, k: String) -> Result<()> {
+        self.map.lock().unwrap().remove(&k);
+        Ok(())
+    }
+}
+
+// Writes go through the cache, so reading them back never reaches the inner engine
+#[test]
+fn cache_hits_skip_inner_engine() -> Result<()> {
+    let inner = CountingEngine::default();
+    let store = CachingEngine::new(inner.clone(), 10);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(inner.gets(), 0);
+
+    Ok(())
+}
+
+// A miss reads from the inner engine once, then the value is cached
+#[test]
+fn cache_miss_fills_cache() -> Result<()> {
+    let inner = CountingEngine::default();
+    inner.set("key1".to_owned(), "value1".to_owned())?;
+    let store = CachingEngine::new(inner.clone(), 10);
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(inner.gets(), 1);
+
+    Ok(())
+}
+
+// Once over capacity the least recently used value is evicted
+#[test]
+fn least_recently_used_evicted() -> Result<()> {
+    let inner = CountingEngine::default();
+    let store = CachingEngine::new(inner.clone(), 2);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    store.get("key1".to_owned())?;
+    store.get("key3".to_owned())?;
+    assert_eq!(inner.gets(), 0);
+
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(inner.gets(), 1);
+
+    Ok(())
+}
+
+// Removing a key must not leave its value behind in the cache
+#[test]
+fn remove_invalidates_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = CachingEngine::new(KvStore::open(temp_dir.path())?, 10);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get_state("key1".to_owned())?, KeyState::Deleted);
+
+    store.set_bytes("key2".to_owned(), vec![0, 255])?;
+    assert_eq!(store.get_bytes("key2".to_owned())?, Some(vec![0, 255]));
+
+    Ok(())
+}
+
+// A value given a TTL isn't cached, so it stops being served once it expires
+#[test]
+fn touched_value_expires_through_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = CachingEngine::new(SledKvsEngine::open(temp_dir.path())?, 10);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.touch("key1".to_owned(), Duration::from_millis(200))?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
@@ -1,12 +1,28 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine, Result, ServerConfig, SledKvsEngine, LOG_FORMAT_VERSION};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
 
+// Builds a log file by hand from JSON commands: the header, then each command framed by its length.
+// Returns the file along with the offset of each record
+fn framed_log(commands: &[&str]) -> (Vec<u8>, Vec<usize>) {
+    let mut log = b"KVS\0".to_vec();
+    log.extend_from_slice(&LOG_FORMAT_VERSION.to_le_bytes());
+    let mut offsets = Vec::new();
+    for command in commands {
+        offsets.push(log.len());
+        log.extend_from_slice(&(command.len() as u32).to_le_bytes());
+        log.extend_from_slice(command.as_bytes());
+    }
+    (log, offsets)
+}
+
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
@@ -151,6 +167,82 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server` given an unknown engine or pool should fail and list the valid ones
+#[test]
+fn server_cli_invalid_choice() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "bogus"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("expected one of: kvs, sled"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--tp", "bogus"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("expected one of: naive, queued, rayon"));
+
+    // Nothing should be written for a server which never started
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
+// `kvs-server --list-engines` and `--list-pools` should print the supported values
+#[test]
+fn server_cli_list_choices() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--list-engines"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("kvs\nsled\n");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--list-pools"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("naive\nqueued\nrayon\n");
+}
+
+// `kvs-server --print-config` prints the merged settings as a config file, flags over the file over the defaults,
+// without starting the server
+#[test]
+fn server_print_config() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(&config_path, "engine = \"sled\"\nthreads = 2\nslow-op-ms = 50\n")?;
+
+    let output = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", config_path.to_str().unwrap(), "--threads", "3", "--tp", "rayon", "--sync", "always"])
+        .arg("--print-config")
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+
+    let printed = ServerConfig::from_toml(&String::from_utf8(output.stdout)?)?;
+    assert_eq!(printed.engine, Some("sled".to_owned()));
+    assert_eq!(printed.threads, Some(3));
+    assert_eq!(printed.tp, Some("rayon".to_owned()));
+    assert_eq!(printed.sync, Some("always".to_owned()));
+    assert_eq!(printed.slow_op_ms, Some(50));
+    assert_eq!(printed.addr, Some("127.0.0.1:4000".to_owned()));
+    assert_eq!(printed.access_log, None);
+
+    // Nothing was started, so the data directory is untouched
+    assert!(!temp_dir.path().join("engine").exists());
+
+    Ok(())
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -212,6 +304,38 @@ fn cli_wrong_engine() {
     }
 }
 
+// A data directory holding another engine's data but no marker is refused rather than claimed for the chosen engine
+#[test]
+fn cli_mixed_engine_data() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("holds data for the kvs engine").and(contains("kvs-admin migrate")));
+    assert!(!temp_dir.path().join("engine").exists());
+
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = SledKvsEngine::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.flush()?;
+    }
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("holds data for the sled engine"));
+    assert!(!temp_dir.path().join("log.log").exists());
+
+    Ok(())
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -335,3 +459,629 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+fn with_strict<'a>(strict: bool, args: &[&'a str]) -> Vec<&'a str> {
+    let mut args = args.to_vec();
+    if strict {
+        args.push("--strict");
+    }
+    args
+}
+
+// `kvs-client get --max-print` should cut long values short, while `--raw` and the default print them whole
+#[test]
+fn cli_get_max_print() {
+    let addr = "127.0.0.1:4012";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be waited on");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let long = "x".repeat(10_000);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "long", &long, "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "short", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "wide", "ééé", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "long", "--max-print", "16", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}...\n", "x".repeat(16)));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "short", "--max-print", "16", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    // Each é is two bytes, so three bytes only has room for one
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "wide", "--max-print", "3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("é...\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "long", "--raw", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", long));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "long", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", long));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "long", "--max-print", "lots", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_get_strict() {
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be waited on");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "present", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "deleted", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "deleted", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    for strict in &[false, true] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(with_strict(*strict, &["get", "present", "--addr", addr]))
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+
+        let assert = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(with_strict(*strict, &["get", "deleted", "--addr", addr]))
+            .current_dir(&temp_dir)
+            .assert()
+            .stdout(contains("removed"));
+        if *strict {
+            assert.code(3);
+        } else {
+            assert.success();
+        }
+
+        let assert = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(with_strict(*strict, &["get", "absent", "--addr", addr]))
+            .current_dir(&temp_dir)
+            .assert()
+            .stdout("Key not found\n");
+        if *strict {
+            assert.code(2);
+        } else {
+            assert.success();
+        }
+    }
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_version_reports_engine() {
+    let addr = "127.0.0.1:4007";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server could not be waited on");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["version", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(env!("CARGO_PKG_VERSION")))
+        .stdout(contains("engine=sled"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--version"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine=sled"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--version"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine=none"));
+}
+
+// `kvs-server --version` should report the engine in the data directory given by flag or config file
+#[test]
+fn server_version_reads_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    fs::write(data_dir.join("engine"), "sled").unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--version", "--data-dir", data_dir.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine=sled"));
+
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(&config_path, format!("data-dir = {:?}\n", data_dir.to_str().unwrap())).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--version", "--config", config_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine=sled"));
+}
+
+// `kvs-admin compact` should shrink a log full of overwrites without losing data
+#[test]
+fn admin_compact() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for round in 0..40 {
+            for key in 0..10 {
+                store
+                    .set(format!("key{}", key), format!("value{}", round))
+                    .unwrap();
+            }
+        }
+    }
+    let log_path = temp_dir.path().join("log.log");
+    let before = fs::metadata(&log_path).unwrap().len();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["compact", "--data-dir", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("Reclaimed"));
+
+    let after = fs::metadata(&log_path).unwrap().len();
+    assert!(after * 10 < before);
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for key in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key)).unwrap(),
+            Some("value39".to_owned())
+        );
+    }
+}
+
+// `kvs-admin verify` should pass a healthy store and report each problem in a damaged one
+#[test]
+fn admin_verify() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        store.remove("key1".to_owned()).unwrap();
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", "--data-dir", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("Checked 3 records").and(contains("No problems found")));
+
+    // A hint which matches the log's length is trusted on open, even when it points at the wrong records
+    let (log, offsets) = framed_log(&[
+        "{\"Set\":{\"k\":\"key1\",\"v\":\"value1\"}}",
+        "{\"Remove\":\"key1\"}",
+        "not a record",
+    ]);
+    fs::write(temp_dir.path().join("log.log"), &log).unwrap();
+    let hint = format!(
+        "{{\"log_len\":{},\"records\":3,\"index\":{{\"key1\":{}}},\"removed\":[]}}",
+        log.len(),
+        offsets[1]
+    );
+    fs::write(temp_dir.path().join("log.hint"), hint).unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", "--data-dir", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stdout(
+            contains(format!("Record at byte {} is not a valid command", offsets[2]))
+                .and(contains(format!(
+                    "Hint points 'key1' at byte {} of the log, which is a remove command",
+                    offsets[1]
+                )))
+                .and(contains("Found 2 problems")),
+        );
+}
+
+// `kvs-admin compact` should refuse a directory used by the sled engine
+#[test]
+fn admin_compact_sled_store() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("engine"), "sled").unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["compact", "--data-dir", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .failure();
+}
+
+// `kvs-admin migrate` should copy every live pair of a KvStore into sled and switch the marker over
+#[test]
+fn admin_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for key in 0..50 {
+            store
+                .set(format!("key{}", key), format!("value{}", key))
+                .unwrap();
+        }
+        store.remove("key7".to_owned()).unwrap();
+    }
+    fs::write(temp_dir.path().join("engine"), "kvs").unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "sled", "--to", "kvs", "--data-dir", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled", "--data-dir", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("Copied 49 pairs"));
+
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "sled"
+    );
+
+    let engine = SledKvsEngine::open(temp_dir.path()).unwrap();
+    for key in 0..50 {
+        let expected = if key == 7 { None } else { Some(format!("value{}", key)) };
+        assert_eq!(engine.get(format!("key{}", key)).unwrap(), expected);
+    }
+}
+
+// `kvs-client wait` should keep pinging until a server comes up, and give up once the timeout passes
+#[test]
+fn client_cli_wait() {
+    let temp_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["wait", "--timeout", "500ms", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let waiting = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["wait", "--timeout", "10s", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+
+    thread::sleep(Duration::from_millis(500));
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+
+    let status = waiting.wait_with_output().unwrap().status;
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    assert!(status.success());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["wait", "--timeout", "soon", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// The PID file holds the server's process ID while it runs and is removed when it's stopped with SIGTERM
+#[test]
+fn server_pid_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let pid_file = temp_dir.path().join("kvs.pid");
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4009", "--pid-file"])
+        .arg(&pid_file)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(
+        fs::read_to_string(&pid_file).unwrap().trim(),
+        server.id().to_string()
+    );
+
+    // A second server can't take over the file while the first is running
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4009", "--pid-file"])
+        .arg(&pid_file)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("still running"));
+
+    Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .assert()
+        .success();
+    assert!(server.wait().unwrap().success());
+    assert!(!pid_file.exists());
+}
+
+// A store which can't be written should stop the server starting, rather than fail the first client.
+// The log points at /dev/full since permissions don't stop root, which the tests may run as
+#[test]
+fn server_unwritable_store() {
+    let temp_dir = TempDir::new().unwrap();
+    std::os::unix::fs::symlink("/dev/full", temp_dir.path().join("log.log")).unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4013"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("No space left on device"));
+}
+
+// The self-test's key is removed again, leaving nothing behind for clients to see
+#[test]
+fn server_self_test_cleans_up() {
+    let temp_dir = TempDir::new().unwrap();
+    for self_test in &["true", "false"] {
+        let mut server = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4013", "--self-test", self_test])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        server.kill().expect("server exited before killed");
+        server.wait().unwrap();
+    }
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert!(store.keys().unwrap().is_empty());
+}
+
+// KVS_ADDR sets the address when --addr isn't given, the flag wins when both are
+#[test]
+fn client_addr_from_env() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .env("KVS_ADDR", "127.0.0.1:4011")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .env("KVS_ADDR", "127.0.0.1:4011")
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4011"])
+        .env("KVS_ADDR", "127.0.0.1:1")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // Neither set, the default address is used. Whether a server answers there doesn't matter
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1"])
+        .env_remove("KVS_ADDR")
+        .current_dir(&temp_dir)
+        .assert()
+        .stderr(contains("address: 127.0.0.1:4000"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+// KVS_ENGINE picks the engine when --engine isn't given, the flag wins when both are
+#[test]
+fn server_engine_from_env() {
+    // The address can't be bound, so each server stops right after recording its engine
+    let engine_used = |flag: Option<&str>, env: Option<&str>| {
+        let temp_dir = TempDir::new().unwrap();
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--addr", "256.0.0.1:4000"])
+            .env_remove("KVS_ENGINE")
+            .current_dir(&temp_dir);
+        if let Some(engine) = flag {
+            cmd.args(["--engine", engine]);
+        }
+        if let Some(engine) = env {
+            cmd.env("KVS_ENGINE", engine);
+        }
+        cmd.assert().failure();
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap()
+    };
+
+    assert_eq!(engine_used(None, Some("sled")), "sled");
+    assert_eq!(engine_used(Some("kvs"), Some("sled")), "kvs");
+    assert_eq!(engine_used(None, None), "kvs");
+}
+
+// Both binaries print completion scripts covering their subcommands and flags, without listing the command itself
+#[test]
+fn completions() {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["completions", "bash"])
+        .assert()
+        .success()
+        .stdout(contains("setnx").and(contains("append")).and(contains("scan")));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["completions", "bash"])
+        .assert()
+        .success()
+        .stdout(contains("--pid-file").and(contains("--engine")));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--help"])
+        .assert()
+        .success()
+        .stdout(contains("completions").not());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["completions", "tcsh"])
+        .assert()
+        .failure();
+}
+
+// --log-file sends each binary's log lines to the file rather than stderr
+#[test]
+fn log_to_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let server_log = temp_dir.path().join("server.log");
+    let client_log = temp_dir.path().join("client.log");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4014", "--log-file", server_log.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4014", "--log-file", client_log.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(is_empty());
+    thread::sleep(Duration::from_millis(500));
+    server.kill().expect("server exited before killed");
+    let output = server.wait_with_output().unwrap();
+
+    let logged = fs::read_to_string(&server_log).unwrap();
+    assert!(logged.contains("Waiting for connections"), "{}", logged);
+    assert!(logged.contains("Store SET successful"), "{}", logged);
+    assert!(output.stderr.is_empty(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let logged = fs::read_to_string(&client_log).unwrap();
+    assert!(logged.contains("CLI arguments processed"), "{}", logged);
+}
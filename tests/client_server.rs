@@ -0,0 +1,1339 @@
+use assert_cmd::prelude::*;
+use kvs::client::Backoff;
+use kvs::network::{Operation, Response, ResponseStatus, TcpMessage};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    KeyState, KvStore, KvsClient, KvsEngine, KvsError, ReplicatedEngine, ReplicationPolicy, Result, ServerConfig,
+    ServerHandle, SetOutcome,
+};
+use slog::{o, Discard, Drain, Logger, Never, OwnedKVList, Record};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn logger() -> Logger {
+    Logger::root(Discard, o!())
+}
+
+// A `kvs-server` running on an ephemeral port in its own temp directory, killed on drop
+struct TestServer {
+    child: Child,
+    addr: SocketAddr,
+    _temp_dir: TempDir,
+}
+
+impl TestServer {
+    fn start(engine: &str, pool: &str) -> TestServer {
+        TestServer::start_with_args(engine, pool, &[])
+    }
+
+    // Extra arguments are passed to the server as is, its stderr is piped for tests to read the log
+    fn start_with_args(engine: &str, pool: &str, args: &[&str]) -> TestServer {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = free_addr();
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--tp", pool, "--addr", &addr.to_string()])
+            .args(args)
+            .current_dir(&temp_dir)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        wait_for_server(addr);
+        TestServer {
+            child,
+            addr,
+            _temp_dir: temp_dir,
+        }
+    }
+
+    fn client(&self) -> KvsClient {
+        KvsClient::new(logger(), self.addr)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.child.kill().expect("server exited before killed");
+        self.child.wait().expect("server could not be waited on");
+    }
+}
+
+// Let the OS pick a free port, then release it for the server to bind
+fn free_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+fn wait_for_server(addr: SocketAddr) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(addr).is_err() {
+        assert!(Instant::now() < deadline, "server did not start listening on {}", addr);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn set_get_remove(engine: &str, pool: &str) -> Result<()> {
+    let server = TestServer::start(engine, pool);
+    let client = server.client();
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    client.set("key2".to_owned(), "".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, Some("".to_owned()));
+
+    assert_eq!(client.get("key3".to_owned())?, None);
+    match client.remove("key3".to_owned()) {
+        Err(err) => assert!(matches!(err.downcast_ref::<KvsError>(), Some(KvsError::KeyNotFound))),
+        Ok(()) => panic!("Expected KvsError::KeyNotFound"),
+    }
+
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    let bytes = vec![0u8, 10, 32, 255, 0];
+    client.set_bytes("key4".to_owned(), bytes.clone())?;
+    assert_eq!(client.get_bytes("key4".to_owned())?, Some(bytes));
+
+    Ok(())
+}
+
+#[test]
+fn kvs_engine_naive_pool() -> Result<()> {
+    set_get_remove("kvs", "naive")
+}
+
+#[test]
+fn kvs_engine_queued_pool() -> Result<()> {
+    set_get_remove("kvs", "queued")
+}
+
+#[test]
+fn kvs_engine_rayon_pool() -> Result<()> {
+    set_get_remove("kvs", "rayon")
+}
+
+#[test]
+fn sled_engine_naive_pool() -> Result<()> {
+    set_get_remove("sled", "naive")
+}
+
+#[test]
+fn sled_engine_queued_pool() -> Result<()> {
+    set_get_remove("sled", "queued")
+}
+
+#[test]
+fn sled_engine_rayon_pool() -> Result<()> {
+    set_get_remove("sled", "rayon")
+}
+
+#[test]
+fn kvs_engine_reports_removed_key() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    let client = server.client();
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get_state("key1".to_owned())?, KeyState::Deleted);
+    assert_eq!(client.get_state("key2".to_owned())?, KeyState::Absent);
+
+    Ok(())
+}
+
+// Malformed requests should get an INVALID response and leave the server running
+#[test]
+fn malformed_request_gets_invalid_response() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+
+    for request in &["set\n", "set onlykey\n", "get\n", "rm\n", "bogus k\n"] {
+        let mut stream = TcpStream::connect(server.addr)?;
+        stream.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        assert!(response.starts_with("INVALID "));
+    }
+
+    let client = server.client();
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// With a zero threshold every operation counts as slow, so the warning must show up in the server's log
+#[test]
+fn slow_operation_logs_warning() -> Result<()> {
+    let mut server = TestServer::start_with_args("kvs", "queued", &["--slow-op-ms", "0"]);
+    let stderr = server.child.stderr.take().unwrap();
+
+    server.client().set("key1".to_owned(), "value1".to_owned())?;
+
+    let found = BufReader::new(stderr)
+        .lines()
+        .map(|line| line.unwrap())
+        .any(|line| line.contains("Slow operation") && line.contains("elapsed_ms"));
+    assert!(found);
+
+    Ok(())
+}
+
+// Sampling logs only one in every N operations, while every connection is still logged
+#[test]
+fn op_log_sampling() -> Result<()> {
+    let mut server = TestServer::start_with_args("kvs", "queued", &["--op-log-sample", "100"]);
+    let stderr = server.child.stderr.take().unwrap();
+
+    let client = server.client();
+    for i in 0..50 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+
+    // Stopping the server closes its stderr once the log is flushed
+    Command::new("kill")
+        .args(["-TERM", &server.child.id().to_string()])
+        .assert()
+        .success();
+    let lines: Vec<String> = BufReader::new(stderr).lines().map(|line| line.unwrap()).collect();
+
+    let count = |text: &str| lines.iter().filter(|line| line.contains(text)).count();
+    assert!(count("Store SET successful") <= 1, "{} sets logged", count("Store SET successful"));
+    assert!(count("TCP connection established") >= 50);
+
+    Ok(())
+}
+
+// Each request gets one line in the access log: client, timestamp, request, status and latency
+#[test]
+fn access_log_records_requests() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.path().join("access.log");
+    let server = TestServer::start_with_args("kvs", "queued", &["--access-log", path.to_str().unwrap()]);
+
+    server.client().set("key1".to_owned(), "value1".to_owned())?;
+
+    let contents = std::fs::read_to_string(&path)?;
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 1);
+
+    let line = lines[0];
+    let (client, rest) = line.split_at(line.find(" - - [").unwrap());
+    assert!(client.parse::<SocketAddr>().is_ok());
+    let rest = &rest[" - - [".len()..];
+    let (timestamp, rest) = rest.split_at(rest.find(']').unwrap());
+    assert!(chrono::DateTime::parse_from_str(timestamp, "%d/%b/%Y:%H:%M:%S %z").is_ok());
+
+    let fields: Vec<&str> = rest.split(' ').collect();
+    assert_eq!(fields[..4], ["]", "\"set", "key1\"", "OK"]);
+    assert!(fields[4].parse::<f64>().unwrap() >= 0.0);
+    assert_eq!(fields.len(), 5);
+
+    Ok(())
+}
+
+// Clients which close without sending anything shouldn't disturb the server, and one connection can carry many operations
+#[test]
+fn connection_lifecycle() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+
+    for _ in 0..10 {
+        drop(TcpStream::connect(server.addr)?);
+    }
+
+    {
+        let mut stream = TcpStream::connect(server.addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        for request in &["set key1 value1\n", "get key1\n"] {
+            stream.write_all(request.as_bytes())?;
+            let mut response = String::new();
+            reader.read_line(&mut response)?;
+            assert!(response.starts_with("OK"));
+        }
+    }
+
+    let client = server.client();
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Operations should work whether or not Nagle's algorithm is disabled on either end
+#[test]
+fn nodelay_settings() -> Result<()> {
+    for &nodelay in &[true, false] {
+        let server = TestServer::start_with_args("kvs", "queued", &["--nodelay", &nodelay.to_string()]);
+        let client = server.client().nodelay(nodelay);
+
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+// Settings come from the config file unless a flag overrides them
+#[test]
+fn config_file_with_overrides() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    std::fs::write(
+        &config_path,
+        "addr = \"127.0.0.1:1\"\nengine = \"sled\"\ndata-dir = \"data\"\n",
+    )?;
+
+    let addr = free_addr();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", config_path.to_str().unwrap(), "--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    wait_for_server(addr);
+
+    let version = KvsClient::new(logger(), addr).version();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server could not be waited on");
+
+    assert!(version?.contains("engine=sled"));
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.path().join("data").join("engine"))?,
+        "sled"
+    );
+
+    Ok(())
+}
+
+// The same key in different buckets, and outside any bucket, holds independent values
+#[test]
+fn buckets_are_independent() -> Result<()> {
+    for engine in &["kvs", "sled"] {
+        let server = TestServer::start(engine, "queued");
+        let plain = server.client();
+        let app1 = server.client().bucket("app1".to_owned());
+        let app2 = server.client().bucket("app 2".to_owned());
+
+        plain.set("key1".to_owned(), "plain".to_owned())?;
+        app1.set("key1".to_owned(), "one".to_owned())?;
+        app2.set("key1".to_owned(), "two".to_owned())?;
+
+        assert_eq!(plain.get("key1".to_owned())?, Some("plain".to_owned()));
+        assert_eq!(app1.get("key1".to_owned())?, Some("one".to_owned()));
+        assert_eq!(app2.get("key1".to_owned())?, Some("two".to_owned()));
+
+        app1.remove("key1".to_owned())?;
+        assert_eq!(app1.get("key1".to_owned())?, None);
+        assert_eq!(app2.get("key1".to_owned())?, Some("two".to_owned()));
+        assert_eq!(plain.get("key1".to_owned())?, Some("plain".to_owned()));
+
+        let invalid = server.client().bucket("nul\0".to_owned());
+        assert!(invalid.get("key1".to_owned()).is_err());
+    }
+    Ok(())
+}
+
+// A key in a bucket can't be read, overwritten or removed from outside it by naming it with the NUL it's stored with
+#[test]
+fn buckets_unreachable_from_outside() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    let app = server.client().bucket("app".to_owned());
+    let plain = server.client();
+    app.set("secret".to_owned(), "hidden".to_owned())?;
+
+    let stored = "app\0secret".to_owned();
+    assert!(plain.get(stored.clone()).is_err());
+    assert!(plain.set(stored.clone(), "overwritten".to_owned()).is_err());
+    assert!(plain.remove(stored.clone()).is_err());
+    assert!(plain.remove_batch(vec![stored]).is_err());
+
+    // Nor from another bucket
+    let other = server.client().bucket("other".to_owned());
+    assert!(other.get("x\0secret".to_owned()).is_err());
+
+    assert_eq!(app.get("secret".to_owned())?, Some("hidden".to_owned()));
+    Ok(())
+}
+
+// Appends over the network return the new length, with text needing escapes arriving intact
+#[test]
+fn append_over_network() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    let client = server.client();
+
+    assert_eq!(client.append("log".to_owned(), "line one\n".to_owned())?, 9);
+    assert_eq!(client.append("log".to_owned(), "line two\n".to_owned())?, 18);
+    assert_eq!(client.get("log".to_owned())?, Some("line one\nline two\n".to_owned()));
+
+    Ok(())
+}
+
+// Hash increments over the network return the field's new value, and a value which isn't a hash is refused
+#[test]
+fn hincr_over_network() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    let client = server.client();
+
+    assert_eq!(client.hincr("page one".to_owned(), "views".to_owned(), 1)?, 1);
+    assert_eq!(client.hincr("page one".to_owned(), "views".to_owned(), -3)?, -2);
+    assert_eq!(client.hincr("page one".to_owned(), "clicks".to_owned(), 7)?, 7);
+
+    client.set("plain".to_owned(), "text".to_owned())?;
+    let response = client.send(Operation::HIncr("plain".to_owned(), "views".to_owned(), 1))?;
+    assert_eq!(response.status, ResponseStatus::InvalidRequest);
+
+    Ok(())
+}
+
+// A scan streams back every pair in the store, and only the pairs in the client's bucket
+#[test]
+fn scan_streams_every_pair() -> Result<()> {
+    for engine in &["kvs", "sled"] {
+        let server = TestServer::start(engine, "queued");
+        let client = server.client();
+        let bucketed = server.client().bucket("app1".to_owned());
+
+        let mut expected = HashMap::new();
+        for i in 0..1000 {
+            client.set(format!("key {}", i), format!("value\n{}", i))?;
+            expected.insert(format!("key {}", i), format!("value\n{}", i));
+        }
+        for i in (0..1000).step_by(7) {
+            client.remove(format!("key {}", i))?;
+            expected.remove(&format!("key {}", i));
+        }
+        bucketed.set("key1".to_owned(), "bucketed".to_owned())?;
+
+        let mut scanned = Vec::new();
+        client.scan(|key, value| scanned.push((key, value)))?;
+        let mut expected: Vec<(String, String)> = expected.into_iter().collect();
+        expected.sort();
+        assert_eq!(scanned, expected);
+
+        let mut scanned = Vec::new();
+        bucketed.scan(|key, value| scanned.push((key, value)))?;
+        assert_eq!(scanned, vec![("key1".to_owned(), "bucketed".to_owned())]);
+    }
+    Ok(())
+}
+
+// Grep streams only the pairs whose value contains the text, and only from the client's bucket
+#[test]
+fn grep_over_network() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    let client = server.client();
+    let bucketed = server.client().bucket("app1".to_owned());
+
+    client.set("key1".to_owned(), "red apple".to_owned())?;
+    client.set("key2".to_owned(), "green apple".to_owned())?;
+    client.set("key3".to_owned(), "red cherry".to_owned())?;
+    bucketed.set("key4".to_owned(), "red grape".to_owned())?;
+
+    let mut found = Vec::new();
+    client.grep("red ".to_owned(), |key, value| found.push((key, value)))?;
+    found.sort();
+    assert_eq!(
+        found,
+        vec![
+            ("key1".to_owned(), "red apple".to_owned()),
+            ("key3".to_owned(), "red cherry".to_owned()),
+        ]
+    );
+
+    let mut found = Vec::new();
+    bucketed.grep("red".to_owned(), |key, value| found.push((key, value)))?;
+    assert_eq!(found, vec![("key4".to_owned(), "red grape".to_owned())]);
+
+    let mut found = 0;
+    client.grep("banana".to_owned(), |_, _| found += 1)?;
+    assert_eq!(found, 0);
+
+    Ok(())
+}
+
+// Recently written keys come back most recent first, awkward keys included, and only from the client's bucket
+#[test]
+fn recent_keys_over_network() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    let client = server.client();
+
+    for key in &["key1", "two words", "key3"] {
+        client.set(key.to_string(), "value".to_owned())?;
+    }
+    client.set("key1".to_owned(), "value".to_owned())?;
+    server.client().bucket("other".to_owned()).set("elsewhere".to_owned(), "value".to_owned())?;
+
+    assert_eq!(client.recent_keys(2)?, vec!["key1".to_owned(), "key3".to_owned()]);
+    assert_eq!(
+        client.recent_keys(10)?,
+        vec!["key1".to_owned(), "key3".to_owned(), "two words".to_owned()]
+    );
+    assert_eq!(
+        server.client().bucket("other".to_owned()).recent_keys(10)?,
+        vec!["elsewhere".to_owned()]
+    );
+    assert!(server.client().bucket("empty".to_owned()).recent_keys(10)?.is_empty());
+
+    Ok(())
+}
+
+// Only the first setnx of a key sets it, the rest report it was already there
+#[test]
+fn set_if_absent_over_network() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    let client = server.client();
+
+    assert!(client.set_if_absent("lock".to_owned(), "first".to_owned())?);
+    assert!(!client.set_if_absent("lock".to_owned(), "second".to_owned())?);
+    assert_eq!(client.get("lock".to_owned())?, Some("first".to_owned()));
+
+    Ok(())
+}
+
+// A cad only removes the key while it holds the expected value
+#[test]
+fn compare_and_delete_over_network() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    let client = server.client();
+
+    assert!(!client.compare_and_delete("lock".to_owned(), "first".to_owned())?);
+    client.set("lock".to_owned(), "first".to_owned())?;
+    assert!(!client.compare_and_delete("lock".to_owned(), "second".to_owned())?);
+    assert!(client.compare_and_delete("lock".to_owned(), "first".to_owned())?);
+    assert_eq!(client.get("lock".to_owned())?, None);
+
+    Ok(())
+}
+
+// Sends `version` on a new connection, returning the connection along with the response line
+fn request_version(addr: SocketAddr) -> Result<(TcpStream, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"version\n")?;
+    let mut response = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut response)?;
+    Ok((stream, response))
+}
+
+fn request_version_until_ok(addr: SocketAddr) -> Result<TcpStream> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let (stream, response) = request_version(addr)?;
+        if response.starts_with("OK") {
+            return Ok(stream);
+        }
+        assert!(Instant::now() < deadline, "server kept answering {}", response);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// Connections beyond the limit are turned away as BUSY until an open one closes
+#[test]
+fn max_connections_enforced() -> Result<()> {
+    let server = TestServer::start_with_args("kvs", "queued", &["--max-connections", "1"]);
+
+    let open = request_version_until_ok(server.addr)?;
+    for _ in 0..3 {
+        let (_, response) = request_version(server.addr)?;
+        assert_eq!(response, "BUSY\n");
+    }
+
+    drop(open);
+    request_version_until_ok(server.addr)?;
+
+    Ok(())
+}
+
+// Accepts `count` connections, answering each with OK and passing on the request line it read
+fn stub_server(count: usize) -> (SocketAddr, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming().take(count) {
+            let mut stream = stream.unwrap();
+            let mut request = String::new();
+            BufReader::new(stream.try_clone().unwrap())
+                .read_line(&mut request)
+                .unwrap();
+            stream.write_all(b"OK\n").unwrap();
+            tx.send(request).unwrap();
+        }
+    });
+    (addr, rx)
+}
+
+// A server closing the connection without answering should fail the request with ConnectionClosed
+#[test]
+fn client_reports_connection_closed_without_response() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        let stream = listener.incoming().next().unwrap().unwrap();
+        let mut request = String::new();
+        BufReader::new(stream.try_clone().unwrap()).read_line(&mut request).unwrap();
+    });
+
+    let err = KvsClient::new(logger(), addr).get("key1".to_owned()).expect_err("no response was sent");
+    assert!(matches!(err.downcast_ref::<KvsError>(), Some(KvsError::ConnectionClosed)), "unexpected error {}", err);
+
+    Ok(())
+}
+
+// Answers every get with `value`, counting the requests it reads
+fn counting_server(value: &str) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counted = requests.clone();
+    let value = value.to_owned();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let operation = Operation::read_from_stream(logger(), stream.try_clone().unwrap()).unwrap();
+            counted.fetch_add(1, Ordering::SeqCst);
+            let data = match operation {
+                Operation::Get(_) => Some(value.clone()),
+                _ => None,
+            };
+            Response { status: ResponseStatus::Ok, data }.write_to_stream(logger(), stream).unwrap();
+        }
+    });
+    (addr, requests)
+}
+
+// A cached client should answer a repeat get itself, and go back to the server once it writes the key
+#[test]
+fn client_cache_serves_repeat_gets() -> Result<()> {
+    let (addr, requests) = counting_server("value1");
+    let client = KvsClient::new(logger(), addr).cache(16);
+
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+    // Without a cache every get reaches the server
+    let uncached = KvsClient::new(logger(), addr);
+    uncached.get("key1".to_owned())?;
+    uncached.get("key1".to_owned())?;
+    assert_eq!(requests.load(Ordering::SeqCst), 5);
+
+    Ok(())
+}
+
+// Clients sharing a cache but using different buckets should each get their own bucket's value for a key
+#[test]
+fn client_cache_keeps_buckets_apart() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    KvsClient::new(logger(), server.addr).bucket("a".to_owned()).set("key1".to_owned(), "value_a".to_owned())?;
+    KvsClient::new(logger(), server.addr).bucket("b".to_owned()).set("key1".to_owned(), "value_b".to_owned())?;
+
+    let client = KvsClient::new(logger(), server.addr).cache(16);
+    let bucket_a = client.clone().bucket("a".to_owned());
+    let bucket_b = client.clone().bucket("b".to_owned());
+    assert_eq!(bucket_a.get("key1".to_owned())?, Some("value_a".to_owned()));
+    assert_eq!(bucket_b.get("key1".to_owned())?, Some("value_b".to_owned()));
+    assert_eq!(bucket_a.get("key1".to_owned())?, Some("value_a".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Writes should land locally and be forwarded to the replica, reads should stay local
+#[test]
+fn replicated_engine_forwards_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, requests) = stub_server(2);
+    let store = ReplicatedEngine::new(
+        logger(),
+        KvStore::open(temp_dir.path())?,
+        vec![KvsClient::new(logger(), addr)],
+        ReplicationPolicy::Required,
+    );
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.remove("key1".to_owned())?;
+
+    assert_eq!(requests.recv().unwrap(), "set key1 value1\n");
+    assert_eq!(requests.recv().unwrap(), "rm key1\n");
+
+    Ok(())
+}
+
+// An unreachable replica only fails the write when replication is required
+#[test]
+fn replicated_engine_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let local = KvStore::open(temp_dir.path())?;
+    let unreachable = vec![KvsClient::new(logger(), free_addr())];
+
+    let best_effort = ReplicatedEngine::new(
+        logger(),
+        local.clone(),
+        unreachable.clone(),
+        ReplicationPolicy::BestEffort,
+    );
+    best_effort.set("key1".to_owned(), "value1".to_owned())?;
+
+    let required = ReplicatedEngine::new(
+        logger(),
+        local.clone(),
+        unreachable,
+        ReplicationPolicy::Required,
+    );
+    assert!(required
+        .set("key2".to_owned(), "value2".to_owned())
+        .is_err());
+
+    assert_eq!(local.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(local.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// SIGUSR1 drains the server: connections already open are served to the end, new ones are turned away as
+// DRAINING, and once the open ones close the server exits cleanly with their writes on disk
+#[test]
+fn drain_finishes_open_connections() -> Result<()> {
+    let mut server = TestServer::start("kvs", "queued");
+
+    {
+        let mut open = TcpStream::connect(server.addr)?;
+        let mut reader = BufReader::new(open.try_clone()?);
+        let mut request = |request: &str| -> Result<String> {
+            open.write_all(request.as_bytes())?;
+            let mut response = String::new();
+            reader.read_line(&mut response)?;
+            Ok(response)
+        };
+        assert!(request("set key1 value1\n")?.starts_with("OK"));
+
+        Command::new("kill")
+            .args(["-USR1", &server.child.id().to_string()])
+            .assert()
+            .success();
+        thread::sleep(Duration::from_millis(500));
+
+        let (_, response) = request_version(server.addr)?;
+        assert!(response.starts_with("DRAINING"), "{}", response);
+
+        assert!(request("set key2 value2\n")?.starts_with("OK"));
+        assert!(request("get key1\n")?.starts_with("OK"));
+        assert!(server.child.try_wait()?.is_none());
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = server.child.try_wait()? {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "server still running after its connections closed");
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success());
+
+    let store = KvStore::open(server._temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A client sending faster than --rate-limit gets some requests refused, one within it gets them all served
+#[test]
+fn rate_limit_per_client() -> Result<()> {
+    let server = TestServer::start_with_args("kvs", "queued", &["--rate-limit", "10"]);
+
+    let mut stream = TcpStream::connect(server.addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = |request: &str| -> Result<String> {
+        stream.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        reader.read_line(&mut response)?;
+        Ok(response)
+    };
+
+    let mut limited = 0;
+    for _ in 0..40 {
+        if request("version\n")?.starts_with("RATE_LIMITED") {
+            limited += 1;
+        }
+    }
+    assert!(limited > 0, "no requests were limited");
+    assert!(limited < 40, "every request was limited");
+
+    thread::sleep(Duration::from_millis(1100));
+    for _ in 0..10 {
+        let response = request("version\n")?;
+        assert!(response.starts_with("OK"), "{}", response);
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    Ok(())
+}
+
+// Send one HTTP request and read the response's status code and body
+#[cfg(feature = "http")]
+fn http_request(addr: SocketAddr, method: &str, path: &str, body: &[u8]) -> Result<(u16, Vec<u8>)> {
+    use std::io::Read;
+
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n", method, path, addr, body.len())?;
+    stream.write_all(body)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    let end_of_headers = response.windows(4).position(|w| w == b"\r\n\r\n").expect("no end of headers");
+    let head = String::from_utf8_lossy(&response[..end_of_headers]).into_owned();
+    let code = head.split_whitespace().nth(1).expect("no status code").parse()?;
+    Ok((code, response[end_of_headers + 4..].to_vec()))
+}
+
+// PUT, GET and DELETE over HTTP map onto set, get and remove, with 404 for missing keys
+#[cfg(feature = "http")]
+#[test]
+fn http_frontend() -> Result<()> {
+    let http_addr = free_addr();
+    let server = TestServer::start_with_args("kvs", "queued", &["--http-addr", &http_addr.to_string()]);
+    wait_for_server(http_addr);
+
+    assert_eq!(http_request(http_addr, "GET", "/key1", b"")?.0, 404);
+    assert_eq!(http_request(http_addr, "PUT", "/key1", b"value1")?.0, 204);
+    assert_eq!(http_request(http_addr, "GET", "/key1", b"")?, (200, b"value1".to_vec()));
+    assert_eq!(http_request(http_addr, "PUT", "/key%20two", b"value2")?.0, 204);
+
+    // Both protocols serve the same store
+    let client = server.client();
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key two".to_owned())?, Some("value2".to_owned()));
+
+    assert_eq!(http_request(http_addr, "DELETE", "/key1", b"")?.0, 204);
+    assert_eq!(http_request(http_addr, "GET", "/key1", b"")?.0, 404);
+    assert_eq!(http_request(http_addr, "DELETE", "/key1", b"")?.0, 404);
+    assert_eq!(http_request(http_addr, "POST", "/key1", b"")?.0, 405);
+    assert_eq!(http_request(http_addr, "GET", "/", b"")?.0, 400);
+
+    // A NUL would name a key in a bucket
+    server.client().bucket("app".to_owned()).set("secret".to_owned(), "hidden".to_owned())?;
+    assert_eq!(http_request(http_addr, "GET", "/app%00secret", b"")?.0, 400);
+
+    Ok(())
+}
+
+// A request longer than --max-request-bytes is refused and its connection closed without reading the rest
+#[test]
+fn oversized_request_refused() -> Result<()> {
+    let server = TestServer::start_with_args("kvs", "queued", &["--max-request-bytes", "1024"]);
+
+    let mut stream = TcpStream::connect(server.addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let key = "k".repeat(4096);
+    stream.write_all(format!("get {}", key).as_bytes())?;
+
+    let mut response = String::new();
+    reader.read_line(&mut response)?;
+    assert!(response.starts_with("INVALID"), "{}", response);
+    assert!(response.contains("1024"), "{}", response);
+
+    // The server closes the connection rather than reading on, the rest is never parsed as a request
+    response.clear();
+    assert_eq!(reader.read_line(&mut response).unwrap_or(0), 0, "{}", response);
+
+    // Requests within the limit are still served
+    let client = server.client();
+    client.set("key1".to_owned(), "k".repeat(900))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("k".repeat(900)));
+
+    Ok(())
+}
+
+// A kept-alive connection is closed once it has sent nothing for the idle timeout, but one which keeps sending
+// requests stays open however long it lasts
+#[test]
+fn idle_connections_closed() -> Result<()> {
+    let server = TestServer::start_with_args("kvs", "queued", &["--idle-timeout", "300"]);
+    server.client().set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut busy = TcpStream::connect(server.addr)?;
+    let mut busy_reader = BufReader::new(busy.try_clone()?);
+    let mut idle = TcpStream::connect(server.addr)?;
+    let mut idle_reader = BufReader::new(idle.try_clone()?);
+    idle.write_all(b"get key1\n")?;
+    let mut response = String::new();
+    idle_reader.read_line(&mut response)?;
+    assert_eq!(response, "OK value1\n");
+
+    for _ in 0..6 {
+        thread::sleep(Duration::from_millis(100));
+        busy.write_all(b"get key1\n")?;
+        let mut response = String::new();
+        busy_reader.read_line(&mut response)?;
+        assert_eq!(response, "OK value1\n");
+    }
+
+    // Well past the timeout for the idle connection, which the server has closed
+    let mut response = String::new();
+    assert_eq!(idle_reader.read_line(&mut response)?, 0);
+
+    Ok(())
+}
+
+// A client pipelining requests without reading the responses is held back once the server stops reading ahead,
+// rather than the server buffering everything it's sent. Every request is still answered, in order
+#[test]
+fn pipelined_requests_throttled() -> Result<()> {
+    const REQUESTS: usize = 20_000;
+
+    let server = TestServer::start_with_args("kvs", "queued", &["--max-in-flight", "2"]);
+    let key = "k".repeat(4096);
+    let value = "v".repeat(4096);
+    server.client().set(key.clone(), value.clone())?;
+
+    // Far more than the socket buffers between the client and server can hold, both ways
+    let stream = TcpStream::connect(server.addr)?;
+    let sent = Arc::new(AtomicUsize::new(0));
+    let writer = {
+        let mut stream = stream.try_clone()?;
+        let sent = sent.clone();
+        let request = format!("get {}\n", key);
+        thread::spawn(move || {
+            for _ in 0..REQUESTS {
+                stream.write_all(request.as_bytes())?;
+                sent.fetch_add(1, Ordering::SeqCst);
+            }
+            stream.flush()
+        })
+    };
+
+    let mut stalled_at = 0;
+    loop {
+        thread::sleep(Duration::from_millis(200));
+        let now = sent.load(Ordering::SeqCst);
+        if now > 0 && now == stalled_at {
+            break;
+        }
+        stalled_at = now;
+    }
+    assert!(stalled_at < REQUESTS, "every request was accepted without any being answered");
+
+    let mut reader = BufReader::new(stream);
+    let expected = format!("OK {}", value);
+    for i in 0..REQUESTS {
+        let mut response = String::new();
+        reader.read_line(&mut response)?;
+        assert_eq!(response.trim_end(), expected, "response {}", i);
+    }
+    writer.join().unwrap()?;
+
+    Ok(())
+}
+
+// Servers syncing after writes serve them the same, and a sync setting which isn't understood stops startup
+#[test]
+fn sync_setting() -> Result<()> {
+    for sync in &["always", "never", "50"] {
+        let server = TestServer::start_with_args("kvs", "queued", &["--sync", sync]);
+        let client = server.client();
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &free_addr().to_string(), "--sync", "sometimes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+// A read-only engine marker which already names the engine is only read, one which can't be read stops startup
+// with a message naming it
+#[test]
+fn read_only_engine_marker() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let marker = temp_dir.path().join("engine");
+    std::fs::write(&marker, "kvs")?;
+    let mut permissions = std::fs::metadata(&marker)?.permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(&marker, permissions)?;
+
+    let (mut child, addr) = spawn_server_in(&temp_dir, "kvs", &[]);
+    KvsClient::new(logger(), addr).set("key1".to_owned(), "value1".to_owned())?;
+    child.kill()?;
+    child.wait()?;
+    assert_eq!(std::fs::read_to_string(&marker)?, "kvs");
+
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::create_dir(temp_dir.path().join("engine"))?;
+    let output = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &free_addr().to_string()])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("engine marker"));
+
+    Ok(())
+}
+
+// Start a server in `dir` without the test harness's own temporary directory, so it can be restarted there
+fn spawn_server_in(dir: &TempDir, engine: &str, args: &[&str]) -> (Child, SocketAddr) {
+    let addr = free_addr();
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", &addr.to_string()])
+        .args(args)
+        .current_dir(dir)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    wait_for_server(addr);
+    (child, addr)
+}
+
+// Writes acknowledged before the server is killed outright are there after a restart, and a record torn by
+// the kill doesn't stop the kvs engine reopening
+#[test]
+fn recovers_after_kill() -> Result<()> {
+    let durable: [(&str, &[&str]); 2] = [("kvs", &["--sync", "always"]), ("sled", &["--sled-flush-ms", "10"])];
+    for (engine, args) in durable.iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let (mut child, addr) = spawn_server_in(&temp_dir, engine, args);
+        let client = KvsClient::new(logger(), addr);
+        for i in 0..100 {
+            client.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        for i in 0..10 {
+            client.remove(format!("key{}", i))?;
+        }
+        if *engine == "sled" {
+            // Sled acknowledges before flushing, give it time to flush what was written
+            thread::sleep(Duration::from_millis(200));
+        }
+
+        // SIGKILL, so nothing gets to flush or write a hint on the way out
+        child.kill()?;
+        child.wait()?;
+        if *engine == "kvs" {
+            let mut log = std::fs::OpenOptions::new().append(true).open(temp_dir.path().join("log.log"))?;
+            log.write_all(&[200, 0, 0, 0, b'{', b'"', b'S'])?;
+        }
+
+        let (mut child, addr) = spawn_server_in(&temp_dir, engine, args);
+        let client = KvsClient::new(logger(), addr);
+        for i in 0..10 {
+            assert_eq!(client.get(format!("key{}", i))?, None, "{}", engine);
+        }
+        for i in 10..100 {
+            assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)), "{}", engine);
+        }
+        client.set("after".to_owned(), "restart".to_owned())?;
+        assert_eq!(client.get("after".to_owned())?, Some("restart".to_owned()));
+        child.kill()?;
+        child.wait()?;
+    }
+    Ok(())
+}
+
+// With a key pattern only keys wholly matching it can be set, other operations on any key still work
+#[test]
+fn key_pattern() -> Result<()> {
+    let server = TestServer::start_with_args("kvs", "queued", &["--key-pattern", "[a-z0-9:_-]+"]);
+    let client = server.client();
+
+    client.set("user:1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("user:1".to_owned())?, Some("value1".to_owned()));
+    for key in &["User:1", "user 1", "user:1!"] {
+        let response = client.send(Operation::Set(key.to_string(), "value".to_owned()))?;
+        assert_eq!(response.status, ResponseStatus::InvalidRequest, "{}", key);
+        assert_eq!(client.get(key.to_string())?, None);
+    }
+    let response = client.send(Operation::Append("BAD".to_owned(), "text".to_owned()))?;
+    assert_eq!(response.status, ResponseStatus::InvalidRequest);
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &free_addr().to_string(), "--key-pattern", "[a-z"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+// The bench subcommand reports the ops it sent at a nonzero rate, and leaves no keys behind
+#[test]
+fn client_bench() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["bench", "--ops", "301", "--threads", "3", "--value-size", "20", "--addr", &server.addr.to_string()])
+        .output()?;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let report: HashMap<&str, &str> = stdout.lines().filter_map(|line| line.split_once(' ')).collect();
+    assert_eq!(report["ops"], "301");
+    assert!(report["ops_per_sec"].parse::<f64>()? > 0.0, "{}", stdout);
+    assert!(report.contains_key("latency_p99_us"), "{}", stdout);
+
+    let mut pairs = 0;
+    server.client().scan(|_, _| pairs += 1)?;
+    assert_eq!(pairs, 0);
+
+    Ok(())
+}
+
+// A bench whose sets are refused reports why, rather than the cleanup failing to remove keys which were never set
+#[test]
+fn client_bench_reports_failed_sets() -> Result<()> {
+    let server = TestServer::start_with_args("kvs", "queued", &["--key-pattern", "[a-z]+"]);
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["bench", "--ops", "20", "--threads", "2", "--addr", &server.addr.to_string()])
+        .output()?;
+    assert!(!output.status.success());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is not allowed"), "{}", stderr);
+    assert!(!stderr.contains("Key not found"), "{}", stderr);
+
+    Ok(())
+}
+
+// Jittered delays spread out, unjittered ones don't, and both double up to the cap
+#[test]
+fn backoff_delays_vary() {
+    let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+
+    let delays: HashSet<Duration> = (0..100).map(|_| backoff.jitter(1.0).delay(3)).collect();
+    assert!(delays.len() > 50, "only {} distinct delays", delays.len());
+    assert!(delays.iter().all(|&delay| delay <= Duration::from_millis(800)));
+
+    let delays: Vec<Duration> = (0..100).map(|_| backoff.jitter(0.5).delay(3)).collect();
+    assert!(delays.iter().all(|&delay| delay >= Duration::from_millis(400) && delay <= Duration::from_millis(800)));
+    let mean = delays.iter().sum::<Duration>() / delays.len() as u32;
+    assert!(mean > Duration::from_millis(500) && mean < Duration::from_millis(700), "mean delay {:?}", mean);
+
+    let backoff = backoff.jitter(0.0);
+    assert_eq!(backoff.delay(0), Duration::from_millis(100));
+    assert_eq!(backoff.delay(3), Duration::from_millis(800));
+    assert_eq!(backoff.delay(4), Duration::from_secs(1));
+    assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+}
+
+// A client with connect retries reaches a server which starts listening after its first attempt
+#[test]
+fn connect_retries_reach_late_server() -> Result<()> {
+    let addr = free_addr();
+    let server = thread::spawn(move || -> Result<()> {
+        thread::sleep(Duration::from_millis(300));
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        Operation::read_from_stream(logger(), stream.try_clone()?)?;
+        let response = Response { status: ResponseStatus::Ok, data: Some("late".to_owned()) };
+        response.write_to_stream(logger(), stream)
+    });
+
+    let backoff = Backoff::new(Duration::from_millis(50), Duration::from_millis(200));
+    assert!(KvsClient::new(logger(), addr).version().is_err());
+    let client = KvsClient::new(logger(), addr).connect_retries(20).backoff(backoff);
+    assert_eq!(client.version()?, "late");
+    server.join().unwrap()?;
+
+    Ok(())
+}
+
+// Touch goes over the network to engines which expire keys, and fails on those which don't
+#[test]
+fn stats_report_log_size() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    let client = server.client();
+    // The server's startup self-test writes too
+    let before = client.stats()?;
+    for i in 0..20 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+
+    let stats = client.stats()?;
+    let log_bytes = std::fs::metadata(server._temp_dir.path().join("log.log"))?.len();
+    assert_eq!(stats.log_bytes, log_bytes);
+    assert_eq!(stats.engine.writes - before.engine.writes, 20);
+    assert_eq!(stats.engine.removes - before.engine.removes, 1);
+    assert!(stats.disk_free > 0);
+
+    let server = TestServer::start("sled", "queued");
+    let client = server.client();
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.stats()?.log_bytes > 0);
+
+    Ok(())
+}
+
+// TTLs reach the client, and a key which has expired is missing rather than reported with no time left
+#[test]
+fn get_with_ttl_over_network() -> Result<()> {
+    let server = TestServer::start("sled", "queued");
+    let client = server.client();
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.set("key3".to_owned(), "value with spaces".to_owned())?;
+    assert!(client.touch("key1".to_owned(), Duration::from_millis(200))?);
+    assert!(client.touch("key3".to_owned(), Duration::from_secs(600))?);
+
+    let (value, ttl) = client.get_with_ttl("key3".to_owned())?.expect("key3 should hold a value");
+    assert_eq!(value, "value with spaces");
+    assert!(ttl.expect("key3 should expire") > Duration::from_secs(590));
+    assert_eq!(client.get_with_ttl("key2".to_owned())?, Some(("value2".to_owned(), None)));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.get_with_ttl("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// A batch remove reports each key's existence over the wire, keys in a bucket included
+#[test]
+fn remove_batch_over_network() -> Result<()> {
+    let server = TestServer::start("kvs", "queued");
+    let client = server.client();
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+
+    let keys = vec!["key1".to_owned(), "key2".to_owned(), "key3".to_owned()];
+    assert_eq!(client.remove_batch(keys)?, vec![true, false, true]);
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    let bucketed = server.client().bucket("b1".to_owned());
+    bucketed.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(bucketed.remove_batch(vec!["key1".to_owned()])?, vec![true]);
+    Ok(())
+}
+
+// The server reports whether each set created its key, for either engine
+#[test]
+fn set_reporting_over_network() -> Result<()> {
+    for engine in &["kvs", "sled"] {
+        let server = TestServer::start(engine, "queued");
+        let client = server.client();
+        assert_eq!(client.set_reporting("key1".to_owned(), "value1".to_owned())?, SetOutcome::Created);
+        assert_eq!(client.set_reporting("key1".to_owned(), "value2".to_owned())?, SetOutcome::Updated);
+
+        // Plain set still works against the richer response
+        client.set("key2".to_owned(), "value1".to_owned())?;
+        assert_eq!(
+            client.set_reporting("key2".to_owned(), "value2".to_owned())?,
+            SetOutcome::Updated
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn touch_over_network() -> Result<()> {
+    let server = TestServer::start("sled", "queued");
+    let client = server.client();
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.touch("key1".to_owned(), Duration::from_millis(200))?);
+    assert!(!client.touch("key2".to_owned(), Duration::from_millis(200))?);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    let server = TestServer::start("kvs", "queued");
+    let client = server.client();
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.touch("key1".to_owned(), Duration::from_secs(1)).is_err());
+
+    Ok(())
+}
+
+// Drain keeping the message of every record logged through it
+#[derive(Clone, Default)]
+struct CapturingDrain {
+    messages: Arc<Mutex<Vec<String>>>,
+}
+
+impl Drain for CapturingDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, _: &OwnedKVList) -> std::result::Result<(), Never> {
+        self.messages.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
+// A server run from library code logs through the logger it's given rather than to the terminal
+#[test]
+fn run_server_logs_to_injected_drain() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let drain = CapturingDrain::default();
+    let config = ServerConfig {
+        addr: Some(addr.to_string()),
+        data_dir: Some(temp_dir.path().to_path_buf()),
+        ..ServerConfig::default()
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let log = Logger::root(drain.clone(), o!());
+    thread::spawn(move || kvs::run_server(&config, log, store, pool, ServerHandle::new()));
+    wait_for_server(addr);
+
+    let client = KvsClient::new(logger(), addr);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let messages = drain.messages.lock().unwrap().clone();
+    for expected in &["Self-test passed", "Waiting for connections...", "Store SET successful", "Store GET successful"] {
+        assert!(messages.iter().any(|message| message == expected), "{:?} missing from {:?}", expected, messages);
+    }
+
+    Ok(())
+}
+
+// A server run from library code returns once its handle is told to shut down, and stops listening
+#[test]
+fn run_server_stops_on_handle_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_addr();
+    let config = ServerConfig {
+        addr: Some(addr.to_string()),
+        ..ServerConfig::default()
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let handle = ServerHandle::new();
+    let server_handle = handle.clone();
+    let (done_sender, done) = mpsc::channel();
+    thread::spawn(move || {
+        let result = kvs::run_server(&config, logger(), store, pool, server_handle);
+        done_sender.send(result.is_ok()).unwrap();
+    });
+    wait_for_server(addr);
+
+    let client = KvsClient::new(logger(), addr);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    handle.shutdown();
+    assert_eq!(done.recv_timeout(Duration::from_secs(5)), Ok(true));
+    assert!(TcpStream::connect(addr).is_err());
+
+    Ok(())
+}
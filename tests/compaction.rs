@@ -0,0 +1,54 @@
+use kvs::{KvStore, KvsEngine, Result};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::TempDir;
+
+// Tracks the bytes allocated right now and the most allocated at once, so a test can check how much memory an
+// operation needed. This is the only test in its binary, nothing else allocates alongside it
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Compacting 20MB of live values should only ever hold a record or two of them in memory
+#[test]
+fn compaction_memory_is_bounded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = |i: usize, round: usize| format!("{}{}", i % 10, round).repeat(50_000);
+
+    for round in 0..2 {
+        for i in 0..200 {
+            store.set(format!("key{}", i), value(i, round))?;
+        }
+    }
+
+    let baseline = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(baseline, Ordering::SeqCst);
+    let reclaimed = store.compact()?;
+    let used = PEAK.load(Ordering::SeqCst) - baseline;
+
+    assert!(reclaimed >= 20_000_000);
+    assert!(used < 2_000_000, "compaction used {} bytes", used);
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i, 1)));
+    }
+
+    Ok(())
+}
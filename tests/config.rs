@@ -0,0 +1,50 @@
+use kvs::{Result, ServerConfig};
+use std::fs;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+#[test]
+fn parse_config_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &path,
+        r#"
+addr = "127.0.0.1:4010"
+engine = "sled"
+tp = "rayon"
+threads = 2
+data-dir = "/var/lib/kvs"
+sled-cache-mb = 64
+max-connections = 16
+nodelay = false
+"#,
+    )?;
+
+    let config = ServerConfig::load(&path)?;
+    assert_eq!(config.addr, Some("127.0.0.1:4010".to_owned()));
+    assert_eq!(config.engine, Some("sled".to_owned()));
+    assert_eq!(config.tp, Some("rayon".to_owned()));
+    assert_eq!(config.threads, Some(2));
+    assert_eq!(config.data_dir, Some(PathBuf::from("/var/lib/kvs")));
+    assert_eq!(config.sled_cache_mb, Some(64));
+    assert_eq!(config.max_connections, Some(16));
+    assert_eq!(config.nodelay, Some(false));
+    assert_eq!(config.slow_op_ms, None);
+
+    Ok(())
+}
+
+// Settings which are left out stay unset, so the server's defaults apply
+#[test]
+fn empty_config() -> Result<()> {
+    assert_eq!(ServerConfig::from_toml("")?, ServerConfig::default());
+    Ok(())
+}
+
+// A misspelled setting should be reported rather than silently ignored
+#[test]
+fn unknown_setting() {
+    assert!(ServerConfig::from_toml("adress = \"127.0.0.1:4000\"").is_err());
+    assert!(ServerConfig::from_toml("threads = \"many\"").is_err());
+}
@@ -1,9 +1,218 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    sync_strategy, BucketedEngine, Command, EngineStats, IndexHasher, KeyState, KvStore, KvsEngine, KvsError, LogEvent,
+    Pair, Result, SetOutcome, SledKvsEngine, SyncStrategy, LOG_FORMAT_VERSION,
+};
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+// Builds a log file by hand from JSON commands: the header, then each command framed by its length.
+// Returns the file along with the offset of each record
+fn framed_log(commands: &[&str]) -> (Vec<u8>, Vec<usize>) {
+    let mut log = b"KVS\0".to_vec();
+    log.extend_from_slice(&LOG_FORMAT_VERSION.to_le_bytes());
+    let mut offsets = Vec::new();
+    for command in commands {
+        offsets.push(log.len());
+        log.extend_from_slice(&(command.len() as u32).to_le_bytes());
+        log.extend_from_slice(command.as_bytes());
+    }
+    (log, offsets)
+}
+
+// Commands built outside the crate serialize to the log's JSON, and read back the same
+#[test]
+fn build_command() -> Result<()> {
+    let command = Command::Set(Pair::new("key1".to_owned(), "value1".to_owned()));
+    let json = serde_json::to_string(&command)?;
+    assert_eq!(json, r#"{"Set":{"k":"key1","v":"value1"}}"#);
+
+    match serde_json::from_str(&json)? {
+        Command::Set(pair) => {
+            assert_eq!(pair.key(), "key1");
+            assert_eq!(pair.value(), "value1");
+        }
+        other => panic!("read back {:?}", other),
+    }
+
+    // A record built with the command's JSON opens as a store holding the pair
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (log, _) = framed_log(&[&json]);
+    fs::write(temp_dir.path().join("log.log"), log)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Hands back at most one byte per read, and is interrupted before every other one, like a slow socket
+struct TrickleReader {
+    bytes: Vec<u8>,
+    position: usize,
+    interrupt: bool,
+}
+
+impl Read for TrickleReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.interrupt = !self.interrupt;
+        if self.interrupt {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+        }
+        if buf.is_empty() || self.position == self.bytes.len() {
+            return Ok(0);
+        }
+        buf[0] = self.bytes[self.position];
+        self.position += 1;
+        Ok(1)
+    }
+}
+
+// A frame arriving a byte at a time still decodes, and the next one picks up where it ended
+#[test]
+fn read_framed_one_byte_at_a_time() -> Result<()> {
+    let first = Command::Set(Pair::new("key1".to_owned(), "value1".to_owned()));
+    let second = Command::Remove("key1".to_owned());
+    let (log, offsets) = framed_log(&[&serde_json::to_string(&first)?, &serde_json::to_string(&second)?]);
+
+    let mut reader = TrickleReader {
+        bytes: log[offsets[0]..].to_vec(),
+        position: 0,
+        interrupt: false,
+    };
+    assert_eq!(Command::read_framed(&mut reader)?, first);
+    assert_eq!(reader.position, offsets[1] - offsets[0]);
+    assert_eq!(Command::read_framed(&mut reader)?, second);
+
+    // A frame cut short is an error rather than a partial command
+    let mut reader = TrickleReader {
+        bytes: log[offsets[1]..log.len() - 1].to_vec(),
+        position: 0,
+        interrupt: false,
+    };
+    assert!(Command::read_framed(&mut reader).is_err());
+
+    Ok(())
+}
+
+// Commands come back in the order they were written, overwritten sets and removes included
+#[test]
+fn iter_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let commands = store.iter_commands()?.collect::<Result<Vec<Command>>>()?;
+    assert_eq!(
+        commands,
+        vec![
+            Command::Set(Pair::new("key1".to_owned(), "value1".to_owned())),
+            Command::Set(Pair::new("key2".to_owned(), "value2".to_owned())),
+            Command::Set(Pair::new("key1".to_owned(), "value3".to_owned())),
+            Command::Remove("key2".to_owned()),
+        ]
+    );
+
+    // Writes after the iterator is made aren't seen by it
+    let commands = store.iter_commands()?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    assert_eq!(commands.count(), 4);
+
+    Ok(())
+}
+
+// A follower receives the commands written after it subscribed, in order, and is told when the log is compacted
+#[test]
+fn follow_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("before".to_owned(), "value0".to_owned())?;
+
+    let follower = store.follow();
+    let writer = store.clone();
+    thread::spawn(move || -> Result<()> {
+        writer.set("key1".to_owned(), "value1".to_owned())?;
+        writer.set("key1".to_owned(), "value2".to_owned())?;
+        writer.remove("key1".to_owned())?;
+        writer.compact()?;
+        writer.set("key2".to_owned(), "value3".to_owned())
+    })
+    .join()
+    .unwrap()?;
+
+    let events: Vec<LogEvent> = follower.try_iter().collect();
+    assert_eq!(
+        events,
+        vec![
+            LogEvent::Command(Command::Set(Pair::new("key1".to_owned(), "value1".to_owned()))),
+            LogEvent::Command(Command::Set(Pair::new("key1".to_owned(), "value2".to_owned()))),
+            LogEvent::Command(Command::Remove("key1".to_owned())),
+            LogEvent::Compacted,
+            LogEvent::Command(Command::Set(Pair::new("key2".to_owned(), "value3".to_owned()))),
+        ]
+    );
+
+    // Writes carry on once a follower goes away
+    drop(follower);
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// A human-readable log holds the same commands as a compact one and rebuilds the same index from scratch
+#[test]
+fn human_log_round_trip() -> Result<()> {
+    let write = |store: &KvStore| -> Result<()> {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "multi\nline \"value\"".to_owned())?;
+        store.set_bytes("key3".to_owned(), vec![0, 159, 146, 150])?;
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        Ok(())
+    };
+
+    let compact_dir = TempDir::new().expect("unable to create temporary working directory");
+    let human_dir = TempDir::new().expect("unable to create temporary working directory");
+    write(&KvStore::open(compact_dir.path())?)?;
+    write(&KvStore::open(human_dir.path())?.with_human_log(true))?;
+
+    let human_log = fs::read(human_dir.path().join("log.log"))?;
+    let human_log = String::from_utf8_lossy(&human_log);
+    assert!(human_log.contains("\n  \"Set\": {\n    \"k\": \"key1\""));
+    assert!(fs::metadata(human_dir.path().join("log.log"))?.len() > fs::metadata(compact_dir.path().join("log.log"))?.len());
+
+    // Without the hint the index is rebuilt by reading every record
+    for dir in &[&compact_dir, &human_dir] {
+        fs::remove_file(dir.path().join("log.hint"))?;
+    }
+    let compact = KvStore::open(compact_dir.path())?;
+    let human = KvStore::open(human_dir.path())?;
+
+    assert_eq!(
+        compact.iter_commands()?.collect::<Result<Vec<Command>>>()?,
+        human.iter_commands()?.collect::<Result<Vec<Command>>>()?
+    );
+    for key in &["key1", "key2", "key3"] {
+        assert_eq!(compact.get_bytes(key.to_string())?, human.get_bytes(key.to_string())?);
+    }
+    assert_eq!(human.get_state("key2".to_owned())?, KeyState::Deleted);
+    assert_eq!(human.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(KvStore::verify(human_dir.path())?.is_ok());
+
+    Ok(())
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
@@ -81,10 +290,69 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should tell a removed key apart from one which was never set
+#[test]
+fn get_state_of_removed_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    assert_eq!(store.get_state("key1".to_owned())?, KeyState::Present("value1".to_owned()));
+    assert_eq!(store.get_state("key2".to_owned())?, KeyState::Deleted);
+    assert_eq!(store.get_state("key3".to_owned())?, KeyState::Absent);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_state("key2".to_owned())?, KeyState::Deleted);
+
+    // Setting a removed key makes it present again
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get_state("key2".to_owned())?, KeyState::Present("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should store and retrieve an empty value as an empty string
+#[test]
+fn empty_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("".to_owned()));
+
+    Ok(())
+}
+
+// Should reject an empty key with a typed error
+#[test]
+fn empty_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let err = store.set("".to_owned(), "value1".to_owned()).unwrap_err();
+    match err.downcast_ref::<KvsError>() {
+        Some(KvsError::EmptyKey) => {}
+        _ => panic!("Expected KvsError::EmptyKey, got {}", err),
+    }
+    assert!(store.get("".to_owned()).is_err());
+    assert!(store.remove("".to_owned()).is_err());
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
-#[ignore]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
@@ -128,6 +396,234 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Sets which would grow the log past its limit fail with StoreFull, until removing keys lets compaction make room
+#[test]
+fn max_log_bytes() -> Result<()> {
+    const MAX: u64 = 4096;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_max_log_bytes(MAX);
+    let log_len = || fs::metadata(temp_dir.path().join("log.log")).unwrap().len();
+    let value = "v".repeat(100);
+
+    // Overwriting one key never fills the log, each write compacts away the last
+    for _ in 0..100 {
+        store.set("key".to_owned(), value.clone())?;
+    }
+    assert!(log_len() <= MAX);
+
+    let mut written = 0;
+    let err = loop {
+        match store.set(format!("key{}", written), value.clone()) {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+        assert!(written < 100, "the log never filled");
+    };
+    match err.downcast_ref::<KvsError>() {
+        Some(KvsError::StoreFull(max)) => assert_eq!(*max, MAX),
+        _ => panic!("expected StoreFull, got {}", err),
+    }
+    assert!(log_len() <= MAX);
+
+    // Nothing failed part way, every key written before the error is still there
+    for key_id in 0..written {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    // Removes are written past the limit, then compaction reclaims what they free
+    for key_id in 0..written / 2 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set("another".to_owned(), value.clone())?;
+    assert_eq!(store.get("another".to_owned())?, Some(value));
+    assert!(log_len() <= MAX);
+
+    Ok(())
+}
+
+// Values reaching the blob threshold are kept out of the log, and compaction keeps the blobs of live keys only
+#[test]
+fn blob_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blobs = || fs::read_dir(temp_dir.path().join("blobs")).unwrap().count();
+    let log_len = || fs::metadata(temp_dir.path().join("log.log")).unwrap().len();
+    let small = "s".repeat(100);
+    let large = "l".repeat(8192);
+    let binary: Vec<u8> = (0..8192).map(|i| (i % 256) as u8).collect();
+
+    let store = KvStore::open(temp_dir.path())?.with_blob_threshold(1024);
+    store.set("small".to_owned(), small.clone())?;
+    store.set("large".to_owned(), large.clone())?;
+    store.set_bytes("binary".to_owned(), binary.clone())?;
+    assert_eq!(store.get("small".to_owned())?, Some(small.clone()));
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get_bytes("binary".to_owned())?, Some(binary.clone()));
+    assert_eq!(blobs(), 2);
+    assert!(log_len() < 1024, "log is {} bytes", log_len());
+
+    // Overwritten blobs stay until the log no longer refers to them
+    for i in 0..5 {
+        store.set("large".to_owned(), format!("{}{}", large, i))?;
+    }
+    store.remove("binary".to_owned())?;
+    assert_eq!(blobs(), 7);
+    store.compact()?;
+    assert_eq!(blobs(), 1);
+    assert_eq!(store.get("small".to_owned())?, Some(small.clone()));
+    assert_eq!(store.get("large".to_owned())?, Some(format!("{}4", large)));
+    assert_eq!(store.get("binary".to_owned())?, None);
+    drop(store);
+
+    // Blobs are read back after reopening whatever the threshold, and new blobs don't reuse old names
+    let store = KvStore::open(temp_dir.path())?.with_blob_threshold(1024);
+    assert_eq!(store.get("large".to_owned())?, Some(format!("{}4", large)));
+    store.set("other".to_owned(), large.clone())?;
+    assert_eq!(blobs(), 2);
+    assert_eq!(store.get("large".to_owned())?, Some(format!("{}4", large)));
+    assert_eq!(store.get("other".to_owned())?, Some(large));
+    assert!(KvStore::verify(temp_dir.path())?.is_ok());
+
+    Ok(())
+}
+
+// Blobs written while the log is at its limit survive the compaction making room for their record, and a set
+// refused as StoreFull leaves no blob behind
+#[test]
+fn blob_threshold_with_max_log_bytes() -> Result<()> {
+    const MAX: u64 = 2048;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blobs = || fs::read_dir(temp_dir.path().join("blobs")).unwrap().count();
+    let store = KvStore::open(temp_dir.path())?
+        .with_blob_threshold(1024)
+        .with_max_log_bytes(MAX);
+    let large = |i: usize| format!("{}", i % 10).repeat(4096);
+
+    // Overwriting keys fills the log with stale records, so later sets compact before their blob is logged
+    for round in 0..5 {
+        for i in 0..10 {
+            store.set(format!("key{}", i), large(i + round))?;
+        }
+    }
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(large(i + 4)));
+    }
+
+    let mut written = 10;
+    let err = loop {
+        match store.set(format!("key{}", written), large(written)) {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+        assert!(written < 1000, "the log never filled");
+    };
+    assert!(matches!(err.downcast_ref::<KvsError>(), Some(KvsError::StoreFull(_))));
+    store.compact()?;
+    assert_eq!(blobs(), written);
+    assert!(store.set("another".to_owned(), large(0)).is_err());
+    assert_eq!(blobs(), written);
+    for i in 0..written {
+        assert!(store.get(format!("key{}", i))?.is_some());
+    }
+
+    Ok(())
+}
+
+// With compaction off the log keeps every superseded record, through writes and reopening
+#[test]
+fn no_compaction_keeps_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().compaction(false).open(temp_dir.path())?;
+    for iter in 0..2000 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(store.iter_commands()?.count(), 2000);
+    drop(store);
+
+    let store = KvStore::builder().compaction(false).open(temp_dir.path())?;
+    let commands = store.iter_commands()?.collect::<Result<Vec<Command>>>()?;
+    assert_eq!(commands.len(), 2000);
+    assert_eq!(commands[0], Command::Set(Pair::new("key1".to_owned(), "0".to_owned())));
+    assert_eq!(store.get("key1".to_owned())?, Some("1999".to_owned()));
+
+    // Turned back on, the next write compacts away what's stale
+    let store = store.with_compaction(true);
+    store.set("key1".to_owned(), "last".to_owned())?;
+    assert!(store.iter_commands()?.count() < 2000);
+    assert_eq!(store.get("key1".to_owned())?, Some("last".to_owned()));
+
+    Ok(())
+}
+
+// With redundant sets skipped, setting a key to the value it holds leaves the log alone
+#[test]
+fn skip_redundant_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_skip_redundant_sets(true);
+    let log_len = || fs::metadata(temp_dir.path().join("log.log")).unwrap().len();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_bytes("key2".to_owned(), vec![0, 255])?;
+    let len = log_len();
+    for _ in 0..10 {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set_bytes("key2".to_owned(), vec![0, 255])?;
+    }
+    assert_eq!(store.set_reporting("key1".to_owned(), "value1".to_owned())?, SetOutcome::Updated);
+    assert_eq!(log_len(), len);
+    assert_eq!(store.stats().writes, 2);
+
+    // A different value is still written
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(log_len() > len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // Off by default, every set is appended
+    let store = store.with_skip_redundant_sets(false);
+    let len = log_len();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(log_len() > len);
+
+    Ok(())
+}
+
+// Each built-in strategy answers as documented, and the store consults its strategy once per append
+#[test]
+fn sync_strategies() -> Result<()> {
+    assert!(!sync_strategy::Never.should_sync());
+    assert!(sync_strategy::Always.should_sync());
+
+    let periodic = sync_strategy::Periodic::new(Duration::from_millis(100));
+    assert!(!periodic.should_sync());
+    thread::sleep(Duration::from_millis(150));
+    assert!(periodic.should_sync());
+    assert!(!periodic.should_sync());
+
+    #[derive(Debug, Default)]
+    struct Counting(AtomicUsize);
+    impl SyncStrategy for Counting {
+        fn should_sync(&self) -> bool {
+            self.0.fetch_add(1, Ordering::SeqCst).is_multiple_of(2)
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let counting = Arc::new(Counting::default());
+    let store = KvStore::builder().sync_strategy(counting.clone()).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(counting.0.load(Ordering::SeqCst), 3);
+
+    // Syncing after every append changes nothing about what's stored
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().sync_strategy(sync_strategy::Always).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(KvStore::open(temp_dir.path())?.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -210,3 +706,1425 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should round trip arbitrary bytes, including nulls and invalid UTF-8
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let bytes = vec![0u8, 159, 146, 150, 0, 10, 255, 32];
+    store.set_bytes("key1".to_owned(), bytes.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(bytes.clone()));
+    assert_eq!(store.get_bytes("key2".to_owned())?, Some(b"value2".to_vec()));
+    match store.get("key1".to_owned()) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::InvalidUtf8) => {}
+            _ => panic!("Expected KvsError::InvalidUtf8, got {}", err),
+        },
+        Ok(v) => panic!("Expected KvsError::InvalidUtf8, got {:?}", v),
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(bytes));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn sled_binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+
+    let bytes = vec![0u8, 159, 146, 150, 0, 10, 255, 32];
+    store.set_bytes("key1".to_owned(), bytes.clone())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(bytes));
+    assert!(store.get("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// A store already open can't be opened again at once, but a second opener can wait for the first to close it
+#[test]
+fn open_with_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    for result in [
+        KvStore::open(temp_dir.path()),
+        KvStore::open_with_timeout(temp_dir.path(), Duration::from_millis(100)),
+    ] {
+        match result.err().and_then(|err| err.downcast::<KvsError>().ok()) {
+            Some(KvsError::StoreLocked(path)) => assert_eq!(path, temp_dir.path()),
+            other => panic!("expected StoreLocked, got {:?}", other),
+        }
+    }
+
+    let closer = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        drop(store);
+    });
+    let store = KvStore::open_with_timeout(temp_dir.path(), Duration::from_secs(10))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    closer.join().unwrap();
+
+    Ok(())
+}
+
+// A sled store already opened elsewhere can't be opened again, the error should say where and why
+#[test]
+fn sled_open_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let _store = SledKvsEngine::open(temp_dir.path())?;
+
+    match SledKvsEngine::open(temp_dir.path()) {
+        Err(err) => {
+            match err.downcast_ref::<KvsError>() {
+                Some(KvsError::SledOpen(path, _)) => assert_eq!(path, temp_dir.path()),
+                _ => panic!("Expected KvsError::SledOpen, got {}", err),
+            }
+            assert!(err.to_string().contains("no other server"));
+        }
+        Ok(_) => panic!("Expected KvsError::SledOpen"),
+    }
+
+    Ok(())
+}
+
+// Sled opened with its tunables set should behave like the default one and survive a reopen
+#[test]
+fn sled_custom_config() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::builder()
+        .cache_capacity_mb(8)
+        .flush_every_ms(100)
+        .open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let store = SledKvsEngine::builder()
+        .cache_capacity_mb(8)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should create missing directories when opening
+#[test]
+fn open_nested_nonexistent_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("a").join("b").join("c");
+    let store = KvStore::open(&path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(&path)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should fail clearly when the path is a file
+#[test]
+fn open_on_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("file");
+    std::fs::write(&path, "not a directory")?;
+
+    match KvStore::open(&path) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::NotADirectory(p)) => assert_eq!(p, &path),
+            _ => panic!("Expected KvsError::NotADirectory, got {}", err),
+        },
+        Ok(_) => panic!("Expected KvsError::NotADirectory"),
+    }
+
+    Ok(())
+}
+
+// An index rebuilt by scanning a large log, with many overwrites, removes and compactions along the way,
+// agrees with a model of what was written, as does one loaded from the hint
+#[test]
+fn rebuilt_index_matches_model() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut model: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut rng = rand::thread_rng();
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20_000 {
+        let key = format!("key{}", rng.gen_range(0, 500));
+        match rng.gen_range(0, 10) {
+            0 => {
+                if model.remove(&key).is_some() {
+                    store.remove(key)?;
+                }
+            }
+            1 => {
+                let value = vec![(i % 256) as u8, 0, 255];
+                store.set_bytes(key.clone(), value.clone())?;
+                model.insert(key, value);
+            }
+            _ => {
+                let value = format!("value{}", i);
+                store.set(key.clone(), value.clone())?;
+                model.insert(key, value.into_bytes());
+            }
+        }
+    }
+    drop(store);
+
+    let check = |store: &KvStore| -> Result<()> {
+        let mut keys = store.keys()?;
+        keys.sort();
+        let mut expected: Vec<String> = model.keys().cloned().collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+        for i in 0..500 {
+            let key = format!("key{}", i);
+            assert_eq!(store.get_bytes(key.clone())?, model.get(&key).cloned(), "{}", key);
+        }
+        Ok(())
+    };
+
+    check(&KvStore::open(temp_dir.path())?)?;
+    fs::remove_file(temp_dir.path().join("log.hint"))?;
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// A record cut short at the end of the log, as a crash mid-append leaves it, is dropped on open rather than failing
+#[test]
+fn torn_final_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("log.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let whole = fs::metadata(&log_path)?.len();
+
+    // A length promising more than follows, and then just part of a length
+    for torn in &[&[40, 0, 0, 0, b'{', b'"'][..], &[7, 0][..]] {
+        fs::OpenOptions::new().append(true).open(&log_path)?.write_all(torn)?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(fs::metadata(&log_path)?.len(), whole);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    // Writes after the dropped record land where it was, and read back after a reopen
+    fs::OpenOptions::new().append(true).open(&log_path)?.write_all(&[9, 0, 0])?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    fs::remove_file(temp_dir.path().join("log.hint"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert!(KvStore::verify(temp_dir.path())?.is_ok());
+
+    Ok(())
+}
+
+// A clean shutdown leaves a hint file, reopening loads the index from it instead of scanning the log
+#[test]
+fn hint_used_when_valid() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    assert!(temp_dir.path().join("log.hint").exists());
+
+    // Garble the records without changing the log's length, a scan could not parse them but the hint still matches
+    let log_path = temp_dir.path().join("log.log");
+    let mut garbled = std::fs::read(&log_path)?;
+    for byte in garbled.iter_mut().skip(8) {
+        *byte = b'x';
+    }
+    std::fs::write(&log_path, garbled)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(store.get("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// An index entry pointing at the wrong record is caught on open when verifying, and only then
+#[test]
+fn verify_index_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::builder().verify_index(true).open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // Point key2 at key1's record in the hint, which still matches the log's length so it is trusted
+    let hint_path = temp_dir.path().join("log.hint");
+    let mut hint: serde_json::Value = serde_json::from_slice(&fs::read(&hint_path)?)?;
+    hint["index"]["key2"] = hint["index"]["key1"].clone();
+    fs::write(&hint_path, serde_json::to_vec(&hint)?)?;
+
+    let err = KvStore::builder()
+        .verify_index(true)
+        .open(temp_dir.path())
+        .err()
+        .expect("open should fail");
+    match err.downcast_ref::<KvsError>() {
+        Some(KvsError::IndexCorrupt(details)) => {
+            assert!(details.contains("'key2' points at byte"), "{}", details);
+            assert!(details.contains("which sets 'key1'"), "{}", details);
+        }
+        _ => panic!("expected IndexCorrupt, got {}", err),
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.get("key2".to_owned()).is_err());
+
+    Ok(())
+}
+
+// A hint older than the log is ignored and the log is scanned instead
+#[test]
+fn hint_ignored_when_stale() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut log = std::fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("log.log"))?;
+    let (record, _) = framed_log(&["{\"Set\":{\"k\":\"key2\",\"v\":\"value2\"}}"]);
+    log.write_all(&record[8..])?;
+    drop(log);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Records are framed by length, so control characters in keys and values can't break up the log
+#[test]
+fn control_characters_in_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let awkward = [
+        "line\none",
+        "carriage\r\nreturn",
+        "tab\there",
+        "nul\0byte",
+        "escape\u{1b}[0m",
+        "\"quoted\" \\ backslash",
+        "\n",
+    ];
+
+    let store = KvStore::open(temp_dir.path())?;
+    for (i, text) in awkward.iter().enumerate() {
+        store.set(text.to_string(), format!("{}{}", text, i))?;
+    }
+    drop(store);
+
+    // Once from the hint, then again scanning the log
+    for _ in 0..2 {
+        let store = KvStore::open(temp_dir.path())?;
+        for (i, text) in awkward.iter().enumerate() {
+            assert_eq!(store.get(text.to_string())?, Some(format!("{}{}", text, i)));
+        }
+        drop(store);
+        std::fs::remove_file(temp_dir.path().join("log.hint"))?;
+    }
+
+    assert!(KvStore::verify(temp_dir.path())?.is_ok());
+    Ok(())
+}
+
+// A log written before records were framed, one JSON command per line, is upgraded when opened
+#[test]
+fn upgrade_line_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = "{\"Set\":{\"k\":\"key1\",\"v\":\"value1\"}}\n\
+               {\"Set\":{\"k\":\"key2\",\"v\":\"value2\"}}\n\
+               {\"Remove\":\"key1\"}\n";
+    std::fs::write(temp_dir.path().join("log.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_state("key1".to_owned())?, KeyState::Deleted);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let upgraded = std::fs::read(temp_dir.path().join("log.log"))?;
+    assert_eq!(&upgraded[..4], b"KVS\0");
+    assert!(KvStore::verify(temp_dir.path())?.is_ok());
+
+    Ok(())
+}
+
+// A bad record should fail opening with an error naming which record it is and where, for both framed logs
+// and line-per-command logs from before framing
+#[test]
+fn bad_record_error_names_it() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (log, offsets) = framed_log(&[
+        "{\"Set\":{\"k\":\"key1\",\"v\":\"value1\"}}",
+        "{\"Set\":{\"k\":\"key2\",\"v\":\"value2\"}}",
+        "{\"Sit\":{\"k\":\"key3\",\"v\":\"value3\"}}",
+    ]);
+    fs::write(temp_dir.path().join("log.log"), log)?;
+
+    let message = KvStore::open(temp_dir.path()).err().expect("bad record accepted").to_string();
+    assert!(message.contains(&format!("Record 3 at byte {}", offsets[2])), "{}", message);
+    assert!(message.contains("log.log"), "{}", message);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = "{\"Set\":{\"k\":\"key1\",\"v\":\"value1\"}}\n\
+               {\"Set\":{\"k\":\"key2\",\"v\n";
+    fs::write(temp_dir.path().join("log.log"), log)?;
+
+    let message = KvStore::open(temp_dir.path()).err().expect("bad line accepted").to_string();
+    assert!(message.contains("Line 2 of"), "{}", message);
+
+    Ok(())
+}
+
+// Sets, removes and gets racing on one key should never leave the index pointing anywhere but its latest set
+#[test]
+fn interleaved_set_remove_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..300 {
+                    match (t + i) % 3 {
+                        0 => store.set("key".to_owned(), format!("value{}", i)).unwrap(),
+                        1 => match store.remove("key".to_owned()) {
+                            Ok(()) => {}
+                            Err(e) => match e.downcast_ref::<KvsError>() {
+                                Some(KvsError::KeyNotFound) => {}
+                                _ => panic!("Unexpected error removing: {}", e),
+                            },
+                        },
+                        _ => {
+                            store.get("key".to_owned()).unwrap();
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    drop(store);
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_ok(), "{:?}", report.problems);
+
+    Ok(())
+}
+
+// A hint pointing a key at the wrong record gives a corruption error naming the key, not a bare failure
+#[test]
+fn corrupt_index_reports_context() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (log, offsets) = framed_log(&[
+        "{\"Set\":{\"k\":\"key1\",\"v\":\"value1\"}}",
+        "{\"Remove\":\"key1\"}",
+    ]);
+    std::fs::write(temp_dir.path().join("log.log"), &log)?;
+    let hint = format!(
+        "{{\"log_len\":{},\"records\":2,\"index\":{{\"key1\":{}}},\"removed\":[]}}",
+        log.len(),
+        offsets[1]
+    );
+    std::fs::write(temp_dir.path().join("log.hint"), hint)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    match store.get("key1".to_owned()) {
+        Err(e) => match e.downcast_ref::<KvsError>() {
+            Some(KvsError::IndexCorrupt(reason)) => assert!(reason.contains("'key1'")),
+            _ => panic!("Expected KvsError::IndexCorrupt, got {}", e),
+        },
+        Ok(value) => panic!("Expected KvsError::IndexCorrupt, got {:?}", value),
+    }
+
+    Ok(())
+}
+
+fn concurrent_appends<E: KvsEngine>(store: E) -> Result<()> {
+    assert_eq!(store.append("key1".to_owned(), "ab".to_owned())?, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("ab".to_owned()));
+
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                (0..100)
+                    .map(|_| store.append("key2".to_owned(), "x".to_owned()).unwrap())
+                    .collect::<Vec<usize>>()
+            })
+        })
+        .collect();
+
+    // Every append should see every earlier one, so each new length is handed out exactly once
+    let mut lengths: Vec<usize> = threads
+        .into_iter()
+        .flat_map(|thread| thread.join().unwrap())
+        .collect();
+    lengths.sort();
+    assert_eq!(lengths, (1..=800).collect::<Vec<usize>>());
+    assert_eq!(store.get("key2".to_owned())?, Some("x".repeat(800)));
+
+    Ok(())
+}
+
+fn concurrent_hincr<E: KvsEngine>(store: E) -> Result<()> {
+    assert_eq!(store.hincr("page".to_owned(), "views".to_owned(), 5)?, 5);
+    assert_eq!(store.hincr("page".to_owned(), "views".to_owned(), -2)?, 3);
+    assert_eq!(store.get("page".to_owned())?, Some(r#"{"views":3}"#.to_owned()));
+
+    // Two fields of one key, each raced on by its own threads
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            let store = store.clone();
+            let field = if i % 2 == 0 { "views" } else { "clicks" };
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    store.hincr("page".to_owned(), field.to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(store.hincr("page".to_owned(), "views".to_owned(), 0)?, 403);
+    assert_eq!(store.hincr("page".to_owned(), "clicks".to_owned(), 0)?, 400);
+
+    store.set("plain".to_owned(), "not counters".to_owned())?;
+    match store.hincr("plain".to_owned(), "views".to_owned(), 1) {
+        Err(e) => assert!(matches!(e.downcast_ref::<KvsError>(), Some(KvsError::NotAHash(_)))),
+        Ok(n) => panic!("Expected KvsError::NotAHash, got {}", n),
+    }
+    assert!(store.hincr("page".to_owned(), "views".to_owned(), i64::MAX).is_err());
+
+    Ok(())
+}
+
+// Increments to a hash's fields are never lost, even racing on different fields of one key
+#[test]
+fn hincr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_hincr(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_hincr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_hincr(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Every shard should get close to its share of keys, whichever hasher routes them
+fn shards_evenly_loaded(hasher: IndexHasher) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().shards(8).hasher(hasher).open(temp_dir.path())?;
+    for i in 0..8000 {
+        store.set(format!("key{}", i), String::from("value"))?;
+    }
+
+    let lens = store.shard_lens();
+    assert_eq!(lens.len(), 8);
+    assert_eq!(lens.iter().sum::<usize>(), 8000);
+    for len in lens {
+        assert!(len > 800 && len < 1200, "shard holds {} of 8000 keys", len);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn shards_evenly_loaded_siphash() -> Result<()> {
+    shards_evenly_loaded(IndexHasher::SipHash)
+}
+
+#[cfg(feature = "ahash")]
+#[test]
+fn shards_evenly_loaded_ahash() -> Result<()> {
+    shards_evenly_loaded(IndexHasher::AHash)
+}
+
+// Appends create missing keys, and racing appends to one key are never lost
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_appends(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_appends(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn racing_set_if_absent<E: KvsEngine>(store: E) -> Result<()> {
+    let barrier = Arc::new(Barrier::new(16));
+    let threads: Vec<_> = (0..16)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                store.set_if_absent("lock".to_owned(), format!("owner{}", i)).unwrap()
+            })
+        })
+        .collect();
+
+    let winners: Vec<usize> = threads
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, thread)| if thread.join().unwrap() { Some(i) } else { None })
+        .collect();
+    assert_eq!(winners.len(), 1);
+    assert_eq!(store.get("lock".to_owned())?, Some(format!("owner{}", winners[0])));
+
+    // Once removed the key can be taken again
+    store.remove("lock".to_owned())?;
+    assert!(store.set_if_absent("lock".to_owned(), "next".to_owned())?);
+    assert!(!store.set_if_absent("lock".to_owned(), "other".to_owned())?);
+    assert_eq!(store.get("lock".to_owned())?, Some("next".to_owned()));
+
+    Ok(())
+}
+
+// Of many threads racing to set one absent key, exactly one should win
+#[test]
+fn set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    racing_set_if_absent(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    racing_set_if_absent(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn racing_compare_and_delete<E: KvsEngine>(store: E) -> Result<()> {
+    assert!(!store.compare_and_delete("key".to_owned(), "a".to_owned())?);
+    store.set("key".to_owned(), "b".to_owned())?;
+    assert!(!store.compare_and_delete("key".to_owned(), "a".to_owned())?);
+    assert!(store.compare_and_delete("key".to_owned(), "b".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    // One thread keeps replacing "a" while the other tries to delete it, a delete must never take a newer value
+    for _ in 0..20 {
+        store.set("key".to_owned(), "a".to_owned())?;
+        let barrier = Arc::new(Barrier::new(2));
+        let setter = {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..100 {
+                    store.set("key".to_owned(), format!("b{}", i)).unwrap();
+                }
+            })
+        };
+        let deleter = {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                (0..100)
+                    .filter(|_| store.compare_and_delete("key".to_owned(), "a".to_owned()).unwrap())
+                    .count()
+            })
+        };
+        setter.join().unwrap();
+        assert!(deleter.join().unwrap() <= 1);
+        assert_eq!(store.get("key".to_owned())?, Some("b99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Compare-and-delete only removes the expected value, even while another thread is changing it
+#[test]
+fn compare_and_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    racing_compare_and_delete(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_compare_and_delete() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    racing_compare_and_delete(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn keys_in_order<E: KvsEngine>(store: E) -> Result<()> {
+    for key in &["pear", "apple", "b", "a\u{e9}", "banana", "A", "ab"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.remove("b".to_owned())?;
+
+    let expected: Vec<String> = vec!["A", "ab", "apple", "a\u{e9}", "banana", "pear"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    assert_eq!(store.sorted_keys()?, expected);
+
+    let bucketed = BucketedEngine::new(store, "bucket".to_owned())?;
+    bucketed.set("z".to_owned(), "value".to_owned())?;
+    bucketed.set("m".to_owned(), "value".to_owned())?;
+    assert_eq!(bucketed.sorted_keys()?, vec!["m".to_owned(), "z".to_owned()]);
+
+    Ok(())
+}
+
+// Sorted keys come back in the same order whatever order they were written in
+#[test]
+fn sorted_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    keys_in_order(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_sorted_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    keys_in_order(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn ranges_match_model<E: KvsEngine>(store: E) -> Result<()> {
+    let mut model: BTreeMap<String, String> = BTreeMap::new();
+    let mut rng = rand::thread_rng();
+    for i in 0..2_000 {
+        let key = format!("key{}", rng.gen_range(0, 300));
+        if rng.gen_range(0, 4) == 0 {
+            if model.remove(&key).is_some() {
+                store.remove(key)?;
+            }
+        } else {
+            let value = format!("value{}", i);
+            model.insert(key.clone(), value.clone());
+            store.set(key, value)?;
+        }
+    }
+
+    for _ in 0..100 {
+        let from = format!("key{}", rng.gen_range(0, 300));
+        let to = format!("key{}", rng.gen_range(0, 300));
+        let expected: Vec<(String, String)> = model
+            .iter()
+            .filter(|(k, _)| from <= **k && **k < to)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        assert_eq!(store.range(from.clone(), to.clone())?, expected, "range {}..{}", from, to);
+    }
+
+    // Bounds needn't be keys in the store, and an empty range is empty however it's empty
+    assert_eq!(store.range("a".to_owned(), "key".to_owned())?, Vec::new());
+    assert_eq!(store.range("key9".to_owned(), "key1".to_owned())?, Vec::new());
+    assert_eq!(store.range("".to_owned(), "l".to_owned())?.len(), model.len());
+    Ok(())
+}
+
+// Ranges should match a sorted model of the same writes, with the index hashed or ordered
+#[test]
+fn range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ranges_match_model(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn range_ordered_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ranges_match_model(KvStore::open(temp_dir.path())?.with_ordered_index())
+}
+
+// Spilled keys are found too, even though only the in-memory entries are ordered
+#[test]
+fn range_ordered_index_limited() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ranges_match_model(KvStore::open_with_index_limit(temp_dir.path(), 4096)?.with_ordered_index())
+}
+
+#[test]
+fn sled_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ranges_match_model(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Keys already in the store when the index is made ordered stay findable, and a bucket's range stays in the bucket
+#[test]
+fn ordered_index_keeps_existing_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    let store = store.with_ordered_index();
+    store.set("c".to_owned(), "3".to_owned())?;
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(
+        store.range("a".to_owned(), "c".to_owned())?,
+        vec![("a".to_owned(), "1".to_owned()), ("b".to_owned(), "2".to_owned())]
+    );
+
+    let bucketed = BucketedEngine::new(store, "bucket".to_owned())?;
+    bucketed.set("a".to_owned(), "in bucket".to_owned())?;
+    assert_eq!(
+        bucketed.range("".to_owned(), "z".to_owned())?,
+        vec![("a".to_owned(), "in bucket".to_owned())]
+    );
+    Ok(())
+}
+
+fn filtered_values<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("key1".to_owned(), "red apple".to_owned())?;
+    store.set("key2".to_owned(), "green apple".to_owned())?;
+    store.set("key3".to_owned(), "red cherry".to_owned())?;
+    store.set("key4".to_owned(), "removed red".to_owned())?;
+    store.remove("key4".to_owned())?;
+    store.set_bytes("key5".to_owned(), vec![b'r', b'e', b'd', 0xff])?;
+
+    let sorted = |mut pairs: Vec<(String, String)>| {
+        pairs.sort();
+        pairs
+    };
+    assert_eq!(store.filter_values(|value| value.contains("banana"))?, vec![]);
+    assert_eq!(
+        sorted(store.filter_values(|value| value.contains("red"))?),
+        vec![
+            ("key1".to_owned(), "red apple".to_owned()),
+            ("key3".to_owned(), "red cherry".to_owned()),
+        ]
+    );
+
+    // Values which aren't UTF-8 aren't offered to the predicate, even one accepting everything
+    assert_eq!(
+        sorted(store.filter_values(|_| true)?),
+        vec![
+            ("key1".to_owned(), "red apple".to_owned()),
+            ("key2".to_owned(), "green apple".to_owned()),
+            ("key3".to_owned(), "red cherry".to_owned()),
+        ]
+    );
+
+    Ok(())
+}
+
+// Filtering values finds only the live pairs the predicate accepts
+#[test]
+fn filter_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    filtered_values(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_filter_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    filtered_values(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn counted_operations<E: KvsEngine>(store: E) -> Result<()> {
+    assert_eq!(store.stats(), EngineStats::default());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.append("key1".to_owned(), "more".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("missing".to_owned())?;
+    store.get_bytes("key2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert!(!store.set_if_absent("key1".to_owned(), "other".to_owned())?);
+
+    let stats = store.clone().stats();
+    assert_eq!(stats.reads, 3);
+    assert_eq!(stats.writes, 3);
+    assert_eq!(stats.removes, 1);
+    assert!(stats.bytes_written >= ("key1value1".len() * 2 + "key1value1more".len() + "key2".len()) as u64);
+
+    Ok(())
+}
+
+// Reads, writes and removes are counted once each, failed and skipped ones not at all
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    counted_operations(store.clone())?;
+
+    // Each record is framed JSON, so the log grows by exactly the bytes written
+    let log_len = fs::metadata(temp_dir.path().join("log.log"))?.len();
+    assert_eq!(store.stats().bytes_written, log_len - 8);
+
+    Ok(())
+}
+
+#[test]
+fn sled_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    counted_operations(store.clone())?;
+    assert_eq!(store.stats().bytes_written, 10 + 10 + 14 + 4);
+
+    Ok(())
+}
+
+// Compactions are counted whether writes set them off or they're asked for, along with the bytes they reclaim
+#[test]
+fn compaction_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().compactions, 0);
+
+    // Overwriting one key leaves a stale record behind each time, enough of them sets off a compaction
+    for i in 0..1_000 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let stats = store.stats();
+    assert!(stats.compactions >= 1);
+    assert!(stats.bytes_reclaimed > 0);
+
+    for i in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let reclaimed = store.compact()?;
+    let after = store.stats();
+    assert_eq!(after.compactions, stats.compactions + 1);
+    assert_eq!(after.bytes_reclaimed, stats.bytes_reclaimed + reclaimed);
+    assert!(after.compaction_ms >= stats.compaction_ms);
+
+    Ok(())
+}
+
+// Touching a sled key pushes its expiry back without changing its value or its place among recent writes
+#[test]
+fn sled_touch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_millis(300))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.touch("key1".to_owned(), Duration::from_secs(600))?);
+    assert!(store.touch("key2".to_owned(), Duration::from_millis(300))?);
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.recent_keys(10)?, vec!["key1".to_owned()]);
+
+    // Absent and expired keys aren't brought back
+    assert!(!store.touch("key2".to_owned(), Duration::from_secs(600))?);
+    assert!(!store.touch("key3".to_owned(), Duration::from_secs(600))?);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // The kvs engine doesn't expire keys
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = KvStore::open(kvs_dir.path())?;
+    kvs.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(kvs.touch("key1".to_owned(), Duration::from_secs(1)).is_err());
+
+    Ok(())
+}
+
+// A sled key reports how long it has left, a key without a TTL never expires and an expired key is missing
+#[test]
+fn sled_get_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_secs(600))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::from_millis(100))?;
+    thread::sleep(Duration::from_millis(200));
+
+    let (value, ttl) = store.get_with_ttl("key1".to_owned())?.expect("key1 should hold a value");
+    assert_eq!(value, "value1");
+    let ttl = ttl.expect("key1 should expire");
+    assert!(ttl <= Duration::from_secs(600) && ttl > Duration::from_secs(590));
+
+    assert_eq!(store.get_with_ttl("key2".to_owned())?, Some(("value2".to_owned(), None)));
+    assert_eq!(store.get_with_ttl("key3".to_owned())?, None);
+    assert_eq!(store.get_with_ttl("key4".to_owned())?, None);
+
+    // The kvs engine doesn't expire keys, so its values never do
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = KvStore::open(kvs_dir.path())?;
+    kvs.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(kvs.get_with_ttl("key1".to_owned())?, Some(("value1".to_owned(), None)));
+    assert_eq!(kvs.get_with_ttl("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Expired sled keys read as absent straight away, before any sweep has run
+#[test]
+fn sled_ttl_filtered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+
+    store.set_with_ttl("short".to_owned(), "value1".to_owned(), Duration::from_millis(200))?;
+    store.set_with_ttl("long".to_owned(), "value2".to_owned(), Duration::from_secs(600))?;
+    store.set("permanent".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("short".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("permanent".to_owned())?, Some("value3".to_owned()));
+
+    let mut keys = store.keys()?;
+    keys.sort();
+    assert_eq!(keys, vec!["long".to_owned(), "permanent".to_owned()]);
+
+    // An expired key is absent to every other operation too
+    store.set_with_ttl("gone".to_owned(), "value4".to_owned(), Duration::from_millis(0))?;
+    assert!(store.remove("gone".to_owned()).is_err());
+    store.set_with_ttl("gone".to_owned(), "value4".to_owned(), Duration::from_millis(0))?;
+    assert!(store.set_if_absent("gone".to_owned(), "value5".to_owned())?);
+    assert_eq!(store.get("gone".to_owned())?, Some("value5".to_owned()));
+
+    // Setting without a TTL makes the key permanent
+    store.set_with_ttl("kept".to_owned(), "value6".to_owned(), Duration::from_millis(100))?;
+    store.set("kept".to_owned(), "value7".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("kept".to_owned())?, Some("value7".to_owned()));
+
+    Ok(())
+}
+
+// The sweep removes expired keys from sled without them being read
+#[test]
+fn sled_ttl_swept() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set_with_ttl(format!("key{}", i), "value".to_owned(), Duration::from_millis(100))?;
+    }
+    store.set("permanent".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.sweep_expired()?, 10);
+    assert_eq!(store.sweep_expired()?, 0);
+    drop(store);
+
+    let store = SledKvsEngine::builder().ttl_sweep_ms(50).open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set_with_ttl(format!("key{}", i), "value".to_owned(), Duration::from_millis(100))?;
+    }
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.sweep_expired()?, 0);
+    assert_eq!(store.keys()?, vec!["permanent".to_owned()]);
+
+    Ok(())
+}
+
+// Values a sled store held before expiries were supported still read back once it's reopened
+#[test]
+fn sled_values_from_before_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let db = sled::Db::start_default(temp_dir.path()).unwrap();
+        db.set(b"key1", b"value1".to_vec()).unwrap();
+        db.set(b"key2", b"value2".to_vec()).unwrap();
+        db.flush().unwrap();
+    }
+
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // Moved across once, reopening leaves them as they are
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.keys()?.len(), 2);
+
+    Ok(())
+}
+
+// Keys come back most recent first, each once, leaving out removed keys
+fn recent_keys_in_order<E: KvsEngine>(store: E) -> Result<()> {
+    for key in &["a", "b", "c", "a", "d"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.remove("b".to_owned())?;
+
+    let strings = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<Vec<String>>();
+    assert_eq!(store.recent_keys(10)?, strings(&["d", "a", "c"]));
+    assert_eq!(store.recent_keys(2)?, strings(&["d", "a"]));
+    assert!(store.recent_keys(0)?.is_empty());
+
+    store.set("c".to_owned(), "value".to_owned())?;
+    assert_eq!(store.recent_keys(10)?, strings(&["c", "d", "a"]));
+    Ok(())
+}
+
+// With a lowercasing normalizer keys differing only in case are one key, stored lowercased
+#[test]
+fn key_normalizer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_key_normalizer(|key| key.to_lowercase());
+
+    store.set("key".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("KEY".to_owned())?, Some("value1".to_owned()));
+    store.set("Key".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.keys()?, vec!["key".to_owned()]);
+
+    store.remove("KEY".to_owned())?;
+    assert_eq!(store.get_state("kEy".to_owned())?, KeyState::Deleted);
+
+    drop(store);
+
+    // A key which normalizes to nothing is empty
+    let trimmed = KvStore::open(temp_dir.path())?.with_key_normalizer(|key| key.trim().to_owned());
+    trimmed.set(" padded ".to_owned(), "value3".to_owned())?;
+    assert_eq!(trimmed.get("padded".to_owned())?, Some("value3".to_owned()));
+    let err = trimmed.set("   ".to_owned(), "value4".to_owned()).unwrap_err();
+    match err.downcast_ref::<KvsError>() {
+        Some(KvsError::EmptyKey) => {}
+        _ => panic!("Expected KvsError::EmptyKey, got {}", err),
+    }
+
+    Ok(())
+}
+
+// A validator refuses every kind of write to keys it rejects, while keys already stored stay readable and removable
+#[test]
+fn key_validator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("legacy key".to_owned(), "value0".to_owned())?;
+    let store = store.with_key_validator(|key| key.starts_with("app:") && !key.contains(' '));
+
+    store.set("app:user1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.append("app:log".to_owned(), "line".to_owned())?, 4);
+    assert_eq!(store.get("app:user1".to_owned())?, Some("value1".to_owned()));
+
+    let assert_invalid = |result: Result<()>| match result {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::InvalidKey(key)) => assert!(!key.starts_with("app:") || key.contains(' ')),
+            _ => panic!("Expected KvsError::InvalidKey, got {}", err),
+        },
+        Ok(()) => panic!("Expected KvsError::InvalidKey"),
+    };
+    assert_invalid(store.set("user1".to_owned(), "value1".to_owned()));
+    assert_invalid(store.set("app:user 2".to_owned(), "value2".to_owned()));
+    assert_invalid(store.set_bytes("other".to_owned(), vec![1, 2]));
+    assert_invalid(store.append("other".to_owned(), "text".to_owned()).map(|_| ()));
+    assert_invalid(store.set_if_absent("other".to_owned(), "text".to_owned()).map(|_| ()));
+    assert_invalid(store.hincr("other".to_owned(), "field".to_owned(), 1).map(|_| ()));
+    assert_eq!(store.get("other".to_owned())?, None);
+
+    assert_eq!(store.get("legacy key".to_owned())?, Some("value0".to_owned()));
+    store.remove("legacy key".to_owned())?;
+
+    Ok(())
+}
+
+// The dumped index points each live key at its latest set, in log order
+#[test]
+fn dump_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let log_len = || fs::metadata(temp_dir.path().join("log.log")).unwrap().len() as usize;
+    assert!(store.dump_index()?.is_empty());
+
+    let first = log_len();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let second = log_len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.dump_index()?,
+        vec![("key1".to_owned(), first), ("key2".to_owned(), second)]
+    );
+
+    let third = log_len();
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.dump_index()?, vec![("key1".to_owned(), third)]);
+
+    // The offset is where iter_commands finds the key's latest set
+    let commands = store.iter_commands()?.collect::<Result<Vec<Command>>>()?;
+    assert_eq!(commands[2], Command::Set(Pair::new("key1".to_owned(), "value3".to_owned())));
+
+    // Compaction moves the record and the index with it
+    store.compact()?;
+    assert_eq!(store.dump_index()?, vec![("key1".to_owned(), first)]);
+
+    Ok(())
+}
+
+#[test]
+fn recent_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    recent_keys_in_order(store.clone())?;
+
+    // Compaction and reopening keep the order
+    store.compact()?;
+    assert_eq!(store.recent_keys(10)?, vec!["c".to_owned(), "d".to_owned(), "a".to_owned()]);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recent_keys(10)?, vec!["c".to_owned(), "d".to_owned(), "a".to_owned()]);
+
+    Ok(())
+}
+
+#[test]
+fn sled_recent_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    recent_keys_in_order(store.clone())?;
+    drop(store);
+
+    let store = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(store.recent_keys(10)?, vec!["c".to_owned(), "d".to_owned(), "a".to_owned()]);
+
+    Ok(())
+}
+
+// Writes queued from many threads all land, whether their tickets are waited on or dropped
+#[test]
+fn writer_thread() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().writer_thread(true).open(temp_dir.path())?;
+
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut tickets = Vec::new();
+                for i in 0..200 {
+                    let ticket = store.set_async(format!("key{}_{}", t, i), format!("value{}", i))?;
+                    if i % 2 == 0 {
+                        tickets.push(ticket);
+                    }
+                }
+                for ticket in tickets {
+                    ticket.wait()?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+
+    // Writes are carried out in order, so once a later one is done every earlier one is too
+    store.remove_async("key0_0".to_owned())?.wait()?;
+    assert!(store.remove_async("key0_0".to_owned())?.wait().is_err());
+    store.set("key0_1".to_owned(), "updated".to_owned())?;
+    for t in 0..8 {
+        for i in 1..200 {
+            assert!(store.get(format!("key{}_{}", t, i))?.is_some());
+        }
+    }
+
+    // Fire-and-forget writes still made before the store is dropped are in the log once it has been
+    let ticket = store.set_async("last".to_owned(), "value".to_owned())?;
+    drop(ticket);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0_0".to_owned())?, None);
+    assert_eq!(store.get("key0_1".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.get("key7_199".to_owned())?, Some("value199".to_owned()));
+    assert_eq!(store.get("last".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Number of index shards' spill files in a store's directory
+fn spill_files(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("index.spill"))
+        .count()
+}
+
+// With a small index limit most keys are spilled to disk, but every one should still be found
+#[test]
+fn index_limit_spills_to_disk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let expected = |i: usize| -> Option<String> {
+        match i {
+            i if i % 5 == 0 => None,
+            i if i % 3 == 0 => Some(format!("updated{}", i)),
+            i => Some(format!("value{}", i)),
+        }
+    };
+
+    let store = KvStore::open_with_index_limit(temp_dir.path(), 4096)?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..2000 {
+        if i % 5 == 0 {
+            store.remove(format!("key{}", i))?;
+        } else if i % 3 == 0 {
+            store.set(format!("key{}", i), format!("updated{}", i))?;
+        }
+    }
+    assert!(spill_files(temp_dir.path()) > 0);
+    assert_eq!(store.keys()?.len(), 1600);
+
+    for i in 0..2000 {
+        assert_eq!(store.get(format!("key{}", i))?, expected(i));
+    }
+    drop(store);
+    assert_eq!(spill_files(temp_dir.path()), 0);
+
+    // The index is rebuilt from the log, spilling again as it goes
+    let store = KvStore::open_with_index_limit(temp_dir.path(), 4096)?;
+    for i in (0..2000).rev() {
+        assert_eq!(store.get(format!("key{}", i))?, expected(i));
+    }
+
+    Ok(())
+}
+
+// Writes should be on disk once the checkpoint interval has passed, even if the store never shuts down cleanly
+#[test]
+fn checkpoint_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.with_checkpoint_interval(Duration::from_millis(50));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+
+    // Forgetting the store skips everything a clean shutdown would do. Its lock would go with the process, so the
+    // files as they are on disk are reopened from a copy
+    std::mem::forget(store);
+    let crashed_dir = TempDir::new().expect("unable to create temporary working directory");
+    for entry in fs::read_dir(temp_dir.path())? {
+        let entry = entry?;
+        fs::copy(entry.path(), crashed_dir.path().join(entry.file_name()))?;
+    }
+
+    let store = KvStore::open(crashed_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+fn set_reporting_outcomes<E: KvsEngine>(store: E) -> Result<()> {
+    assert_eq!(
+        store.set_reporting("key1".to_owned(), "value1".to_owned())?,
+        SetOutcome::Created
+    );
+    assert_eq!(
+        store.set_reporting("key1".to_owned(), "value2".to_owned())?,
+        SetOutcome::Updated
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // A removed key is created afresh
+    store.remove("key1".to_owned())?;
+    assert_eq!(
+        store.set_reporting("key1".to_owned(), "value3".to_owned())?,
+        SetOutcome::Created
+    );
+    Ok(())
+}
+
+// set_reporting should tell a first write from an overwrite
+#[test]
+fn set_reporting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    set_reporting_outcomes(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_set_reporting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    set_reporting_outcomes(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn remove_reporting_values<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.remove_reporting("key1".to_owned())?, b"value1".to_vec());
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Nothing to report once the key is gone
+    assert!(store.remove_reporting("key1".to_owned()).is_err());
+    Ok(())
+}
+
+fn remove_batch_existence<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // key1 named again is already gone by the time it's reached
+    let keys = vec!["key1", "key2", "key3", "key1"].into_iter().map(String::from).collect();
+    assert_eq!(store.remove_batch(keys)?, vec![true, false, true, false]);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.remove_batch(Vec::new())?, Vec::<bool>::new());
+    Ok(())
+}
+
+// remove_batch should report which keys held a value and remove only those
+#[test]
+fn remove_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_batch_existence(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_remove_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_batch_existence(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A batch of removes is in the log like any other, so it survives reopening
+#[test]
+fn remove_batch_persists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove_batch(vec!["key1".to_owned(), "key3".to_owned()])?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// remove_reporting should hand back the value it removed
+#[test]
+fn remove_reporting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_reporting_values(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_remove_reporting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    remove_reporting_values(SledKvsEngine::open(temp_dir.path())?)
+}
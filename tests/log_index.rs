@@ -0,0 +1,77 @@
+use kvs::{KvStore, KvsEngine};
+use proptest::prelude::*;
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(String, String),
+    Remove(String),
+}
+
+// Keys come from a small alphabet so sequences hit the same key repeatedly
+fn op_strategy() -> impl Strategy<Value = Op> {
+    let key = "[a-e]{1,2}";
+    prop_oneof![
+        (key, "[a-z0-9 ]{0,12}").prop_map(|(k, v)| Op::Set(k, v)),
+        key.prop_map(Op::Remove),
+    ]
+}
+
+// Apply ops to a store and a reference HashMap, then check every key agrees before and after reopening
+fn check_against_model(ops: Vec<Op>) -> Result<(), TestCaseError> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let mut model = HashMap::new();
+
+    for op in ops.iter().cloned() {
+        match op {
+            Op::Set(k, v) => {
+                store.set(k.clone(), v.clone()).unwrap();
+                model.insert(k, v);
+            }
+            Op::Remove(k) => {
+                let removed = store.remove(k.clone()).is_ok();
+                prop_assert_eq!(removed, model.remove(&k).is_some());
+            }
+        }
+    }
+
+    let keys: Vec<String> = ops
+        .iter()
+        .map(|op| match op {
+            Op::Set(k, _) | Op::Remove(k) => k.clone(),
+        })
+        .collect();
+
+    for k in &keys {
+        prop_assert_eq!(store.get(k.clone()).unwrap(), model.get(k).cloned());
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for k in &keys {
+        prop_assert_eq!(store.get(k.clone()).unwrap(), model.get(k).cloned());
+    }
+
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn log_index_round_trip(ops in prop::collection::vec(op_strategy(), 1..60)) {
+        check_against_model(ops)?;
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(4))]
+
+    // Long enough to leave more stale records than the compaction threshold
+    #[test]
+    fn log_index_round_trip_through_compaction(ops in prop::collection::vec(op_strategy(), 600..700)) {
+        check_against_model(ops)?;
+    }
+}
@@ -0,0 +1,329 @@
+use kvs::network::{self, Operation, Response, ResponseStatus, ScanItem, ServerStats, TcpMessage};
+use kvs::{EngineStats, KvsError, Result};
+use slog::{o, Discard, Logger};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+fn logger() -> Logger {
+    Logger::root(Discard, o!())
+}
+
+// Text written to the stream is terminated by a newline, parsing should ignore it
+fn round_trip_operation(op: Operation) -> Result<Operation> {
+    Operation::from_text(logger(), format!("{}\n", op.to_text()))
+}
+
+fn round_trip_response(response: Response) -> Result<Response> {
+    Response::from_text(logger(), format!("{}\n", response.to_text()))
+}
+
+#[test]
+fn set_empty_value_round_trip() -> Result<()> {
+    let op = Operation::Set("key1".to_owned(), "".to_owned());
+    assert_eq!(round_trip_operation(op.clone())?, op);
+    Ok(())
+}
+
+#[test]
+fn response_empty_data_round_trip() -> Result<()> {
+    let response = Response {
+        status: ResponseStatus::Ok,
+        data: Some("".to_owned()),
+    };
+    assert_eq!(round_trip_response(response)?.data, Some("".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn response_data_round_trip() -> Result<()> {
+    let response = Response {
+        status: ResponseStatus::Ok,
+        data: Some("value1".to_owned()),
+    };
+    assert_eq!(round_trip_response(response)?.data, Some("value1".to_owned()));
+
+    let response = Response {
+        status: ResponseStatus::Ok,
+        data: None,
+    };
+    assert_eq!(round_trip_response(response)?.data, None);
+    Ok(())
+}
+
+fn assert_protocol_error<T: std::fmt::Debug>(result: Result<T>) {
+    match result {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::Protocol(_)) => {}
+            _ => panic!("Expected KvsError::Protocol, got {}", err),
+        },
+        Ok(op) => panic!("Expected KvsError::Protocol, got {:?}", op),
+    }
+}
+
+#[test]
+fn malformed_operations_are_protocol_errors() {
+    assert_protocol_error(Operation::from_text(logger(), "set\n".to_owned()));
+    assert_protocol_error(Operation::from_text(logger(), "set k\n".to_owned()));
+    assert!(Operation::from_text(logger(), "".to_owned()).is_err());
+    assert!(Operation::from_text(logger(), "\n".to_owned()).is_err());
+}
+
+#[test]
+fn keys_with_nul_are_refused() {
+    for request in &["get app\0secret\n", "set app\0secret v\n", "rm app\0secret\n", "rmbatch k app\0secret\n"] {
+        match Operation::from_text(logger(), (*request).to_owned()) {
+            Err(err) => assert!(matches!(err.downcast_ref::<KvsError>(), Some(KvsError::InvalidKey(_))), "{}", err),
+            Ok(op) => panic!("Expected KvsError::InvalidKey, got {:?}", op),
+        }
+    }
+    // Only keys are refused, values can hold a NUL
+    assert_eq!(
+        Operation::from_text(logger(), "set k a\0b\n".to_owned()).unwrap(),
+        Operation::Set("k".to_owned(), "a\0b".to_owned())
+    );
+}
+
+#[test]
+fn malformed_responses_are_errors() {
+    assert!(Response::from_text(logger(), "".to_owned()).is_err());
+    assert!(Response::from_text(logger(), "\n".to_owned()).is_err());
+    assert!(Response::from_text(logger(), "BOGUS data\n".to_owned()).is_err());
+}
+
+#[test]
+fn wrong_argument_counts_are_protocol_errors() {
+    for request in &["set\n", "set k\n", "set k v extra\n", "get\n", "get k extra\n", "rm\n", "rm k extra\n"] {
+        assert_protocol_error(Operation::from_text(logger(), request.to_string()));
+    }
+}
+
+// The count of keys asked for by `recent` must be a number
+// Keys are escaped one by one, and a batch of none is still a batch
+#[test]
+fn remove_batch_round_trip() -> Result<()> {
+    let op = Operation::RemoveBatch(vec!["key1".to_owned(), "key with spaces".to_owned(), "".to_owned()]);
+    assert_eq!(round_trip_operation(op.clone())?, op);
+    let op = Operation::RemoveBatch(Vec::new());
+    assert_eq!(round_trip_operation(op.clone())?, op);
+    Ok(())
+}
+
+#[test]
+fn touch_round_trip() -> Result<()> {
+    let op = Operation::Touch("key with spaces".to_owned(), Duration::from_millis(1500));
+    assert_eq!(round_trip_operation(op.clone())?, op);
+    assert_protocol_error(Operation::from_text(logger(), "touch key soon\n".to_owned()));
+    assert_protocol_error(Operation::from_text(logger(), "touch key\n".to_owned()));
+    Ok(())
+}
+
+// A GetTtl response carries the value and the milliseconds left, or none for a value which never expires
+#[test]
+fn ttl_data_round_trip() -> Result<()> {
+    let op = Operation::GetTtl("key with spaces".to_owned());
+    assert_eq!(round_trip_operation(op.clone())?, op);
+
+    let data = network::join_ttl("value with spaces".to_owned(), Some(Duration::from_millis(1500)));
+    assert_eq!(network::split_ttl(&data)?, ("value with spaces".to_owned(), Some(Duration::from_millis(1500))));
+    let data = network::join_ttl("none".to_owned(), None);
+    assert_eq!(network::split_ttl(&data)?, ("none".to_owned(), None));
+
+    assert!(network::split_ttl("value").is_err());
+    assert!(network::split_ttl("value soon").is_err());
+    Ok(())
+}
+
+#[test]
+fn hincr_round_trip() -> Result<()> {
+    let op = Operation::HIncr("key one".to_owned(), "field one".to_owned(), -12);
+    assert_eq!(round_trip_operation(op.clone())?, op);
+    assert_protocol_error(Operation::from_text(logger(), "hincr key field lots\n".to_owned()));
+    assert_protocol_error(Operation::from_text(logger(), "hincr key field\n".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn stats_round_trip() -> Result<()> {
+    assert_eq!(round_trip_operation(Operation::Stats)?, Operation::Stats);
+    assert_protocol_error(Operation::from_text(logger(), "stats now\n".to_owned()));
+
+    let stats = ServerStats {
+        engine: EngineStats {
+            reads: 1,
+            writes: 2,
+            removes: 3,
+            bytes_written: 40,
+            compactions: 4,
+            bytes_reclaimed: 700,
+            compaction_ms: 8,
+        },
+        log_bytes: 500,
+        disk_free: 6000,
+    };
+    assert_eq!(ServerStats::from_text(&stats.to_text())?, stats);
+    let newer = format!("{} uptime_ms=7", stats.to_text());
+    assert_eq!(ServerStats::from_text(&newer)?, stats);
+    assert_protocol_error(ServerStats::from_text("reads=1 writes=2"));
+
+    // Servers from before compaction was counted leave those entries out
+    let older = "reads=1 writes=2 removes=3 bytes_written=40 log_bytes=500 disk_free=6000";
+    assert_eq!(ServerStats::from_text(older)?.engine.compactions, 0);
+    assert_protocol_error(ServerStats::from_text(&stats.to_text().replace("500", "lots")));
+    Ok(())
+}
+
+#[test]
+fn recent_round_trip() -> Result<()> {
+    let op = Operation::Recent(25);
+    assert_eq!(round_trip_operation(op.clone())?, op);
+    assert_protocol_error(Operation::from_text(logger(), "recent lots\n".to_owned()));
+    assert_protocol_error(Operation::from_text(logger(), "recent\n".to_owned()));
+    assert!(network::split_fields("")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn set_bytes_round_trip() -> Result<()> {
+    let op = Operation::SetBytes("key1".to_owned(), vec![0, 10, 32, 255]);
+    assert_eq!(round_trip_operation(op.clone())?, op);
+    assert_protocol_error(Operation::from_text(logger(), "setb key1 not*base64\n".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn response_data_with_spaces_round_trip() -> Result<()> {
+    let response = Response {
+        status: ResponseStatus::Ok,
+        data: Some("0.1.0 engine=kvs format=kvs-log-v1".to_owned()),
+    };
+    assert_eq!(
+        round_trip_response(response)?.data,
+        Some("0.1.0 engine=kvs format=kvs-log-v1".to_owned())
+    );
+    assert_eq!(round_trip_operation(Operation::Version)?, Operation::Version);
+    Ok(())
+}
+
+// Spaces, newlines and backslashes are escaped on the wire and must come back unchanged
+#[test]
+fn escaped_text_round_trip() -> Result<()> {
+    let awkward = [
+        "multi word value",
+        "line one\nline two",
+        "back\\slash",
+        "\\s is not a space",
+        " \\\n ",
+        "\\",
+    ];
+
+    for text in awkward.iter() {
+        let op = Operation::Set(text.to_string(), text.to_string());
+        assert_eq!(round_trip_operation(op.clone())?, op);
+
+        let op = Operation::Get(text.to_string());
+        assert_eq!(round_trip_operation(op.clone())?, op);
+
+        let op = Operation::Use(text.to_string());
+        assert_eq!(round_trip_operation(op.clone())?, op);
+
+        let op = Operation::Append(text.to_string(), text.to_string());
+        assert_eq!(round_trip_operation(op.clone())?, op);
+
+        let op = Operation::SetNx(text.to_string(), text.to_string());
+        assert_eq!(round_trip_operation(op.clone())?, op);
+
+        let op = Operation::Cad(text.to_string(), text.to_string());
+        assert_eq!(round_trip_operation(op.clone())?, op);
+
+        let op = Operation::Grep(text.to_string());
+        assert_eq!(round_trip_operation(op.clone())?, op);
+
+        let fields = vec![text.to_string(), String::from("plain"), text.to_string()];
+        let response = Response {
+            status: ResponseStatus::Ok,
+            data: Some(network::join_fields(&fields)),
+        };
+        let data = round_trip_response(response)?.data.unwrap();
+        assert_eq!(network::split_fields(&data)?, fields);
+
+        let response = Response {
+            status: ResponseStatus::Ok,
+            data: Some(text.to_string()),
+        };
+        assert_eq!(round_trip_response(response)?.data, Some(text.to_string()));
+    }
+
+    // Scan items carry two escaped fields, anything else is the response ending the scan
+    for text in awkward.iter() {
+        let item = ScanItem {
+            key: text.to_string(),
+            value: text.to_string(),
+        };
+        assert_eq!(ScanItem::from_text(&format!("{}\n", item.to_text()))?, Some(item));
+    }
+    assert_eq!(ScanItem::from_text("OK\n")?, None);
+    assert_eq!(round_trip_operation(Operation::Scan)?, Operation::Scan);
+
+    // Escaped text is a single line with no separators in it
+    let text = Operation::Set("a key".to_owned(), "a\nvalue".to_owned()).to_text();
+    assert_eq!(text, "set a\\skey a\\nvalue");
+    Ok(())
+}
+
+// A backslash must start one of the known escape sequences
+#[test]
+fn invalid_escape() {
+    assert_protocol_error(Operation::from_text(logger(), "get key\\x\n".to_owned()));
+    assert_protocol_error(Operation::from_text(logger(), "get key\\\n".to_owned()));
+}
+
+// Every status should survive the wire, with or without a description
+#[test]
+fn response_status_round_trip() -> Result<()> {
+    let statuses = [
+        ResponseStatus::Ok,
+        ResponseStatus::Fail,
+        ResponseStatus::Deleted,
+        ResponseStatus::KeyNotFound,
+        ResponseStatus::InvalidRequest,
+        ResponseStatus::Internal,
+        ResponseStatus::Busy,
+        ResponseStatus::Draining,
+        ResponseStatus::RateLimited,
+    ];
+
+    for status in statuses.iter() {
+        let response = Response {
+            status: *status,
+            data: None,
+        };
+        assert_eq!(round_trip_response(response)?.status, *status);
+
+        let response = Response {
+            status: *status,
+            data: Some("Key not found".to_owned()),
+        };
+        let parsed = round_trip_response(response)?;
+        assert_eq!(parsed.status, *status);
+        assert_eq!(parsed.data, Some("Key not found".to_owned()));
+    }
+    Ok(())
+}
+
+// A client which connects and closes without sending anything is told apart from a malformed request
+#[test]
+fn read_from_closed_connection() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    drop(TcpStream::connect(listener.local_addr()?)?);
+    let (stream, _) = listener.accept()?;
+
+    match Operation::read_from_stream(logger(), stream) {
+        Err(err) => match err.downcast_ref::<KvsError>() {
+            Some(KvsError::ConnectionClosed) => {}
+            _ => panic!("Expected KvsError::ConnectionClosed, got {}", err),
+        },
+        Ok(op) => panic!("Expected KvsError::ConnectionClosed, got {:?}", op),
+    }
+
+    Ok(())
+}
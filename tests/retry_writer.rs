@@ -0,0 +1,72 @@
+use kvs::RetryWriter;
+use std::io::{self, ErrorKind, Write};
+
+// Fails its first `failures` writes and flushes with `kind`, then accepts at most 3 bytes per write
+struct FlakyWriter {
+    failures: usize,
+    kind: ErrorKind,
+    written: Vec<u8>,
+}
+
+impl FlakyWriter {
+    fn new(failures: usize, kind: ErrorKind) -> FlakyWriter {
+        FlakyWriter {
+            failures,
+            kind,
+            written: Vec::new(),
+        }
+    }
+
+    fn fail(&mut self) -> io::Result<()> {
+        if self.failures > 0 {
+            self.failures -= 1;
+            return Err(io::Error::new(self.kind, "flaky"));
+        }
+        Ok(())
+    }
+}
+
+impl Write for FlakyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.fail()?;
+        let len = buf.len().min(3);
+        self.written.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.fail()
+    }
+}
+
+// Transient failures are retried until the write gets through, with no bytes lost or repeated
+#[test]
+fn retries_transient_failures() {
+    for kind in [ErrorKind::Interrupted, ErrorKind::WouldBlock] {
+        let mut writer = RetryWriter::new(FlakyWriter::new(3, kind), 3);
+        writer.write_all(b"some record").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.get_ref().written, b"some record");
+    }
+
+    let mut writer = RetryWriter::new(FlakyWriter::new(1, ErrorKind::WouldBlock), 3);
+    writer.flush().unwrap();
+}
+
+// Once the retries run out the last error is returned, and other errors aren't retried at all
+#[test]
+fn gives_up() {
+    let mut writer = RetryWriter::new(FlakyWriter::new(4, ErrorKind::WouldBlock), 3);
+    assert_eq!(
+        writer.write(b"some record").unwrap_err().kind(),
+        ErrorKind::WouldBlock
+    );
+    assert!(writer.get_ref().written.is_empty());
+
+    let mut writer = RetryWriter::new(FlakyWriter::new(1, ErrorKind::PermissionDenied), 3);
+    assert_eq!(
+        writer.write(b"some record").unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    assert_eq!(writer.write(b"abc").unwrap(), 3);
+}
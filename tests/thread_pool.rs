@@ -1,5 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
 use kvs::Result;
@@ -64,7 +66,69 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn naive_thread_pool_bounds_threads() -> Result<()> {
+    const THREADS: usize = 3;
+
+    let pool = NaiveThreadPool::new(THREADS)?;
+    let wg = WaitGroup::new();
+    let running = Arc::new(AtomicUsize::new(0));
+    let most_running = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..20 {
+        let running = Arc::clone(&running);
+        let most_running = Arc::clone(&most_running);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most_running.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            running.fetch_sub(1, Ordering::SeqCst);
+            drop(wg);
+        })
+    }
+
+    wg.wait();
+    assert!(most_running.load(Ordering::SeqCst) <= THREADS);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_queue_len() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    let (release, blocked) = mpsc::channel::<()>();
+    pool.spawn(move || {
+        let _ = blocked.recv();
+    });
+    for _ in 0..5 {
+        pool.spawn(|| {});
+    }
+    wait_for_queue_len(&pool, 5);
+
+    release.send(()).unwrap();
+    wait_for_queue_len(&pool, 0);
+    Ok(())
+}
+
+fn wait_for_queue_len(pool: &SharedQueueThreadPool, expected: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while pool.queue_len() != expected {
+        assert!(Instant::now() < deadline, "queue length stuck at {}, expected {}", pool.queue_len(), expected);
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn naive_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<NaiveThreadPool>()
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
@@ -0,0 +1,100 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvsClient, KvsEngine, KvsError, Result, ServerConfig, ServerHandle, TimeoutEngine};
+use slog::{o, Discard, Logger};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// In-memory engine which stalls for a second on any key starting with "slow"
+#[derive(Clone, Default)]
+struct SlowEngine {
+    map: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl SlowEngine {
+    fn stall(k: &str) {
+        if k.starts_with("slow") {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+impl KvsEngine for SlowEngine {
+    fn set(&self, k: String, v: String) -> Result<()> {
+        SlowEngine::stall(&k);
+        self.map.lock().unwrap().insert(k, v);
+        Ok(())
+    }
+
+    fn get(&self, k: String) -> Result<Option<String>> {
+        SlowEngine::stall(&k);
+        Ok(self.map.lock().unwrap().get(&k).cloned())
+    }
+
+    fn remove(&self, k: String) -> Result<()> {
+        SlowEngine::stall(&k);
+        self.map.lock().unwrap().remove(&k);
+        Ok(())
+    }
+}
+
+fn is_timeout(result: Result<Option<String>>) -> bool {
+    match result {
+        Err(e) => matches!(e.downcast_ref::<KvsError>(), Some(KvsError::Timeout(_))),
+        Ok(_) => false,
+    }
+}
+
+// A stalled operation fails once the timeout passes rather than when the engine gets round to it
+#[test]
+fn slow_operation_times_out() -> Result<()> {
+    let store = TimeoutEngine::new(SlowEngine::default(), Some(Duration::from_millis(100)));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let start = Instant::now();
+    assert!(is_timeout(store.get("slow1".to_owned())));
+    assert!(start.elapsed() < Duration::from_millis(900));
+    Ok(())
+}
+
+// With no timeout the wrapper waits as long as the engine takes
+#[test]
+fn no_timeout_waits() -> Result<()> {
+    let store = TimeoutEngine::new(SlowEngine::default(), None);
+    store.set("slow1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("slow1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The server answers a request whose operation times out, and keeps serving the rest
+#[test]
+fn server_answers_despite_slow_engine() -> Result<()> {
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let config = ServerConfig {
+        addr: Some(addr.to_string()),
+        op_timeout_ms: Some(100),
+        ..ServerConfig::default()
+    };
+    let pool = SharedQueueThreadPool::new(2)?;
+    let log = Logger::root(Discard, o!());
+    thread::spawn(move || kvs::run_server(&config, log, SlowEngine::default(), pool, ServerHandle::new()));
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(addr).is_err() {
+        assert!(Instant::now() < deadline, "server did not start listening on {}", addr);
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let client = KvsClient::new(Logger::root(Discard, o!()), addr);
+    let start = Instant::now();
+    let err = client.get("slow1".to_owned()).expect_err("the slow get should fail");
+    assert!(err.to_string().contains("took longer than 100ms"), "unexpected error {}", err);
+    assert!(start.elapsed() < Duration::from_millis(900));
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}